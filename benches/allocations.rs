@@ -0,0 +1,102 @@
+//! Counts the heap allocations made verifying and serializing a macaroon with many caveats
+//!
+//! Run with `cargo bench --features bench --bench allocations`. The counts are exact and
+//! deterministic, so this is a check on the allocation behaviour of the hot paths rather than a
+//! timing benchmark (for which see `benches/macaroon.rs`).
+use macaroon::bench_support::{self, fixture, predicate};
+use macaroon::{Format, Macaroon};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const FIRST_PARTY_CAVEATS: usize = 100;
+const THIRD_PARTY_CAVEATS: usize = 10;
+// Few enough for the caveats to be held inline
+const SMALL_CAVEATS: usize = 4;
+
+fn count<T, F: FnOnce() -> T>(name: &str, f: F) -> T {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let result = f();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!("{:<12} {:>6} allocations", name, allocations);
+    result
+}
+
+fn main() {
+    macaroon::initialize().unwrap();
+    let fixture = fixture(FIRST_PARTY_CAVEATS, THIRD_PARTY_CAVEATS);
+    let key = macaroon::bench_support::key();
+
+    println!(
+        "{} first-party and {} third-party caveats:",
+        FIRST_PARTY_CAVEATS, THIRD_PARTY_CAVEATS
+    );
+    let verified = count("verify", || {
+        fixture
+            .verifier
+            .verify(&fixture.macaroon, &key, &fixture.discharges)
+            .unwrap()
+    });
+    assert!(verified);
+    count("serialize V2", || {
+        fixture.macaroon.serialize(Format::V2).unwrap()
+    });
+    count("serialize V2J", || {
+        fixture.macaroon.serialize(Format::V2J).unwrap()
+    });
+
+    let predicates: Vec<String> = (0..SMALL_CAVEATS).map(predicate).collect();
+    let small = bench_support::fixture(SMALL_CAVEATS, 0);
+    println!("\n{} first-party caveats:", SMALL_CAVEATS);
+    count("mint", || {
+        let mut macaroon =
+            Macaroon::create_with_key(bench_support::LOCATION, &key, "benchmark").unwrap();
+        for predicate in &predicates {
+            macaroon.add_first_party_caveat(predicate);
+        }
+        macaroon
+    });
+    let verified = count("verify", || {
+        small
+            .verifier
+            .verify(&small.macaroon, &key, &small.discharges)
+            .unwrap()
+    });
+    assert!(verified);
+
+    #[cfg(feature = "arena")]
+    {
+        let serialized = small.macaroon.serialize(Format::V2).unwrap();
+        let mut arena = macaroon::arena::MacaroonArena::new();
+        arena.deserialize(&serialized).unwrap();
+        arena.reset();
+        count("arena parse", || {
+            arena.deserialize(&serialized).unwrap();
+        });
+        count("ref parse", || {
+            macaroon::MacaroonRef::deserialize(&serialized).unwrap();
+        });
+    }
+}
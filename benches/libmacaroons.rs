@@ -0,0 +1,384 @@
+//! Interoperability and performance comparison with the C libmacaroons
+//!
+//! Run with `cargo bench --features interop --bench libmacaroons`. libmacaroons is loaded at
+//! run time (from `LIBMACAROONS_PATH` if that's set, otherwise from the usual library search
+//! path), and the comparison is skipped if it can't be found.
+//!
+//! For each scenario in the matrix, the same macaroon is minted by both libraries. Without
+//! third-party caveats the two should be byte-for-byte identical: the signatures are compared,
+//! as are libmacaroons' serializations and ours (`canonical_bytes`) in each format. Third-party
+//! caveats have random nonces, so for those each library deserializes the other's macaroon
+//! instead, and we discharge and verify libmacaroons' macaroon, which checks that its verifier
+//! ids have the layout we expect. Then minting and serializing are timed in each library.
+use libloading::{Library, Symbol};
+use macaroon::bench_support::predicate;
+use macaroon::{Format, Macaroon, MacaroonKey, VerifierBuilder};
+use std::env;
+use std::os::raw::{c_int, c_uchar};
+use std::ptr;
+use std::time::{Duration, Instant};
+
+const LOCATION: &str = "http://example.org/";
+const KEY: &[u8] = b"interop root key";
+const IDENTIFIER: &str = "interop";
+const CAVEAT_LOCATION: &str = "http://auth.example.org/";
+const CAVEAT_KEY: &[u8] = b"interop caveat key";
+const ITERATIONS: u32 = 1000;
+
+// enum macaroon_returncode
+const MACAROON_SUCCESS: c_int = 2048;
+
+// enum macaroon_format
+const FORMATS: &[(&str, Format, c_int)] = &[
+    ("V1", Format::V1, 0),
+    ("V2", Format::V2, 1),
+    ("V2J", Format::V2J, 2),
+];
+
+#[repr(C)]
+struct CMacaroon {
+    _private: [u8; 0],
+}
+
+type Create = unsafe extern "C" fn(
+    *const c_uchar,
+    usize,
+    *const c_uchar,
+    usize,
+    *const c_uchar,
+    usize,
+    *mut c_int,
+) -> *mut CMacaroon;
+type AddFirstParty =
+    unsafe extern "C" fn(*const CMacaroon, *const c_uchar, usize, *mut c_int) -> *mut CMacaroon;
+type AddThirdParty = unsafe extern "C" fn(
+    *const CMacaroon,
+    *const c_uchar,
+    usize,
+    *const c_uchar,
+    usize,
+    *const c_uchar,
+    usize,
+    *mut c_int,
+) -> *mut CMacaroon;
+type Destroy = unsafe extern "C" fn(*mut CMacaroon);
+type Signature = unsafe extern "C" fn(*const CMacaroon, *mut *const c_uchar, *mut usize);
+type SerializeSizeHint = unsafe extern "C" fn(*const CMacaroon, c_int) -> usize;
+type Serialize =
+    unsafe extern "C" fn(*const CMacaroon, c_int, *mut c_uchar, usize, *mut c_int) -> usize;
+type Deserialize = unsafe extern "C" fn(*const c_uchar, usize, *mut c_int) -> *mut CMacaroon;
+
+// The libmacaroons functions the harness uses
+struct LibMacaroons<'lib> {
+    create: Symbol<'lib, Create>,
+    add_first_party: Symbol<'lib, AddFirstParty>,
+    add_third_party: Symbol<'lib, AddThirdParty>,
+    destroy: Symbol<'lib, Destroy>,
+    signature: Symbol<'lib, Signature>,
+    serialize_size_hint: Symbol<'lib, SerializeSizeHint>,
+    serialize: Symbol<'lib, Serialize>,
+    deserialize: Symbol<'lib, Deserialize>,
+}
+
+// A macaroon owned by libmacaroons
+struct Owned<'a, 'lib> {
+    lib: &'a LibMacaroons<'lib>,
+    macaroon: *mut CMacaroon,
+}
+
+impl Drop for Owned<'_, '_> {
+    fn drop(&mut self) {
+        unsafe { (self.lib.destroy)(self.macaroon) }
+    }
+}
+
+fn check(what: &str, macaroon: *mut CMacaroon, error: c_int) -> *mut CMacaroon {
+    assert!(
+        !macaroon.is_null() && error == MACAROON_SUCCESS,
+        "libmacaroons {} failed with {}",
+        what,
+        error
+    );
+    macaroon
+}
+
+impl<'lib> LibMacaroons<'lib> {
+    fn load(library: &'lib Library) -> Result<LibMacaroons<'lib>, libloading::Error> {
+        unsafe {
+            Ok(LibMacaroons {
+                create: library.get(b"macaroon_create")?,
+                add_first_party: library.get(b"macaroon_add_first_party_caveat")?,
+                add_third_party: library.get(b"macaroon_add_third_party_caveat")?,
+                destroy: library.get(b"macaroon_destroy")?,
+                signature: library.get(b"macaroon_signature")?,
+                serialize_size_hint: library.get(b"macaroon_serialize_size_hint")?,
+                serialize: library.get(b"macaroon_serialize")?,
+                deserialize: library.get(b"macaroon_deserialize")?,
+            })
+        }
+    }
+
+    fn mint(&self, scenario: &Scenario) -> Owned<'_, 'lib> {
+        let mut error = 0;
+        let location = scenario.location.unwrap_or("");
+        let mut macaroon = Owned {
+            lib: self,
+            macaroon: check(
+                "create",
+                unsafe {
+                    (self.create)(
+                        location.as_ptr(),
+                        location.len(),
+                        KEY.as_ptr(),
+                        KEY.len(),
+                        IDENTIFIER.as_ptr(),
+                        IDENTIFIER.len(),
+                        &mut error,
+                    )
+                },
+                error,
+            ),
+        };
+        for n in 0..scenario.first_party {
+            let predicate = predicate(n);
+            let next = unsafe {
+                (self.add_first_party)(
+                    macaroon.macaroon,
+                    predicate.as_ptr(),
+                    predicate.len(),
+                    &mut error,
+                )
+            };
+            macaroon = Owned {
+                lib: self,
+                macaroon: check("add_first_party_caveat", next, error),
+            };
+        }
+        for n in 0..scenario.third_party {
+            let id = format!("caveat {}", n);
+            let next = unsafe {
+                (self.add_third_party)(
+                    macaroon.macaroon,
+                    CAVEAT_LOCATION.as_ptr(),
+                    CAVEAT_LOCATION.len(),
+                    CAVEAT_KEY.as_ptr(),
+                    CAVEAT_KEY.len(),
+                    id.as_ptr(),
+                    id.len(),
+                    &mut error,
+                )
+            };
+            macaroon = Owned {
+                lib: self,
+                macaroon: check("add_third_party_caveat", next, error),
+            };
+        }
+        macaroon
+    }
+
+    fn signature(&self, macaroon: &Owned) -> Vec<u8> {
+        let mut signature = ptr::null();
+        let mut size = 0;
+        unsafe {
+            (self.signature)(macaroon.macaroon, &mut signature, &mut size);
+            std::slice::from_raw_parts(signature, size).to_vec()
+        }
+    }
+
+    fn serialize(&self, macaroon: &Owned, format: c_int) -> Vec<u8> {
+        let mut error = 0;
+        unsafe {
+            let mut buffer = vec![0; (self.serialize_size_hint)(macaroon.macaroon, format)];
+            let size = (self.serialize)(
+                macaroon.macaroon,
+                format,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut error,
+            );
+            assert_eq!(MACAROON_SUCCESS, error, "libmacaroons serialize failed");
+            buffer.truncate(size);
+            buffer
+        }
+    }
+
+    fn deserialize(&self, data: &[u8]) -> Option<Owned<'_, 'lib>> {
+        let mut error = 0;
+        let macaroon = unsafe { (self.deserialize)(data.as_ptr(), data.len(), &mut error) };
+        match macaroon.is_null() || error != MACAROON_SUCCESS {
+            true => None,
+            false => Some(Owned {
+                lib: self,
+                macaroon,
+            }),
+        }
+    }
+}
+
+struct Scenario {
+    location: Option<&'static str>,
+    first_party: usize,
+    third_party: usize,
+}
+
+impl Scenario {
+    fn name(&self) -> String {
+        format!(
+            "{} first-party, {} third-party{}",
+            self.first_party,
+            self.third_party,
+            if self.location.is_some() {
+                ""
+            } else {
+                ", no location"
+            }
+        )
+    }
+
+    fn mint(&self) -> Macaroon {
+        let mut macaroon = Macaroon::create(self.location.unwrap_or(""), KEY, IDENTIFIER).unwrap();
+        for n in 0..self.first_party {
+            macaroon.add_first_party_caveat(&predicate(n));
+        }
+        for n in 0..self.third_party {
+            macaroon.add_third_party_caveat(CAVEAT_LOCATION, CAVEAT_KEY, &format!("caveat {}", n));
+        }
+        macaroon
+    }
+
+    // Discharges the macaroon's third-party caveats and verifies it
+    fn verify(&self, macaroon: &Macaroon) -> bool {
+        let mut builder = VerifierBuilder::new();
+        for n in 0..self.first_party {
+            builder.satisfy_exact(&predicate(n));
+        }
+        let discharges: Vec<Macaroon> = (0..self.third_party)
+            .map(|n| {
+                let mut discharge =
+                    Macaroon::create(CAVEAT_LOCATION, CAVEAT_KEY, &format!("caveat {}", n))
+                        .unwrap();
+                macaroon.bind(&mut discharge);
+                discharge
+            })
+            .collect();
+        builder
+            .build()
+            .verify(macaroon, &MacaroonKey::generate(KEY), &discharges)
+            .unwrap_or(false)
+    }
+}
+
+fn scenarios() -> Vec<Scenario> {
+    let mut scenarios = Vec::new();
+    for &location in &[Some(LOCATION), None] {
+        for &(first_party, third_party) in &[(0, 0), (1, 0), (10, 0), (100, 0), (1, 1), (10, 3)] {
+            scenarios.push(Scenario {
+                location,
+                first_party,
+                third_party,
+            });
+        }
+    }
+    scenarios
+}
+
+fn load() -> Option<Library> {
+    let names = match env::var("LIBMACAROONS_PATH") {
+        Ok(path) => vec![path],
+        Err(_) => vec![
+            libloading::library_filename("macaroons")
+                .to_string_lossy()
+                .into_owned(),
+            String::from("libmacaroons.so.0"),
+        ],
+    };
+    names
+        .iter()
+        .find_map(|name| unsafe { Library::new(name) }.ok())
+}
+
+// Compares the two libraries on a scenario, returning the differences
+fn compare(lib: &LibMacaroons, scenario: &Scenario) -> Vec<String> {
+    let mut differences = Vec::new();
+    let ours = scenario.mint();
+    let theirs = lib.mint(scenario);
+    for &(name, format, c_format) in FORMATS {
+        let our_bytes = ours.canonical_bytes(format).unwrap();
+        let their_bytes = lib.serialize(&theirs, c_format);
+        if scenario.third_party == 0 && our_bytes != their_bytes {
+            differences.push(format!("{} serializations differ", name));
+        }
+        match Macaroon::deserialize(&their_bytes) {
+            Ok(parsed) if parsed.signature().as_bytes()[..] != lib.signature(&theirs)[..] => {
+                differences.push(format!("{} from libmacaroons: wrong signature", name))
+            }
+            Ok(parsed) if !scenario.verify(&parsed) => {
+                differences.push(format!("{} from libmacaroons: doesn't verify", name))
+            }
+            Ok(_) => (),
+            Err(error) => differences.push(format!("{} from libmacaroons: {}", name, error)),
+        }
+        match lib.deserialize(&ours.serialize(format).unwrap()) {
+            Some(parsed) if lib.signature(&parsed)[..] == ours.signature().as_bytes()[..] => (),
+            Some(_) => differences.push(format!("{} to libmacaroons: wrong signature", name)),
+            None => differences.push(format!("{} to libmacaroons: rejected", name)),
+        }
+    }
+    if scenario.third_party == 0 && ours.signature().as_bytes()[..] != lib.signature(&theirs)[..] {
+        differences.push(String::from("signatures differ"));
+    }
+    differences
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let library = match load() {
+        Some(library) => library,
+        None => {
+            println!("libmacaroons not found (set LIBMACAROONS_PATH to its location), skipping");
+            return;
+        }
+    };
+    let lib = LibMacaroons::load(&library).expect("libmacaroons is missing functions");
+    macaroon::initialize().unwrap();
+
+    let mut incompatible = 0;
+    println!(
+        "{:<40} {:>12} {:>12} {:>8}  compatibility",
+        "scenario", "ours", "libmacaroons", "ratio"
+    );
+    for scenario in scenarios() {
+        let differences = compare(&lib, &scenario);
+        let ours = time(|| {
+            scenario.mint().serialize(Format::V2).unwrap();
+        });
+        let theirs = time(|| {
+            lib.serialize(&lib.mint(&scenario), 1);
+        });
+        println!(
+            "{:<40} {:>10.1?} {:>12.1?} {:>8.2}  {}",
+            scenario.name(),
+            ours,
+            theirs,
+            ours.as_secs_f64() / theirs.as_secs_f64(),
+            match differences.is_empty() {
+                true => String::from("ok"),
+                false => differences.join("; "),
+            }
+        );
+        if !differences.is_empty() {
+            incompatible += 1;
+        }
+    }
+    if incompatible > 0 {
+        println!("{} scenarios incompatible", incompatible);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,139 @@
+//! Timing benchmarks for minting, serialization and verification
+//!
+//! Run with `cargo bench --features bench --bench macaroon`.
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use macaroon::bench_support::{self, fixture, predicate};
+use macaroon::oven::{MemoryRootKeyStore, Oven};
+use macaroon::{
+    DerivedKey, Format, Macaroon, MacaroonKey, Verifier, VerifierBuilder, VerifyContext,
+};
+
+const FORMATS: &[(&str, Format)] = &[("V1", Format::V1), ("V2", Format::V2), ("V2J", Format::V2J)];
+
+fn mint(c: &mut Criterion) {
+    c.bench_function("mint", |b| {
+        b.iter(|| {
+            Macaroon::create(
+                black_box(bench_support::LOCATION),
+                black_box(bench_support::ROOT_KEY),
+                black_box("benchmark"),
+            )
+        })
+    });
+    let predicates: Vec<String> = (0..100).map(predicate).collect();
+    c.bench_function("add 100 caveats", |b| {
+        b.iter_batched(
+            || {
+                Macaroon::create(
+                    bench_support::LOCATION,
+                    bench_support::ROOT_KEY,
+                    "benchmark",
+                )
+                .unwrap()
+            },
+            |mut macaroon| {
+                for predicate in &predicates {
+                    macaroon.add_first_party_caveat(predicate);
+                }
+                macaroon
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn serialization(c: &mut Criterion) {
+    let macaroon = fixture(100, 10).macaroon;
+    let mut group = c.benchmark_group("serialize");
+    for &(name, format) in FORMATS {
+        group.bench_function(name, |b| b.iter(|| macaroon.serialize(black_box(format))));
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("deserialize");
+    for &(name, format) in FORMATS {
+        let serialized = macaroon.serialize(format).unwrap();
+        group.bench_function(name, |b| {
+            b.iter(|| Macaroon::deserialize(black_box(&serialized)))
+        });
+    }
+    group.finish();
+}
+
+fn verification(c: &mut Criterion) {
+    let mut group = c.benchmark_group("verify");
+    let first_party = fixture(100, 0);
+    group.bench_function("100 first-party caveats", |b| {
+        b.iter(|| first_party.verify())
+    });
+    let third_party = fixture(100, 10);
+    group.bench_function("100 first-party and 10 third-party caveats", |b| {
+        b.iter(|| third_party.verify())
+    });
+    group.finish();
+}
+
+// Deriving the key from the root key on every call, against deriving it once
+fn derived_keys(c: &mut Criterion) {
+    let key: DerivedKey = MacaroonKey::generate(bench_support::ROOT_KEY);
+    let mut group = c.benchmark_group("create with");
+    group.bench_function("root key", |b| {
+        b.iter(|| {
+            Macaroon::create(
+                bench_support::LOCATION,
+                black_box(bench_support::ROOT_KEY),
+                "benchmark",
+            )
+        })
+    });
+    group.bench_function("derived key", |b| {
+        b.iter(|| Macaroon::create_with_key(bench_support::LOCATION, black_box(&key), "benchmark"))
+    });
+    group.finish();
+
+    let macaroon = Macaroon::create_with_key(bench_support::LOCATION, &key, "benchmark").unwrap();
+    let verifier = Verifier::default();
+    let mut group = c.benchmark_group("verify with");
+    group.bench_function("root key", |b| {
+        b.iter(|| {
+            let key = MacaroonKey::generate(black_box(bench_support::ROOT_KEY));
+            verifier.verify(&macaroon, &key, &[])
+        })
+    });
+    group.bench_function("derived key", |b| {
+        b.iter(|| verifier.verify(black_box(&macaroon), &key, &[]))
+    });
+    // A verifier with a domain personalizes each root key once
+    let personalized = Macaroon::create_with_key(
+        bench_support::LOCATION,
+        &key.with_domain("benchmark"),
+        "benchmark",
+    )
+    .unwrap();
+    group.bench_function("domain, personalized every call", |b| {
+        b.iter(|| {
+            let key = key.with_domain(black_box("benchmark"));
+            verifier.verify(&personalized, &key, &[])
+        })
+    });
+    let mut builder = VerifierBuilder::new();
+    builder.set_domain("benchmark");
+    let with_domain = builder.build();
+    group.bench_function("domain, personalized once", |b| {
+        b.iter(|| with_domain.verify(black_box(&personalized), &key, &[]))
+    });
+    group.finish();
+
+    // The oven derives each root key once, by id
+    let mut store = MemoryRootKeyStore::new();
+    store.insert("1", bench_support::ROOT_KEY);
+    let oven = Oven::new(bench_support::LOCATION, store);
+    let minted = oven.mint(None).unwrap();
+    let context = VerifyContext::new();
+    c.bench_function("oven verify", |b| {
+        b.iter(|| oven.verify(&verifier, black_box(&minted), &[], &context))
+    });
+}
+
+criterion_group!(benches, mint, serialization, verification, derived_keys);
+criterion_main!(benches);
@@ -0,0 +1,115 @@
+//! Deserializing macaroons into an arena
+//!
+//! `MacaroonRef` already avoids copying a macaroon's fields, but still allocates its list of
+//! caveats, and borrows the serialized macaroon, which a service has usually had to decode from
+//! base64 first. A `MacaroonArena` puts both of these in a bump allocator instead, so that a
+//! service deserializing a macaroon for every request can keep one arena per worker thread,
+//! `reset` it between requests, and after the first few requests not allocate at all.
+//!
+//! As with `MacaroonRef`, only version 2 macaroons can be parsed, either binary or encoded as
+//! base64 text (as they are in HTTP headers). Decoding base64 still allocates a buffer, which is
+//! freed before `deserialize` returns.
+use crate::{error::MacaroonError, macaroon_ref::MacaroonRef, serialization::v2};
+use bumpalo::Bump;
+use std::borrow::Cow;
+
+/// An arena which macaroons are parsed into
+///
+/// The macaroons parsed borrow from the arena, so it can't be reset until they've all been
+/// dropped.
+#[derive(Debug, Default)]
+pub struct MacaroonArena {
+    bump: Bump,
+}
+
+impl MacaroonArena {
+    /// Create an empty arena, which allocates its first chunk of memory when it's first used
+    pub fn new() -> MacaroonArena {
+        Default::default()
+    }
+
+    /// Create an arena with room for this many bytes before it has to allocate any more
+    pub fn with_capacity(bytes: usize) -> MacaroonArena {
+        MacaroonArena {
+            bump: Bump::with_capacity(bytes),
+        }
+    }
+
+    /// Parse a version 2 macaroon, binary or as base64 text, into the arena
+    ///
+    /// This is as lenient as `MacaroonRef::deserialize`.
+    pub fn deserialize<'a>(&'a self, data: &[u8]) -> Result<MacaroonRef<'a>, MacaroonError> {
+        #[cfg(feature = "rustc-serialize")]
+        let decoded = match data.first() {
+            Some(0x02) | None => None,
+            Some(_) => crate::serialization::decode_base64_wrapped(
+                data,
+                crate::serialization::ParseMode::Lenient,
+            )?,
+        };
+        #[cfg(feature = "rustc-serialize")]
+        let data = decoded.as_deref().unwrap_or(data);
+        let data: &'a [u8] = self.bump.alloc_slice_copy(data);
+        v2::parse_v2_with(data, None, |caveats| {
+            Cow::Borrowed(self.bump.alloc_slice_copy(caveats))
+        })
+    }
+
+    /// The number of bytes the arena has allocated, all of which `reset` keeps for reuse
+    pub fn allocated_bytes(&self) -> usize {
+        self.bump.allocated_bytes()
+    }
+
+    /// Free everything parsed into the arena, for parsing more
+    pub fn reset(&mut self) {
+        self.bump.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MacaroonArena;
+    use crate::{error::MacaroonError, Format, Macaroon, MacaroonRef};
+
+    #[test]
+    fn test_arena_deserialize() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("https://auth.mybank.com", b"caveat key", "caveat");
+        let serialized = macaroon.serialize(Format::V2).unwrap();
+
+        let mut arena = MacaroonArena::with_capacity(4096);
+        {
+            let parsed = arena.deserialize(&serialized).unwrap();
+            assert_eq!(MacaroonRef::deserialize(&serialized).unwrap(), parsed);
+            assert_eq!(macaroon, parsed.to_owned().unwrap());
+        }
+        arena.reset();
+        let allocated = arena.allocated_bytes();
+        for _ in 0..100 {
+            let parsed = arena.deserialize(&serialized).unwrap();
+            assert_eq!("keyid", parsed.identifier());
+            assert_eq!(2, parsed.caveats().len());
+            arena.reset();
+        }
+        assert_eq!(allocated, arena.allocated_bytes());
+
+        #[cfg(feature = "rustc-serialize")]
+        {
+            use crate::{Base64Encoding, SerializeOptions};
+
+            let options = SerializeOptions {
+                base64: Some(Base64Encoding::URL_SAFE_NO_PAD),
+                ..SerializeOptions::new(Format::V2)
+            };
+            let text = macaroon.serialize_with(&options).unwrap();
+            let parsed = arena.deserialize(&text).unwrap();
+            assert_eq!(serialized, parsed.as_bytes());
+        }
+
+        match arena.deserialize(b"") {
+            Err(MacaroonError::Truncated { .. }) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Fixtures for benchmarks
+//!
+//! Generates macaroons of a given shape (numbers of first- and third-party caveats), along with
+//! their bound discharges and a verifier which authorizes them, so benchmarks (the crate's own,
+//! in `benches/`, and those of applications) measure the library rather than the setup.
+//! Predicates and identifiers are numbered, so the same shape always gives macaroons of the same
+//! size, although verifier ids (and so signatures) differ from run to run.
+use crate::{Macaroon, MacaroonKey, Verifier};
+
+/// Location of the generated macaroons
+pub const LOCATION: &str = "http://example.org/";
+
+/// Root key of the generated macaroons
+pub const ROOT_KEY: &[u8] = b"benchmark root key";
+
+/// Location of the generated third-party caveats and discharges
+pub const THIRD_PARTY_LOCATION: &str = "http://auth.example.org/";
+
+/// Key of the generated third-party caveats
+pub const CAVEAT_KEY: &[u8] = b"benchmark caveat key";
+
+/// A generated macaroon, with its discharges and a verifier which authorizes it
+pub struct Fixture {
+    pub macaroon: Macaroon,
+    pub discharges: Vec<Macaroon>,
+    pub verifier: Verifier,
+}
+
+impl Fixture {
+    /// Verify the macaroon with its discharges
+    pub fn verify(&self) -> bool {
+        self.verifier
+            .verify(&self.macaroon, &key(), &self.discharges)
+            .unwrap()
+    }
+}
+
+/// The derived root key, for verifying the generated macaroons
+pub fn key() -> MacaroonKey {
+    MacaroonKey::generate(ROOT_KEY)
+}
+
+/// The predicate of the `n`th generated first-party caveat
+pub fn predicate(n: usize) -> String {
+    format!("account = {:08}", n)
+}
+
+/// Generate a macaroon with the given numbers of caveats
+///
+/// Each third-party caveat has a discharge, bound to the macaroon, with one first-party caveat
+/// of its own.
+pub fn fixture(first_party: usize, third_party: usize) -> Fixture {
+    let mut macaroon = Macaroon::create(LOCATION, ROOT_KEY, "benchmark").unwrap();
+    let mut verifier = Verifier::builder();
+    for n in 0..first_party {
+        let predicate = predicate(n);
+        macaroon.add_first_party_caveat(&predicate);
+        verifier.satisfy_exact(&predicate);
+    }
+    let mut discharges = Vec::with_capacity(third_party);
+    for n in 0..third_party {
+        let id = format!("caveat {:08}", n);
+        macaroon.add_third_party_caveat(THIRD_PARTY_LOCATION, CAVEAT_KEY, &id);
+        let mut discharge = Macaroon::create(THIRD_PARTY_LOCATION, CAVEAT_KEY, &id).unwrap();
+        discharge.add_first_party_caveat(&predicate(first_party + n));
+        verifier.satisfy_exact(&predicate(first_party + n));
+        discharges.push(discharge);
+    }
+    for discharge in &mut discharges {
+        macaroon.bind(discharge);
+    }
+    Fixture {
+        macaroon,
+        discharges,
+        verifier: verifier.build(),
+    }
+}
+
+/// Generate a corpus of macaroons of increasing size
+///
+/// The `n`th macaroon has `n * step` first-party caveats, and a third-party caveat for every ten
+/// of those.
+pub fn corpus(count: usize, step: usize) -> Vec<Macaroon> {
+    (0..count)
+        .map(|n| fixture(n * step, n * step / 10).macaroon)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{corpus, fixture};
+
+    #[test]
+    fn test_fixture() {
+        let fixture = fixture(20, 3);
+        assert_eq!(20, fixture.macaroon.first_party_caveats().count());
+        assert_eq!(3, fixture.macaroon.third_party_caveats().count());
+        assert_eq!(3, fixture.discharges.len());
+        assert!(fixture.verify());
+    }
+
+    #[test]
+    fn test_corpus() {
+        let corpus = corpus(4, 10);
+        let sizes: Vec<(usize, usize)> = corpus
+            .iter()
+            .map(|m| {
+                (
+                    m.first_party_caveats().count(),
+                    m.third_party_caveats().count(),
+                )
+            })
+            .collect();
+        assert_eq!(vec![(0, 0), (10, 1), (20, 2), (30, 3)], sizes);
+    }
+}
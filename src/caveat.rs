@@ -1,5 +1,13 @@
-use crate::{crypto, error::MacaroonError, verifier::Verifier, Macaroon};
-use std::fmt::Debug;
+use crate::{
+    crypto, error::MacaroonError, redaction, redaction::Secret, verifier::Verification, Macaroon,
+};
+use smallvec::SmallVec;
+use std::fmt::{self, Debug};
+
+// Most macaroons have only a few caveats, so that many are held inline rather than allocated
+pub(crate) const INLINE_CAVEATS: usize = 8;
+
+pub(crate) type Caveats = SmallVec<[Box<dyn Caveat>; INLINE_CAVEATS]>;
 
 #[derive(PartialEq)]
 pub enum CaveatType {
@@ -7,8 +15,14 @@ pub enum CaveatType {
     ThirdParty,
 }
 
-pub trait Caveat: Debug {
-    fn verify(&self, macaroon: &Macaroon, verifier: &mut Verifier) -> Result<bool, MacaroonError>;
+pub(crate) trait Caveat: Debug + Send + Sync {
+    // The index is the caveat's among those of the macaroon, for reporting it if it fails
+    fn verify(
+        &self,
+        macaroon: &Macaroon,
+        index: usize,
+        verification: &mut Verification,
+    ) -> Result<bool, MacaroonError>;
 
     fn sign(&self, key: &[u8; 32]) -> [u8; 32];
     fn get_type(&self) -> CaveatType;
@@ -49,7 +63,7 @@ impl PartialEq for dyn Caveat {
 /// Struct for a first-party caveat
 #[derive(Clone, Debug, PartialEq)]
 pub struct FirstPartyCaveat {
-    predicate: String,
+    pub(crate) predicate: String,
 }
 
 impl FirstPartyCaveat {
@@ -60,15 +74,24 @@ impl FirstPartyCaveat {
 }
 
 impl Caveat for FirstPartyCaveat {
-    fn verify(&self, macaroon: &Macaroon, verifier: &mut Verifier) -> Result<bool, MacaroonError> {
-        let result = Ok(verifier.verify_predicate(&self.predicate));
+    fn verify(
+        &self,
+        macaroon: &Macaroon,
+        index: usize,
+        verification: &mut Verification,
+    ) -> Result<bool, MacaroonError> {
+        let result = Ok(verification.verify_first_party(&self.predicate));
         if let Ok(false) = result {
-            info!(
+            debug!(
                 "FirstPartyCaveat::verify: Caveat {:?} of macaroon {:?} failed verification",
                 self, macaroon
             );
+            verification.fail(MacaroonError::CaveatNotSatisfied {
+                caveat: redaction::content(&self.predicate),
+                index,
+            });
         }
-        verifier.update_signature(|t| self.sign(t));
+        verification.update_signature(|t| self.sign(t));
         result
     }
 
@@ -94,11 +117,22 @@ impl Caveat for FirstPartyCaveat {
 }
 
 /// Struct for a third-party caveat
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct ThirdPartyCaveat {
-    id: String,
-    verifier_id: Vec<u8>,
-    location: String,
+    pub(crate) id: String,
+    pub(crate) verifier_id: Vec<u8>,
+    pub(crate) location: String,
+}
+
+// The verifier id is the caveat key, encrypted with the signature so far
+impl Debug for ThirdPartyCaveat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThirdPartyCaveat")
+            .field("id", &self.id)
+            .field("verifier_id", &Secret(&self.verifier_id))
+            .field("location", &self.location)
+            .finish()
+    }
 }
 
 impl ThirdPartyCaveat {
@@ -116,23 +150,52 @@ impl ThirdPartyCaveat {
     pub fn location(&self) -> String {
         self.location.clone()
     }
+
+    // Decrypt the caveat key from the verifier id, given the signature chain up to the caveat
+    pub(crate) fn decrypt_key(&self, signature: [u8; 32]) -> Result<[u8; 32], MacaroonError> {
+        let error = |reason| MacaroonError::CaveatKeyDecryption {
+            id: redaction::identifier(&self.id),
+            location: redaction::content(&self.location),
+            reason,
+        };
+        let key = crypto::decrypt(signature, &self.verifier_id).map_err(|e| {
+            error(match e {
+                MacaroonError::Crypto(reason) => reason,
+                _ => "decryption failed",
+            })
+        })?;
+        // A crafted verifier id can hold a key of any length, which mustn't reach the HMAC
+        if key.len() > 32 {
+            return Err(error("caveat key is longer than 32 bytes"));
+        }
+        crypto::key_array(&key)
+    }
 }
 
 impl Caveat for ThirdPartyCaveat {
-    fn verify(&self, macaroon: &Macaroon, verifier: &mut Verifier) -> Result<bool, MacaroonError> {
-        let result = verifier.verify_caveat(self, macaroon);
+    fn verify(
+        &self,
+        macaroon: &Macaroon,
+        index: usize,
+        verification: &mut Verification,
+    ) -> Result<bool, MacaroonError> {
+        let result = verification.verify_caveat(self);
         if let Ok(false) = result {
-            info!(
+            debug!(
                 "ThirdPartyCaveat::verify: Caveat {:?} of macaroon {:?} failed verification",
                 self, macaroon
             );
+            verification.fail(MacaroonError::CaveatNotSatisfied {
+                caveat: redaction::identifier(&self.id),
+                index,
+            });
         }
-        verifier.update_signature(|t| self.sign(t));
+        verification.update_signature(|t| self.sign(t));
         result
     }
 
     fn sign(&self, key: &[u8; 32]) -> [u8; 32] {
-        crypto::hmac2(key, &self.verifier_id, self.id.as_bytes())
+        crypto::hmac_concat(key, &self.verifier_id, self.id.as_bytes())
     }
 
     fn get_type(&self) -> CaveatType {
@@ -182,6 +245,7 @@ impl CaveatBuilder {
         self.id = Some(id);
     }
 
+    #[cfg(feature = "v1")]
     pub fn has_id(&self) -> bool {
         self.id.is_some()
     }
@@ -194,32 +258,19 @@ impl CaveatBuilder {
         self.location = Some(location);
     }
 
-    pub fn has_location(&self) -> bool {
-        self.location.is_some()
-    }
-
     pub fn build(self) -> Result<Box<dyn Caveat>, MacaroonError> {
-        if self.id.is_none() {
-            return Err(MacaroonError::BadMacaroon("No identifier found"));
-        }
-        if self.verifier_id.is_none() && self.location.is_none() {
-            return Ok(Box::new(new_first_party(&self.id.unwrap())));
-        }
-        if self.verifier_id.is_some() && self.location.is_some() {
-            return Ok(Box::new(new_third_party(
-                &self.id.unwrap(),
-                self.verifier_id.unwrap(),
-                &self.location.unwrap(),
-            )));
-        }
-        if self.verifier_id.is_none() {
-            return Err(MacaroonError::BadMacaroon(
-                "Location but no verifier ID found",
-            ));
+        let id = match self.id {
+            Some(id) => id,
+            None => return Err(MacaroonError::MissingField("caveat identifier")),
+        };
+        match (self.verifier_id, self.location) {
+            (None, None) => Ok(Box::new(new_first_party(&id))),
+            (Some(verifier_id), Some(location)) => {
+                Ok(Box::new(new_third_party(&id, verifier_id, &location)))
+            }
+            (None, Some(_)) => Err(MacaroonError::MissingField("caveat verifier id")),
+            (Some(_), None) => Err(MacaroonError::MissingField("caveat location")),
         }
-        Err(MacaroonError::BadMacaroon(
-            "Verifier ID but no location found",
-        ))
     }
 }
 
@@ -0,0 +1,148 @@
+//! go-macaroon-bakery cookies
+//!
+//! Web clients of go-macaroon-bakery services (Juju and candid, say) keep the macaroons they
+//! acquire in cookies whose names start with `macaroon-`. Each holds one stack - a macaroon
+//! followed by its discharges - as a JSON array of version 2J serializations, base64-encoded with
+//! the standard alphabet. Services look at every cookie with the prefix, so the rest of the name
+//! only has to keep different stacks apart: `cookie_name` derives it from the macaroon's
+//! identifier.
+//!
+//! Only version 2J serializations are supported, not the version 1 JSON format used by versions
+//! of go-macaroon-bakery before v2.
+use crate::{
+    error::{base64_field, MacaroonError},
+    Macaroon,
+};
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use sodiumoxide::crypto::hash::sha256;
+
+/// The prefix of the names of cookies which hold macaroons
+pub const COOKIE_PREFIX: &str = "macaroon-";
+
+/// The name of the cookie to keep a macaroon's stack in
+///
+/// This is the prefix followed by the SHA-256 hash of the identifier in hex, so it's the same
+/// whenever the same macaroon (or another with the same identifier) is stored, and only uses
+/// characters which are valid in a cookie name.
+pub fn cookie_name(macaroon: &Macaroon) -> String {
+    let sha256::Digest(digest) = sha256::hash(macaroon.identifier().as_bytes());
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}{}", COOKIE_PREFIX, hex)
+}
+
+/// Encode a macaroon and its discharges as a cookie value
+///
+/// # Errors
+/// Returns `MacaroonError::MissingField` if the stack is empty.
+pub fn encode_cookie(stack: &[Macaroon]) -> Result<String, MacaroonError> {
+    if stack.is_empty() {
+        return Err(MacaroonError::MissingField("macaroon"));
+    }
+    Ok(Macaroon::serialize_slice(stack)?.to_base64(STANDARD))
+}
+
+/// Decode a cookie value into a macaroon and its discharges
+///
+/// Either base64 alphabet is accepted, with or without padding.
+///
+/// # Errors
+/// Returns `MacaroonError::MissingField` if the cookie holds no macaroons, and otherwise passes
+/// through any error decoding the cookie or deserializing the macaroons in it.
+pub fn decode_cookie(value: &str) -> Result<Vec<Macaroon>, MacaroonError> {
+    let macaroons = Macaroon::deserialize_slice(&base64_field("cookie", value)?)?;
+    if macaroons.is_empty() {
+        return Err(MacaroonError::MissingField("macaroon"));
+    }
+    Ok(macaroons)
+}
+
+/// The stacks in the macaroon cookies of a `Cookie` request header
+///
+/// Other cookies are ignored. As in go-macaroon-bakery, so are macaroon cookies which can't be
+/// decoded, since one stale or corrupt cookie shouldn't stop the others being used.
+pub fn stacks_from_header(header: &str) -> Vec<Vec<Macaroon>> {
+    let mut stacks = Vec::new();
+    for cookie in header.split(';') {
+        let (name, value) = match cookie.trim().split_once('=') {
+            Some((name, value)) if name.starts_with(COOKIE_PREFIX) => (name, value),
+            _ => continue,
+        };
+        match decode_cookie(value.trim_matches('"')) {
+            Ok(stack) => stacks.push(stack),
+            Err(error) => info!(
+                "cookie::stacks_from_header: Ignoring cookie {}: {}",
+                name, error
+            ),
+        }
+    }
+    stacks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cookie_name, decode_cookie, encode_cookie, stacks_from_header, COOKIE_PREFIX};
+    use crate::testing::{self, discharged_stack};
+    use crate::{error::MacaroonError, MacaroonKey, Verifier};
+
+    #[test]
+    fn test_cookie_name() {
+        let stack = discharged_stack();
+        let name = cookie_name(&stack[0]);
+        assert!(name.starts_with(COOKIE_PREFIX));
+        assert_eq!(COOKIE_PREFIX.len() + 64, name.len());
+        assert!(name[COOKIE_PREFIX.len()..]
+            .bytes()
+            .all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(name, cookie_name(&stack[0].clone()));
+        assert_ne!(name, cookie_name(&stack[1]));
+    }
+
+    #[test]
+    fn test_encode_decode_cookie() {
+        let stack = discharged_stack();
+        let cookie = encode_cookie(&stack).unwrap();
+        let decoded = decode_cookie(&cookie).unwrap();
+        assert_eq!(stack, decoded);
+        let mut verifier = Verifier::builder();
+        verifier.satisfy_exact("account = 3735928559");
+        assert!(verifier
+            .build()
+            .verify(&decoded[0], &testing::root_key(), &decoded[1..])
+            .unwrap());
+
+        match encode_cookie(&[]) {
+            Err(MacaroonError::MissingField(field)) => assert_eq!("macaroon", field),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        // "[]"
+        assert!(decode_cookie("W10=").is_err());
+        assert!(decode_cookie("not a cookie").unwrap_err().is_malformed());
+    }
+
+    #[test]
+    fn test_decode_bakery_cookie() {
+        // As a go-macaroon-bakery client would set it: standard base64, with padding
+        let cookie = "W3siYyI6W3siaSI6ImFjY291bnQgPSAzNzM1OTI4NTU5In1dLCJpIjoia2V5aWQiLCJsIjoiaHR0\
+                      cDovL2V4YW1wbGUub3JnLyIsInM2NCI6IjlVZ0g5dHh1MzRpX0QzTUdzNElsWXFOaVV6Ml9jem02\
+                      WVhaZHBMMGxuWWMiLCJ2IjoyfV0=";
+        let stack = decode_cookie(cookie).unwrap();
+        assert_eq!(1, stack.len());
+        assert_eq!("keyid", stack[0].identifier());
+        assert!(stack[0].verify_signature(MacaroonKey::generate(b"this is the key").as_ref()));
+    }
+
+    #[test]
+    fn test_stacks_from_header() {
+        let stack = discharged_stack();
+        let header = format!(
+            "session=abc; {}={}; macaroon-stale=bm90IGpzb24; {}=\"{}\"",
+            cookie_name(&stack[0]),
+            encode_cookie(&stack).unwrap(),
+            cookie_name(&stack[1]),
+            encode_cookie(&stack[1..]).unwrap()
+        );
+        let stacks = stacks_from_header(&header);
+        assert_eq!(vec![stack.clone(), stack[1..].to_vec()], stacks);
+        assert!(stacks_from_header("").is_empty());
+    }
+}
@@ -0,0 +1,303 @@
+//! Client-side storage of macaroons and their discharges
+//!
+//! Clients which talk to a service repeatedly (CLI tools and daemons, say) shouldn't have to
+//! acquire discharges from third parties for every request, or every time they restart. A
+//! `CredentialStore` holds the bound stack for each service (the root macaroon followed by its
+//! discharges, as returned by `discharge_all`), keyed by the service's location, and
+//! `discharge_with_store` uses one to only acquire discharges when the stored stack is missing
+//! or has expired.
+//!
+//! There are two implementations: `MemoryCredentialStore`, for the lifetime of a process, and
+//! `FileCredentialStore`, which keeps one file per service in a directory, optionally encrypted.
+use crate::{
+    crypto,
+    discharge::{discharge_all, DischargeAcquirer},
+    error::MacaroonError,
+    serialization::Format,
+    std_caveats, store, Macaroon,
+};
+use sodiumoxide::crypto::hash::sha256;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Storage for bound macaroon stacks, keyed by the location of the service they are for
+///
+/// Stores are shared, so the methods take `&self`, and implementations handle any locking.
+pub trait CredentialStore {
+    /// Returns the stack stored for a location, if there is one
+    fn load(&self, location: &str) -> Result<Option<Vec<Macaroon>>, MacaroonError>;
+
+    /// Store a stack for a location, replacing any stored already
+    fn save(&self, location: &str, stack: &[Macaroon]) -> Result<(), MacaroonError>;
+
+    /// Remove the stack for a location, if there is one
+    fn remove(&self, location: &str) -> Result<(), MacaroonError>;
+}
+
+/// Credential store which keeps stacks in memory
+#[derive(Debug, Default)]
+pub struct MemoryCredentialStore {
+    stacks: Mutex<HashMap<String, Vec<Macaroon>>>,
+}
+
+impl MemoryCredentialStore {
+    /// Create an empty store
+    pub fn new() -> MemoryCredentialStore {
+        Default::default()
+    }
+}
+
+impl CredentialStore for MemoryCredentialStore {
+    fn load(&self, location: &str) -> Result<Option<Vec<Macaroon>>, MacaroonError> {
+        Ok(self.stacks.lock().unwrap().get(location).cloned())
+    }
+
+    fn save(&self, location: &str, stack: &[Macaroon]) -> Result<(), MacaroonError> {
+        self.stacks
+            .lock()
+            .unwrap()
+            .insert(String::from(location), stack.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, location: &str) -> Result<(), MacaroonError> {
+        self.stacks.lock().unwrap().remove(location);
+        Ok(())
+    }
+}
+
+/// Credential store which keeps each stack in a file in a directory
+///
+/// Each file is named after a hash of the location, and holds the stack as a token archive (see
+/// the `store` module) of version 2 serializations. Files are replaced atomically, and on Unix
+/// are only readable by their owner. With `encrypted`, the files are also encrypted, so the
+/// macaroons are protected at rest by a key kept elsewhere (in the system keyring, say).
+pub struct FileCredentialStore {
+    directory: PathBuf,
+    key: Option<[u8; 32]>,
+}
+
+impl FileCredentialStore {
+    /// Create a store using the given directory, which is created if it doesn't exist
+    pub fn new<P: AsRef<Path>>(directory: P) -> Result<FileCredentialStore, MacaroonError> {
+        fs::create_dir_all(directory.as_ref())?;
+        Ok(FileCredentialStore {
+            directory: directory.as_ref().to_path_buf(),
+            key: None,
+        })
+    }
+
+    /// Create a store using the given directory, encrypting the files with a key derived from
+    /// `key`
+    pub fn encrypted<P: AsRef<Path>>(
+        directory: P,
+        key: &[u8],
+    ) -> Result<FileCredentialStore, MacaroonError> {
+        let mut store = FileCredentialStore::new(directory)?;
+        store.key = Some(crypto::generate_derived_key(key));
+        Ok(store)
+    }
+
+    fn path(&self, location: &str) -> PathBuf {
+        let sha256::Digest(digest) = sha256::hash(location.as_bytes());
+        let name: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.directory.join(name)
+    }
+}
+
+impl CredentialStore for FileCredentialStore {
+    fn load(&self, location: &str) -> Result<Option<Vec<Macaroon>>, MacaroonError> {
+        let mut data = match fs::read(self.path(location)) {
+            Ok(data) => data,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        if let Some(key) = self.key {
+            data = crypto::decrypt(key, &data)?;
+        }
+        let mut stack = Vec::new();
+        let mut reader = data.as_slice();
+        while let Some(token) = store::read_token(&mut reader)? {
+            stack.push(Macaroon::deserialize(&token)?);
+        }
+        Ok(Some(stack))
+    }
+
+    fn save(&self, location: &str, stack: &[Macaroon]) -> Result<(), MacaroonError> {
+        let mut data = Vec::new();
+        for macaroon in stack {
+            store::write_token(&mut data, macaroon, Format::V2)?;
+        }
+        if let Some(key) = self.key {
+            data = crypto::encrypt(key, &data);
+        }
+        let path = self.path(location);
+        let temporary = path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&temporary)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+
+    fn remove(&self, location: &str) -> Result<(), MacaroonError> {
+        match fs::remove_file(self.path(location)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Acquire and bind all the discharge macaroons needed for a macaroon, reusing a stored stack
+/// if possible
+///
+/// If the store has a stack for the macaroon's location which starts with the same macaroon,
+/// and which hasn't expired (see `std_caveats::stack_expiry`), that is returned. Otherwise, the
+/// discharges are acquired with `discharge_all`, and the new stack is stored.
+pub fn discharge_with_store<A, S>(
+    macaroon: &Macaroon,
+    acquirer: &A,
+    store: &S,
+) -> Result<Vec<Macaroon>, MacaroonError>
+where
+    A: DischargeAcquirer + ?Sized,
+    S: CredentialStore + ?Sized,
+{
+    let location = macaroon.location().unwrap_or_default();
+    if let Some(stack) = store.load(&location)? {
+        let expired =
+            std_caveats::stack_expiry(&stack).is_some_and(|expiry| expiry <= std_caveats::now());
+        if stack.first() == Some(macaroon) && !expired {
+            return Ok(stack);
+        }
+    }
+    let stack = discharge_all(macaroon, acquirer)?;
+    store.save(&location, &stack)?;
+    Ok(stack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        discharge_with_store, CredentialStore, FileCredentialStore, MemoryCredentialStore,
+    };
+    use crate::testing::{discharged_stack, ROOT_KEY, THIRD_PARTY_KEY, THIRD_PARTY_LOCATION};
+    use crate::{error::MacaroonError, std_caveats, Macaroon};
+    use std::cell::Cell;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!(
+            "macaroon-credentials-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn check_store<S: CredentialStore>(store: &S) {
+        let stack = discharged_stack();
+        assert_eq!(None, store.load("http://example.org/").unwrap());
+        store.save("http://example.org/", &stack).unwrap();
+        assert_eq!(
+            Some(stack.clone()),
+            store.load("http://example.org/").unwrap()
+        );
+        assert_eq!(None, store.load("http://example.com/").unwrap());
+        store.save("http://example.org/", &stack[..1]).unwrap();
+        assert_eq!(
+            Some(stack[..1].to_vec()),
+            store.load("http://example.org/").unwrap()
+        );
+        store.remove("http://example.org/").unwrap();
+        store.remove("http://example.org/").unwrap();
+        assert_eq!(None, store.load("http://example.org/").unwrap());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_store(&MemoryCredentialStore::new());
+    }
+
+    #[test]
+    fn test_file_store() {
+        let directory = directory("plain");
+        check_store(&FileCredentialStore::new(&directory).unwrap());
+
+        // Stacks survive the store being recreated, as they would a restart
+        let stack = discharged_stack();
+        FileCredentialStore::new(&directory)
+            .unwrap()
+            .save("http://example.org/", &stack)
+            .unwrap();
+        let store = FileCredentialStore::new(&directory).unwrap();
+        assert_eq!(Some(stack), store.load("http://example.org/").unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_file_store() {
+        let directory = directory("encrypted");
+        let store = FileCredentialStore::encrypted(&directory, b"storage key").unwrap();
+        check_store(&store);
+
+        let stack = discharged_stack();
+        store.save("http://example.org/", &stack).unwrap();
+        let file = fs::read(store.path("http://example.org/")).unwrap();
+        assert!(!file.windows(11).any(|window| window == b"bank caveat"));
+        let wrong_key = FileCredentialStore::encrypted(&directory, b"other key").unwrap();
+        assert!(wrong_key.load("http://example.org/").is_err());
+        let unencrypted = FileCredentialStore::new(&directory).unwrap();
+        assert!(unencrypted.load("http://example.org/").is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_discharge_with_store() {
+        let store = MemoryCredentialStore::new();
+        let acquired = Cell::new(0);
+        let acquirer = |location: &str, id: &[u8]| -> Result<Macaroon, MacaroonError> {
+            acquired.set(acquired.get() + 1);
+            let id = std::str::from_utf8(id).unwrap();
+            let mut discharge = Macaroon::create(location, THIRD_PARTY_KEY, id)?;
+            discharge.add_first_party_caveat(&std_caveats::time_before(
+                SystemTime::now() + Duration::from_secs(3600),
+            ));
+            Ok(discharge)
+        };
+        let macaroon = discharged_stack().remove(0);
+        let first = discharge_with_store(&macaroon, &acquirer, &store).unwrap();
+        let second = discharge_with_store(&macaroon, &acquirer, &store).unwrap();
+        assert_eq!(1, acquired.get());
+        assert_eq!(first, second);
+
+        // A different macaroon for the same service replaces the stored stack
+        let mut other = Macaroon::create("http://example.org/", ROOT_KEY, "other").unwrap();
+        other.add_third_party_caveat(THIRD_PARTY_LOCATION, THIRD_PARTY_KEY, "caveat");
+        let third = discharge_with_store(&other, &acquirer, &store).unwrap();
+        assert_eq!(2, acquired.get());
+        assert_eq!(Some(third), store.load("http://example.org/").unwrap());
+
+        // As does an expired one
+        let mut expired = discharged_stack();
+        expired[1].add_first_party_caveat(&std_caveats::time_before(
+            SystemTime::now() - Duration::from_secs(1),
+        ));
+        store.save("http://example.org/", &expired).unwrap();
+        discharge_with_store(&expired[0], &acquirer, &store).unwrap();
+        assert_eq!(3, acquired.get());
+    }
+}
@@ -1,65 +1,525 @@
-use crate::error::MacaroonError;
+//! The cryptographic primitives macaroons are built from
+//!
+//! These are the same as those of libmacaroons, and are provided for code which has to work with
+//! the parts of a macaroon directly - a third-party discharger which adds its own caveats, say -
+//! so that it doesn't have to reimplement them:
+//!
+//! - keys are derived from secrets of any length by `derive_key`, an HMAC-SHA256 keyed with a
+//!   fixed string (or, where policy calls for it, with HKDF-SHA256, as `hkdf_sha256`, though
+//!   only this crate knows to derive keys that way - see `KeyDerivation`)
+//! - each caveat extends the signature chain by `hmac` (for first-party caveats, of the
+//!   predicate) or `hmac_concat` (for third-party caveats, of the verifier id and identifier)
+//! - the verifier id of a third-party caveat is the caveat key encrypted with the signature at
+//!   the point the caveat is added, as `encrypt_caveat_key`: a random `NONCE_LEN`-byte nonce,
+//!   followed by the NaCl `secretbox` (XSalsa20-Poly1305) ciphertext of the derived key
+//! - a discharge macaroon is bound to the macaroon it discharges by replacing its signature
+//!   with `hmac_concat` of the two signatures, keyed with 32 zero bytes (or, for peers which
+//!   bind discharges another way, as the `BindingMode` says)
+//!
+//! Services sharing root keys (by accident, or because they share a key store) would accept
+//! each other's macaroons. `MacaroonKey::with_domain` personalizes a key with a domain, such as
+//! the service's name, so that macaroons minted with the personalized key only verify with the
+//! same domain (see `VerifierBuilder::set_domain`). This is particular to this crate: other
+//! implementations don't know about domains, and can only verify such macaroons if they're
+//! handed the personalized key.
+#[cfg(feature = "rustc-serialize")]
+use crate::error::base64_field;
+use crate::{error::MacaroonError, redaction::Secret};
+#[cfg(feature = "rustc-serialize")]
+use rustc_serialize::hex::FromHex;
 use sodiumoxide::crypto::auth::hmacsha256::{self, Key, Tag};
 use sodiumoxide::crypto::secretbox;
+use sodiumoxide::utils::memcmp;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+#[cfg(feature = "rustc-serialize")]
+use std::{env, fs, path::Path};
 
 const KEY_GENERATOR: &[u8; 32] = b"macaroons-key-generator\0\0\0\0\0\0\0\0\0";
 
-pub fn generate_derived_key(key: &[u8]) -> [u8; 32] {
-    hmac(KEY_GENERATOR, key)
+// Prefixes the domain a key is personalized with, so that it can't collide with anything else
+// signed with the key
+const DOMAIN_SEPARATOR: &[u8] = b"macaroons-domain\0";
+
+/// The length of the nonces used to encrypt third-party caveat keys
+pub const NONCE_LEN: usize = secretbox::NONCEBYTES;
+
+/// The length of a key wrapped by `wrap_key`
+pub const WRAPPED_KEY_LEN: usize = NONCE_LEN + secretbox::MACBYTES + 32;
+
+/// Secret key used to sign and verify macaroons
+///
+/// Macaroons are signed using a 32-byte key derived from whatever secret the caller supplies,
+/// which is what this type holds. Its `Debug` output doesn't include the key.
+#[derive(Clone, Copy, PartialEq)]
+pub struct MacaroonKey(pub(crate) [u8; 32]);
+
+impl fmt::Debug for MacaroonKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MacaroonKey(..)")
+    }
+}
+
+impl MacaroonKey {
+    /// Derive a key from a secret of any length, in the same way `Macaroon::create` does
+    pub fn generate(seed: &[u8]) -> MacaroonKey {
+        MacaroonKey(generate_derived_key(seed))
+    }
+
+    /// Derive a key from a secret of any length, in the given way
+    pub fn derive(seed: &[u8], derivation: KeyDerivation) -> MacaroonKey {
+        match derivation {
+            KeyDerivation::Legacy => MacaroonKey::generate(seed),
+            KeyDerivation::HkdfSha256 { salt, info } => hkdf_sha256(seed, salt, info),
+        }
+    }
+
+    /// Personalize the key with a domain, such as the name of the service using it
+    ///
+    /// Macaroons minted with the personalized key (with `Macaroon::create_with_key`) only
+    /// verify with the same key, which a verifier with the same domain set derives from the
+    /// original. Not interoperable with other implementations of macaroons: see the module
+    /// documentation.
+    pub fn with_domain(&self, domain: &str) -> MacaroonKey {
+        MacaroonKey(personalize_key(&self.0, domain))
+    }
+}
+
+/// A key derived from a root key once, to be reused rather than derived on every call
+///
+/// This is another name for `MacaroonKey`, which always holds a derived key. Functions taking
+/// a root key as bytes (`Macaroon::create`, say) derive it each time they're called, while
+/// those taking a `MacaroonKey` (`Macaroon::create_with_key`, `Verifier::verify` and the like)
+/// use it as it is, so on hot paths the key should be derived once, with
+/// `MacaroonKey::generate`, and kept.
+pub type DerivedKey = MacaroonKey;
+
+/// How a `MacaroonKey` is derived from a secret
+///
+/// Every implementation of macaroons derives keys the `Legacy` way, which is what
+/// `Macaroon::create` (and every function taking a secret rather than a `MacaroonKey`) does,
+/// so it's the default. Keys derived with HKDF are for where a `MacaroonKey` is taken, as by
+/// `Macaroon::create_with_key` and `Verifier::verify`, and whatever verifies macaroons minted
+/// with them has to derive them the same way: anything expecting legacy keys rejects them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum KeyDerivation<'a> {
+    /// HMAC-SHA256 of the secret keyed with `macaroons-key-generator`, as libmacaroons does
+    #[default]
+    Legacy,
+    /// HKDF-SHA256 (RFC 5869) of the secret with the salt and info, giving the first 32 bytes
+    /// of output
+    HkdfSha256 { salt: &'a [u8], info: &'a [u8] },
+}
+
+/// Loading keys which have already been derived, as stored in configuration
+///
+/// Keys have to be exactly 32 bytes once decoded: anything shorter or longer is rejected rather
+/// than being padded or truncated. So are keys with too few distinct bytes to have come from a
+/// random number generator (fewer than 16 distinct values, which a random key has a negligible
+/// chance of), such as all zeroes or a string typed in by hand. Whitespace around the encoded
+/// key, such as the newline at the end of a file, is ignored.
+#[cfg(feature = "rustc-serialize")]
+impl MacaroonKey {
+    /// Load a key from hex (64 digits, in either case)
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::InvalidField` if the key isn't hex, isn't 32 bytes or has too
+    /// little entropy.
+    pub fn from_hex(hex: &str) -> Result<MacaroonKey, MacaroonError> {
+        let key = hex
+            .trim()
+            .from_hex()
+            .map_err(|_| MacaroonError::InvalidField {
+                field: "key",
+                reason: "isn't valid hex",
+            })?;
+        MacaroonKey::from_loaded(&key)
+    }
+
+    /// Load a key from base64, with either alphabet and with or without padding
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::Base64` if the key isn't base64, or
+    /// `MacaroonError::InvalidField` if it isn't 32 bytes or has too little entropy.
+    pub fn from_base64(base64: &str) -> Result<MacaroonKey, MacaroonError> {
+        MacaroonKey::from_loaded(&base64_field("key", base64.trim())?)
+    }
+
+    /// Load a key from a PEM file, such as `openssl rand 32 | openssl base64` wrapped in
+    /// `-----BEGIN MACAROON KEY-----` and `-----END MACAROON KEY-----` lines
+    ///
+    /// Any label is accepted, as long as the two lines match, but not PEM headers.
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::Io` if the file can't be read, `MacaroonError::Base64` if the key
+    /// isn't base64, and `MacaroonError::InvalidField` if the file isn't PEM or the key isn't
+    /// 32 bytes or has too little entropy.
+    pub fn from_pem_file<P: AsRef<Path>>(path: P) -> Result<MacaroonKey, MacaroonError> {
+        let pem = fs::read_to_string(path)?;
+        let not_pem = MacaroonError::InvalidField {
+            field: "key",
+            reason: "isn't a PEM file",
+        };
+        let mut lines = pem.lines().map(str::trim).filter(|line| !line.is_empty());
+        let label = match lines.next() {
+            Some(line) if line.starts_with("-----BEGIN ") && line.ends_with("-----") => {
+                &line["-----BEGIN ".len()..line.len() - "-----".len()]
+            }
+            _ => return Err(not_pem),
+        };
+        let end = format!("-----END {}-----", label);
+        let mut base64 = String::new();
+        for line in &mut lines {
+            if line == end {
+                if lines.next().is_some() {
+                    return Err(not_pem);
+                }
+                return MacaroonKey::from_base64(&base64);
+            }
+            if line.contains(':') || line.starts_with("-----") {
+                return Err(not_pem);
+            }
+            base64.push_str(line);
+        }
+        Err(not_pem)
+    }
+
+    /// Load a key from an environment variable, holding it in hex (if it's 64 hex digits) or
+    /// base64
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::MissingField` if the variable isn't set,
+    /// `MacaroonError::Base64` if the key isn't base64, and `MacaroonError::InvalidField` if
+    /// the variable isn't Unicode or the key isn't 32 bytes or has too little entropy.
+    pub fn from_env(var: &str) -> Result<MacaroonKey, MacaroonError> {
+        let value = match env::var(var) {
+            Ok(value) => value,
+            Err(env::VarError::NotPresent) => {
+                return Err(MacaroonError::MissingField("key environment variable"))
+            }
+            Err(env::VarError::NotUnicode(_)) => {
+                return Err(MacaroonError::InvalidField {
+                    field: "key",
+                    reason: "isn't Unicode",
+                })
+            }
+        };
+        let value = value.trim();
+        match value.len() == 64 && value.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            true => MacaroonKey::from_hex(value),
+            false => MacaroonKey::from_base64(value),
+        }
+    }
+
+    fn from_loaded(key: &[u8]) -> Result<MacaroonKey, MacaroonError> {
+        let key = <[u8; 32]>::try_from(key).map_err(|_| MacaroonError::InvalidField {
+            field: "key",
+            reason: "must be 32 bytes",
+        })?;
+        let mut seen = [false; 256];
+        for &byte in &key {
+            seen[byte as usize] = true;
+        }
+        if seen.iter().filter(|&&seen| seen).count() < 16 {
+            return Err(MacaroonError::InvalidField {
+                field: "key",
+                reason: "has too little entropy",
+            });
+        }
+        Ok(MacaroonKey(key))
+    }
+}
+
+impl From<[u8; 32]> for MacaroonKey {
+    /// Use an already-derived key as-is
+    fn from(key: [u8; 32]) -> MacaroonKey {
+        MacaroonKey(key)
+    }
+}
+
+impl AsRef<[u8]> for MacaroonKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// How discharge macaroons are bound to the macaroon they discharge
+///
+/// The binding has to match whatever binds or verifies the discharges on the other end.
+/// Everything in this crate binds and verifies the default way unless told otherwise (with
+/// `Macaroon::bind_with_mode`, `Macaroon::check_bindings_with_mode` or
+/// `VerifierBuilder::set_binding_mode`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BindingMode {
+    /// `hmac_concat` of the macaroon's and the discharge's signatures, keyed with 32 zero
+    /// bytes, as libmacaroons, pymacaroons, go-macaroon and js-macaroon bind discharges
+    #[default]
+    Libmacaroons,
+    /// `hmac` of the discharge's signature, keyed with the macaroon's signature, as some other
+    /// implementations bind discharges
+    RootSignatureKey,
+}
+
+impl BindingMode {
+    /// The signature of a discharge bound to the macaroon with the signature
+    pub fn bind(self, root_signature: &Signature, discharge_signature: &Signature) -> Signature {
+        Signature(match self {
+            BindingMode::Libmacaroons => {
+                hmac_concat(&[0; 32], &root_signature.0, &discharge_signature.0)
+            }
+            BindingMode::RootSignatureKey => hmac(&root_signature.0, &discharge_signature.0),
+        })
+    }
+}
+
+/// Signature of a macaroon
+///
+/// An HMAC-SHA256 tag, so always 32 bytes: deserializers reject signatures of any other length
+/// with `MacaroonError::InvalidField`. Signatures are compared in constant time, and their
+/// `Debug` output is redacted (see the `redaction` module).
+#[derive(Clone, Copy)]
+pub struct Signature(pub(crate) [u8; 32]);
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Signature").field(&Secret(&self.0)).finish()
+    }
+}
+
+impl Signature {
+    /// The signature's bytes
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for Signature {
+    fn from(signature: [u8; 32]) -> Signature {
+        Signature(signature)
+    }
+}
+
+impl From<Signature> for [u8; 32] {
+    fn from(signature: Signature) -> [u8; 32] {
+        signature.0
+    }
+}
+
+impl TryFrom<&[u8]> for Signature {
+    type Error = MacaroonError;
+
+    /// Checks the length, as when deserializing
+    fn try_from(signature: &[u8]) -> Result<Signature, MacaroonError> {
+        match signature.try_into() {
+            Ok(signature) => Ok(Signature(signature)),
+            Err(_) => Err(MacaroonError::InvalidField {
+                field: "signature",
+                reason: "must be 32 bytes",
+            }),
+        }
+    }
+}
+
+impl AsRef<[u8]> for Signature {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl PartialEq for Signature {
+    fn eq(&self, other: &Signature) -> bool {
+        memcmp(&self.0, &other.0)
+    }
+}
+
+impl Eq for Signature {}
+
+impl Hash for Signature {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+/// Derive a key from a secret of any length
+///
+/// This is the same as `MacaroonKey::generate`.
+pub fn derive_key(secret: &[u8]) -> MacaroonKey {
+    MacaroonKey(generate_derived_key(secret))
 }
 
-pub fn generate_signature(key: &[u8], text: &str) -> [u8; 32] {
-    let mut key_bytes: [u8; 32] = [0; 32];
-    key_bytes[..key.len()].clone_from_slice(key);
-    hmac(&key_bytes, text.as_bytes())
+/// HKDF-SHA256 (RFC 5869) of a secret of any length, giving a 32-byte key
+///
+/// An empty salt is the same as the 32 zero bytes RFC 5869 calls for when there's no salt.
+pub fn hkdf_sha256(secret: &[u8], salt: &[u8], info: &[u8]) -> MacaroonKey {
+    // Extract a pseudorandom key, then expand it, which only takes one block for 32 bytes
+    let mut extract = hmacsha256::State::init(salt);
+    extract.update(secret);
+    let Tag(pseudorandom_key) = extract.finalize();
+    let mut expand = hmacsha256::State::init(&pseudorandom_key);
+    expand.update(info);
+    expand.update(&[1]);
+    let Tag(key) = expand.finalize();
+    MacaroonKey(key)
 }
 
-pub fn hmac<'r>(key: &'r [u8; 32], text: &'r [u8]) -> [u8; 32] {
+/// HMAC-SHA256 of `text`
+pub fn hmac(key: &[u8; 32], text: &[u8]) -> [u8; 32] {
     let Tag(result_bytes) = hmacsha256::authenticate(text, &Key(*key));
     result_bytes
 }
 
-pub fn hmac2<'r>(key: &'r [u8; 32], text1: &'r [u8], text2: &'r [u8]) -> [u8; 32] {
-    let tmp1: [u8; 32] = hmac(key, text1);
-    let tmp2: [u8; 32] = hmac(key, text2);
-    let tmp = [tmp1, tmp2].concat();
-    hmac(key, &tmp)
+/// HMAC-SHA256 of the concatenation of the HMAC-SHA256s of `text1` and `text2`, all with the
+/// same key
+pub fn hmac_concat(key: &[u8; 32], text1: &[u8], text2: &[u8]) -> [u8; 32] {
+    let mut state = hmacsha256::State::init(key);
+    state.update(&hmac(key, text1));
+    state.update(&hmac(key, text2));
+    let Tag(result_bytes) = state.finalize();
+    result_bytes
+}
+
+/// Encrypt a third-party caveat's key, giving the caveat's verifier id
+///
+/// `signature` is the macaroon's signature before the caveat is added, and `caveat_key` is the
+/// key the discharge macaroon will be minted with (`derive_key` of the secret shared with the
+/// third party).
+pub fn encrypt_caveat_key(signature: &Signature, caveat_key: &MacaroonKey) -> Vec<u8> {
+    encrypt(signature.0, &caveat_key.0)
+}
+
+/// Decrypt a third-party caveat's key from its verifier id
+///
+/// `signature` is the macaroon's signature before the caveat was added.
+///
+/// # Errors
+/// Returns `MacaroonError::Crypto` if the verifier id is too short to hold a nonce, wasn't
+/// encrypted with the signature or has been altered, or doesn't hold a 32-byte key.
+pub fn decrypt_caveat_key(
+    signature: &Signature,
+    verifier_id: &[u8],
+) -> Result<MacaroonKey, MacaroonError> {
+    let key = decrypt(signature.0, verifier_id)?;
+    match <[u8; 32]>::try_from(key.as_slice()) {
+        Ok(key) => Ok(MacaroonKey(key)),
+        Err(_) => Err(MacaroonError::Crypto("Caveat key isn't 32 bytes")),
+    }
+}
+
+/// Encrypt a key with a wrapping key, so that only whoever holds the wrapping key can recover it
+///
+/// This is how session-key macaroons carry their root keys in their identifiers (see
+/// `oven::Oven::set_session_keys`). The result is `WRAPPED_KEY_LEN` bytes, and is different
+/// each time, since the nonce is random.
+pub fn wrap_key(wrapping_key: &MacaroonKey, key: &MacaroonKey) -> Vec<u8> {
+    encrypt(wrapping_key.0, &key.0)
+}
+
+/// Decrypt a key wrapped by `wrap_key`
+///
+/// # Errors
+/// Returns `MacaroonError::Crypto` if the key wasn't wrapped with the wrapping key or has been
+/// altered, or doesn't hold a 32-byte key.
+pub fn unwrap_key(
+    wrapping_key: &MacaroonKey,
+    wrapped: &[u8],
+) -> Result<MacaroonKey, MacaroonError> {
+    let key = decrypt(wrapping_key.0, wrapped)?;
+    match <[u8; 32]>::try_from(key.as_slice()) {
+        Ok(key) => Ok(MacaroonKey(key)),
+        Err(_) => Err(MacaroonError::Crypto("Wrapped key isn't 32 bytes")),
+    }
+}
+
+// A random key, such as a session key
+#[cfg(feature = "rustc-serialize")]
+pub(crate) fn gen_key() -> MacaroonKey {
+    MacaroonKey(secretbox::gen_key().0)
+}
+
+pub(crate) fn generate_derived_key(key: &[u8]) -> [u8; 32] {
+    hmac(KEY_GENERATOR, key)
+}
+
+// The HMAC-SHA256 of the separator and domain, keyed with the key
+pub(crate) fn personalize_key(key: &[u8], domain: &str) -> [u8; 32] {
+    let mut state = hmacsha256::State::init(key);
+    state.update(DOMAIN_SEPARATOR);
+    state.update(domain.as_bytes());
+    let Tag(key) = state.finalize();
+    key
+}
+
+pub(crate) fn generate_signature(key: &[u8; 32], text: &str) -> [u8; 32] {
+    hmac(key, text.as_bytes())
 }
 
-pub fn encrypt(key: [u8; 32], plaintext: &[u8]) -> Vec<u8> {
-    let nonce = secretbox::gen_nonce();
+// A key passed as a slice, zero-padded to 32 bytes as libmacaroons pads shorter keys
+pub(crate) fn key_array(key: &[u8]) -> Result<[u8; 32], MacaroonError> {
+    if key.len() > 32 {
+        return Err(MacaroonError::InvalidField {
+            field: "key",
+            reason: "longer than 32 bytes",
+        });
+    }
+    let mut key_bytes = [0; 32];
+    key_bytes[..key.len()].copy_from_slice(key);
+    Ok(key_bytes)
+}
+
+pub(crate) fn encrypt(key: [u8; 32], plaintext: &[u8]) -> Vec<u8> {
+    encrypt_with_nonce(key, plaintext, gen_nonce())
+}
+
+pub(crate) fn encrypt_with_nonce(
+    key: [u8; 32],
+    plaintext: &[u8],
+    nonce: [u8; NONCE_LEN],
+) -> Vec<u8> {
+    let nonce = secretbox::Nonce(nonce);
     let encrypted = secretbox::seal(plaintext, &nonce, &secretbox::Key(key));
-    let mut ret: Vec<u8> = Vec::new();
+    let mut ret: Vec<u8> = Vec::with_capacity(NONCE_LEN + encrypted.len());
     ret.extend_from_slice(nonce.as_ref());
     ret.extend(encrypted);
     ret
 }
 
-pub fn decrypt(key: [u8; 32], data: &[u8]) -> Result<Vec<u8>, MacaroonError> {
+pub(crate) fn gen_nonce() -> [u8; NONCE_LEN] {
+    secretbox::gen_nonce().0
+}
+
+pub(crate) fn decrypt(key: [u8; 32], data: &[u8]) -> Result<Vec<u8>, MacaroonError> {
     if data.len() <= secretbox::NONCEBYTES {
-        error!("crypto::decrypt: Encrypted data {:?} too short", data);
-        return Err(MacaroonError::DecryptionError("Encrypted data too short"));
+        error!(
+            "crypto::decrypt: Encrypted data {:?} too short",
+            Secret(data)
+        );
+        return Err(MacaroonError::Crypto("Encrypted data too short"));
     }
     let mut nonce: [u8; secretbox::NONCEBYTES] = [0; secretbox::NONCEBYTES];
     nonce.clone_from_slice(&data[..secretbox::NONCEBYTES]);
-    let mut temp: Vec<u8> = Vec::new();
-    temp.extend_from_slice(&data[secretbox::NONCEBYTES..]);
-    let ciphertext = temp.as_slice();
+    let ciphertext = &data[secretbox::NONCEBYTES..];
     match secretbox::open(ciphertext, &secretbox::Nonce(nonce), &secretbox::Key(key)) {
         Ok(plaintext) => Ok(plaintext),
         Err(()) => {
             error!(
                 "crypto::decrypt: Unknown decryption error decrypting {:?}",
-                data
+                Secret(data)
             );
-            Err(MacaroonError::DecryptionError("Unknown decryption error"))
+            Err(MacaroonError::Crypto("Unknown decryption error"))
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{decrypt, encrypt};
+    use super::{
+        decrypt, decrypt_caveat_key, derive_key, encrypt, encrypt_caveat_key, hkdf_sha256,
+        hmac_concat, key_array, unwrap_key, wrap_key, BindingMode, KeyDerivation, Signature,
+        WRAPPED_KEY_LEN,
+    };
+    use crate::{error::MacaroonError, inspect, Macaroon, MacaroonKey, Verifier, NONCE_LEN};
+    use std::convert::TryFrom;
 
     #[test]
     fn test_encrypt_decrypt() {
@@ -69,4 +529,343 @@ mod test {
         let decrypted = decrypt(*key, encrypted.as_slice()).unwrap();
         assert_eq!(secret.to_vec(), decrypted);
     }
+
+    #[test]
+    fn test_hkdf_sha256() {
+        // RFC 5869 test cases 1 and 3, of which the key is the first 32 bytes of output
+        let secret = [0x0b; 22];
+        let salt: Vec<u8> = (0x00..=0x0c).collect();
+        let info: Vec<u8> = (0xf0..=0xf9).collect();
+        assert_eq!(
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf",
+            inspect::hex(hkdf_sha256(&secret, &salt, &info).as_ref())
+        );
+        assert_eq!(
+            "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d",
+            inspect::hex(hkdf_sha256(&secret, &[], &[]).as_ref())
+        );
+        assert_eq!(
+            hkdf_sha256(&secret, &[], &[]),
+            hkdf_sha256(&secret, &[0; 32], &[])
+        );
+
+        assert_eq!(
+            MacaroonKey::generate(b"secret"),
+            MacaroonKey::derive(b"secret", KeyDerivation::default())
+        );
+        let hkdf = KeyDerivation::HkdfSha256 {
+            salt: b"salt",
+            info: b"macaroons",
+        };
+        assert_eq!(
+            hkdf_sha256(b"secret", b"salt", b"macaroons"),
+            MacaroonKey::derive(b"secret", hkdf)
+        );
+        assert_ne!(
+            MacaroonKey::generate(b"secret"),
+            MacaroonKey::derive(b"secret", hkdf)
+        );
+
+        let key = MacaroonKey::derive(b"secret", hkdf);
+        let macaroon = Macaroon::create_with_key("http://example.org/", &key, "keyid").unwrap();
+        assert!(Verifier::default().verify(&macaroon, &key, &[]).unwrap());
+        let legacy = Macaroon::create("http://example.org/", b"secret", "keyid").unwrap();
+        assert!(!Verifier::default().verify(&legacy, &key, &[]).unwrap());
+    }
+
+    #[cfg(feature = "rustc-serialize")]
+    #[test]
+    fn test_load_key() {
+        use rustc_serialize::base64::{ToBase64, STANDARD, URL_SAFE};
+        use std::{env, fs};
+
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        let key = MacaroonKey::from(bytes);
+        let hex = inspect::hex(&bytes);
+        assert_eq!(key, MacaroonKey::from_hex(&hex).unwrap());
+        assert_eq!(
+            key,
+            MacaroonKey::from_hex(&format!("{}\n", hex.to_uppercase())).unwrap()
+        );
+        assert_eq!(
+            key,
+            MacaroonKey::from_base64(&bytes.to_base64(STANDARD)).unwrap()
+        );
+        assert_eq!(
+            key,
+            MacaroonKey::from_base64(&bytes.to_base64(URL_SAFE)).unwrap()
+        );
+
+        let invalid = |result: Result<MacaroonKey, MacaroonError>| match result {
+            Err(MacaroonError::InvalidField { field, reason }) => {
+                assert_eq!("key", field);
+                reason
+            }
+            result => panic!("Unexpected result {:?}", result),
+        };
+        assert_eq!("isn't valid hex", invalid(MacaroonKey::from_hex("key")));
+        assert_eq!(
+            "must be 32 bytes",
+            invalid(MacaroonKey::from_hex(&hex[..62]))
+        );
+        assert_eq!(
+            "must be 32 bytes",
+            invalid(MacaroonKey::from_hex(&format!("{}00", hex)))
+        );
+        assert_eq!(
+            "must be 32 bytes",
+            invalid(MacaroonKey::from_base64(&bytes[..31].to_base64(STANDARD)))
+        );
+        assert_eq!(
+            "has too little entropy",
+            invalid(MacaroonKey::from_hex(&"00".repeat(32)))
+        );
+        assert_eq!(
+            "has too little entropy",
+            invalid(MacaroonKey::from_base64(
+                &b"passwordpasswordpasswordpassword".to_base64(STANDARD)
+            ))
+        );
+        assert!(matches!(
+            MacaroonKey::from_base64("not base64!"),
+            Err(MacaroonError::Base64 { .. })
+        ));
+
+        let path = env::temp_dir().join(format!("macaroon-key-{}.pem", std::process::id()));
+        let base64 = bytes.to_base64(STANDARD);
+        fs::write(
+            &path,
+            format!(
+                "-----BEGIN MACAROON KEY-----\n{}\n{}\n-----END MACAROON KEY-----\n",
+                &base64[..20],
+                &base64[20..]
+            ),
+        )
+        .unwrap();
+        assert_eq!(key, MacaroonKey::from_pem_file(&path).unwrap());
+        for pem in [
+            base64.clone(),
+            format!("-----BEGIN MACAROON KEY-----\n{}\n", base64),
+            format!(
+                "-----BEGIN MACAROON KEY-----\n{}\n-----END KEY-----",
+                base64
+            ),
+            format!(
+                "-----BEGIN MACAROON KEY-----\nProc-Type: 4,ENCRYPTED\n{}\n\
+                 -----END MACAROON KEY-----",
+                base64
+            ),
+        ] {
+            fs::write(&path, pem).unwrap();
+            assert_eq!(
+                "isn't a PEM file",
+                invalid(MacaroonKey::from_pem_file(&path))
+            );
+        }
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            MacaroonKey::from_pem_file(&path),
+            Err(MacaroonError::Io(_))
+        ));
+
+        let var = format!("MACAROON_TEST_KEY_{}", std::process::id());
+        assert!(matches!(
+            MacaroonKey::from_env(&var),
+            Err(MacaroonError::MissingField(_))
+        ));
+        env::set_var(&var, &hex);
+        assert_eq!(key, MacaroonKey::from_env(&var).unwrap());
+        env::set_var(&var, &base64);
+        assert_eq!(key, MacaroonKey::from_env(&var).unwrap());
+        env::remove_var(&var);
+    }
+
+    #[test]
+    fn test_caveat_key_encryption() {
+        let signature = Signature::from([7; 32]);
+        let caveat_key = derive_key(b"caveat key");
+        let verifier_id = encrypt_caveat_key(&signature, &caveat_key);
+        assert_eq!(
+            caveat_key,
+            decrypt_caveat_key(&signature, &verifier_id).unwrap()
+        );
+        assert!(decrypt_caveat_key(&Signature::from([8; 32]), &verifier_id).is_err());
+        assert!(decrypt_caveat_key(&signature, &verifier_id[..24]).is_err());
+        let short = encrypt([7; 32], b"short key");
+        match decrypt_caveat_key(&signature, &short) {
+            Err(MacaroonError::Crypto(reason)) => assert_eq!("Caveat key isn't 32 bytes", reason),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // As the macaroon does it
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        let before = *macaroon.signature();
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"caveat key", "caveat");
+        let caveat = macaroon.third_party_caveats().next().unwrap();
+        assert_eq!(
+            MacaroonKey::generate(b"caveat key"),
+            decrypt_caveat_key(&before, &caveat.verifier_id()).unwrap()
+        );
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"caveat key", "caveat").unwrap();
+        let unbound = *discharge.signature();
+        macaroon.bind(&mut discharge);
+        assert_eq!(
+            hmac_concat(
+                &[0; 32],
+                macaroon.signature().as_bytes(),
+                unbound.as_bytes()
+            ),
+            *discharge.signature().as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_binding_modes() {
+        // Generated outside this crate with Python's standard library HMAC-SHA256, following
+        // libmacaroons for the default binding
+        const ROOT: &str = "f87aa8139459038c81ae2f90d62492c243f30e707a723a3e54de64a6f2859100";
+        const UNBOUND: &str = "c639e66a7c2db8412ce243883571688a51d8f26446166d2e0a976f80d4360231";
+        const LIBMACAROONS: &str =
+            "71d6a5dbf9ddbf6004aa8807983afc70f823f185a5e54e323546106e1b4d3e91";
+        const ROOT_SIGNATURE_KEY: &str =
+            "70b8c89cff69a84adf8165a719a914686be739b12f33e34e16db113567ad4c73";
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        assert_eq!(ROOT, macaroon.signature_hex());
+        let discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        assert_eq!(UNBOUND, discharge.signature_hex());
+
+        for (mode, expected) in [
+            (BindingMode::Libmacaroons, LIBMACAROONS),
+            (BindingMode::RootSignatureKey, ROOT_SIGNATURE_KEY),
+        ] {
+            let mut bound = discharge.clone();
+            macaroon.bind_with_mode(&mut bound, mode);
+            assert_eq!(expected, bound.signature_hex(), "{:?}", mode);
+            assert_eq!(
+                *bound.signature(),
+                mode.bind(macaroon.signature(), discharge.signature())
+            );
+        }
+        let mut bound = discharge;
+        macaroon.bind(&mut bound);
+        assert_eq!(LIBMACAROONS, bound.signature_hex());
+    }
+
+    #[test]
+    fn test_verifier_id_layout() {
+        // The root macaroon in tests/pymacaroons/fixture.txt, with a fixed nonce in place of a
+        // random one, as computed outside the crate with Python's HMAC-SHA256 and an
+        // XSalsa20-Poly1305 giving the nonce followed by the ciphertext, as PyNaCl's
+        // `SecretBox.encrypt` does
+        const VERIFIER_ID: &str = "000102030405060708090a0b0c0d0e0f1011121314151617482b369a6097\
+                                   91640a8160b1a5982031d6c9f888bc7c1e2b8ec2b5c13edaf542bcbc5cb8\
+                                   c2775c85620af8b33fa57de7";
+        const SIGNATURE: &str = "a5ca7b05495350524672d7518179e9cb838c8c261a0f80dec876734f53a9c379";
+        let mut nonce = [0; NONCE_LEN];
+        for (i, byte) in nonce.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        let before = *macaroon.signature();
+        macaroon.add_third_party_caveat_with_nonce(
+            "http://auth.mybank/",
+            b"bank key",
+            "bank caveat",
+            nonce,
+        );
+        let verifier_id = macaroon.third_party_caveats().next().unwrap().verifier_id();
+        assert_eq!(VERIFIER_ID, inspect::hex(&verifier_id));
+        assert_eq!(SIGNATURE, macaroon.signature_hex());
+
+        // The nonce, then the 16-byte authenticator, then the encrypted 32-byte key
+        assert_eq!(NONCE_LEN + 16 + 32, verifier_id.len());
+        assert_eq!(&nonce[..], &verifier_id[..NONCE_LEN]);
+        assert_eq!(
+            derive_key(b"bank key"),
+            decrypt_caveat_key(&before, &verifier_id).unwrap()
+        );
+
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        macaroon.bind(&mut discharge);
+        let mut verifier = Verifier::builder();
+        verifier.satisfy_exact("account = 3735928559");
+        assert!(verifier
+            .build()
+            .verify(&macaroon, &derive_key(b"this is the key"), &[discharge])
+            .unwrap());
+    }
+
+    #[test]
+    fn test_signature_length() {
+        let signature = Signature::try_from(&[7; 32][..]).unwrap();
+        assert_eq!(&[7; 32], signature.as_bytes());
+        assert_eq!(Signature::from([7; 32]), signature);
+        assert_ne!(Signature::from([8; 32]), signature);
+        for &len in &[0, 31, 33, 64] {
+            match Signature::try_from(&vec![7; len][..]) {
+                Err(MacaroonError::InvalidField { field, reason }) => {
+                    assert_eq!("signature", field);
+                    assert_eq!("must be 32 bytes", reason);
+                }
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_wrap_key() {
+        let wrapping_key = MacaroonKey::generate(b"wrapping key");
+        let key = MacaroonKey::generate(b"session key");
+        let wrapped = wrap_key(&wrapping_key, &key);
+        assert_eq!(WRAPPED_KEY_LEN, wrapped.len());
+        assert_ne!(wrapped, wrap_key(&wrapping_key, &key));
+        assert_eq!(key, unwrap_key(&wrapping_key, &wrapped).unwrap());
+        let other = MacaroonKey::generate(b"other key");
+        assert!(matches!(
+            unwrap_key(&other, &wrapped),
+            Err(MacaroonError::Crypto(_))
+        ));
+        let short = encrypt(wrapping_key.0, b"short");
+        match unwrap_key(&wrapping_key, &short) {
+            Err(MacaroonError::Crypto(reason)) => assert_eq!("Wrapped key isn't 32 bytes", reason),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_key_array() {
+        assert_eq!([7; 32], key_array(&[7; 32]).unwrap());
+        let mut padded = [0; 32];
+        padded[..3].copy_from_slice(b"key");
+        assert_eq!(padded, key_array(b"key").unwrap());
+        // Padded keys sign as libmacaroons signs with them
+        let macaroon = Macaroon::create_with_key("", &MacaroonKey::from(padded), "keyid").unwrap();
+        assert!(macaroon.verify_signature(b"key"));
+        assert_eq!(
+            macaroon.signature(),
+            &macaroon.generate_signature(b"key").unwrap()
+        );
+
+        // Longer keys are rejected rather than truncated
+        let long = [7; 33];
+        assert!(matches!(
+            key_array(&long),
+            Err(MacaroonError::InvalidField { field: "key", .. })
+        ));
+        assert!(macaroon.generate_signature(&long).is_err());
+        assert!(!macaroon.verify_signature(&long));
+        assert!(matches!(
+            macaroon.verify(&long, &Verifier::default()),
+            Err(MacaroonError::InvalidField { field: "key", .. })
+        ));
+    }
 }
@@ -0,0 +1,217 @@
+//! Comparing macaroons field by field
+//!
+//! `Macaroon::diff` says how two macaroons differ, for working out why a token a client
+//! presents doesn't match the one a service expects (it was re-minted with a different
+//! identifier, say, or has a caveat too many), and for clearer test failures than `assert_eq!`
+//! gives. Caveats are matched up in order, as a line diff would, so a caveat added or removed in
+//! the middle shows up as just that rather than as every caveat after it changing.
+//!
+//! Third-party caveats' verifier ids are encrypted with a random nonce, so the same caveat added
+//! to two macaroons has different verifier ids. Caveats with the same id and location are
+//! matched up regardless, and a different verifier id is reported as a change of its own.
+//!
+//! Differences hold identifiers and predicates as they are, so a diff which is logged anywhere
+//! sensitive should go through the `redaction` module.
+use crate::{CaveatRef, Macaroon};
+use std::fmt;
+
+/// The differences between two macaroons, from `Macaroon::diff`
+#[derive(Clone, Debug, PartialEq)]
+pub struct MacaroonDiff<'a> {
+    /// The locations of the first and second macaroons, if they differ
+    pub location: Option<(Option<&'a str>, Option<&'a str>)>,
+    /// The identifiers of the first and second macaroons, if they differ
+    pub identifier: Option<(&'a str, &'a str)>,
+    /// The differences between the caveats, in order
+    pub caveats: Vec<CaveatChange<'a>>,
+    /// Whether the signatures differ
+    pub signature: bool,
+}
+
+/// A difference between the caveats of two macaroons
+#[derive(Clone, Debug, PartialEq)]
+pub enum CaveatChange<'a> {
+    /// A caveat which only the first macaroon has, at the index in its caveats
+    Removed { index: usize, caveat: CaveatRef<'a> },
+    /// A caveat which only the second macaroon has, at the index in its caveats
+    Added { index: usize, caveat: CaveatRef<'a> },
+    /// A third-party caveat which both macaroons have, but with different verifier ids
+    VerifierIdChanged {
+        index: usize,
+        other_index: usize,
+        caveat: CaveatRef<'a>,
+    },
+}
+
+impl MacaroonDiff<'_> {
+    /// Whether the macaroons are the same
+    pub fn is_empty(&self) -> bool {
+        self.location.is_none()
+            && self.identifier.is_none()
+            && self.caveats.is_empty()
+            && !self.signature
+    }
+}
+
+// One line per difference, with `-` for the first macaroon and `+` for the second
+impl fmt::Display for MacaroonDiff<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((first, second)) = self.location {
+            writeln!(f, "- location {:?}", first)?;
+            writeln!(f, "+ location {:?}", second)?;
+        }
+        if let Some((first, second)) = self.identifier {
+            writeln!(f, "- identifier {:?}", first)?;
+            writeln!(f, "+ identifier {:?}", second)?;
+        }
+        for change in &self.caveats {
+            match change {
+                CaveatChange::Removed { index, caveat } => {
+                    writeln!(f, "- caveat {} {:?}", index, caveat.id())?
+                }
+                CaveatChange::Added { index, caveat } => {
+                    writeln!(f, "+ caveat {} {:?}", index, caveat.id())?
+                }
+                CaveatChange::VerifierIdChanged {
+                    index,
+                    other_index,
+                    caveat,
+                } => writeln!(
+                    f,
+                    "~ caveat {} {:?} has a different verifier id (caveat {})",
+                    index,
+                    caveat.id(),
+                    other_index
+                )?,
+            }
+        }
+        if self.signature {
+            writeln!(f, "~ signature")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn diff<'a>(first: &'a Macaroon, second: &'a Macaroon) -> MacaroonDiff<'a> {
+    let first = first.parts();
+    let second = second.parts();
+    MacaroonDiff {
+        location: match first.location == second.location {
+            true => None,
+            false => Some((first.location, second.location)),
+        },
+        identifier: match first.identifier == second.identifier {
+            true => None,
+            false => Some((first.identifier, second.identifier)),
+        },
+        caveats: diff_caveats(&first.caveats, &second.caveats),
+        signature: first.signature != second.signature,
+    }
+}
+
+fn same_caveat(first: &CaveatRef, second: &CaveatRef) -> bool {
+    first.id() == second.id()
+        && first.location() == second.location()
+        && first.is_third_party() == second.is_third_party()
+}
+
+// Based on the longest common subsequence, as `inspect::diff_snapshot` is
+fn diff_caveats<'a>(first: &[CaveatRef<'a>], second: &[CaveatRef<'a>]) -> Vec<CaveatChange<'a>> {
+    let mut common = vec![vec![0usize; second.len() + 1]; first.len() + 1];
+    for i in (0..first.len()).rev() {
+        for j in (0..second.len()).rev() {
+            common[i][j] = if same_caveat(&first[i], &second[j]) {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < first.len() || j < second.len() {
+        if i < first.len() && j < second.len() && same_caveat(&first[i], &second[j]) {
+            if first[i].verifier_id() != second[j].verifier_id() {
+                changes.push(CaveatChange::VerifierIdChanged {
+                    index: i,
+                    other_index: j,
+                    caveat: first[i],
+                });
+            }
+            i += 1;
+            j += 1;
+        } else if i < first.len() && (j == second.len() || common[i + 1][j] >= common[i][j + 1]) {
+            changes.push(CaveatChange::Removed {
+                index: i,
+                caveat: first[i],
+            });
+            i += 1;
+        } else {
+            changes.push(CaveatChange::Added {
+                index: j,
+                caveat: second[j],
+            });
+            j += 1;
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CaveatChange;
+    use crate::Macaroon;
+
+    #[test]
+    fn test_diff() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"caveat key", "caveat");
+        macaroon.add_first_party_caveat("user = alice");
+        let diff = macaroon.diff(&macaroon);
+        assert!(diff.is_empty());
+        assert_eq!("", diff.to_string());
+
+        let mut other = Macaroon::create("http://example.com/", b"key", "keyid").unwrap();
+        other.add_third_party_caveat("http://auth.mybank/", b"caveat key", "caveat");
+        other.add_first_party_caveat("user = alice");
+        other.add_first_party_caveat("action = read");
+        let diff = macaroon.diff(&other);
+        assert!(!diff.is_empty());
+        assert_eq!(
+            Some((Some("http://example.org/"), Some("http://example.com/"))),
+            diff.location
+        );
+        assert_eq!(None, diff.identifier);
+        assert!(diff.signature);
+        assert!(matches!(
+            diff.caveats[..],
+            [
+                CaveatChange::Removed { index: 0, .. },
+                CaveatChange::VerifierIdChanged {
+                    index: 1,
+                    other_index: 0,
+                    ..
+                },
+                CaveatChange::Added { index: 2, .. },
+            ]
+        ));
+        assert_eq!(
+            "- location Some(\"http://example.org/\")\n\
+             + location Some(\"http://example.com/\")\n\
+             - caveat 0 \"account = 3735928559\"\n\
+             ~ caveat 1 \"caveat\" has a different verifier id (caveat 0)\n\
+             + caveat 2 \"action = read\"\n\
+             ~ signature\n",
+            diff.to_string()
+        );
+
+        // The same caveats under a different key only differ in their signature
+        let mut rekeyed = Macaroon::create("http://example.org/", b"other key", "keyid").unwrap();
+        rekeyed.add_first_party_caveat("account = 3735928559");
+        let mut original = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        original.add_first_party_caveat("account = 3735928559");
+        let diff = original.diff(&rekeyed);
+        assert_eq!("~ signature\n", diff.to_string());
+    }
+}
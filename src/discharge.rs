@@ -0,0 +1,304 @@
+use crate::{caveat::ThirdPartyCaveat, error::MacaroonError, redaction, Macaroon};
+#[cfg(feature = "async")]
+use std::future::Future;
+
+/// The most discharges `discharge_all` acquires for one macaroon
+///
+/// Each discharge may add third-party caveats of its own, so without a limit, a third party
+/// which kept minting discharges with fresh caveats would keep the client acquiring them for
+/// ever.
+pub const MAX_DISCHARGES: usize = 64;
+
+/// Source of discharge macaroons for third-party caveats
+///
+/// An acquirer is handed the location and identifier of a third-party caveat, and is expected
+/// to contact the third party (over whatever transport is appropriate) and return the discharge
+/// macaroon it mints. The returned macaroon should *not* be bound yet - `discharge_all` binds it
+/// to the root macaroon.
+///
+/// Caveat identifiers are opaque bytes, which third parties such as the bakery's needn't make
+/// UTF-8.
+///
+/// Any closure of the form `Fn(&str, &[u8]) -> Result<Macaroon, MacaroonError>` is an acquirer,
+/// which is convenient for in-process third parties and for testing.
+pub trait DischargeAcquirer {
+    fn acquire(&self, location: &str, caveat_id: &[u8]) -> Result<Macaroon, MacaroonError>;
+}
+
+impl<F> DischargeAcquirer for F
+where
+    F: Fn(&str, &[u8]) -> Result<Macaroon, MacaroonError>,
+{
+    fn acquire(&self, location: &str, caveat_id: &[u8]) -> Result<Macaroon, MacaroonError> {
+        self(location, caveat_id)
+    }
+}
+
+/// Asynchronous source of discharge macaroons for third-party caveats
+///
+/// The same as `DischargeAcquirer`, for acquirers which talk to the third party using async
+/// I/O. Implementations can use `async fn acquire`.
+#[cfg(feature = "async")]
+pub trait AsyncDischargeAcquirer {
+    fn acquire(
+        &self,
+        location: &str,
+        caveat_id: &[u8],
+    ) -> impl Future<Output = Result<Macaroon, MacaroonError>> + Send;
+}
+
+/// Acquire and bind all the discharge macaroons needed to verify a macaroon
+///
+/// Walks the third-party caveats of `macaroon`, acquiring a discharge for each one using
+/// `acquirer`. Since discharge macaroons may themselves contain third-party caveats, those are
+/// discharged as well. Each caveat identifier is only discharged once. Every discharge is bound
+/// to `macaroon`.
+///
+/// Returns the complete stack - the root macaroon first, followed by the bound discharge
+/// macaroons - ready to be sent along with a request.
+///
+/// # Errors
+/// Any error from `acquirer`, and `MacaroonError::DischargeError` if a discharge doesn't match
+/// its caveat, or more than `MAX_DISCHARGES` discharges would be needed.
+pub fn discharge_all<A>(macaroon: &Macaroon, acquirer: &A) -> Result<Vec<Macaroon>, MacaroonError>
+where
+    A: DischargeAcquirer + ?Sized,
+{
+    let mut stack: Vec<Macaroon> = vec![macaroon.clone()];
+    let mut pending: Vec<ThirdPartyCaveat> = macaroon.third_party_caveats().cloned().collect();
+    while !pending.is_empty() {
+        let caveat = pending.remove(0);
+        if stack[1..]
+            .iter()
+            .any(|discharge| *discharge.identifier() == caveat.id())
+        {
+            continue;
+        }
+        check_discharge_count(&stack)?;
+        let mut discharge = acquirer.acquire(&caveat.location(), caveat.id().as_bytes())?;
+        check_discharge_id(&caveat, &discharge)?;
+        pending.extend(discharge.third_party_caveats().cloned());
+        macaroon.bind(&mut discharge);
+        stack.push(discharge);
+    }
+    debug!("discharge_all: {:?}", stack);
+
+    Ok(stack)
+}
+
+/// Acquire and bind all the discharge macaroons needed to verify a macaroon, asynchronously
+///
+/// The same as `discharge_all`, using an `AsyncDischargeAcquirer`. Discharges are acquired one
+/// at a time, in the same order.
+#[cfg(feature = "async")]
+pub async fn discharge_all_async<A>(
+    macaroon: &Macaroon,
+    acquirer: &A,
+) -> Result<Vec<Macaroon>, MacaroonError>
+where
+    A: AsyncDischargeAcquirer + ?Sized,
+{
+    let mut stack: Vec<Macaroon> = vec![macaroon.clone()];
+    let mut pending: Vec<ThirdPartyCaveat> = macaroon.third_party_caveats().cloned().collect();
+    while !pending.is_empty() {
+        let caveat = pending.remove(0);
+        if stack[1..]
+            .iter()
+            .any(|discharge| *discharge.identifier() == caveat.id())
+        {
+            continue;
+        }
+        check_discharge_count(&stack)?;
+        let mut discharge = acquirer
+            .acquire(&caveat.location(), caveat.id().as_bytes())
+            .await?;
+        check_discharge_id(&caveat, &discharge)?;
+        pending.extend(discharge.third_party_caveats().cloned());
+        macaroon.bind(&mut discharge);
+        stack.push(discharge);
+    }
+    debug!("discharge_all_async: {:?}", stack);
+
+    Ok(stack)
+}
+
+// Check that there's room in the stack (the root macaroon and its discharges) for another
+fn check_discharge_count(stack: &[Macaroon]) -> Result<(), MacaroonError> {
+    match stack.len() > MAX_DISCHARGES {
+        true => Err(MacaroonError::DischargeError(format!(
+            "More than {} discharges needed",
+            MAX_DISCHARGES
+        ))),
+        false => Ok(()),
+    }
+}
+
+fn check_discharge_id(
+    caveat: &ThirdPartyCaveat,
+    discharge: &Macaroon,
+) -> Result<(), MacaroonError> {
+    if *discharge.identifier() != caveat.id() {
+        error!(
+            "check_discharge_id: Discharge for caveat {:?} has identifier {:?}",
+            caveat.id(),
+            discharge.identifier()
+        );
+        return Err(MacaroonError::DischargeError(format!(
+            "Discharge macaroon identifier does not match caveat id {:?}",
+            redaction::identifier(&caveat.id())
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{discharge_all, MAX_DISCHARGES};
+    use crate::{error::MacaroonError, Macaroon, VerifierBuilder};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn acquire(location: &str, caveat_id: &[u8]) -> Result<Macaroon, MacaroonError> {
+        match caveat_id {
+            b"bank caveat" => {
+                let mut discharge = Macaroon::create(location, b"bank key", "bank caveat")?;
+                discharge.add_first_party_caveat("account = 12345678");
+                discharge.add_third_party_caveat("http://auth.id/", b"id key", "id caveat");
+                Ok(discharge)
+            }
+            b"id caveat" => {
+                let mut discharge = Macaroon::create(location, b"id key", "id caveat")?;
+                discharge.add_first_party_caveat("user = alice");
+                Ok(discharge)
+            }
+            _ => Err(MacaroonError::DischargeError(String::from(
+                "Unknown caveat",
+            ))),
+        }
+    }
+
+    #[test]
+    fn test_discharge_all() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let stack = discharge_all(&macaroon, &acquire).unwrap();
+        assert_eq!(3, stack.len());
+        assert_eq!(macaroon, stack[0]);
+        assert_eq!("bank caveat", stack[1].identifier());
+        assert_eq!("id caveat", stack[2].identifier());
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 12345678");
+        builder.satisfy_exact("user = alice");
+        builder.add_discharge_macaroons(&stack[1..]);
+        let key = crate::crypto::generate_derived_key(b"root key");
+        let verifier = builder.build();
+        assert!(macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[test]
+    fn test_discharge_all_acquirer_error() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        macaroon.add_third_party_caveat("http://elsewhere/", b"other key", "other caveat");
+        assert!(discharge_all(&macaroon, &acquire).is_err());
+    }
+
+    // Each discharge has a third-party caveat of its own, needing another discharge
+    fn acquire_endlessly(
+        acquired: &AtomicUsize,
+        location: &str,
+        caveat_id: &[u8],
+    ) -> Result<Macaroon, MacaroonError> {
+        let id = std::str::from_utf8(caveat_id).unwrap();
+        acquired.fetch_add(1, Ordering::Relaxed);
+        let mut discharge = Macaroon::create(location, b"key", id)?;
+        discharge.add_third_party_caveat(location, b"key", &format!("{}+", id));
+        Ok(discharge)
+    }
+
+    #[test]
+    fn test_discharge_all_too_many() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"key", "caveat");
+        let acquired = AtomicUsize::new(0);
+        let acquirer =
+            |location: &str, caveat_id: &[u8]| acquire_endlessly(&acquired, location, caveat_id);
+        assert!(matches!(
+            discharge_all(&macaroon, &acquirer),
+            Err(MacaroonError::DischargeError(_))
+        ));
+        assert_eq!(MAX_DISCHARGES, acquired.load(Ordering::Relaxed));
+
+        // Up to the limit is fine
+        let mut chain = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        for i in 0..MAX_DISCHARGES {
+            chain.add_third_party_caveat("http://auth.mybank/", b"key", &format!("caveat {}", i));
+        }
+        let acquirer = |location: &str, caveat_id: &[u8]| {
+            Macaroon::create(location, b"key", std::str::from_utf8(caveat_id).unwrap())
+        };
+        assert_eq!(
+            MAX_DISCHARGES + 1,
+            discharge_all(&chain, &acquirer).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_discharge_all_no_third_party_caveats() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        macaroon.add_first_party_caveat("account = 12345678");
+        let stack = discharge_all(&macaroon, &acquire).unwrap();
+        assert_eq!(vec![macaroon], stack);
+    }
+
+    #[cfg(feature = "async")]
+    struct AsyncAcquirer;
+
+    #[cfg(feature = "async")]
+    impl super::AsyncDischargeAcquirer for AsyncAcquirer {
+        async fn acquire(
+            &self,
+            location: &str,
+            caveat_id: &[u8],
+        ) -> Result<Macaroon, MacaroonError> {
+            acquire(location, caveat_id)
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_discharge_all_async() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let stack = crate::block_on(super::discharge_all_async(&macaroon, &AsyncAcquirer)).unwrap();
+        let identifiers: Vec<&String> = stack.iter().map(Macaroon::identifier).collect();
+        assert_eq!(vec!["root", "bank caveat", "id caveat"], identifiers);
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 12345678");
+        builder.satisfy_exact("user = alice");
+        let verifier = builder.build();
+        let key = crate::MacaroonKey::generate(b"root key");
+        assert!(verifier.verify(&macaroon, &key, &stack[1..]).unwrap());
+
+        struct Endless(AtomicUsize);
+
+        impl super::AsyncDischargeAcquirer for Endless {
+            async fn acquire(
+                &self,
+                location: &str,
+                caveat_id: &[u8],
+            ) -> Result<Macaroon, MacaroonError> {
+                acquire_endlessly(&self.0, location, caveat_id)
+            }
+        }
+
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"key", "caveat");
+        let acquirer = Endless(AtomicUsize::new(0));
+        assert!(matches!(
+            crate::block_on(super::discharge_all_async(&macaroon, &acquirer)),
+            Err(MacaroonError::DischargeError(_))
+        ));
+        assert_eq!(MAX_DISCHARGES, acquirer.0.load(Ordering::Relaxed));
+    }
+}
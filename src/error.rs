@@ -1,44 +1,291 @@
+#[cfg(feature = "serde_json")]
+use crate::redaction::{self, RedactionPolicy};
+#[cfg(feature = "rustc-serialize")]
 use rustc_serialize::base64;
-use std::{num, str, string};
+use std::{error, fmt, io, str};
 
 #[derive(Debug)]
 pub enum MacaroonError {
+    /// The cryptographic library couldn't be initialized
     InitializationError,
-    HashFailed,
-    NotUTF8(str::Utf8Error),
-    UnknownSerialization,
-    DeserializationError(String),
+    /// A cryptographic operation failed, such as decrypting a third-party caveat's verifier id
+    Crypto(&'static str),
+    /// A field which should be text wasn't valid UTF-8
+    Utf8 {
+        field: &'static str,
+        source: str::Utf8Error,
+    },
+    /// A base64-encoded field couldn't be decoded
+    #[cfg(feature = "rustc-serialize")]
+    Base64 {
+        field: &'static str,
+        source: base64::FromBase64Error,
+    },
+    /// The JSON serialization couldn't be parsed or produced
+    #[cfg(feature = "serde_json")]
+    Json(serde_json::Error),
+    /// The MessagePack serialization couldn't be parsed
+    #[cfg(feature = "msgpack")]
+    MsgPack(rmp_serde::decode::Error),
+    /// The data isn't in the format expected at the given byte offset
+    ///
+    /// This is what is returned for data which isn't a serialized macaroon at all, or is in an
+    /// unsupported version (at offset 0).
+    Format {
+        offset: usize,
+        expected: &'static str,
+        found: String,
+    },
+    /// The data ended part way through, at the given byte offset
+    Truncated { offset: usize },
+    /// A required field is missing
+    MissingField(&'static str),
+    /// A field appears more than once, or in two alternative encodings
+    DuplicateField(&'static str),
+    /// A field is present but its value is malformed
+    InvalidField {
+        field: &'static str,
+        reason: &'static str,
+    },
+    /// The macaroon is well-formed, but not usable as requested
     BadMacaroon(&'static str),
-    KeyError(&'static str),
-    DecryptionError(&'static str),
+    /// The signature of a macaroon, or the binding of a discharge macaroon, is wrong
+    InvalidSignature,
+    /// A caveat wasn't satisfied
+    ///
+    /// Holds the predicate (or the identifier of a third-party caveat), subject to the redaction
+    /// policy, and the caveat's index among those of the macaroon it belongs to, which is a
+    /// discharge rather than the root macaroon if the caveat is one of a discharge's.
+    CaveatNotSatisfied { caveat: String, index: usize },
+    /// A caveat isn't in any of the namespaces the verifier routes to checkers, and nothing else
+    /// satisfied it (holds the first word of its predicate)
+    UnknownCaveat(String),
+    /// The key of a third-party caveat couldn't be decrypted from its verifier id, which means
+    /// the verifier id is corrupt, or wasn't encrypted with the macaroon's signature chain
+    ///
+    /// Holds the caveat's identifier and location (both subject to the redaction policy), and
+    /// why decryption failed. Key material is never included.
+    CaveatKeyDecryption {
+        id: String,
+        location: String,
+        reason: &'static str,
+    },
+    /// A discharge macaroon couldn't be acquired or issued
+    DischargeError(String),
+    /// A discharge macaroon wasn't needed to verify a macaroon (holds its identifier)
+    DischargeNotUsed(String),
+    /// A discharge macaroon doesn't discharge any of the caveats of a macaroon, or isn't bound
+    /// to it (holds its identifier, and which of the two)
+    DischargeNotBound { id: String, reason: &'static str },
+    /// Reading or writing tokens failed
+    Io(io::Error),
 }
 
+impl MacaroonError {
+    /// Whether the error means the token is corrupt (or isn't a token at all)
+    ///
+    /// This is as opposed to a token which was parsed fine but isn't valid or authorized, or
+    /// an error in the environment such as an I/O error.
+    pub fn is_malformed(&self) -> bool {
+        match self {
+            MacaroonError::Utf8 { .. }
+            | MacaroonError::Format { .. }
+            | MacaroonError::Truncated { .. }
+            | MacaroonError::MissingField(_)
+            | MacaroonError::DuplicateField(_)
+            | MacaroonError::InvalidField { .. } => true,
+            #[cfg(feature = "rustc-serialize")]
+            MacaroonError::Base64 { .. } => true,
+            #[cfg(feature = "serde_json")]
+            MacaroonError::Json(_) => true,
+            #[cfg(feature = "msgpack")]
+            MacaroonError::MsgPack(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for MacaroonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MacaroonError::InitializationError => {
+                write!(f, "Failed to initialize cryptographic library")
+            }
+            MacaroonError::Crypto(message) => write!(f, "Cryptographic error: {}", message),
+            MacaroonError::Utf8 { field, source } => {
+                write!(f, "Field {} is not valid UTF-8: {}", field, source)
+            }
+            #[cfg(feature = "rustc-serialize")]
+            MacaroonError::Base64 { field, source } => {
+                write!(f, "Field {} is not valid base64: {}", field, source)
+            }
+            // serde_json's messages can quote the offending input, so only give its position
+            // unless the redaction policy allows token content in errors
+            #[cfg(feature = "serde_json")]
+            MacaroonError::Json(error) => match redaction::policy() {
+                RedactionPolicy::Full => write!(f, "JSON error: {}", error),
+                _ => write!(
+                    f,
+                    "JSON {:?} error at line {} column {}",
+                    error.classify(),
+                    error.line(),
+                    error.column()
+                ),
+            },
+            // As with JSON, the messages can quote the offending input
+            #[cfg(feature = "msgpack")]
+            MacaroonError::MsgPack(error) => match redaction::policy() {
+                RedactionPolicy::Full => write!(f, "MessagePack error: {}", error),
+                _ => write!(f, "Invalid MessagePack serialization"),
+            },
+            MacaroonError::Format {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Expected {} at offset {}, found {}",
+                expected, offset, found
+            ),
+            MacaroonError::Truncated { offset } => {
+                write!(f, "Data truncated at offset {}", offset)
+            }
+            MacaroonError::MissingField(field) => write!(f, "Missing field {}", field),
+            MacaroonError::DuplicateField(field) => write!(f, "Duplicate field {}", field),
+            MacaroonError::InvalidField { field, reason } => {
+                write!(f, "Invalid field {}: {}", field, reason)
+            }
+            MacaroonError::BadMacaroon(message) => write!(f, "Bad macaroon: {}", message),
+            MacaroonError::InvalidSignature => write!(f, "Invalid macaroon signature"),
+            MacaroonError::CaveatNotSatisfied { caveat, index } => {
+                write!(f, "Caveat {} not satisfied: {}", index, caveat)
+            }
+            MacaroonError::UnknownCaveat(prefix) => {
+                write!(f, "No checker for caveats starting {}", prefix)
+            }
+            MacaroonError::CaveatKeyDecryption {
+                id,
+                location,
+                reason,
+            } => write!(
+                f,
+                "Couldn't decrypt the key of caveat {} for {}: {}",
+                id, location, reason
+            ),
+            MacaroonError::DischargeError(message) => write!(f, "Discharge error: {}", message),
+            MacaroonError::DischargeNotUsed(id) => {
+                write!(f, "Discharge macaroon {} not used", id)
+            }
+            MacaroonError::DischargeNotBound { id, reason } => {
+                write!(f, "Discharge macaroon {} not bound: {}", id, reason)
+            }
+            MacaroonError::Io(error) => write!(f, "I/O error: {}", error),
+        }
+    }
+}
+
+impl error::Error for MacaroonError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MacaroonError::Utf8 { source, .. } => Some(source),
+            #[cfg(feature = "rustc-serialize")]
+            MacaroonError::Base64 { source, .. } => Some(source),
+            #[cfg(feature = "serde_json")]
+            MacaroonError::Json(error) => Some(error),
+            #[cfg(feature = "msgpack")]
+            MacaroonError::MsgPack(error) => Some(error),
+            MacaroonError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "serde_json")]
 impl From<serde_json::Error> for MacaroonError {
     fn from(error: serde_json::Error) -> MacaroonError {
-        MacaroonError::DeserializationError(format!("{}", error))
+        MacaroonError::Json(error)
     }
 }
 
-impl From<string::FromUtf8Error> for MacaroonError {
-    fn from(error: string::FromUtf8Error) -> MacaroonError {
-        MacaroonError::DeserializationError(format!("{}", error))
+#[cfg(feature = "msgpack")]
+impl From<rmp_serde::decode::Error> for MacaroonError {
+    fn from(error: rmp_serde::decode::Error) -> MacaroonError {
+        MacaroonError::MsgPack(error)
     }
 }
 
-impl From<base64::FromBase64Error> for MacaroonError {
-    fn from(error: base64::FromBase64Error) -> MacaroonError {
-        MacaroonError::DeserializationError(format!("{}", error))
+// Only writing to a failing writer can fail, since every macaroon can be encoded
+#[cfg(feature = "msgpack")]
+impl From<rmp_serde::encode::Error> for MacaroonError {
+    fn from(error: rmp_serde::encode::Error) -> MacaroonError {
+        MacaroonError::Io(io::Error::other(error))
     }
 }
 
-impl From<num::ParseIntError> for MacaroonError {
-    fn from(error: num::ParseIntError) -> MacaroonError {
-        MacaroonError::DeserializationError(format!("{}", error))
+impl From<io::Error> for MacaroonError {
+    fn from(error: io::Error) -> MacaroonError {
+        MacaroonError::Io(error)
     }
 }
 
-impl From<str::Utf8Error> for MacaroonError {
-    fn from(error: str::Utf8Error) -> MacaroonError {
-        MacaroonError::DeserializationError(format!("{}", error))
+/// Convert a field to a string, naming the field if it isn't valid UTF-8
+#[cfg(feature = "rustc-serialize")]
+pub(crate) fn utf8_field(field: &'static str, bytes: Vec<u8>) -> Result<String, MacaroonError> {
+    String::from_utf8(bytes).map_err(|error: std::string::FromUtf8Error| MacaroonError::Utf8 {
+        field,
+        source: error.utf8_error(),
+    })
+}
+
+/// Borrow a field as a string, naming the field if it isn't valid UTF-8
+pub(crate) fn utf8_str<'a>(field: &'static str, bytes: &'a [u8]) -> Result<&'a str, MacaroonError> {
+    str::from_utf8(bytes).map_err(|source| MacaroonError::Utf8 { field, source })
+}
+
+/// Decode a base64-encoded field, naming the field if it isn't valid base64
+#[cfg(feature = "rustc-serialize")]
+pub(crate) fn base64_field<T>(field: &'static str, encoded: &T) -> Result<Vec<u8>, MacaroonError>
+where
+    T: base64::FromBase64 + ?Sized,
+{
+    encoded
+        .from_base64()
+        .map_err(|source| MacaroonError::Base64 { field, source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{utf8_str, MacaroonError};
+    use std::error::Error;
+
+    #[test]
+    fn test_utf8_str() {
+        assert_eq!(
+            "caf\u{e9}",
+            utf8_str("identifier", "caf\u{e9}".as_bytes()).unwrap()
+        );
+        let error = utf8_str("identifier", &[0x63, 0xe9]).unwrap_err();
+        assert!(error.is_malformed());
+        assert!(error.source().is_some());
+        match error {
+            MacaroonError::Utf8 { field, .. } => assert_eq!("identifier", field),
+            _ => panic!("Wrong error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        let error = MacaroonError::Format {
+            offset: 0,
+            expected: "version 2",
+            found: String::from("version 3"),
+        };
+        assert_eq!(
+            "Expected version 2 at offset 0, found version 3",
+            error.to_string()
+        );
+        assert!(error.is_malformed());
+        assert!(!MacaroonError::InvalidSignature.is_malformed());
+        assert!(MacaroonError::InvalidSignature.source().is_none());
     }
 }
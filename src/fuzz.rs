@@ -0,0 +1,125 @@
+//! Entry points for fuzzing the deserializers
+//!
+//! Each takes arbitrary input, and panics if deserializing it panics, or if a macaroon it
+//! deserializes can't be serialized again, or doesn't round-trip. The cargo-fuzz targets in the
+//! `fuzz` directory call these (`cargo fuzz run deserialize_v2`, say). They aren't part of the
+//! public API.
+#[cfg(feature = "msgpack")]
+use crate::serialization::msgpack;
+#[cfg(feature = "v1")]
+use crate::serialization::v1;
+#[cfg(feature = "v2j")]
+use crate::serialization::v2j;
+use crate::{error::MacaroonError, serialization::v2, Format, Macaroon, ParseMode};
+
+const MODES: [ParseMode; 2] = [ParseMode::Lenient, ParseMode::Strict];
+
+fn check_round_trip(result: Result<Macaroon, MacaroonError>, format: Format) {
+    let macaroon = match result.and_then(Macaroon::validate) {
+        Ok(macaroon) => macaroon,
+        Err(_) => return,
+    };
+    let serialized = macaroon
+        .serialize(format)
+        .expect("deserialized macaroon failed to serialize");
+    let deserialized =
+        Macaroon::deserialize(&serialized).expect("serialized macaroon failed to deserialize");
+    assert_eq!(macaroon, deserialized, "macaroon didn't round-trip");
+}
+
+/// Deserialize arbitrary input as a version 1 macaroon
+#[cfg(feature = "v1")]
+pub fn fuzz_deserialize_v1(data: &[u8]) {
+    for &mode in &MODES {
+        check_round_trip(v1::deserialize_v1(data, mode), Format::V1);
+    }
+}
+
+/// Deserialize arbitrary input as a version 2 macaroon
+pub fn fuzz_deserialize_v2(data: &[u8]) {
+    for &mode in &MODES {
+        check_round_trip(v2::deserialize_v2(data, mode), Format::V2);
+    }
+}
+
+/// Deserialize arbitrary input as a version 2J macaroon
+#[cfg(feature = "v2j")]
+pub fn fuzz_deserialize_v2j(data: &[u8]) {
+    for &mode in &MODES {
+        check_round_trip(v2j::deserialize_v2j(data, mode), Format::V2J);
+    }
+}
+
+/// Deserialize arbitrary input as a MessagePack macaroon
+#[cfg(feature = "msgpack")]
+pub fn fuzz_deserialize_msgpack(data: &[u8]) {
+    for &mode in &MODES {
+        check_round_trip(msgpack::deserialize_msgpack(data, mode), Format::V2MsgPack);
+    }
+}
+
+/// Deserialize arbitrary input in whatever format it appears to be in
+pub fn fuzz_deserialize(data: &[u8]) {
+    let _ = Macaroon::deserialize(data);
+    let _ = Macaroon::deserialize_with_mode(data, ParseMode::Strict);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Format, Macaroon};
+
+    // Cheap stand-in for a fuzzer: every truncation of a valid serialization, and every
+    // single-byte corruption of it, must be handled without panicking
+    fn mutate<F: Fn(&[u8])>(serialized: &[u8], fuzz: F) {
+        for len in 0..serialized.len() {
+            fuzz(&serialized[..len]);
+        }
+        let mut data = serialized.to_vec();
+        for index in 0..data.len() {
+            let original = data[index];
+            for &byte in &[0x00, 0x01, 0x02, 0x06, 0x7f, 0x80, 0xff, b'{', b'"', b'0'] {
+                data[index] = byte;
+                fuzz(&data);
+            }
+            data[index] = original;
+        }
+    }
+
+    fn macaroon() -> Macaroon {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"caveat key", "caveat");
+        macaroon
+    }
+
+    #[cfg(feature = "v1")]
+    #[test]
+    fn test_fuzz_deserialize_v1() {
+        let serialized = macaroon().serialize(Format::V1).unwrap();
+        mutate(&serialized, super::fuzz_deserialize_v1);
+        mutate(&serialized, super::fuzz_deserialize);
+    }
+
+    #[test]
+    fn test_fuzz_deserialize_v2() {
+        let serialized = macaroon().serialize(Format::V2).unwrap();
+        mutate(&serialized, super::fuzz_deserialize_v2);
+        mutate(&serialized, super::fuzz_deserialize);
+    }
+
+    #[cfg(feature = "v2j")]
+    #[test]
+    fn test_fuzz_deserialize_v2j() {
+        let serialized = macaroon().serialize(Format::V2J).unwrap();
+        mutate(&serialized, super::fuzz_deserialize_v2j);
+        mutate(&serialized, super::fuzz_deserialize);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_fuzz_deserialize_msgpack() {
+        let serialized = macaroon().serialize(Format::V2MsgPack).unwrap();
+        mutate(&serialized, super::fuzz_deserialize_msgpack);
+        mutate(&serialized, super::fuzz_deserialize);
+    }
+}
@@ -0,0 +1,318 @@
+//! Discharging third-party caveats over HTTP
+//!
+//! This implements the `/discharge` protocol used by go-macaroon-bakery and candid: the client
+//! POSTs the caveat id as a form field (`id`, or `id64` for base64-encoded ids) to
+//! `<caveat location>/discharge`, and the third party responds with a JSON object of the form
+//! `{"Macaroon": <discharge macaroon in V2J format>}`.
+//!
+//! It also has helpers for services verifying the macaroons sent with HTTP requests.
+use crate::{
+    discharge::DischargeAcquirer,
+    error::{base64_field, utf8_field, MacaroonError},
+    redaction,
+    serialization::Format,
+    Macaroon, VerifyContext,
+};
+use rustc_serialize::base64::{ToBase64, URL_SAFE};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::net::IpAddr;
+
+const DISCHARGE_PATH: &str = "discharge";
+
+// The most of a discharge response which is read; discharges are rarely more than a few
+// kilobytes, and this keeps a broken or hostile third party from using up the client's memory
+const MAX_DISCHARGE_RESPONSE_LEN: u64 = 1 << 20;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DischargeResponse {
+    #[serde(rename = "Macaroon")]
+    macaroon: serde_json::Value,
+}
+
+/// Acquires discharge macaroons from third parties over HTTP
+///
+/// Responses of more than a megabyte are rejected.
+#[derive(Default)]
+pub struct HttpAcquirer {}
+
+impl HttpAcquirer {
+    /// Create a new HttpAcquirer
+    pub fn new() -> HttpAcquirer {
+        Default::default()
+    }
+
+    fn discharge_url(location: &str) -> String {
+        format!("{}/{}", location.trim_end_matches('/'), DISCHARGE_PATH)
+    }
+}
+
+impl DischargeAcquirer for HttpAcquirer {
+    fn acquire(&self, location: &str, caveat_id: &[u8]) -> Result<Macaroon, MacaroonError> {
+        let url = HttpAcquirer::discharge_url(location);
+        let (field, value) = discharge_request_field(caveat_id);
+        let mut response = match ureq::post(&url).send_form([(field, value.as_str())]) {
+            Ok(response) => response,
+            Err(error) => {
+                error!("HttpAcquirer::acquire: Error requesting {}: {}", url, error);
+                return Err(MacaroonError::DischargeError(format!(
+                    "Error requesting discharge from {}: {}",
+                    redaction::content(&url),
+                    error
+                )));
+            }
+        };
+        let body = read_discharge_response(response.body_mut().as_reader(), &url)?;
+        parse_discharge_response(&body)
+    }
+}
+
+fn read_discharge_response<R: Read>(reader: R, url: &str) -> Result<Vec<u8>, MacaroonError> {
+    let mut body: Vec<u8> = Vec::new();
+    // One byte more than the limit, to tell a response of exactly the limit from a longer one
+    if let Err(error) = reader
+        .take(MAX_DISCHARGE_RESPONSE_LEN + 1)
+        .read_to_end(&mut body)
+    {
+        return Err(MacaroonError::DischargeError(format!(
+            "Error reading discharge response from {}: {}",
+            redaction::content(url),
+            error
+        )));
+    }
+    if body.len() as u64 > MAX_DISCHARGE_RESPONSE_LEN {
+        return Err(MacaroonError::DischargeError(format!(
+            "Discharge response from {} is longer than {} bytes",
+            redaction::content(url),
+            MAX_DISCHARGE_RESPONSE_LEN
+        )));
+    }
+    Ok(body)
+}
+
+// The form field a caveat id is sent in: ids which aren't UTF-8 are sent base64-encoded, as the
+// bakery sends them
+fn discharge_request_field(caveat_id: &[u8]) -> (&'static str, String) {
+    match std::str::from_utf8(caveat_id) {
+        Ok(caveat_id) => ("id", String::from(caveat_id)),
+        Err(_) => ("id64", caveat_id.to_base64(URL_SAFE)),
+    }
+}
+
+fn parse_discharge_response(body: &[u8]) -> Result<Macaroon, MacaroonError> {
+    let response: DischargeResponse = serde_json::from_slice(body)?;
+    Macaroon::deserialize(&serde_json::to_vec(&response.macaroon)?)
+}
+
+fn parse_discharge_request(body: &[u8]) -> Result<String, MacaroonError> {
+    let mut caveat_id: Option<String> = None;
+    for (key, value) in form_urlencoded::parse(body) {
+        let id = match key.as_ref() {
+            "id" => value.into_owned(),
+            "id64" => utf8_field("id64", base64_field("id64", value.as_ref())?)?,
+            _ => continue,
+        };
+        if caveat_id.is_some() {
+            return Err(MacaroonError::DischargeError(String::from(
+                "Multiple caveat ids in discharge request",
+            )));
+        }
+        caveat_id = Some(id);
+    }
+    match caveat_id {
+        Some(id) if !id.is_empty() => Ok(id),
+        _ => Err(MacaroonError::DischargeError(String::from(
+            "No caveat id in discharge request",
+        ))),
+    }
+}
+
+/// Handle a discharge request on the third-party side
+///
+/// Takes the (form-encoded) body of a POST to the discharge endpoint, extracts the caveat id,
+/// and hands it to `discharger`, which is responsible for checking whatever conditions the
+/// third party requires and minting the discharge macaroon. Returns the JSON body to send back
+/// to the client.
+///
+/// # Errors
+/// Returns `MacaroonError::DischargeError` if the request is malformed or the discharge minted
+/// doesn't match the requested caveat id, and passes through any error from `discharger`. The
+/// caller should respond with an error status in this case.
+pub fn handle_discharge_request<F>(body: &[u8], discharger: F) -> Result<Vec<u8>, MacaroonError>
+where
+    F: Fn(&str) -> Result<Macaroon, MacaroonError>,
+{
+    let caveat_id = parse_discharge_request(body)?;
+    let discharge = discharger(&caveat_id)?;
+    if *discharge.identifier() != caveat_id {
+        return Err(MacaroonError::DischargeError(format!(
+            "Discharge macaroon identifier does not match caveat id {:?}",
+            redaction::identifier(&caveat_id)
+        )));
+    }
+    let response = DischargeResponse {
+        macaroon: serde_json::from_slice(&discharge.serialize(Format::V2J)?)?,
+    };
+    Ok(serde_json::to_vec(&response)?)
+}
+
+/// Create the context for verifying the macaroons sent with an HTTP request
+///
+/// The context has the address of the client and the digest of the request body, so that
+/// `std_caveats::check_body_sha256` can check `body-sha256` caveats.
+pub fn request_context(peer_addr: IpAddr, body: &[u8]) -> VerifyContext {
+    let mut context = VerifyContext::new();
+    context.set_peer_addr(peer_addr);
+    context.set_body(body);
+    context
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        discharge_request_field, handle_discharge_request, parse_discharge_request,
+        read_discharge_response, request_context, HttpAcquirer, MAX_DISCHARGE_RESPONSE_LEN,
+    };
+    use crate::{
+        discharge::discharge_all, error::MacaroonError, std_caveats, Macaroon, MacaroonKey,
+        VerifierBuilder,
+    };
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn discharger(caveat_id: &str) -> Result<Macaroon, MacaroonError> {
+        match caveat_id {
+            "bank caveat" => {
+                let mut discharge =
+                    Macaroon::create("http://auth.mybank/", b"bank key", caveat_id)?;
+                discharge.add_first_party_caveat("account = 12345678");
+                Ok(discharge)
+            }
+            _ => Err(MacaroonError::DischargeError(String::from(
+                "Unknown caveat",
+            ))),
+        }
+    }
+
+    fn serve_one(listener: TcpListener) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(length) = line.to_lowercase().strip_prefix("content-length: ") {
+                content_length = length.parse().unwrap();
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        let (status, response) = match handle_discharge_request(&body, discharger) {
+            Ok(response) => ("200 OK", response),
+            Err(_) => ("403 Forbidden", b"{}".to_vec()),
+        };
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            status,
+            response.len()
+        )
+        .unwrap();
+        stream.write_all(&response).unwrap();
+    }
+
+    #[test]
+    fn test_request_context() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "upload").unwrap();
+        macaroon.add_first_party_caveat(&std_caveats::body_sha256(b"file contents"));
+        let key = MacaroonKey::generate(b"root key");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_general(std_caveats::check_body_sha256);
+        let verifier = builder.build();
+
+        let peer = "192.0.2.1".parse().unwrap();
+        let context = request_context(peer, b"file contents");
+        assert_eq!(Some(peer), context.peer_addr());
+        assert!(verifier
+            .verify_with_context(&macaroon, &key, &[], &context)
+            .unwrap());
+        let context = request_context(peer, b"other contents");
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &context)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_parse_discharge_request() {
+        assert_eq!(
+            "bank caveat",
+            parse_discharge_request(b"id=bank+caveat").unwrap()
+        );
+        assert_eq!(
+            "bank caveat",
+            parse_discharge_request(b"id64=YmFuayBjYXZlYXQ").unwrap()
+        );
+        assert!(parse_discharge_request(b"").is_err());
+        assert!(parse_discharge_request(b"id=a&id=b").is_err());
+
+        assert_eq!(
+            ("id", String::from("bank caveat")),
+            discharge_request_field(b"bank caveat")
+        );
+        assert_eq!(
+            ("id64", String::from("_wABAg")),
+            discharge_request_field(&[0xff, 0, 1, 2])
+        );
+    }
+
+    #[test]
+    fn test_handle_discharge_request() {
+        let response = handle_discharge_request(b"id=bank+caveat", discharger).unwrap();
+        let discharge = super::parse_discharge_response(&response).unwrap();
+        assert_eq!("bank caveat", discharge.identifier());
+        assert!(handle_discharge_request(b"id=other+caveat", discharger).is_err());
+    }
+
+    #[test]
+    fn test_read_discharge_response() {
+        let url = "http://auth.mybank/discharge";
+        let response = handle_discharge_request(b"id=bank+caveat", discharger).unwrap();
+        assert_eq!(
+            response,
+            read_discharge_response(response.as_slice(), url).unwrap()
+        );
+        let limit = MAX_DISCHARGE_RESPONSE_LEN as usize;
+        assert_eq!(
+            limit,
+            read_discharge_response(std::io::repeat(b' ').take(limit as u64), url)
+                .unwrap()
+                .len()
+        );
+        // A response which never ends is cut off
+        match read_discharge_response(std::io::repeat(b' '), url) {
+            Err(MacaroonError::DischargeError(message)) => assert!(message.contains("longer")),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_http_acquirer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let location = format!("http://{}/", listener.local_addr().unwrap());
+        let server = thread::spawn(move || serve_one(listener));
+
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        macaroon.add_third_party_caveat(&location, b"bank key", "bank caveat");
+        let stack = discharge_all(&macaroon, &HttpAcquirer::new()).unwrap();
+        server.join().unwrap();
+        assert_eq!(2, stack.len());
+        assert_eq!("bank caveat", stack[1].identifier());
+    }
+}
@@ -0,0 +1,650 @@
+//! Structured macaroon identifiers
+//!
+//! A macaroon's identifier is the one field the issuer controls which the holder can't change,
+//! so it's where the issuer records what it needs to verify the macaroon later: which root key
+//! it was minted with, when, and anything else the service wants to look up. `IdentifierCodec`
+//! is a standard way of doing this, so that each issuer doesn't have to invent its own.
+//!
+//! There are two encodings. The binary one is compact, and is base64-encoded with the URL-safe
+//! alphabet and no padding so that it's a valid identifier. The JSON one (which needs the `v2j`
+//! feature) is longer, but readable. Either can be decoded by any codec, since a JSON identifier
+//! always starts with `{`, which can't appear in base64.
+//!
+//! Identifiers can be read by anyone holding the macaroon, so a payload which the holder
+//! shouldn't see (internal account ids, say) can be encrypted with a key only the service has,
+//! with `IdentifierCodec::set_payload_key`. The rest of the metadata is always readable.
+//!
+//! Third-party caveats need ids too, which must differ between caveats, since discharges are
+//! matched to caveats by id. `ThirdPartyCaveatId` makes them from a random nonce, along with a
+//! payload for the third party if there is one.
+//!
+//! Times are recorded to the second.
+use crate::{
+    crypto::{self, WRAPPED_KEY_LEN},
+    error::{base64_field, MacaroonError},
+    MacaroonKey, NONCE_LEN,
+};
+use rustc_serialize::base64::{Config, ToBase64, URL_SAFE};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The first byte of a binary identifier, for changes to its layout
+const BINARY_VERSION: u8 = 1;
+
+// Flags for the optional fields of a binary identifier
+const HAS_EXPIRY: u8 = 1;
+const HAS_PAYLOAD: u8 = 2;
+const ENCRYPTED_PAYLOAD: u8 = 4;
+const HAS_WRAPPED_KEY: u8 = 8;
+
+const BASE64: Config = Config {
+    pad: false,
+    ..URL_SAFE
+};
+
+/// What an identifier records about a macaroon
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdentifierMetadata {
+    /// The version of the service's own identifier schema (this is 0 unless it's set), which
+    /// lets it change what goes in the payload
+    pub version: u32,
+    /// Identifies the root key the macaroon was minted with, for looking it up at verification
+    pub key_id: String,
+    /// When the macaroon was minted
+    pub minted_at: SystemTime,
+    /// When the macaroon expires, if the issuer decided that at minting
+    pub expires_at: Option<SystemTime>,
+    /// Anything else the service wants to record
+    pub payload: Option<Vec<u8>>,
+    /// The macaroon's own root key, wrapped with `crypto::wrap_key` under the key named by
+    /// `key_id`, for session-key macaroons (see `oven::Oven::set_session_keys`)
+    pub wrapped_key: Option<Vec<u8>>,
+}
+
+impl IdentifierMetadata {
+    /// Metadata with just a root key id and mint time
+    pub fn new(key_id: &str, minted_at: SystemTime) -> IdentifierMetadata {
+        IdentifierMetadata {
+            version: 0,
+            key_id: String::from(key_id),
+            minted_at,
+            expires_at: None,
+            payload: None,
+            wrapped_key: None,
+        }
+    }
+}
+
+/// How `IdentifierCodec` encodes identifiers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdentifierEncoding {
+    /// Compact binary, base64-encoded
+    Binary,
+    /// A JSON object
+    #[cfg(feature = "v2j")]
+    Json,
+}
+
+/// Packs `IdentifierMetadata` into macaroon identifiers, and unpacks it again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IdentifierCodec {
+    encoding: IdentifierEncoding,
+    payload_key: Option<MacaroonKey>,
+}
+
+impl IdentifierCodec {
+    /// A codec which encodes identifiers with the given encoding
+    pub fn new(encoding: IdentifierEncoding) -> IdentifierCodec {
+        IdentifierCodec {
+            encoding,
+            payload_key: None,
+        }
+    }
+
+    /// Encrypt payloads with this key, and decrypt them when decoding
+    ///
+    /// Payloads are encrypted with XSalsa20-Poly1305, as third-party caveat keys are. The key
+    /// should be kept apart from the root keys, and, like them, only by the service.
+    pub fn set_payload_key(&mut self, key: MacaroonKey) {
+        self.payload_key = Some(key);
+    }
+
+    /// The encoding identifiers are encoded with
+    pub fn encoding(&self) -> IdentifierEncoding {
+        self.encoding
+    }
+
+    /// Encode metadata as an identifier
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::InvalidField` if a time is before 1970, a wrapped key isn't
+    /// `WRAPPED_KEY_LEN` bytes, or (for the binary encoding) the key id is longer than 65535
+    /// bytes.
+    pub fn encode(&self, metadata: &IdentifierMetadata) -> Result<String, MacaroonError> {
+        if let Some(wrapped_key) = &metadata.wrapped_key {
+            if wrapped_key.len() != WRAPPED_KEY_LEN {
+                return Err(invalid("wrapped key is the wrong length"));
+            }
+        }
+        let encrypted;
+        let metadata = match (self.payload_key, &metadata.payload) {
+            (Some(key), Some(payload)) => {
+                encrypted = IdentifierMetadata {
+                    payload: Some(crypto::encrypt(key.0, payload)),
+                    ..metadata.clone()
+                };
+                &encrypted
+            }
+            _ => metadata,
+        };
+        let is_encrypted = self.payload_key.is_some() && metadata.payload.is_some();
+        match self.encoding {
+            IdentifierEncoding::Binary => encode_binary(metadata, is_encrypted),
+            #[cfg(feature = "v2j")]
+            IdentifierEncoding::Json => json::encode(metadata, is_encrypted),
+        }
+    }
+
+    /// Decode an identifier, in either encoding, decrypting the payload if it's encrypted
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::InvalidField` or `MacaroonError::Base64` if the identifier isn't
+    /// one encoded by a codec, or its payload is encrypted and this codec doesn't have the key,
+    /// or a JSON error if it's malformed JSON.
+    pub fn decode(&self, identifier: &str) -> Result<IdentifierMetadata, MacaroonError> {
+        let (mut metadata, is_encrypted) = decode(identifier)?;
+        if is_encrypted {
+            let key = self
+                .payload_key
+                .ok_or_else(|| invalid("payload is encrypted"))?;
+            metadata.payload = match metadata.payload {
+                Some(payload) => Some(
+                    crypto::decrypt(key.0, &payload)
+                        .map_err(|_| invalid("payload couldn't be decrypted"))?,
+                ),
+                None => None,
+            };
+        }
+        Ok(metadata)
+    }
+}
+
+impl Default for IdentifierCodec {
+    /// A codec using the binary encoding
+    fn default() -> IdentifierCodec {
+        IdentifierCodec::new(IdentifierEncoding::Binary)
+    }
+}
+
+/// The mint time recorded in an identifier, for `VerifierBuilder::set_issued_at_extractor`
+pub fn issued_at(identifier: &str) -> Option<SystemTime> {
+    decode(identifier)
+        .ok()
+        .map(|(metadata, _)| metadata.minted_at)
+}
+
+/// A third-party caveat id, which is a random nonce followed by an optional payload
+///
+/// The nonce makes ids collision-resistant, so that a macaroon never has two third-party
+/// caveats with the same id, even with the same payload, and a discharge for one can't be used
+/// for another. The payload is whatever the third party needs to decide whether to discharge
+/// the caveat; like identifiers, it can be read by anyone holding the macaroon. Ids are
+/// base64-encoded with the URL-safe alphabet and no padding.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThirdPartyCaveatId {
+    /// The random part of the id
+    pub nonce: [u8; NONCE_LEN],
+    /// Anything the issuer wants to tell the third party, which may be empty
+    pub payload: Vec<u8>,
+}
+
+impl ThirdPartyCaveatId {
+    /// An id with a new random nonce, and no payload
+    pub fn random() -> ThirdPartyCaveatId {
+        ThirdPartyCaveatId::with_payload(&[])
+    }
+
+    /// An id with a new random nonce, and the given payload
+    pub fn with_payload(payload: &[u8]) -> ThirdPartyCaveatId {
+        ThirdPartyCaveatId {
+            nonce: crypto::gen_nonce(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    /// Encode the id, for `Macaroon::add_third_party_caveat`
+    pub fn encode(&self) -> String {
+        let mut data = self.nonce.to_vec();
+        data.extend_from_slice(&self.payload);
+        data.to_base64(BASE64)
+    }
+
+    /// Decode an id, as the third party does to read its payload
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::Base64` if the id isn't base64, or `MacaroonError::InvalidField`
+    /// if it's too short to hold a nonce.
+    pub fn decode(id: &str) -> Result<ThirdPartyCaveatId, MacaroonError> {
+        let data = base64_field("caveat identifier", id)?;
+        if data.len() < NONCE_LEN {
+            return Err(MacaroonError::InvalidField {
+                field: "caveat identifier",
+                reason: "too short to hold a nonce",
+            });
+        }
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&data[..NONCE_LEN]);
+        Ok(ThirdPartyCaveatId {
+            nonce,
+            payload: data[NONCE_LEN..].to_vec(),
+        })
+    }
+}
+
+// Decode an identifier, and whether its payload is encrypted
+fn decode(identifier: &str) -> Result<(IdentifierMetadata, bool), MacaroonError> {
+    if identifier.starts_with('{') {
+        #[cfg(feature = "v2j")]
+        return json::decode(identifier);
+        #[cfg(not(feature = "v2j"))]
+        return Err(invalid("JSON identifiers need the v2j feature"));
+    }
+    decode_binary(&base64_field("identifier", identifier)?)
+}
+
+fn invalid(reason: &'static str) -> MacaroonError {
+    MacaroonError::InvalidField {
+        field: "identifier",
+        reason,
+    }
+}
+
+fn to_secs(time: SystemTime) -> Result<u64, MacaroonError> {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .map_err(|_| invalid("time is before 1970"))
+}
+
+fn from_secs(secs: u64) -> Result<SystemTime, MacaroonError> {
+    UNIX_EPOCH
+        .checked_add(Duration::from_secs(secs))
+        .ok_or_else(|| invalid("time is out of range"))
+}
+
+// The version, flags, schema version and mint time, then the expiry if there is one, then the
+// key id preceded by its length, then the wrapped key if there is one (which is always
+// `WRAPPED_KEY_LEN` bytes), then the payload (if there is one) to the end
+fn encode_binary(metadata: &IdentifierMetadata, encrypted: bool) -> Result<String, MacaroonError> {
+    let key_len =
+        u16::try_from(metadata.key_id.len()).map_err(|_| invalid("key id is too long"))?;
+    let mut flags = 0;
+    if metadata.expires_at.is_some() {
+        flags |= HAS_EXPIRY;
+    }
+    if metadata.payload.is_some() {
+        flags |= HAS_PAYLOAD;
+    }
+    if encrypted {
+        flags |= ENCRYPTED_PAYLOAD;
+    }
+    if metadata.wrapped_key.is_some() {
+        flags |= HAS_WRAPPED_KEY;
+    }
+    let mut data = vec![BINARY_VERSION, flags];
+    data.extend_from_slice(&metadata.version.to_be_bytes());
+    data.extend_from_slice(&to_secs(metadata.minted_at)?.to_be_bytes());
+    if let Some(expires_at) = metadata.expires_at {
+        data.extend_from_slice(&to_secs(expires_at)?.to_be_bytes());
+    }
+    data.extend_from_slice(&key_len.to_be_bytes());
+    data.extend_from_slice(metadata.key_id.as_bytes());
+    if let Some(wrapped_key) = &metadata.wrapped_key {
+        data.extend_from_slice(wrapped_key);
+    }
+    if let Some(payload) = &metadata.payload {
+        data.extend_from_slice(payload);
+    }
+    Ok(data.to_base64(BASE64))
+}
+
+// Reads the fields of a binary identifier in turn
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MacaroonError> {
+        if self.0.len() < len {
+            return Err(invalid("truncated metadata"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, MacaroonError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MacaroonError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_be_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32, MacaroonError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, MacaroonError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+fn decode_binary(data: &[u8]) -> Result<(IdentifierMetadata, bool), MacaroonError> {
+    let mut reader = Reader(data);
+    if reader.u8()? != BINARY_VERSION {
+        return Err(invalid("unknown metadata version"));
+    }
+    let flags = reader.u8()?;
+    if flags & !(HAS_EXPIRY | HAS_PAYLOAD | ENCRYPTED_PAYLOAD | HAS_WRAPPED_KEY) != 0
+        || flags & (HAS_PAYLOAD | ENCRYPTED_PAYLOAD) == ENCRYPTED_PAYLOAD
+    {
+        return Err(invalid("unknown metadata flags"));
+    }
+    let version = reader.u32()?;
+    let minted_at = from_secs(reader.u64()?)?;
+    let expires_at = match flags & HAS_EXPIRY {
+        0 => None,
+        _ => Some(from_secs(reader.u64()?)?),
+    };
+    let key_len = reader.u16()?;
+    let key_id = std::str::from_utf8(reader.take(usize::from(key_len))?)
+        .map_err(|_| invalid("key id isn't valid UTF-8"))?;
+    let wrapped_key = match flags & HAS_WRAPPED_KEY {
+        0 => None,
+        _ => Some(reader.take(WRAPPED_KEY_LEN)?.to_vec()),
+    };
+    let payload = match flags & HAS_PAYLOAD {
+        0 if !reader.0.is_empty() => return Err(invalid("unexpected payload")),
+        0 => None,
+        _ => Some(reader.0.to_vec()),
+    };
+    let metadata = IdentifierMetadata {
+        version,
+        key_id: String::from(key_id),
+        minted_at,
+        expires_at,
+        payload,
+        wrapped_key,
+    };
+    Ok((metadata, flags & ENCRYPTED_PAYLOAD != 0))
+}
+
+#[cfg(feature = "v2j")]
+mod json {
+    use super::{from_secs, invalid, to_secs, IdentifierMetadata, BASE64};
+    use crate::{
+        crypto::WRAPPED_KEY_LEN,
+        error::{base64_field, MacaroonError},
+    };
+    use rustc_serialize::base64::ToBase64;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    #[serde(deny_unknown_fields)]
+    struct JsonIdentifier {
+        v: u32,
+        k: String,
+        t: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        e: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        p: Option<String>,
+        // The payload, when it's encrypted
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        x: Option<String>,
+        // The wrapped key
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        w: Option<String>,
+    }
+
+    pub(super) fn encode(
+        metadata: &IdentifierMetadata,
+        encrypted: bool,
+    ) -> Result<String, MacaroonError> {
+        let payload = metadata
+            .payload
+            .as_ref()
+            .map(|payload| payload.to_base64(BASE64));
+        let json = JsonIdentifier {
+            v: metadata.version,
+            k: metadata.key_id.clone(),
+            t: to_secs(metadata.minted_at)?,
+            e: metadata.expires_at.map(to_secs).transpose()?,
+            p: if encrypted { None } else { payload.clone() },
+            x: if encrypted { payload } else { None },
+            w: metadata
+                .wrapped_key
+                .as_ref()
+                .map(|key| key.to_base64(BASE64)),
+        };
+        Ok(serde_json::to_string(&json)?)
+    }
+
+    pub(super) fn decode(identifier: &str) -> Result<(IdentifierMetadata, bool), MacaroonError> {
+        let json: JsonIdentifier = serde_json::from_str(identifier)?;
+        if json.p.is_some() && json.x.is_some() {
+            return Err(MacaroonError::DuplicateField("identifier payload"));
+        }
+        let encrypted = json.x.is_some();
+        let metadata = IdentifierMetadata {
+            version: json.v,
+            key_id: json.k,
+            minted_at: from_secs(json.t)?,
+            expires_at: json.e.map(from_secs).transpose()?,
+            payload: json
+                .p
+                .or(json.x)
+                .map(|payload| base64_field("identifier payload", payload.as_str()))
+                .transpose()?,
+            wrapped_key: json
+                .w
+                .map(|key| base64_field("wrapped key", key.as_str()))
+                .transpose()?,
+        };
+        if let Some(wrapped_key) = &metadata.wrapped_key {
+            if wrapped_key.len() != WRAPPED_KEY_LEN {
+                return Err(invalid("wrapped key is the wrong length"));
+            }
+        }
+        Ok((metadata, encrypted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{issued_at, IdentifierCodec, IdentifierMetadata, ThirdPartyCaveatId};
+    use crate::{
+        crypto, error::MacaroonError, Macaroon, MacaroonKey, VerifierBuilder, VerifyContext,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn metadata() -> IdentifierMetadata {
+        let minted_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        IdentifierMetadata {
+            version: 3,
+            key_id: String::from("2023-11"),
+            minted_at,
+            expires_at: Some(minted_at + Duration::from_secs(3600)),
+            payload: Some(b"user=alice".to_vec()),
+            wrapped_key: None,
+        }
+    }
+
+    #[test]
+    fn test_binary_identifier() {
+        let codec = IdentifierCodec::default();
+        let identifier = codec.encode(&metadata()).unwrap();
+        assert_eq!(
+            "AQMAAAADAAAAAGVT8QAAAAAAZVP_EAAHMjAyMy0xMXVzZXI9YWxpY2U",
+            identifier
+        );
+        assert_eq!(metadata(), codec.decode(&identifier).unwrap());
+
+        let minimal = IdentifierMetadata::new("key", UNIX_EPOCH + Duration::from_secs(60));
+        let identifier = codec.encode(&minimal).unwrap();
+        assert_eq!(minimal, codec.decode(&identifier).unwrap());
+
+        let before_1970 = IdentifierMetadata::new("key", UNIX_EPOCH - Duration::from_secs(60));
+        assert!(codec.encode(&before_1970).is_err());
+        let long_key = IdentifierMetadata::new(&"k".repeat(70_000), UNIX_EPOCH);
+        assert!(codec.encode(&long_key).is_err());
+
+        // Truncated, and with an unknown version
+        for identifier in &["AQMAAAAD", "AgAAAAAAAAAAAAAAAAAAAA"] {
+            match codec.decode(identifier) {
+                Err(MacaroonError::InvalidField { field, .. }) => assert_eq!("identifier", field),
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+        assert!(codec.decode("not an identifier!").is_err());
+    }
+
+    #[cfg(feature = "v2j")]
+    #[test]
+    fn test_json_identifier() {
+        use super::IdentifierEncoding;
+
+        let codec = IdentifierCodec::new(IdentifierEncoding::Json);
+        let identifier = codec.encode(&metadata()).unwrap();
+        assert_eq!(
+            r#"{"v":3,"k":"2023-11","t":1700000000,"e":1700003600,"p":"dXNlcj1hbGljZQ"}"#,
+            identifier
+        );
+        assert_eq!(metadata(), codec.decode(&identifier).unwrap());
+        assert_eq!(
+            metadata(),
+            IdentifierCodec::default().decode(&identifier).unwrap()
+        );
+        assert!(codec.decode(r#"{"v":3,"k":"2023-11"}"#).is_err());
+        assert!(codec
+            .decode(r#"{"v":3,"k":"2023-11","t":1,"x":1}"#)
+            .is_err());
+    }
+
+    #[test]
+    fn test_wrapped_key() {
+        let wrapping_key = MacaroonKey::generate(b"wrapping key");
+        let session_key = MacaroonKey::generate(b"session key");
+        let mut metadata = metadata();
+        metadata.wrapped_key = Some(crypto::wrap_key(&wrapping_key, &session_key));
+        let mut codecs = vec![IdentifierCodec::default()];
+        #[cfg(feature = "v2j")]
+        codecs.push(IdentifierCodec::new(super::IdentifierEncoding::Json));
+        for codec in &codecs {
+            let identifier = codec.encode(&metadata).unwrap();
+            let decoded = codec.decode(&identifier).unwrap();
+            assert_eq!(metadata, decoded);
+            let wrapped = decoded.wrapped_key.unwrap();
+            assert_eq!(
+                session_key,
+                crypto::unwrap_key(&wrapping_key, &wrapped).unwrap()
+            );
+        }
+
+        let mut truncated = metadata.clone();
+        truncated.wrapped_key = Some(vec![0; 10]);
+        match IdentifierCodec::default().encode(&truncated) {
+            Err(MacaroonError::InvalidField { reason, .. }) => {
+                assert_eq!("wrapped key is the wrong length", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_encrypted_payload() {
+        let mut codec = IdentifierCodec::default();
+        codec.set_payload_key(MacaroonKey::generate(b"payload key"));
+        let identifier = codec.encode(&metadata()).unwrap();
+        assert_eq!(metadata(), codec.decode(&identifier).unwrap());
+        match IdentifierCodec::default().decode(&identifier) {
+            Err(MacaroonError::InvalidField { reason, .. }) => {
+                assert_eq!("payload is encrypted", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+        // Payloads which weren't encrypted are still read
+        let plain = IdentifierCodec::default().encode(&metadata()).unwrap();
+        assert_eq!(metadata(), codec.decode(&plain).unwrap());
+        // As is the mint time, by anyone
+        assert_eq!(Some(metadata().minted_at), issued_at(&identifier));
+    }
+
+    #[test]
+    fn test_issued_at_extractor() {
+        let metadata = metadata();
+        let identifier = IdentifierCodec::default().encode(&metadata).unwrap();
+        assert_eq!(Some(metadata.minted_at), issued_at(&identifier));
+        assert_eq!(None, issued_at("keyid"));
+
+        let macaroon = Macaroon::create("http://example.org/", b"key", &identifier).unwrap();
+        let mut builder = VerifierBuilder::new();
+        builder.set_max_age(Duration::from_secs(3600));
+        builder.set_issued_at_extractor(issued_at);
+        let verifier = builder.build();
+        let mut context = VerifyContext::new();
+        context.set_time(metadata.minted_at + Duration::from_secs(60));
+        let key = MacaroonKey::generate(b"key");
+        assert!(verifier
+            .verify_with_context(&macaroon, &key, &[], &context)
+            .unwrap());
+        context.set_time(metadata.minted_at + Duration::from_secs(7200));
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &context)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_third_party_caveat_id() {
+        let first = ThirdPartyCaveatId::with_payload(b"user = alice");
+        let second = ThirdPartyCaveatId::with_payload(b"user = alice");
+        assert_ne!(first.encode(), second.encode());
+        assert_eq!(first, ThirdPartyCaveatId::decode(&first.encode()).unwrap());
+        let random = ThirdPartyCaveatId::random();
+        assert!(random.payload.is_empty());
+        assert_eq!(
+            random,
+            ThirdPartyCaveatId::decode(&random.encode()).unwrap()
+        );
+        match ThirdPartyCaveatId::decode("c2hvcnQ") {
+            Err(MacaroonError::InvalidField { reason, .. }) => {
+                assert_eq!("too short to hold a nonce", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // Caveats with the same payload each need their own discharge
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        for id in &[first.encode(), second.encode()] {
+            macaroon.add_third_party_caveat("http://auth.mybank/", b"bank key", id);
+        }
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", &first.encode()).unwrap();
+        macaroon.bind(&mut discharge);
+        let verifier = VerifierBuilder::new().build();
+        let key = MacaroonKey::generate(b"key");
+        assert!(!verifier
+            .verify(&macaroon, &key, std::slice::from_ref(&discharge))
+            .unwrap());
+        let mut other =
+            Macaroon::create("http://auth.mybank/", b"bank key", &second.encode()).unwrap();
+        macaroon.bind(&mut other);
+        assert!(verifier
+            .verify(&macaroon, &key, &[discharge, other])
+            .unwrap());
+    }
+}
@@ -0,0 +1,202 @@
+//! Human-readable dumps of macaroons, for debugging and snapshot tests
+//!
+//! `inspect` gives one line per field, in the same order as the version 1 serialization (and
+//! libmacaroons' `macaroon_inspect`), starting with a line giving the version of the format.
+//! The format only changes along with `FORMAT_VERSION`, so the output can be checked into
+//! snapshot tests. Fields are escaped so that each always fits on one line, and binary fields
+//! (verifier ids and the signature) are hex-encoded.
+//!
+//! Verifier ids are encrypted with a random nonce, and so differ every time a third-party
+//! caveat is added, which also changes the signature. `snapshot` leaves both out, giving only
+//! the structure of the macaroon, which is what a snapshot test usually wants to pin down.
+use crate::{caveat::CaveatType, Macaroon};
+use std::fmt::Write;
+
+/// Version of the `inspect` output format
+pub const FORMAT_VERSION: u32 = 1;
+
+// Stands in for fields which `snapshot` leaves out
+const ELIDED: &str = "...";
+
+/// Dump a macaroon in the stable `inspect` format
+pub fn inspect(macaroon: &Macaroon) -> String {
+    dump(macaroon, false)
+}
+
+/// Dump a macaroon in the `inspect` format, without its verifier ids or signature
+pub fn snapshot(macaroon: &Macaroon) -> String {
+    dump(macaroon, true)
+}
+
+/// Compare a macaroon's `snapshot` with the expected snapshot, returning a line diff if they
+/// differ
+///
+/// Lines only in the expected snapshot are prefixed with `-`, those only in the macaroon's
+/// snapshot with `+`, and those in both with a space. Whitespace at the start and end of each
+/// line is ignored, so the expected snapshot can be written as an indented raw string literal.
+pub fn diff_snapshot(macaroon: &Macaroon, expected: &str) -> Option<String> {
+    let actual = snapshot(macaroon);
+    let expected: Vec<&str> = expected.trim().lines().map(str::trim).collect();
+    let actual: Vec<&str> = actual.lines().map(str::trim).collect();
+    if expected == actual {
+        return None;
+    }
+    Some(diff_lines(&expected, &actual))
+}
+
+fn dump(macaroon: &Macaroon, elide: bool) -> String {
+    let mut output = format!("format {}\n", FORMAT_VERSION);
+    if let Some(location) = macaroon.location() {
+        field(&mut output, "location", &escape(&location));
+    }
+    field(&mut output, "identifier", &escape(macaroon.identifier()));
+    for caveat in macaroon.caveats() {
+        match caveat.get_type() {
+            CaveatType::FirstParty => {
+                let first_party = caveat.as_first_party().unwrap();
+                field(&mut output, "cid", &escape(&first_party.predicate()));
+            }
+            CaveatType::ThirdParty => {
+                let third_party = caveat.as_third_party().unwrap();
+                field(&mut output, "cid", &escape(&third_party.id()));
+                match elide {
+                    true => field(&mut output, "vid", ELIDED),
+                    false => field(&mut output, "vid", &hex(&third_party.verifier_id())),
+                }
+                field(&mut output, "cl", &escape(&third_party.location()));
+            }
+        }
+    }
+    match elide {
+        true => field(&mut output, "signature", ELIDED),
+        false => field(
+            &mut output,
+            "signature",
+            &hex(macaroon.signature().as_ref()),
+        ),
+    }
+    output
+}
+
+fn field(output: &mut String, name: &str, value: &str) {
+    writeln!(output, "{} {}", name, value).unwrap();
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c if c.is_control() => write!(escaped, "\\u{{{:x}}}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Line diff based on the longest common subsequence, which is plenty for macaroon-sized input
+fn diff_lines(expected: &[&str], actual: &[&str]) -> String {
+    let mut common = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            writeln!(diff, "  {}", expected[i]).unwrap();
+            i += 1;
+            j += 1;
+        } else if i < expected.len() && (j == actual.len() || common[i + 1][j] >= common[i][j + 1])
+        {
+            writeln!(diff, "- {}", expected[i]).unwrap();
+            i += 1;
+        } else {
+            writeln!(diff, "+ {}", actual[j]).unwrap();
+            j += 1;
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_snapshot, inspect, snapshot};
+    use crate::Macaroon;
+
+    #[test]
+    fn test_inspect() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_first_party_caveat("note = two\nlines");
+        let expected = "format 1\n\
+                        location http://example.org/\n\
+                        identifier keyid\n\
+                        cid account = 3735928559\n\
+                        cid note = two\\nlines\n";
+        let output = inspect(&macaroon);
+        assert!(output.starts_with(expected));
+        let signature = output.lines().last().unwrap();
+        assert_eq!("signature ".len() + 64, signature.len());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"caveat key", "caveat");
+        let expected = r"
+            format 1
+            location http://example.org/
+            identifier keyid
+            cid account = 3735928559
+            cid caveat
+            vid ...
+            cl http://auth.mybank/
+            signature ...
+        ";
+        assert_eq!(None, diff_snapshot(&macaroon, expected));
+
+        // Adding the same third-party caveat again gives a different verifier id, but the same
+        // snapshot
+        let mut other = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        other.add_first_party_caveat("account = 3735928559");
+        other.add_third_party_caveat("http://auth.mybank/", b"caveat key", "caveat");
+        assert_ne!(inspect(&macaroon), inspect(&other));
+        assert_eq!(snapshot(&macaroon), snapshot(&other));
+    }
+
+    #[test]
+    fn test_diff_snapshot() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_first_party_caveat("user = alice");
+        let expected = r"
+            format 1
+            location http://example.org/
+            identifier keyid
+            cid account = 3735928559
+            cid user = bob
+            signature ...
+        ";
+        assert_eq!(
+            Some(String::from(
+                "  format 1\n  location http://example.org/\n  identifier keyid\n  \
+                 cid account = 3735928559\n- cid user = bob\n+ cid user = alice\n  signature ...\n"
+            )),
+            diff_snapshot(&macaroon, expected)
+        );
+    }
+}
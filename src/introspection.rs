@@ -0,0 +1,274 @@
+//! Token introspection, for gateways which speak OAuth
+//!
+//! OAuth-aware gateways check the tokens they're presented by asking an introspection endpoint
+//! (RFC 7662) whether each is active, and what it grants. An `Introspector` does the work of
+//! such an endpoint for macaroons: given a token as the client presented it, it verifies it
+//! with an `Oven` (which finds the root key) and a `Verifier`, and describes the result as an
+//! `IntrospectionResult`, which `to_json` turns into the endpoint's response. Serving the
+//! endpoint, and authenticating the gateway calling it, are left to the service.
+//!
+//! A token is either a single serialized macaroon, in any format `Macaroon::deserialize`
+//! accepts, or a macaroon and its discharges encoded as a go-macaroon-bakery cookie value (see
+//! the `cookie` module). As RFC 7662 requires, tokens which can't be parsed, aren't authorized
+//! or have expired are all just inactive, and nothing else is said about them.
+use crate::{
+    cookie, oven::Oven, oven::RootKeyStore, std_caveats, Macaroon, Verifier, VerifyContext,
+};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What an introspection endpoint says about a token
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IntrospectionResult {
+    /// Whether the token is authorized; if not, none of the other fields are set
+    pub active: bool,
+    /// When the token expires: the earliest of any expiry recorded in its identifier and its
+    /// macaroons' `time-before` caveats
+    pub expires_at: Option<SystemTime>,
+    /// When the macaroon was minted, from its identifier
+    pub issued_at: Option<SystemTime>,
+    /// The macaroon's location
+    pub issuer: Option<String>,
+    /// The attributes declared by `declared` caveats (see `std_caveats::declared`)
+    pub declared: BTreeMap<String, String>,
+    /// The predicates of the first-party caveats of the macaroon and its discharges, in order
+    pub caveats: Vec<String>,
+    /// The locations of the third parties which discharged the macaroon's third-party caveats
+    pub third_parties: Vec<String>,
+}
+
+impl IntrospectionResult {
+    /// The result for a token which isn't active
+    pub fn inactive() -> IntrospectionResult {
+        Default::default()
+    }
+
+    /// The RFC 7662 response
+    ///
+    /// Times are given as seconds since the epoch, in `exp` and `iat`, and the location as
+    /// `iss`. The rest of the result goes in the extension fields `declared`, `caveats` and
+    /// `third_parties`. An inactive token is just `{"active":false}`.
+    pub fn to_json(&self) -> String {
+        if !self.active {
+            return json!({ "active": false }).to_string();
+        }
+        let mut response = Map::new();
+        response.insert(String::from("active"), Value::Bool(true));
+        let seconds = |time: SystemTime| {
+            json!(time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs())
+        };
+        if let Some(expires_at) = self.expires_at {
+            response.insert(String::from("exp"), seconds(expires_at));
+        }
+        if let Some(issued_at) = self.issued_at {
+            response.insert(String::from("iat"), seconds(issued_at));
+        }
+        if let Some(issuer) = &self.issuer {
+            response.insert(String::from("iss"), json!(issuer));
+        }
+        response.insert(String::from("declared"), json!(self.declared));
+        response.insert(String::from("caveats"), json!(self.caveats));
+        response.insert(String::from("third_parties"), json!(self.third_parties));
+        Value::Object(response).to_string()
+    }
+}
+
+/// Answers introspection requests for macaroons minted by an oven
+pub struct Introspector<S: RootKeyStore> {
+    oven: Oven<S>,
+    verifier: Verifier,
+}
+
+impl<S: RootKeyStore> Introspector<S> {
+    /// An introspector verifying macaroons minted by the oven against the verifier
+    ///
+    /// `declared` caveats are satisfied and collected whether or not the verifier does so
+    /// itself (see `VerifierBuilder::collect_declarations`).
+    pub fn new(oven: Oven<S>, verifier: Verifier) -> Introspector<S> {
+        Introspector { oven, verifier }
+    }
+
+    /// The oven the macaroons are minted by
+    pub fn oven(&self) -> &Oven<S> {
+        &self.oven
+    }
+
+    /// Describe a token, as of now
+    pub fn introspect(&self, token: &str) -> IntrospectionResult {
+        self.introspect_with_context(token, &VerifyContext::new())
+    }
+
+    /// Describe a token, verifying it against the details of a particular request
+    ///
+    /// The context is passed to the verifier's general checkers, and its time is the one the
+    /// token has to be active at.
+    pub fn introspect_with_context(
+        &self,
+        token: &str,
+        context: &VerifyContext,
+    ) -> IntrospectionResult {
+        let stack = match Macaroon::deserialize(token.as_bytes()) {
+            Ok(macaroon) => vec![macaroon],
+            Err(_) => match cookie::decode_cookie(token) {
+                Ok(stack) => stack,
+                Err(error) => {
+                    debug!("Introspector::introspect: Unparseable token: {}", error);
+                    return IntrospectionResult::inactive();
+                }
+            },
+        };
+        let (macaroon, discharges) = (&stack[0], &stack[1..]);
+        let (metadata, key) = match self.oven.root_key(macaroon, context) {
+            Ok(Some(found)) => found,
+            Ok(None) => return IntrospectionResult::inactive(),
+            Err(error) => {
+                debug!("Introspector::introspect: Unknown identifier: {}", error);
+                return IntrospectionResult::inactive();
+            }
+        };
+        let declared = match self
+            .verifier
+            .verify_declarations(macaroon, &key, discharges, context)
+        {
+            Ok(Some(declared)) => declared,
+            Ok(None) => return IntrospectionResult::inactive(),
+            Err(error) => {
+                debug!("Introspector::introspect: Verification failed: {}", error);
+                return IntrospectionResult::inactive();
+            }
+        };
+        let expires_at = metadata
+            .expires_at
+            .into_iter()
+            .chain(std_caveats::stack_expiry(&stack))
+            .min();
+        IntrospectionResult {
+            active: true,
+            expires_at,
+            issued_at: Some(metadata.minted_at),
+            issuer: macaroon.location(),
+            declared: declared.into_iter().collect(),
+            caveats: stack
+                .iter()
+                .flat_map(Macaroon::first_party_caveats)
+                .map(|caveat| caveat.predicate())
+                .collect(),
+            third_parties: macaroon
+                .third_party_caveats()
+                .map(|caveat| caveat.location())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IntrospectionResult, Introspector};
+    use crate::{
+        cookie,
+        oven::{MemoryRootKeyStore, Oven, StandardMintPolicy},
+        std_caveats, Format, Macaroon, VerifierBuilder, VerifyContext,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn introspector() -> Introspector<MemoryRootKeyStore> {
+        let mut store = MemoryRootKeyStore::new();
+        store.insert("2023-11", b"november key");
+        let mut oven = Oven::new("http://example.org/", store);
+        let mut policy = StandardMintPolicy::new();
+        policy.set_max_lifetime(Duration::from_secs(3600));
+        oven.set_mint_policy(policy);
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_general(std_caveats::check_time_before);
+        Introspector::new(oven, builder.build())
+    }
+
+    #[test]
+    fn test_introspect() {
+        let introspector = introspector();
+        let mut macaroon = introspector.oven().mint(None).unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_first_party_caveat(&std_caveats::declared("user", "alice"));
+        let token = String::from_utf8(macaroon.serialize(Format::V2J).unwrap()).unwrap();
+
+        let result = introspector.introspect(&token);
+        assert!(result.active);
+        let expires_at = macaroon.expiry().unwrap();
+        assert_eq!(Some(expires_at), result.expires_at);
+        assert!(result.issued_at.unwrap() + Duration::from_secs(3600) >= expires_at);
+        assert_eq!(Some("http://example.org/"), result.issuer.as_deref());
+        assert_eq!(
+            Some("alice"),
+            result.declared.get("user").map(String::as_str)
+        );
+        assert_eq!(3, result.caveats.len());
+        assert!(result.third_parties.is_empty());
+
+        let json: serde_json::Value = serde_json::from_str(&result.to_json()).unwrap();
+        assert_eq!(true, json["active"]);
+        assert_eq!(
+            expires_at.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            json["exp"].as_u64().unwrap()
+        );
+        assert_eq!("http://example.org/", json["iss"]);
+        assert_eq!("alice", json["declared"]["user"]);
+        assert_eq!("account = 3735928559", json["caveats"][1]);
+
+        // Expired
+        let mut context = VerifyContext::new();
+        context.set_time(expires_at);
+        assert!(
+            !introspector
+                .introspect_with_context(&token, &context)
+                .active
+        );
+    }
+
+    #[test]
+    fn test_introspect_stack() {
+        let introspector = introspector();
+        let mut macaroon = introspector.oven().mint(None).unwrap();
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        discharge.add_first_party_caveat(&std_caveats::declared("user", "alice"));
+        macaroon.bind(&mut discharge);
+
+        // Not active without its discharge
+        let token = String::from_utf8(macaroon.serialize(Format::V2J).unwrap()).unwrap();
+        assert_eq!(
+            IntrospectionResult::inactive(),
+            introspector.introspect(&token)
+        );
+        let token = cookie::encode_cookie(&[macaroon, discharge]).unwrap();
+        let result = introspector.introspect(&token);
+        assert!(result.active);
+        assert_eq!(vec!["http://auth.mybank/"], result.third_parties);
+        assert_eq!(
+            Some("alice"),
+            result.declared.get("user").map(String::as_str)
+        );
+    }
+
+    #[test]
+    fn test_introspect_inactive() {
+        let introspector = introspector();
+        let mut unsatisfied = introspector.oven().mint(None).unwrap();
+        unsatisfied.add_first_party_caveat("user = bob");
+        let forged = Macaroon::create("http://example.org/", b"key", "not metadata").unwrap();
+        for token in [
+            String::from("not a token"),
+            String::from_utf8(unsatisfied.serialize(Format::V2J).unwrap()).unwrap(),
+            String::from_utf8(forged.serialize(Format::V2J).unwrap()).unwrap(),
+        ] {
+            let result = introspector.introspect(&token);
+            assert_eq!(IntrospectionResult::inactive(), result);
+            assert_eq!(r#"{"active":false}"#, result.to_json());
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Carrying macaroons in JSON Web Tokens
+//!
+//! API gateways and service meshes often only pass on, or only authenticate, JWTs. A
+//! `JwtBridge` wraps a stack (a macaroon followed by its discharges) in a JWT signed with
+//! HS256, with a key shared with the infrastructure, so that it can travel through these, and
+//! unwraps it again on the other side.
+//!
+//! The stack is held in a single private claim, `macaroons` unless `set_claim` says otherwise,
+//! as the same JSON array of version 2J serializations that go-macaroon-bakery uses (see
+//! `Macaroon::serialize_slice`), with the root macaroon first. Since a private claim can clash
+//! with a claim some other party adds, a claim name under a URL the service controls
+//! (`https://example.org/macaroons`) is safer where the JWT is shared. No other claims are
+//! added, and any which the infrastructure adds are ignored: the JWT's signature only says that
+//! the stack came through the infrastructure unchanged, and it's the macaroons which still have
+//! to be verified.
+//!
+//! JWTs usually travel in HTTP headers, which servers and proxies limit to a few kilobytes, and
+//! a stack of macaroons with many caveats can easily be larger. So JWTs longer than the maximum
+//! length (`DEFAULT_MAX_LEN` unless `set_max_len` says otherwise) are neither produced nor
+//! parsed, which also keeps oversized input from being decoded at all.
+use crate::{
+    error::{base64_field, MacaroonError},
+    Macaroon,
+};
+use rustc_serialize::base64::{ToBase64, URL_SAFE};
+use serde_json::value::RawValue;
+use sodiumoxide::{crypto::auth::hmacsha256, utils};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The name of the claim holding the stack, unless `JwtBridge::set_claim` says otherwise
+pub const DEFAULT_CLAIM: &str = "macaroons";
+
+/// The maximum length of a JWT, unless `JwtBridge::set_max_len` says otherwise
+pub const DEFAULT_MAX_LEN: usize = 8 * 1024;
+
+// The only algorithm produced or accepted, so that a JWT can't pick a weaker one (or `none`)
+const HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+/// Wraps stacks of macaroons in JWTs, and unwraps them again
+#[derive(Clone)]
+pub struct JwtBridge {
+    key: Vec<u8>,
+    claim: String,
+    max_len: usize,
+}
+
+impl JwtBridge {
+    /// Create a bridge which signs and verifies JWTs with the HS256 key
+    pub fn new(key: &[u8]) -> JwtBridge {
+        JwtBridge {
+            key: key.to_vec(),
+            claim: String::from(DEFAULT_CLAIM),
+            max_len: DEFAULT_MAX_LEN,
+        }
+    }
+
+    /// Hold the stack in this claim, rather than `DEFAULT_CLAIM`
+    pub fn set_claim(&mut self, claim: &str) {
+        self.claim = String::from(claim);
+    }
+
+    /// Neither produce nor parse JWTs longer than this, rather than `DEFAULT_MAX_LEN`
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+    }
+
+    /// Wrap a macaroon and its discharges in a signed JWT
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::MissingField` if the stack is empty, and
+    /// `MacaroonError::InvalidField` if the JWT would be longer than the maximum length.
+    pub fn encode(&self, stack: &[Macaroon]) -> Result<String, MacaroonError> {
+        if stack.is_empty() {
+            return Err(MacaroonError::MissingField("macaroon"));
+        }
+        let serialized = String::from_utf8(Macaroon::serialize_slice(stack)?)
+            .expect("JSON serializations are UTF-8");
+        let macaroons = RawValue::from_string(serialized)?;
+        let mut claims = BTreeMap::new();
+        claims.insert(self.claim.as_str(), &*macaroons);
+        let signed = format!(
+            "{}.{}",
+            HEADER.as_bytes().to_base64(URL_SAFE),
+            serde_json::to_vec(&claims)?.to_base64(URL_SAFE)
+        );
+        let jwt = format!("{}.{}", signed, self.sign(&signed).to_base64(URL_SAFE));
+        if jwt.len() > self.max_len {
+            return Err(too_long());
+        }
+        Ok(jwt)
+    }
+
+    /// Verify a JWT's signature, and unwrap the macaroon and discharges it holds
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::InvalidField` if the JWT is longer than the maximum length, isn't
+    /// a JWT or doesn't use HS256, `MacaroonError::InvalidSignature` if its signature is wrong,
+    /// and `MacaroonError::MissingField` if it doesn't have the claim or the claim holds no
+    /// macaroons. Otherwise passes through any error deserializing the macaroons.
+    pub fn decode(&self, jwt: &str) -> Result<Vec<Macaroon>, MacaroonError> {
+        if jwt.len() > self.max_len {
+            return Err(too_long());
+        }
+        let (signed, signature) = jwt.rsplit_once('.').ok_or_else(not_a_jwt)?;
+        let (header, claims) = signed.split_once('.').ok_or_else(not_a_jwt)?;
+        let header: BTreeMap<String, serde_json::Value> =
+            serde_json::from_slice(&base64_field("JWT header", header)?)?;
+        if header.get("alg").and_then(serde_json::Value::as_str) != Some("HS256") {
+            return Err(MacaroonError::InvalidField {
+                field: "JWT header",
+                reason: "algorithm isn't HS256",
+            });
+        }
+        let signature = base64_field("JWT signature", signature)?;
+        if !utils::memcmp(&signature, &self.sign(signed)) {
+            return Err(MacaroonError::InvalidSignature);
+        }
+        let claims = base64_field("JWT claims", claims)?;
+        let mut claims: BTreeMap<String, &RawValue> = serde_json::from_slice(&claims)?;
+        let macaroons = claims
+            .remove(&self.claim)
+            .ok_or(MacaroonError::MissingField("macaroons claim"))?;
+        let stack = Macaroon::deserialize_slice(macaroons.get().as_bytes())?;
+        if stack.is_empty() {
+            return Err(MacaroonError::MissingField("macaroon"));
+        }
+        Ok(stack)
+    }
+
+    fn sign(&self, signed: &str) -> [u8; 32] {
+        let mut state = hmacsha256::State::init(&self.key);
+        state.update(signed.as_bytes());
+        let hmacsha256::Tag(tag) = state.finalize();
+        tag
+    }
+}
+
+// The key is never shown
+impl fmt::Debug for JwtBridge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JwtBridge")
+            .field("claim", &self.claim)
+            .field("max_len", &self.max_len)
+            .finish_non_exhaustive()
+    }
+}
+
+fn not_a_jwt() -> MacaroonError {
+    MacaroonError::InvalidField {
+        field: "JWT",
+        reason: "isn't three base64 parts separated by dots",
+    }
+}
+
+fn too_long() -> MacaroonError {
+    MacaroonError::InvalidField {
+        field: "JWT",
+        reason: "longer than the maximum length",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JwtBridge, DEFAULT_MAX_LEN};
+    use crate::testing::{self, discharged_stack};
+    use crate::{error::MacaroonError, Macaroon, Verifier};
+    use rustc_serialize::base64::{ToBase64, URL_SAFE};
+
+    #[test]
+    fn test_encode_decode_jwt() {
+        let stack = discharged_stack();
+        let bridge = JwtBridge::new(b"gateway secret");
+        let jwt = bridge.encode(&stack).unwrap();
+        assert!(jwt.starts_with("eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9."));
+        let decoded = bridge.decode(&jwt).unwrap();
+        assert_eq!(stack, decoded);
+        let mut verifier = Verifier::builder();
+        verifier.satisfy_exact("account = 3735928559");
+        assert!(verifier
+            .build()
+            .verify(&decoded[0], &testing::root_key(), &decoded[1..])
+            .unwrap());
+        assert!(!format!("{:?}", bridge).contains("secret"));
+
+        // The claim is JSON, not a string holding it
+        let claims = jwt.split('.').nth(1).unwrap();
+        let claims = crate::error::base64_field("claims", claims).unwrap();
+        let claims: serde_json::Value = serde_json::from_slice(&claims).unwrap();
+        assert_eq!(2, claims["macaroons"].as_array().unwrap().len());
+
+        let mut renamed = JwtBridge::new(b"gateway secret");
+        renamed.set_claim("https://example.org/macaroons");
+        let jwt = renamed.encode(&stack).unwrap();
+        assert_eq!(stack, renamed.decode(&jwt).unwrap());
+        match bridge.decode(&jwt) {
+            Err(MacaroonError::MissingField(field)) => assert_eq!("macaroons claim", field),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        match bridge.encode(&[]) {
+            Err(MacaroonError::MissingField(field)) => assert_eq!("macaroon", field),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_decode_jwt_errors() {
+        let bridge = JwtBridge::new(b"gateway secret");
+        let jwt = bridge.encode(&discharged_stack()).unwrap();
+        assert!(matches!(
+            JwtBridge::new(b"other secret").decode(&jwt),
+            Err(MacaroonError::InvalidSignature)
+        ));
+        let (signed, _) = jwt.rsplit_once('.').unwrap();
+        assert!(matches!(
+            bridge.decode(signed),
+            Err(MacaroonError::InvalidSignature) | Err(MacaroonError::InvalidField { .. })
+        ));
+        assert!(bridge.decode("not a jwt").unwrap_err().is_malformed());
+
+        // Unsigned JWTs are never accepted
+        let (_, rest) = jwt.split_once('.').unwrap();
+        let (claims, _) = rest.split_once('.').unwrap();
+        let unsigned = format!("{}.{}.", br#"{"alg":"none"}"#.to_base64(URL_SAFE), claims);
+        match bridge.decode(&unsigned) {
+            Err(MacaroonError::InvalidField { reason, .. }) => {
+                assert_eq!("algorithm isn't HS256", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let mut large = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        for i in 0..200 {
+            large.add_first_party_caveat(&format!("scope = projects/{}/logs", i));
+        }
+        let mut unlimited = JwtBridge::new(b"gateway secret");
+        unlimited.set_max_len(usize::MAX);
+        let jwt = unlimited.encode(&[large.clone()]).unwrap();
+        assert!(jwt.len() > DEFAULT_MAX_LEN);
+        assert_eq!(vec![large.clone()], unlimited.decode(&jwt).unwrap());
+        for result in [
+            bridge.encode(&[large]).map(|_| ()),
+            bridge.decode(&jwt).map(|_| ()),
+        ] {
+            match result {
+                Err(MacaroonError::InvalidField { reason, .. }) => {
+                    assert_eq!("longer than the maximum length", reason)
+                }
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+    }
+}
@@ -28,7 +28,7 @@
 //!
 //! # Example
 //! ```
-//! use macaroon::{Macaroon, Verifier};
+//! use macaroon::{Macaroon, MacaroonKey, VerifierBuilder};
 //!
 //! // Initialize to make crypto primitives thread-safe
 //! macaroon::initialize().unwrap(); // Force panic if initialization fails
@@ -46,13 +46,16 @@
 //!
 //! // Now we verify the macaroon
 //! // First we create the verifier
-//! let mut verifier = Verifier::new();
+//! let mut builder = VerifierBuilder::new();
 //!
 //! // We assert that the account number is "12345678"
-//! verifier.satisfy_exact("account = 12345678");
+//! builder.satisfy_exact("account = 12345678");
+//!
+//! // The verifier can't be changed once built, so it can be shared between threads
+//! let verifier = builder.build();
 //!
 //! // Now we verify the macaroon. It should return `Ok(true)` if the user is authorized
-//! match macaroon.verify(b"key", &mut verifier) {
+//! match macaroon.verify(b"key", &verifier) {
 //!     Ok(true) => println!("Macaroon verified!"),
 //!     Ok(false) => println!("Macaroon verification failed"),
 //!     Err(error) => println!("Error validating macaroon: {:?}", error),
@@ -77,10 +80,9 @@
 //! // Once we receive the discharge macaroon, we bind it to the original macaroon
 //! macaroon.bind(&mut discharge);
 //!
-//! // Then we can verify using the same verifier (which will verify both the existing
-//! // first-party caveat and the third party one)
-//! verifier.add_discharge_macaroons(&vec![discharge]);
-//! match macaroon.verify(b"key", &mut verifier) {
+//! // Then we can verify using the same verifier, passing it the discharge macaroons (which will
+//! // verify both the existing first-party caveat and the third party one)
+//! match verifier.verify(&macaroon, &MacaroonKey::generate(b"key"), &[discharge]) {
 //!     Ok(true) => println!("Macaroon verified!"),
 //!     Ok(false) => println!("Macaroon verification failed"),
 //!     Err(error) => println!("Error validating macaroon: {:?}", error),
@@ -89,25 +91,171 @@
 //! # Supported Features
 //! This crate supports all the following features:
 //!
-//! - verification of first-party caveats either via exact string match or passed-in function
+//! - verification of first-party caveats either via exact string match, comparisons of keys with values (such as `account < 1000`, with `satisfy_key_value`), or passed-in function, optionally routed to checkers by prefix with `register_prefix`
 //! - verification of third-party caveats using discharge macaroons (including ones that themselves have embedded third-party caveats)
-//! - serialization and deserialization of caveats via version 1, 2 or 2J serialization formats (fully compatible with libmacaroons)
+//! - binding discharges as libmacaroons does, or with the root signature as the key for peers which bind them that way, with `BindingMode`
+//! - serialization and deserialization of caveats via version 1, 2 or 2J serialization formats (fully compatible with libmacaroons), or MessagePack (with the `msgpack` feature)
+//! - zero-copy parsing of version 2 macaroons with `MacaroonRef`, which borrows its fields from the input
+//! - acquisition of discharge macaroons using the go-macaroon-bakery `/discharge` HTTP protocol (with the `http` feature)
+//! - go-macaroon-bakery cookies holding a macaroon and its discharges, in the `cookie` module (with the `v2j` feature)
+//! - macaroons and their discharges wrapped in JWTs, for gateways which only pass JWTs on, in the `jwt` module (with the `v2j` feature)
+//! - go-macaroon-bakery's JSON arrays of macaroons, with `Macaroon::serialize_slice` and `Macaroon::deserialize_slice` (with the `v2j` feature)
+//! - structured identifiers recording the root key id, mint time and other metadata, in the `identifier` module (with either the `v1` or `v2j` feature)
+//! - minting and verifying macaroons with rotating root keys, optionally with encrypted identifier payloads, session keys wrapped in the identifier and a central minting policy, in the `oven` module (with either the `v1` or `v2j` feature)
+//! - RFC 7662 token introspection for OAuth-aware gateways, in the `introspection` module (with the `v2j` feature)
+//! - session cookies with sliding expiry, in the `session` module (with either the `v1` or `v2j` feature)
+//! - exchanging inbound macaroons for narrower internal ones at a proxy, in the `proxy` module
+//! - macaroons which can only be attenuated, never have their signature replaced, with `SealedMacaroon`
+//! - re-minting a macaroon with a filtered set of caveats, for server-side token upgrades by the issuer, with `Macaroon::remint`
+//! - a stable, human-readable dump of macaroons for debugging and snapshot tests, in the `inspect` module
+//! - comparing macaroons field by field with `Macaroon::diff`, in the `diff` module
+//! - client-side storage of acquired discharges, in memory or (optionally encrypted) files, in the `credentials` module
+//! - the underlying cryptographic primitives, including third-party caveat key encryption, in the `crypto` module
+//! - keys personalized with a domain, so that services which share root keys can't verify each other's macaroons, with `MacaroonKey::with_domain` and `VerifierBuilder::set_domain` (not interoperable with other implementations)
+//! - computing root signatures in a KMS or HSM, so that root keys never enter the process, in the `signer` module
+//! - loading keys from hex, base64, PEM files or environment variables, checking their length and entropy, with `MacaroonKey::from_hex` and friends (with either the `v1` or `v2j` feature)
+//! - binding macaroons to a TLS connection or client certificate, so that stolen ones can't be replayed elsewhere, with `std_caveats::tls_exporter` and `std_caveats::tls_client_cert`
+//! - listing every caveat which stops a macaroon being authorized, for debugging access denials, with `Verifier::dry_run`
+//! - verifying caveats as they arrive from a parser, without building a `Macaroon`, with `IncrementalVerifier`
+//! - audit and trace hooks reporting each step of verification, in the `observer` module
+//! - counting verifications by outcome, and reporting the counts to Prometheus (with the `prometheus` feature), in the `metrics` module
+//! - revoking macaroons before they expire, with a deny list or a checker of your own, in the `revocation` module
+//! - checking macaroon and discharge locations against the service's own URLs, normalized as described in the `location` module
+//! - limits on the number of caveats and the depth of discharge chains, against oversized macaroons from untrusted clients (see the `limits` module)
+//!
+//! # Cargo Features
+//! The data model, signature chain and version 2 (binary) serialization format only depend on
+//! `sodiumoxide` (for the cryptographic primitives), `log` and `smallvec`. Everything else is
+//! behind a feature flag, so that building with `default-features = false` gives a minimal core
+//! for environments where every dependency must be audited:
+//!
+//! - `v1` (default) - version 1 serialization, which adds `rustc-serialize`
+//! - `v2j` (default) - version 2J (JSON) serialization, which adds `rustc-serialize`, `serde` and `serde_json`
+//!
+//!   The `identifier`, `oven` and `session` modules use `rustc-serialize` too, so they're
+//!   available with either of these features
+//! - `msgpack` - MessagePack serialization (`Format::V2MsgPack`), which adds `rmp-serde` and
+//!   `serde_bytes`, and implies `v2j`
+//! - `deflate` and `zstd` - compression of serialized macaroons (see `SerializeOptions`), which
+//!   add `flate2` and `zstd` respectively
+//! - `http` - the HTTP discharge protocol, which adds `ureq` and `form_urlencoded`, and implies `v2j`
+//! - `test-util` - the `testing` module, with fixtures, a mock clock, deterministic nonces and
+//!   `proptest` strategies for use in tests, which adds `proptest`
+//! - `rayon` - verifying batches of macaroons (`Verifier::verify_batch`), and checking the
+//!   bindings of discharges (`Macaroon::check_bindings`), in parallel, which adds `rayon`
+//! - `regex` - satisfying caveats which match regular expressions
+//!   (`VerifierBuilder::satisfy_pattern`), which adds `regex`
+//! - `prometheus` - reporting verification metrics to Prometheus (`metrics::PrometheusMetrics`),
+//!   which adds `prometheus`
+//! - `arena` - deserializing version 2 macaroons into a reusable arena (`arena::MacaroonArena`),
+//!   without allocating, which adds `bumpalo`
+//! - `async` - asynchronous verification callbacks and discharge acquisition, which don't depend
+//!   on any particular runtime
+//! - `bench` - the `bench_support` module, which generates macaroons for benchmarks (run the
+//!   crate's own with `cargo bench --features bench`)
+//! - `tracing` - spans around minting, serializing and verifying macaroons, with `tracing`. The
+//!   spans record formats and counts, never the contents of tokens
+//! - `unredacted_debug` - for local debugging only, shows signatures and verifier ids in full in
+//!   `Debug` output, rather than a hash of them (see the `redaction` module)
+//! - `interop` - for development only, compares this crate with the C libmacaroons, if it's
+//!   installed (`cargo bench --features interop --bench libmacaroons`)
 #[macro_use]
 extern crate log;
 
+// Enters a span for the rest of the enclosing block, with the `tracing` feature
+macro_rules! trace_span {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!($($args)*).entered();
+    };
+}
+
+#[cfg(feature = "arena")]
+pub mod arena;
+#[cfg(feature = "bench")]
+pub mod bench_support;
 mod caveat;
-mod crypto;
+#[cfg(feature = "v2j")]
+pub mod cookie;
+pub mod credentials;
+pub mod crypto;
+pub mod diff;
+pub mod discharge;
 pub mod error;
+#[doc(hidden)]
+pub mod fuzz;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "rustc-serialize")]
+pub mod identifier;
+pub mod inspect;
+#[cfg(feature = "v2j")]
+pub mod introspection;
+#[cfg(feature = "v2j")]
+pub mod jwt;
+pub mod limits;
+pub mod lint;
+pub mod location;
+mod macaroon_ref;
+pub mod metrics;
+pub mod migration;
+pub mod observer;
+#[cfg(feature = "rustc-serialize")]
+pub mod oven;
+pub mod predicate;
+pub mod proxy;
+pub mod redaction;
+pub mod revocation;
+pub mod sealed;
 mod serialization;
+#[cfg(feature = "rustc-serialize")]
+pub mod session;
+pub mod signer;
+pub mod std_caveats;
+pub mod store;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
 pub mod verifier;
 
 pub use caveat::{FirstPartyCaveat, ThirdPartyCaveat};
+pub use crypto::{BindingMode, DerivedKey, KeyDerivation, MacaroonKey, Signature, NONCE_LEN};
+pub use discharge::{discharge_all, DischargeAcquirer};
+#[cfg(feature = "async")]
+pub use discharge::{discharge_all_async, AsyncDischargeAcquirer};
 pub use error::MacaroonError;
-pub use serialization::Format;
-pub use verifier::Verifier;
+pub use macaroon_ref::{CaveatRef, MacaroonRef};
+pub use sealed::SealedMacaroon;
+#[cfg(feature = "rustc-serialize")]
+pub use serialization::{Base64Alphabet, Base64Encoding};
+pub use serialization::{
+    Compression, DeserializeOptions, Format, ParseMode, SerializeOptions, UnknownField,
+    UnknownJsonField,
+};
+pub use verifier::{
+    Checker, IncrementalVerifier, UnusedDischargePolicy, Verifier, VerifierBuilder, VerifyContext,
+};
 
-use caveat::{Caveat, CaveatType};
-use log::{debug, info};
+use caveat::{Caveat, Caveats};
+use log::debug;
+use std::hash::{Hash, Hasher};
+use std::time::SystemTime;
+use verifier::Verification;
+
+// A caveat of the macaroon, in the form the public API gives caveats
+fn caveat_ref(caveat: &dyn Caveat) -> CaveatRef<'_> {
+    match caveat.as_third_party() {
+        Ok(third_party) => CaveatRef {
+            id: &third_party.id,
+            location: Some(&third_party.location),
+            verifier_id: Some(&third_party.verifier_id),
+        },
+        Err(_) => CaveatRef {
+            id: &caveat.as_first_party().unwrap().predicate,
+            location: None,
+            verifier_id: None,
+        },
+    }
+}
 
 /// Initializes the cryptographic libraries. Although you can use libmacaroon-rs without
 /// calling this, the underlying random-number generator is not guaranteed to be thread-safe
@@ -119,31 +267,116 @@ pub fn initialize() -> Result<(), MacaroonError> {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// A macaroon
+///
+/// Macaroons are equal if their canonical serializations (see `canonical_bytes`) are, and hash
+/// consistently with that, so they can be used as keys of maps and sets.
+#[derive(Clone, Debug)]
 pub struct Macaroon {
     identifier: String,
     location: Option<String>,
-    signature: [u8; 32],
-    caveats: Vec<Box<dyn Caveat>>,
+    signature: Signature,
+    caveats: Caveats,
+    unknown_fields: Vec<UnknownField>,
+    unknown_json_fields: Vec<UnknownJsonField>,
+}
+
+/// The fields of a macaroon, borrowed from it by `Macaroon::parts`
+#[derive(Clone, Debug, PartialEq)]
+pub struct MacaroonParts<'a> {
+    pub location: Option<&'a str>,
+    pub identifier: &'a str,
+    pub caveats: Vec<CaveatRef<'a>>,
+    pub signature: &'a Signature,
+}
+
+// Equality goes by the version 2 serialization, which is canonical, and holds every field
+impl PartialEq for Macaroon {
+    fn eq(&self, other: &Macaroon) -> bool {
+        self.signature == other.signature
+            && serialization::v2::write_v2(self) == serialization::v2::write_v2(other)
+    }
+}
+
+impl Eq for Macaroon {}
+
+impl Hash for Macaroon {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        serialization::v2::write_v2(self).hash(state)
+    }
 }
 
 impl Macaroon {
     /// Construct a macaroon, given a location and identifier, and a key to sign it with
     ///
     /// # Errors
-    /// Returns `MacaroonError::BadMacaroon` if the identifier is is empty
+    /// Returns `MacaroonError::MissingField` if the identifier is empty
     pub fn create<'r>(
         location: &'r str,
         key: &[u8],
         identifier: &'r str,
     ) -> Result<Macaroon, MacaroonError> {
-        let macaroon_key = crypto::generate_derived_key(key);
+        trace_span!("Macaroon::create");
+        Macaroon::create_with_key(location, &MacaroonKey::generate(key), identifier)
+    }
+
+    /// Construct a macaroon without a location, given an identifier and a key to sign it with
+    ///
+    /// Locations are only a hint to the holder, and are often left out (by bakery-style services,
+    /// say) when there's only one place the macaroon could be meant for. Every format can
+    /// serialize a macaroon without one, and verification doesn't need one, unless the
+    /// verifier checks locations (see `VerifierBuilder::add_location`).
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::MissingField` if the identifier is empty
+    pub fn create_unlocated(key: &[u8], identifier: &str) -> Result<Macaroon, MacaroonError> {
+        trace_span!("Macaroon::create_unlocated");
+        Macaroon::mint(None, &MacaroonKey::generate(key), identifier)
+    }
+
+    /// Construct a macaroon with an already-derived key
+    ///
+    /// The same as `create`, for keys derived some other way than it derives them (see
+    /// `KeyDerivation`).
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::MissingField` if the identifier is empty
+    pub fn create_with_key(
+        location: &str,
+        key: &MacaroonKey,
+        identifier: &str,
+    ) -> Result<Macaroon, MacaroonError> {
+        Macaroon::create_with_signer(location, key, identifier)
+    }
 
+    /// Construct a macaroon, with its first signature computed by a signer
+    ///
+    /// This is for root keys which never leave a KMS or HSM (see the `signer` module). The
+    /// signer is only asked for the signature of the identifier: caveats are added locally.
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::MissingField` if the identifier is empty, or whatever error the
+    /// signer gives.
+    pub fn create_with_signer<S: signer::Signer + ?Sized>(
+        location: &str,
+        signer: &S,
+        identifier: &str,
+    ) -> Result<Macaroon, MacaroonError> {
+        Macaroon::mint(Some(location), signer, identifier)
+    }
+
+    fn mint<S: signer::Signer + ?Sized>(
+        location: Option<&str>,
+        signer: &S,
+        identifier: &str,
+    ) -> Result<Macaroon, MacaroonError> {
         let macaroon: Macaroon = Macaroon {
-            location: Some(String::from(location)),
+            location: location.map(String::from),
             identifier: String::from(identifier),
-            signature: crypto::generate_signature(&macaroon_key, identifier),
-            caveats: Vec::new(),
+            signature: signer.sign(identifier)?,
+            caveats: Caveats::new(),
+            unknown_fields: Vec::new(),
+            unknown_json_fields: Vec::new(),
         };
         debug!("Macaroon::create: {:?}", macaroon);
         macaroon.validate()
@@ -160,56 +393,244 @@ impl Macaroon {
     }
 
     /// Returns the macaroon's signature
-    pub fn signature(&self) -> &[u8; 32] {
+    pub fn signature(&self) -> &Signature {
         &self.signature
     }
 
-    fn caveats(&self) -> &Vec<Box<dyn Caveat>> {
+    /// Returns the macaroon's signature in lowercase hex, as libmacaroons and
+    /// go-macaroon-bakery display it
+    pub fn signature_hex(&self) -> String {
+        inspect::hex(self.signature.as_ref())
+    }
+
+    /// Compare the macaroon with another, field by field
+    ///
+    /// See the `diff` module for how caveats are compared.
+    pub fn diff<'a>(&'a self, other: &'a Macaroon) -> diff::MacaroonDiff<'a> {
+        diff::diff(self, other)
+    }
+
+    /// Borrow all of the macaroon's fields at once
+    ///
+    /// The caveats are in the same form as those of a `MacaroonRef`, first- and third-party
+    /// together, in order.
+    pub fn parts(&self) -> MacaroonParts<'_> {
+        MacaroonParts {
+            location: self.location.as_deref(),
+            identifier: &self.identifier,
+            caveats: self.caveats.iter().map(|c| caveat_ref(&**c)).collect(),
+            signature: &self.signature,
+        }
+    }
+
+    fn caveats(&self) -> &[Box<dyn Caveat>] {
         &self.caveats
     }
 
-    /// Retrieve a list of the first-party caveats for the macaroon
-    pub fn first_party_caveats(&self) -> Vec<FirstPartyCaveat> {
-        self.caveats
-            .iter()
-            .filter(|c| c.get_type() == CaveatType::FirstParty)
-            .map(|c| c.as_first_party().unwrap().clone())
-            .collect()
+    /// Returns the fields of the version 2 macaroon this was deserialized from which this crate
+    /// doesn't know (see `UnknownField`)
+    pub fn unknown_fields(&self) -> &[UnknownField] {
+        &self.unknown_fields
     }
 
-    /// Retrieve a list of the third-party caveats for the macaroon
-    pub fn third_party_caveats(&self) -> Vec<ThirdPartyCaveat> {
-        self.caveats
-            .iter()
-            .filter(|c| c.get_type() == CaveatType::ThirdParty)
-            .map(|c| c.as_third_party().unwrap().clone())
-            .collect()
+    /// Returns the fields of the version 2J macaroon this was deserialized from which aren't in
+    /// the format (see `UnknownJsonField`)
+    pub fn unknown_json_fields(&self) -> &[UnknownJsonField] {
+        &self.unknown_json_fields
+    }
+
+    /// The first-party caveats of the macaroon, in order
+    pub fn first_party_caveats(&self) -> impl Iterator<Item = &FirstPartyCaveat> {
+        self.caveats.iter().filter_map(|c| c.as_first_party().ok())
+    }
+
+    /// The third-party caveats of the macaroon, in order
+    pub fn third_party_caveats(&self) -> impl Iterator<Item = &ThirdPartyCaveat> {
+        self.caveats.iter().filter_map(|c| c.as_third_party().ok())
+    }
+
+    // The predicates of the first-party caveats, borrowed rather than copied
+    fn predicates(&self) -> impl Iterator<Item = &str> {
+        self.first_party_caveats().map(|c| c.predicate.as_str())
+    }
+
+    /// Returns the earliest expiry time of any of the macaroon's `time-before` caveats
+    ///
+    /// This doesn't take discharge macaroons into account - see `std_caveats::stack_expiry` for
+    /// that. Returns `None` if the macaroon doesn't expire.
+    pub fn expiry(&self) -> Option<SystemTime> {
+        self.predicates()
+            .filter_map(std_caveats::parse_time_before)
+            .min()
+    }
+
+    /// Returns the earliest issue time of any of the macaroon's `issued-at` caveats
+    ///
+    /// Returns `None` if the macaroon doesn't record when it was issued.
+    pub fn issued_at(&self) -> Option<SystemTime> {
+        self.predicates()
+            .filter_map(std_caveats::parse_issued_at)
+            .min()
+    }
+
+    /// A human-readable dump of the macaroon, in a stable format (see the `inspect` module)
+    pub fn inspect(&self) -> String {
+        inspect::inspect(self)
     }
 
     /// Validate the macaroon - used mainly for validating deserialized macaroons
     pub fn validate(self) -> Result<Self, MacaroonError> {
         if self.identifier.is_empty() {
-            return Err(MacaroonError::BadMacaroon("No macaroon identifier"));
-        }
-        if self.signature.is_empty() {
-            return Err(MacaroonError::BadMacaroon("No macaroon signature"));
+            return Err(MacaroonError::MissingField("identifier"));
         }
-
         Ok(self)
     }
 
     /// Generate a signature for the given macaroon
-    pub fn generate_signature(&self, key: &[u8]) -> [u8; 32] {
-        let signature: [u8; 32] = crypto::generate_signature(key, &self.identifier);
-        self.caveats
-            .iter()
-            .fold(signature, |sig, caveat| caveat.sign(&sig))
+    ///
+    /// Keys shorter than 32 bytes are padded with zeros, as libmacaroons pads them.
+    ///
+    /// # Errors
+    /// `MacaroonError::InvalidField` if the key is longer than 32 bytes.
+    pub fn generate_signature(&self, key: &[u8]) -> Result<Signature, MacaroonError> {
+        Ok(self.sign_with(&crypto::key_array(key)?))
     }
 
     /// Verify the signature of the macaroon given the key
+    ///
+    /// The key is padded as `generate_signature` pads it. No macaroon has a signature made
+    /// with a key longer than 32 bytes, so the signature never verifies with one.
     pub fn verify_signature(&self, key: &[u8]) -> bool {
-        let signature = self.generate_signature(key);
-        signature == self.signature
+        match crypto::key_array(key) {
+            Ok(key) => self.verify_signature_with(&key),
+            Err(_) => false,
+        }
+    }
+
+    /// Recompute the macaroon's signature with the key, for comparing with `signature`
+    pub fn recompute_signature(&self, key: &MacaroonKey) -> Signature {
+        self.sign_with(&key.0)
+    }
+
+    // The signature chain, kept in a fixed array from the identifier to the last caveat
+    fn sign_with(&self, key: &[u8; 32]) -> Signature {
+        let signature = crypto::generate_signature(key, &self.identifier);
+        Signature(
+            self.caveats
+                .iter()
+                .fold(signature, |sig, caveat| caveat.sign(&sig)),
+        )
+    }
+
+    fn verify_signature_with(&self, key: &[u8; 32]) -> bool {
+        self.sign_with(key) == self.signature
+    }
+
+    /// Recompute the macaroon's signature chain with the key
+    ///
+    /// The first signature is that of the identifier alone, and each one after that is the
+    /// signature once the next caveat is added, so there's one more than there are caveats and
+    /// the last is the same as `recompute_signature` gives. Comparing the chains of two copies
+    /// of a macaroon (say the one which was minted and one which fails verification) shows
+    /// which caveat they first differ at.
+    pub fn signature_chain(&self, key: &MacaroonKey) -> Vec<Signature> {
+        let mut chain = Vec::with_capacity(self.caveats.len() + 1);
+        let mut signature = crypto::generate_signature(&key.0, &self.identifier);
+        chain.push(Signature(signature));
+        for caveat in &self.caveats {
+            signature = caveat.sign(&signature);
+            chain.push(Signature(signature));
+        }
+        chain
+    }
+
+    /// Check the macaroon's signature chain, without checking any of its caveats
+    ///
+    /// This is the cheap part of verification, for a relay to reject forged macaroons before
+    /// passing them on to whatever checks the caveats (with `Verifier::evaluate_caveats`, say).
+    /// `check_bindings` does the same for a macaroon along with its discharges.
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::InvalidSignature` if the signature is wrong.
+    pub fn verify_integrity(&self, key: &MacaroonKey) -> Result<(), MacaroonError> {
+        match self.verify_signature_with(&key.0) {
+            true => Ok(()),
+            false => Err(MacaroonError::InvalidSignature),
+        }
+    }
+
+    /// Mint a fresh macaroon with the same identifier and location, and only the caveats
+    /// `keep` accepts
+    ///
+    /// This is for the issuer, holding the root key, to upgrade a token server-side (dropping a
+    /// caveat that a later check has made redundant, say) without rebuilding it by hand. The
+    /// caveats kept are in the same order, and third-party caveats keep their keys, re-encrypted
+    /// for the new signature chain. Unknown fields go with the caveats they're in. Discharges
+    /// bound to this macaroon will need to be bound again to the new one.
+    ///
+    /// Dropping caveats only ever widens what a macaroon grants, so it's up to the issuer to
+    /// decide what `keep` may drop.
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::InvalidSignature` if the macaroon wasn't minted with the key, and
+    /// `MacaroonError::CaveatKeyDecryption` if a third-party caveat kept has a verifier id which
+    /// doesn't decrypt.
+    pub fn remint<F>(&self, key: &MacaroonKey, keep: F) -> Result<Macaroon, MacaroonError>
+    where
+        F: Fn(&CaveatRef) -> bool,
+    {
+        self.verify_integrity(key)?;
+        let mut reminted = Macaroon::mint(self.location.as_deref(), key, &self.identifier)?;
+        let mut old_signature = crypto::generate_signature(&key.0, &self.identifier);
+        let mut indexes = Vec::with_capacity(self.caveats.len());
+        for caveat in &self.caveats {
+            if keep(&caveat_ref(&**caveat)) {
+                indexes.push(Some(reminted.caveats.len()));
+                match caveat.as_third_party() {
+                    Ok(third_party) => {
+                        let caveat_key = third_party.decrypt_key(old_signature)?;
+                        let verifier_id = crypto::encrypt(reminted.signature.0, &caveat_key);
+                        let caveat = caveat::new_third_party(
+                            &third_party.id,
+                            verifier_id,
+                            &third_party.location,
+                        );
+                        reminted.signature = Signature(caveat.sign(&reminted.signature.0));
+                        reminted.caveats.push(Box::new(caveat));
+                    }
+                    Err(_) => {
+                        reminted.signature = Signature(caveat.sign(&reminted.signature.0));
+                        reminted.caveats.push(caveat.clone());
+                    }
+                }
+            } else {
+                indexes.push(None);
+            }
+            old_signature = caveat.sign(&old_signature);
+        }
+        // Fields in a dropped caveat's section go with it
+        let reindex = |caveat: Option<usize>| match caveat {
+            Some(index) => indexes.get(index).copied().flatten().map(Some),
+            None => Some(None),
+        };
+        for field in &self.unknown_fields {
+            if let Some(caveat) = reindex(field.caveat) {
+                reminted.unknown_fields.push(UnknownField {
+                    caveat,
+                    ..field.clone()
+                });
+            }
+        }
+        for field in &self.unknown_json_fields {
+            if let Some(caveat) = reindex(field.caveat) {
+                reminted.unknown_json_fields.push(UnknownJsonField {
+                    caveat,
+                    ..field.clone()
+                });
+            }
+        }
+        debug!("Macaroon::remint: {:?}", reminted);
+        Ok(reminted)
     }
 
     /// Add a first-party caveat to the macaroon
@@ -218,26 +639,125 @@ impl Macaroon {
     /// DSL which can be verified either by exact string match,
     /// or by using a function to parse the string and validate it
     /// (see Verifier for more info).
-    pub fn add_first_party_caveat<'r>(&mut self, predicate: &'r str) {
+    pub fn add_first_party_caveat(&mut self, predicate: &str) {
         let caveat: caveat::FirstPartyCaveat = caveat::new_first_party(predicate);
-        self.signature = caveat.sign(&self.signature);
+        self.signature = Signature(caveat.sign(&self.signature.0));
         self.caveats.push(Box::new(caveat));
         debug!("Macaroon::add_first_party_caveat: {:?}", self);
     }
 
+    /// Add a first-party caveat to the macaroon, canonicalizing the predicate first
+    ///
+    /// Use this together with `VerifierBuilder::set_canonicalizer` (using the same canonicalizer) so
+    /// that predicates are written and matched in the same form.
+    pub fn add_canonical_first_party_caveat(
+        &mut self,
+        predicate: &str,
+        canonicalizer: predicate::Canonicalizer,
+    ) {
+        self.add_first_party_caveat(&canonicalizer(predicate));
+    }
+
     /// Add a third-party caveat to the macaroon
     ///
     /// A third-party caveat is a caveat which must be verified by a third party
     /// using macaroons provided by them (referred to as "discharge macaroons").
     pub fn add_third_party_caveat(&mut self, location: &str, key: &[u8], id: &str) {
+        self.add_third_party_caveat_with_nonce(location, key, id, crypto::gen_nonce());
+    }
+
+    /// Add a third-party caveat to the macaroon, encrypting its key with the given nonce
+    ///
+    /// `add_third_party_caveat` uses a random nonce, so the same caveat added twice gives
+    /// different verifier ids. This is for reproducing fixed test vectors: a nonce must never be
+    /// used twice with the same signature, since that leaks information about the caveat keys
+    /// (for a reproducible sequence of nonces, see `testing::DeterministicNonces`, with the
+    /// `test-util` feature).
+    pub fn add_third_party_caveat_with_nonce(
+        &mut self,
+        location: &str,
+        key: &[u8],
+        id: &str,
+        nonce: [u8; NONCE_LEN],
+    ) {
         let derived_key: [u8; 32] = crypto::generate_derived_key(key);
-        let vid: Vec<u8> = crypto::encrypt(self.signature, &derived_key);
+        let vid: Vec<u8> = crypto::encrypt_with_nonce(self.signature.0, &derived_key, nonce);
         let caveat: caveat::ThirdPartyCaveat = caveat::new_third_party(id, vid, location);
-        self.signature = caveat.sign(&self.signature);
+        self.signature = Signature(caveat.sign(&self.signature.0));
         self.caveats.push(Box::new(caveat));
         debug!("Macaroon::add_third_party_caveat: {:?}", self);
     }
 
+    /// Add a first-party caveat, as `add_first_party_caveat`, unless that would take the macaroon
+    /// over the size budget
+    ///
+    /// The macaroon is unchanged if the caveat isn't added.
+    pub fn add_first_party_caveat_within(
+        &mut self,
+        predicate: &str,
+        budget: &limits::SizeBudget,
+    ) -> Result<(), MacaroonError> {
+        self.add_within(budget, |macaroon| {
+            macaroon.add_first_party_caveat(predicate)
+        })
+    }
+
+    /// Add a third-party caveat, as `add_third_party_caveat`, unless that would take the macaroon
+    /// over the size budget
+    ///
+    /// The macaroon is unchanged if the caveat isn't added.
+    pub fn add_third_party_caveat_within(
+        &mut self,
+        location: &str,
+        key: &[u8],
+        id: &str,
+        budget: &limits::SizeBudget,
+    ) -> Result<(), MacaroonError> {
+        self.add_within(budget, |macaroon| {
+            macaroon.add_third_party_caveat(location, key, id)
+        })
+    }
+
+    fn add_within<F>(&mut self, budget: &limits::SizeBudget, add: F) -> Result<(), MacaroonError>
+    where
+        F: FnOnce(&mut Macaroon),
+    {
+        let signature = self.signature;
+        add(self);
+        if let Err(error) = budget.check(self) {
+            self.caveats.pop();
+            self.signature = signature;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Add a first-party caveat, as `add_first_party_caveat`, unless the macaroon already has
+    /// `max_caveats` caveats
+    pub fn try_add_first_party_caveat(
+        &mut self,
+        predicate: &str,
+        max_caveats: usize,
+    ) -> Result<(), MacaroonError> {
+        limits::check_caveat_count(self.caveats.len() + 1, Some(max_caveats))?;
+        self.add_first_party_caveat(predicate);
+        Ok(())
+    }
+
+    /// Add a third-party caveat, as `add_third_party_caveat`, unless the macaroon already has
+    /// `max_caveats` caveats
+    pub fn try_add_third_party_caveat(
+        &mut self,
+        location: &str,
+        key: &[u8],
+        id: &str,
+        max_caveats: usize,
+    ) -> Result<(), MacaroonError> {
+        limits::check_caveat_count(self.caveats.len() + 1, Some(max_caveats))?;
+        self.add_third_party_caveat(location, key, id);
+        Ok(())
+    }
+
     /// Bind a discharge macaroon to the original macaroon
     ///
     /// When a macaroon with third-party caveats must be authorized, you send off to the various
@@ -245,14 +765,134 @@ impl Macaroon {
     /// of one or more "discharge macaroons" which are used to verify the caveat. In order to ensure
     /// that the discharge macaroons aren't re-used in some other context, we bind them to the original
     /// macaroon so that they can't be used in a different context.
+    ///
+    /// Discharges are bound as libmacaroons binds them: see `bind_with_mode` to bind them some
+    /// other way.
     pub fn bind(&self, discharge: &mut Macaroon) {
-        discharge.signature = crypto::hmac2(&[0; 32], &self.signature, &discharge.signature);
+        self.bind_with_mode(discharge, BindingMode::default());
+    }
+
+    /// Bind a discharge macaroon to the original macaroon, as the binding mode says
+    pub fn bind_with_mode(&self, discharge: &mut Macaroon, mode: BindingMode) {
+        discharge.signature = mode.bind(&self.signature, &discharge.signature);
         debug!(
             "Macaroon::bind: original: {:?}, discharge: {:?}",
             self, discharge
         );
     }
 
+    /// Check that discharge macaroons are bound to this macaroon, without verifying any caveats
+    ///
+    /// Each discharge must discharge a third-party caveat of this macaroon, or of one of the
+    /// other discharges, and must have been minted with that caveat's key and bound to this
+    /// macaroon (see `bind`). This is much cheaper than verification, so suits checking
+    /// discharges as they're received, where a failure can be reported precisely, rather than
+    /// part way through verification.
+    ///
+    /// With the `rayon` feature, the discharges are checked in parallel. Which error is returned
+    /// when more than one discharge fails doesn't depend on the order the checks finish in.
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::InvalidSignature` if the macaroon's own signature is wrong,
+    /// `MacaroonError::DischargeNotBound` for a discharge which doesn't check out, and
+    /// `MacaroonError::CaveatKeyDecryption` if a caveat key can't be decrypted.
+    pub fn check_bindings(
+        &self,
+        key: &MacaroonKey,
+        discharges: &[Macaroon],
+    ) -> Result<(), MacaroonError> {
+        self.check_bindings_with_mode(key, discharges, BindingMode::default())
+    }
+
+    /// Check that discharge macaroons are bound to this macaroon as the binding mode says,
+    /// without verifying any caveats
+    ///
+    /// The same as `check_bindings` otherwise.
+    pub fn check_bindings_with_mode(
+        &self,
+        key: &MacaroonKey,
+        discharges: &[Macaroon],
+        mode: BindingMode,
+    ) -> Result<(), MacaroonError> {
+        if !self.verify_signature_with(&key.0) {
+            return Err(MacaroonError::InvalidSignature);
+        }
+        let mut pending = self.caveat_keys(&key.0)?;
+        let mut bound = vec![false; discharges.len()];
+        // The discharges for the caveats found so far are checked together (in parallel, with
+        // the rayon feature), then those for their own third-party caveats, and so on
+        while !pending.is_empty() {
+            let mut matched = Vec::new();
+            for (id, caveat_key) in pending.drain(..) {
+                for (discharge, bound) in discharges.iter().zip(bound.iter_mut()) {
+                    if !*bound && discharge.identifier == id {
+                        *bound = true;
+                        matched.push((discharge, caveat_key));
+                    }
+                }
+            }
+            #[cfg(feature = "rayon")]
+            let checked: Vec<_> = {
+                use rayon::prelude::*;
+                matched
+                    .par_iter()
+                    .map(|(discharge, caveat_key)| {
+                        discharge.check_binding(&self.signature, caveat_key, mode)
+                    })
+                    .collect()
+            };
+            #[cfg(not(feature = "rayon"))]
+            let checked: Vec<_> = matched
+                .iter()
+                .map(|(discharge, caveat_key)| {
+                    discharge.check_binding(&self.signature, caveat_key, mode)
+                })
+                .collect();
+            for caveat_keys in checked {
+                pending.extend(caveat_keys?);
+            }
+        }
+        match discharges.iter().zip(&bound).find(|(_, &bound)| !bound) {
+            Some((unbound, _)) => Err(MacaroonError::DischargeNotBound {
+                id: redaction::identifier(&unbound.identifier),
+                reason: "no third-party caveat has its identifier",
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Check that this is a discharge for a caveat with the key, bound to the root macaroon with
+    // the signature, and return the keys of its own third-party caveats
+    fn check_binding(
+        &self,
+        root_signature: &Signature,
+        caveat_key: &[u8; 32],
+        mode: BindingMode,
+    ) -> Result<Vec<(&str, [u8; 32])>, MacaroonError> {
+        let signature = self.sign_with(caveat_key);
+        if !self.verify_discharge_signature(root_signature, &signature, mode) {
+            return Err(MacaroonError::DischargeNotBound {
+                id: redaction::identifier(&self.identifier),
+                reason: "signature is not that of a discharge for the caveat, bound to this \
+                         macaroon",
+            });
+        }
+        self.caveat_keys(caveat_key)
+    }
+
+    // The keys of the third-party caveats, decrypted along the signature chain from the key
+    fn caveat_keys(&self, key: &[u8; 32]) -> Result<Vec<(&str, [u8; 32])>, MacaroonError> {
+        let mut signature = crypto::generate_signature(key, &self.identifier);
+        let mut keys = Vec::new();
+        for caveat in &self.caveats {
+            if let Ok(third_party) = caveat.as_third_party() {
+                keys.push((third_party.id.as_str(), third_party.decrypt_key(signature)?));
+            }
+            signature = caveat.sign(&signature);
+        }
+        Ok(keys)
+    }
+
     /// Verify a macaroon
     ///
     /// Verifies that the bearer of the macaroon is authorized to perform the actions requested.
@@ -261,52 +901,93 @@ impl Macaroon {
     /// to satisfy any third-party caveats, which must be already bound to this macaroon.
     ///
     /// Returns `Ok(true)` if authorized, `Ok(false)` if not, and `MacaroonError` if there was an error
-    /// verifying the macaroon.
-    pub fn verify(&self, key: &[u8], verifier: &mut Verifier) -> Result<bool, MacaroonError> {
-        if !self.verify_signature(key) {
-            info!(
-                "Macaroon::verify: Macaroon {:?} failed signature verification",
-                self
-            );
-            return Ok(false);
-        }
-        verifier.reset();
-        verifier.set_signature(crypto::generate_signature(key, &self.identifier));
-        self.verify_caveats(verifier)
+    /// verifying the macaroon, including if any of the verifier's discharge macaroons weren't used,
+    /// or if the key is longer than 32 bytes.
+    pub fn verify(&self, key: &[u8], verifier: &Verifier) -> Result<bool, MacaroonError> {
+        self.verify_with_context(key, verifier, &VerifyContext::new())
     }
 
-    fn verify_caveats(&self, verifier: &mut Verifier) -> Result<bool, MacaroonError> {
-        for caveat in &self.caveats {
-            match caveat.verify(self, verifier) {
+    /// Verify a macaroon against the details of a particular request
+    ///
+    /// The same as `verify`, but the context is passed to the verifier's general checkers.
+    ///
+    pub fn verify_with_context(
+        &self,
+        key: &[u8],
+        verifier: &Verifier,
+        context: &VerifyContext,
+    ) -> Result<bool, MacaroonError> {
+        verifier.verify_macaroon(self, &crypto::key_array(key)?, context)
+    }
+
+    fn verify_caveats(&self, verification: &mut Verification) -> Result<bool, MacaroonError> {
+        verification.enter(self);
+        let result = self.verify_each_caveat(verification);
+        verification.leave();
+        result
+    }
+
+    fn verify_each_caveat(&self, verification: &mut Verification) -> Result<bool, MacaroonError> {
+        let mut satisfied = true;
+        for (index, caveat) in self.caveats.iter().enumerate() {
+            match caveat.verify(self, index, verification) {
                 Ok(true) => (),
+                // A dry run carries on to find every caveat which isn't satisfied
+                Ok(false) if verification.is_dry_run() => satisfied = false,
+                Err(error) if verification.is_dry_run() => {
+                    verification.fail(error);
+                    satisfied = false;
+                }
                 Ok(false) => return Ok(false),
                 Err(error) => return Err(error),
             }
         }
 
-        Ok(true)
+        Ok(satisfied)
     }
 
     fn verify_as_discharge(
         &self,
-        verifier: &mut Verifier,
-        root_macaroon: &Macaroon,
-        key: &[u8],
+        verification: &mut Verification,
+        root_signature: &Signature,
+        key: &[u8; 32],
     ) -> Result<bool, MacaroonError> {
-        let signature = self.generate_signature(key);
-        if !self.verify_discharge_signature(root_macaroon, &signature) {
-            info!(
+        let signature = self.sign_with(key);
+        let valid = self.verify_discharge_signature(
+            root_signature,
+            &signature,
+            verification.binding_mode(),
+        );
+        verification.observe(observer::VerificationEvent::SignatureChecked {
+            identifier: &self.identifier,
+            discharge: true,
+            valid,
+        });
+        if !valid {
+            debug!(
                 "Macaroon::verify_as_discharge: Signature of discharge macaroon {:?} failed \
                    verification",
                 self
             );
+            verification.fail(MacaroonError::InvalidSignature);
             return Ok(false);
         }
-        self.verify_caveats(verifier)
+        // The discharge's caveats are verified against its own signature chain, after which
+        // we pick the root macaroon's chain back up where we left off
+        let root_signature = verification.signature();
+        verification.set_signature(Signature(crypto::generate_signature(key, &self.identifier)));
+        let result = self.verify_caveats(verification);
+        verification.set_signature(root_signature);
+        result
     }
 
-    fn verify_discharge_signature(&self, root_macaroon: &Macaroon, signature: &[u8; 32]) -> bool {
-        let discharge_signature = crypto::hmac2(&[0; 32], &root_macaroon.signature, signature);
+    fn verify_discharge_signature(
+        &self,
+        root_signature: &Signature,
+        signature: &Signature,
+        mode: BindingMode,
+    ) -> bool {
+        let discharge_signature = mode.bind(root_signature, signature);
         debug!(
             "Macaroon::verify_discharge_signature: self.signature = {:?}, discharge signature \
                 = {:?}",
@@ -317,31 +998,227 @@ impl Macaroon {
 
     /// Serialize the macaroon using the serialization format provided
     pub fn serialize(&self, format: serialization::Format) -> Result<Vec<u8>, MacaroonError> {
+        trace_span!("Macaroon::serialize", ?format);
         match format {
+            #[cfg(feature = "v1")]
             serialization::Format::V1 => serialization::v1::serialize_v1(self),
             serialization::Format::V2 => serialization::v2::serialize_v2(self),
+            #[cfg(feature = "v2j")]
             serialization::Format::V2J => serialization::v2j::serialize_v2j(self),
+            #[cfg(feature = "msgpack")]
+            serialization::Format::V2MsgPack => serialization::msgpack::serialize_msgpack(self),
+        }
+    }
+
+    /// Serialize the macaroon as the options say, compressing it and encoding it as base64 if
+    /// asked to
+    pub fn serialize_with(&self, options: &SerializeOptions) -> Result<Vec<u8>, MacaroonError> {
+        #[cfg(feature = "v1")]
+        if let (Format::V1, None, Some(base64)) = (options.format, options.compress, options.base64)
+        {
+            return serialization::v1::serialize_v1_with(self, base64.config());
+        }
+        let serialized = self.serialize(options.format)?;
+        let serialized = match options.compress {
+            Some(method) => serialization::compression::compress(&serialized, method)?,
+            None => serialized,
+        };
+        #[cfg(feature = "rustc-serialize")]
+        if let Some(base64) = options.base64 {
+            use rustc_serialize::base64::ToBase64;
+            return Ok(serialized.to_base64(base64.config()).into_bytes());
+        }
+        Ok(serialized)
+    }
+
+    /// The length of the macaroon when serialized in the given format
+    ///
+    /// This is exact, and is worked out without serializing the macaroon (though for the
+    /// version 2J and MessagePack formats, it's serialized without keeping the output).
+    ///
+    /// # Errors
+    /// As `serialize`, if the macaroon can't be serialized in this format.
+    pub fn serialized_len(&self, format: serialization::Format) -> Result<usize, MacaroonError> {
+        match format {
+            #[cfg(feature = "v1")]
+            serialization::Format::V1 => serialization::v1::serialized_len_v1(self),
+            serialization::Format::V2 => Ok(serialization::v2::serialized_len_v2(self)),
+            #[cfg(feature = "v2j")]
+            serialization::Format::V2J => serialization::v2j::serialized_len_v2j(self),
+            #[cfg(feature = "msgpack")]
+            serialization::Format::V2MsgPack => {
+                serialization::msgpack::serialized_len_msgpack(self)
+            }
+        }
+    }
+
+    /// Serialize the macaroon deterministically, so the result can be compared byte-wise or
+    /// used as a cache key
+    ///
+    /// Equal macaroons always give the same bytes, and deserializing them and serializing the
+    /// result again gives the same bytes back. The version 1 format is URL-safe base64 without
+    /// padding, and the version 2J format has its fields in a fixed order, no optional fields
+    /// or whitespace, and binary fields in URL-safe base64 without padding. The version 2
+    /// and MessagePack formats are the same as `serialize` gives, since those are already
+    /// deterministic.
+    ///
+    /// Note that a third-party caveat's verifier id is encrypted with a random nonce, so adding
+    /// the same third-party caveat twice gives two different macaroons.
+    pub fn canonical_bytes(&self, format: serialization::Format) -> Result<Vec<u8>, MacaroonError> {
+        match format {
+            #[cfg(feature = "v1")]
+            serialization::Format::V1 => serialization::v1::serialize_v1_canonical(self),
+            serialization::Format::V2 => serialization::v2::serialize_v2(self),
+            #[cfg(feature = "v2j")]
+            serialization::Format::V2J => serialization::v2j::serialize_v2j_canonical(self),
+            #[cfg(feature = "msgpack")]
+            serialization::Format::V2MsgPack => serialization::msgpack::serialize_msgpack(self),
         }
     }
 
     /// Deserialize a macaroon
+    ///
+    /// This accepts any encoding which can be understood, as `ParseMode::Lenient`.
     pub fn deserialize(data: &[u8]) -> Result<Macaroon, MacaroonError> {
-        let macaroon: Macaroon = match data[0] as char {
-            '{' => serialization::v2j::deserialize_v2j(data)?,
-            '\x02' => serialization::v2::deserialize_v2(data)?,
+        Macaroon::deserialize_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Deserialize a macaroon, checking it as strictly as the mode requires
+    pub fn deserialize_with_mode(data: &[u8], mode: ParseMode) -> Result<Macaroon, MacaroonError> {
+        Macaroon::deserialize_with(data, &DeserializeOptions::new(mode))
+    }
+
+    /// Deserialize a macaroon with the given options
+    ///
+    /// # Errors
+    /// As `deserialize_with_mode`, and `MacaroonError::BadMacaroon` if the macaroon has more
+    /// caveats than `options.max_caveats` allows.
+    pub fn deserialize_with(
+        data: &[u8],
+        options: &DeserializeOptions,
+    ) -> Result<Macaroon, MacaroonError> {
+        trace_span!("Macaroon::deserialize", len = data.len(), mode = ?options.mode);
+        if serialization::compression::is_compressed(data) {
+            return Macaroon::deserialize_compressed(data, options);
+        }
+        let first = match data.first() {
+            Some(&byte) => byte,
+            None => return Err(MacaroonError::Truncated { offset: 0 }),
+        };
+        let macaroon: Macaroon = match first as char {
+            #[cfg(feature = "v2j")]
+            '{' => serialization::v2j::deserialize_v2j(data, *options)?,
+            '\x02' => serialization::v2::deserialize_v2(data, *options)?,
+            #[cfg(feature = "rustc-serialize")]
             'a'..='z' | 'A'..='Z' | '0'..='9' | '+' | '-' | '/' | '_' => {
-                serialization::v1::deserialize_v1(data)?
+                match serialization::decode_base64_wrapped(data, options.mode)? {
+                    Some(decoded) => return Macaroon::deserialize_with(&decoded, options),
+                    #[cfg(feature = "v1")]
+                    None => serialization::v1::deserialize_v1(data, *options)?,
+                    #[cfg(not(feature = "v1"))]
+                    None => {
+                        return Err(MacaroonError::Format {
+                            offset: 0,
+                            expected: "macaroon serialization",
+                            found: String::from("base64 text"),
+                        })
+                    }
+                }
+            }
+            #[cfg(feature = "msgpack")]
+            _ if serialization::msgpack::is_msgpack(first) => {
+                serialization::msgpack::deserialize_msgpack(data, *options)?
+            }
+            _ => {
+                return Err(MacaroonError::Format {
+                    offset: 0,
+                    expected: "macaroon serialization",
+                    found: format!("byte {:#04x}", first),
+                })
             }
-            _ => return Err(MacaroonError::UnknownSerialization),
         };
         macaroon.validate()
     }
+
+    /// Serialize a macaroon and its discharges as a JSON array of version 2J serializations
+    ///
+    /// This is go-macaroon-bakery's `macaroon.Slice`, which is how its HTTP responses (and,
+    /// base64-encoded, its cookies: see the `cookie` module) carry a macaroon along with its
+    /// discharges, the macaroon first.
+    #[cfg(feature = "v2j")]
+    pub fn serialize_slice(macaroons: &[Macaroon]) -> Result<Vec<u8>, MacaroonError> {
+        serialization::v2j::serialize_v2j_slice(macaroons)
+    }
+
+    /// Deserialize a JSON array of version 2J serializations, as `serialize_slice` gives
+    ///
+    /// This accepts what `Macaroon::deserialize` accepts of each version 2J serialization, as
+    /// `ParseMode::Lenient`. An empty array gives no macaroons.
+    #[cfg(feature = "v2j")]
+    pub fn deserialize_slice(data: &[u8]) -> Result<Vec<Macaroon>, MacaroonError> {
+        Macaroon::deserialize_slice_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Deserialize a JSON array of version 2J serializations, checking each as strictly as the
+    /// mode requires
+    #[cfg(feature = "v2j")]
+    pub fn deserialize_slice_with_mode(
+        data: &[u8],
+        mode: ParseMode,
+    ) -> Result<Vec<Macaroon>, MacaroonError> {
+        trace_span!("Macaroon::deserialize_slice", len = data.len(), ?mode);
+        serialization::v2j::deserialize_v2j_slice(data, mode)
+    }
+
+    fn deserialize_compressed(
+        data: &[u8],
+        options: &DeserializeOptions,
+    ) -> Result<Macaroon, MacaroonError> {
+        if options.mode == ParseMode::Strict {
+            return Err(MacaroonError::Format {
+                offset: 0,
+                expected: "uncompressed macaroon",
+                found: String::from("compressed macaroon"),
+            });
+        }
+        let decompressed = serialization::compression::decompress(data)?;
+        // A compressed macaroon holding another, directly or as base64 text, could go on
+        // decompressing for ever, so only one layer is allowed
+        #[cfg(feature = "rustc-serialize")]
+        let wrapped = serialization::decode_base64_wrapped(&decompressed, options.mode)?.is_some();
+        #[cfg(not(feature = "rustc-serialize"))]
+        let wrapped = false;
+        if wrapped || serialization::compression::is_compressed(&decompressed) {
+            return Err(MacaroonError::InvalidField {
+                field: "compressed macaroon",
+                reason: "is compressed twice",
+            });
+        }
+        Macaroon::deserialize_with(&decompressed, options)
+    }
+}
+
+// Minimal executor for the async tests, which don't need a real runtime since nothing they
+// await is ever pending
+#[cfg(all(test, feature = "async"))]
+pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::task::{Context, Poll, Waker};
+    let mut future = std::pin::pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Macaroon;
-    use crate::{caveat::Caveat, error::MacaroonError};
+    use super::{Macaroon, MacaroonParts};
+    use crate::{
+        caveat::Caveat, error::MacaroonError, serialization::macaroon_builder::MacaroonBuilder,
+        Format, MacaroonKey, MacaroonRef, ParseMode, Verifier, VerifierBuilder, NONCE_LEN,
+    };
 
     #[test]
     fn create_macaroon() {
@@ -353,13 +1230,513 @@ mod tests {
         let macaroon_res = Macaroon::create("location", key, "identifier");
         assert!(macaroon_res.is_ok());
         let macaroon = macaroon_res.unwrap();
+        assert_eq!(
+            "8ee30a1c5073b5b07038735f809c27148711cfcc02505af94428643c2fdc05e0",
+            macaroon.signature_hex()
+        );
         assert!(macaroon.location.is_some());
         assert_eq!("location", macaroon.location.unwrap());
         assert_eq!("identifier", macaroon.identifier);
-        assert_eq!(signature.to_vec(), macaroon.signature);
+        assert_eq!(signature, macaroon.signature.0);
         assert_eq!(0, macaroon.caveats.len());
     }
 
+    #[test]
+    fn test_caveat_iterators() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("https://auth.mybank.com", b"caveat key", "caveat");
+        macaroon.add_first_party_caveat("user = alice");
+        let predicates: Vec<String> = macaroon
+            .first_party_caveats()
+            .map(|c| c.predicate())
+            .collect();
+        assert_eq!(vec!["account = 3735928559", "user = alice"], predicates);
+        let third_parties: Vec<(String, String)> = macaroon
+            .third_party_caveats()
+            .map(|c| (c.id(), c.location()))
+            .collect();
+        assert_eq!(
+            vec![(
+                String::from("caveat"),
+                String::from("https://auth.mybank.com")
+            )],
+            third_parties
+        );
+    }
+
+    #[test]
+    fn test_add_third_party_caveat_with_nonce() {
+        let nonce = [7; NONCE_LEN];
+        let mint = || {
+            let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+            macaroon.add_third_party_caveat_with_nonce(
+                "https://auth.mybank.com",
+                b"caveat key",
+                "caveat",
+                nonce,
+            );
+            macaroon
+        };
+        let macaroon = mint();
+        assert_eq!(macaroon, mint());
+        let verifier_id = macaroon.third_party_caveats().next().unwrap().verifier_id();
+        assert_eq!(&nonce[..], &verifier_id[..NONCE_LEN]);
+
+        let mut discharge =
+            Macaroon::create("https://auth.mybank.com", b"caveat key", "caveat").unwrap();
+        macaroon.bind(&mut discharge);
+        assert!(Verifier::default()
+            .verify(&macaroon, &MacaroonKey::generate(b"key"), &[discharge])
+            .unwrap());
+    }
+
+    #[test]
+    fn test_verify_checks_signature_chain() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("account = 0");
+        let verifier = builder.build();
+        let key = crate::crypto::generate_derived_key(b"key");
+        assert!(macaroon.verify(&key, &verifier).unwrap());
+        let other_key = crate::crypto::generate_derived_key(b"other key");
+        assert!(!macaroon.verify(&other_key, &verifier).unwrap());
+
+        // Dropping, changing or adding caveats without the key breaks the chain
+        let tampered = |predicates: &[&str]| {
+            let mut builder = MacaroonBuilder::new();
+            builder.set_location("http://example.org/");
+            builder.set_identifier("keyid");
+            builder
+                .set_signature(macaroon.signature().as_bytes())
+                .unwrap();
+            for predicate in predicates {
+                builder.add_caveat(Box::new(crate::caveat::new_first_party(predicate)));
+            }
+            builder.build().unwrap()
+        };
+        assert!(tampered(&["account = 3735928559"])
+            .verify(&key, &verifier)
+            .unwrap());
+        for predicates in [
+            &[][..],
+            &["account = 0"][..],
+            &["account = 3735928559", "account = 0"][..],
+        ] {
+            assert!(!tampered(predicates).verify(&key, &verifier).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_signature_chain() {
+        let key = MacaroonKey::generate(b"key");
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        assert_eq!(vec![*macaroon.signature()], macaroon.signature_chain(&key));
+        let mut signatures = vec![*macaroon.signature()];
+        macaroon.add_first_party_caveat("account = 3735928559");
+        signatures.push(*macaroon.signature());
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        signatures.push(*macaroon.signature());
+        macaroon.add_first_party_caveat("user = alice");
+        signatures.push(*macaroon.signature());
+        assert_eq!(signatures, macaroon.signature_chain(&key));
+        assert_eq!(macaroon.signature(), &macaroon.recompute_signature(&key));
+
+        // A changed caveat changes the chain from there on
+        let mut tampered = macaroon.serialize(Format::V2).unwrap();
+        let at = tampered.iter().position(|&byte| byte == b'3').unwrap();
+        tampered[at] = b'4';
+        let tampered = Macaroon::deserialize(&tampered).unwrap();
+        assert_ne!(macaroon.signature(), &tampered.recompute_signature(&key));
+        let chain = tampered.signature_chain(&key);
+        assert_eq!(signatures[0], chain[0]);
+        assert!(signatures[1..]
+            .iter()
+            .zip(&chain[1..])
+            .all(|(signature, tampered)| signature != tampered));
+    }
+
+    #[test]
+    fn test_serialized_len() {
+        let mut macaroons = vec![
+            Macaroon::create("http://example.org/", b"key", "keyid").unwrap(),
+            Macaroon::create("", b"key", &"long identifier ".repeat(20)).unwrap(),
+        ];
+        macaroons[0].add_first_party_caveat("account = 3735928559");
+        macaroons[0].add_first_party_caveat("quoted \"value\"\n\t\u{1} caf\u{e9}");
+        macaroons[0].add_third_party_caveat("https://auth.mybank.com", b"caveat key", "caveat");
+        macaroons[1].add_first_party_caveat(&"x".repeat(20_000));
+        let formats = [
+            Format::V2,
+            #[cfg(feature = "v1")]
+            Format::V1,
+            #[cfg(feature = "v2j")]
+            Format::V2J,
+            #[cfg(feature = "msgpack")]
+            Format::V2MsgPack,
+        ];
+        for macaroon in &macaroons {
+            for &format in &formats {
+                assert_eq!(
+                    macaroon.serialize(format).unwrap().len(),
+                    macaroon.serialized_len(format).unwrap(),
+                    "{:?}",
+                    format
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_parts() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("https://auth.mybank.com", b"caveat key", "caveat");
+        let MacaroonParts {
+            location,
+            identifier,
+            caveats,
+            signature,
+        } = macaroon.parts();
+        assert_eq!(Some("http://example.org/"), location);
+        assert_eq!("keyid", identifier);
+        assert_eq!(macaroon.signature(), signature);
+        assert_eq!(2, caveats.len());
+        assert_eq!("account = 3735928559", caveats[0].id());
+        assert!(!caveats[0].is_third_party());
+        assert_eq!("caveat", caveats[1].id());
+        assert_eq!(Some("https://auth.mybank.com"), caveats[1].location());
+
+        // The same as parsing the serialization in place
+        let serialized = macaroon.serialize(Format::V2).unwrap();
+        let view = MacaroonRef::deserialize(&serialized).unwrap();
+        assert_eq!(view.caveats(), &caveats[..]);
+    }
+
+    #[test]
+    fn test_eq_hash() {
+        use std::collections::HashSet;
+
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("https://auth.mybank.com", b"caveat key", "caveat");
+        let mut other = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        other.add_first_party_caveat("account = 3735928559");
+        assert_ne!(macaroon, other);
+
+        let mut set = HashSet::new();
+        set.insert(macaroon.clone());
+        set.insert(other.clone());
+        for format in formats() {
+            let round_tripped =
+                Macaroon::deserialize(&macaroon.serialize(format).unwrap()).unwrap();
+            assert_eq!(macaroon, round_tripped);
+            assert!(!set.insert(round_tripped));
+        }
+        // Same signature chain, but a different location
+        let mut moved = other.clone();
+        moved.location = Some(String::from("http://example.com/"));
+        assert_eq!(other.signature, moved.signature);
+        assert_ne!(other, moved);
+        assert!(set.insert(moved));
+        assert_eq!(3, set.len());
+    }
+
+    fn formats() -> Vec<Format> {
+        vec![
+            #[cfg(feature = "v1")]
+            Format::V1,
+            Format::V2,
+            #[cfg(feature = "v2j")]
+            Format::V2J,
+            #[cfg(feature = "msgpack")]
+            Format::V2MsgPack,
+        ]
+    }
+
+    #[test]
+    fn test_unlocated() {
+        let mut macaroon = Macaroon::create_unlocated(b"key", "keyid").unwrap();
+        assert_eq!(None, macaroon.location());
+        assert_eq!(
+            Macaroon::create("http://example.org/", b"key", "keyid")
+                .unwrap()
+                .signature(),
+            macaroon.signature()
+        );
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"caveat key", "caveat");
+        let mut discharge = Macaroon::create_unlocated(b"caveat key", "caveat").unwrap();
+        macaroon.bind(&mut discharge);
+        for &format in &formats() {
+            for macaroon in [&macaroon, &discharge] {
+                let serialized = macaroon.serialize(format).unwrap();
+                assert_eq!(macaroon.serialized_len(format).unwrap(), serialized.len());
+                for mode in [ParseMode::Lenient, ParseMode::Strict] {
+                    let deserialized = Macaroon::deserialize_with_mode(&serialized, mode).unwrap();
+                    assert_eq!(None, deserialized.location(), "{:?}", format);
+                    assert_eq!(macaroon, &deserialized);
+                }
+            }
+        }
+        let serialized = macaroon.serialize(Format::V2).unwrap();
+        assert_eq!(
+            None,
+            MacaroonRef::deserialize(&serialized).unwrap().location()
+        );
+        assert!(!macaroon.inspect().contains("location"));
+
+        let key = MacaroonKey::generate(b"key");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        let verifier = builder.build();
+        assert!(verifier
+            .verify(&macaroon, &key, std::slice::from_ref(&discharge))
+            .unwrap());
+        macaroon
+            .check_bindings(&key, std::slice::from_ref(&discharge))
+            .unwrap();
+
+        // Unless the verifier checks locations
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.add_location("http://example.org/");
+        assert!(!builder
+            .build()
+            .verify(&macaroon, &key, &[discharge])
+            .unwrap());
+    }
+
+    #[cfg(feature = "rustc-serialize")]
+    #[test]
+    fn test_serialize_with() {
+        use crate::{Base64Alphabet, Base64Encoding, SerializeOptions};
+        use rustc_serialize::base64::FromBase64;
+
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("https://auth.mybank.com", b"caveat key", "caveat");
+        for &format in &formats() {
+            let serialized = macaroon.serialize(format).unwrap();
+            assert_eq!(
+                serialized,
+                macaroon
+                    .serialize_with(&SerializeOptions::new(format))
+                    .unwrap()
+            );
+            for &pad in &[true, false] {
+                for &alphabet in &[Base64Alphabet::Standard, Base64Alphabet::UrlSafe] {
+                    let options = SerializeOptions {
+                        base64: Some(Base64Encoding { alphabet, pad }),
+                        ..SerializeOptions::new(format)
+                    };
+                    let text = macaroon.serialize_with(&options).unwrap();
+                    match pad {
+                        true => assert_eq!(0, text.len() % 4, "{:?}", options),
+                        false => assert!(!text.ends_with(b"="), "{:?}", options),
+                    }
+                    assert!(text.iter().all(u8::is_ascii_graphic), "{:?}", options);
+                    #[cfg(feature = "v1")]
+                    if format == Format::V1 {
+                        assert_eq!(macaroon, Macaroon::deserialize(&text).unwrap());
+                        continue;
+                    }
+                    assert_eq!(serialized, text.from_base64().unwrap(), "{:?}", options);
+                }
+            }
+        }
+
+        #[cfg(feature = "v1")]
+        assert_eq!(
+            macaroon.canonical_bytes(Format::V1).unwrap(),
+            macaroon
+                .serialize_with(&SerializeOptions {
+                    base64: Some(Base64Encoding::URL_SAFE_NO_PAD),
+                    ..SerializeOptions::new(Format::V1)
+                })
+                .unwrap()
+        );
+    }
+
+    #[cfg(feature = "rustc-serialize")]
+    #[test]
+    fn test_deserialize_base64_wrapped() {
+        use crate::{Base64Encoding, SerializeOptions};
+
+        // A version 2 macaroon as libmacaroons encodes it
+        let serialized = "AgETaHR0cDovL2V4YW1wbGUub3JnLwIFa2V5aWQAAhRhY2NvdW50ID0gMzczNTkyODU1OQAC\
+                          DHVzZXIgPSBhbGljZQAABiBL6WfNHqDGsmuvakqU7psFsViG2gsoXoxCqTyNDhJe_A";
+        let macaroon =
+            Macaroon::deserialize_with_mode(serialized.as_bytes(), ParseMode::Strict).unwrap();
+        assert_eq!("keyid", macaroon.identifier());
+        assert_eq!(2, macaroon.caveats().len());
+        assert_eq!(75, macaroon.signature().as_bytes()[0]);
+
+        for &format in &formats() {
+            for &base64 in &[Base64Encoding::STANDARD, Base64Encoding::URL_SAFE_NO_PAD] {
+                let options = SerializeOptions {
+                    base64: Some(base64),
+                    ..SerializeOptions::new(format)
+                };
+                let text = macaroon.serialize_with(&options).unwrap();
+                assert_eq!(
+                    macaroon,
+                    Macaroon::deserialize_with_mode(&text, ParseMode::Strict).unwrap(),
+                    "{:?}",
+                    options
+                );
+            }
+        }
+
+        // Base64 with a line break isn't canonical, so is only accepted leniently
+        let mut broken = serialized.as_bytes().to_vec();
+        broken.insert(64, b'\n');
+        assert_eq!(macaroon, Macaroon::deserialize(&broken).unwrap());
+        assert!(Macaroon::deserialize_with_mode(&broken, ParseMode::Strict).is_err());
+    }
+
+    #[test]
+    fn test_canonical_bytes() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("https://auth.mybank.com", b"caveat key", "caveat");
+        for format in formats() {
+            let canonical = macaroon.canonical_bytes(format).unwrap();
+            let deserialized = Macaroon::deserialize_with_mode(&canonical, ParseMode::Strict)
+                .unwrap_or_else(|error| panic!("{:?}: {}", format, error));
+            assert_eq!(macaroon, deserialized);
+            assert_eq!(canonical, deserialized.canonical_bytes(format).unwrap());
+        }
+
+        #[cfg(feature = "v2j")]
+        {
+            let v2j = macaroon.canonical_bytes(Format::V2J).unwrap();
+            let v2j = String::from_utf8(v2j).unwrap();
+            assert!(v2j.starts_with("{\"v\":2,\"l\":\"http://example.org/\",\"i\":\"keyid\","));
+            assert!(!v2j.contains("null"));
+        }
+        #[cfg(feature = "v1")]
+        {
+            let v1 = macaroon.canonical_bytes(Format::V1).unwrap();
+            assert!(!v1.iter().any(|byte| b"+/=".contains(byte)));
+        }
+    }
+
+    #[test]
+    fn test_serialize_fixed_point() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("https://auth.mybank.com", b"caveat key", "caveat");
+        for format in formats() {
+            let serialized = macaroon.serialize(format).unwrap();
+            let deserialized = Macaroon::deserialize(&serialized).unwrap();
+            assert_eq!(serialized, deserialized.serialize(format).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_deserialize_unknown_format() {
+        match Macaroon::deserialize(b"") {
+            Err(MacaroonError::Truncated { offset: 0 }) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match Macaroon::deserialize(b"\x03") {
+            Err(MacaroonError::Format { offset, found, .. }) => {
+                assert_eq!(0, offset);
+                assert_eq!("byte 0x03", found);
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_remint() {
+        let key = MacaroonKey::generate(b"root key");
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("plan = trial");
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.unknown_fields.push(crate::UnknownField {
+            caveat: Some(2),
+            tag: 9,
+            value: b"extension".to_vec(),
+        });
+
+        let upgraded = macaroon
+            .remint(&key, |caveat| caveat.id() != "plan = trial")
+            .unwrap();
+        assert_eq!(macaroon.identifier(), upgraded.identifier());
+        assert_eq!(macaroon.location(), upgraded.location());
+        let predicates: Vec<&str> = upgraded.predicates().collect();
+        assert_eq!(vec!["account = 3735928559"], predicates);
+        assert_eq!(1, upgraded.third_party_caveats().count());
+        assert_eq!(Some(1), upgraded.unknown_fields()[0].caveat);
+
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        upgraded.bind(&mut discharge);
+        let mut verifier = VerifierBuilder::new();
+        verifier.satisfy_exact("account = 3735928559");
+        let verifier = verifier.build();
+        assert!(verifier.verify(&upgraded, &key, &[discharge]).unwrap());
+
+        // Keeping every first-party caveat mints the same macaroon
+        let mut first_party =
+            Macaroon::create("http://example.org/", b"root key", "keyid").unwrap();
+        first_party.add_first_party_caveat("account = 3735928559");
+        assert_eq!(first_party, first_party.remint(&key, |_| true).unwrap());
+
+        assert!(matches!(
+            macaroon.remint(&MacaroonKey::generate(b"wrong key"), |_| true),
+            Err(MacaroonError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn test_check_bindings() {
+        let key = MacaroonKey::generate(b"key");
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"caveat key", "caveat");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"caveat key", "caveat").unwrap();
+        // A discharge can have third-party caveats of its own
+        discharge.add_third_party_caveat("http://auth.other/", b"other key", "other");
+        let mut nested = Macaroon::create("http://auth.other/", b"other key", "other").unwrap();
+        let unbound = vec![discharge.clone(), nested.clone()];
+        macaroon.bind(&mut discharge);
+        macaroon.bind(&mut nested);
+        macaroon
+            .check_bindings(&key, &[nested.clone(), discharge.clone()])
+            .unwrap();
+
+        let not_bound = |result| match result {
+            Err(MacaroonError::DischargeNotBound { id, reason }) => (id, reason),
+            result => panic!("Unexpected result {:?}", result),
+        };
+        let (id, _) = not_bound(macaroon.check_bindings(&key, &unbound));
+        assert_eq!("caveat", id);
+        let mut wrong_key =
+            Macaroon::create("http://auth.mybank/", b"wrong key", "caveat").unwrap();
+        macaroon.bind(&mut wrong_key);
+        let (id, _) = not_bound(macaroon.check_bindings(&key, &[wrong_key]));
+        assert_eq!("caveat", id);
+        let mut unrelated = Macaroon::create("http://auth.mybank/", b"caveat key", "nope").unwrap();
+        macaroon.bind(&mut unrelated);
+        let (id, reason) = not_bound(macaroon.check_bindings(&key, &[discharge, unrelated]));
+        assert_eq!("nope", id);
+        assert_eq!("no third-party caveat has its identifier", reason);
+        // The nested discharge can't be checked without the one which has its caveat
+        let (id, _) = not_bound(macaroon.check_bindings(&key, &[nested]));
+        assert_eq!("other", id);
+
+        match macaroon.check_bindings(&MacaroonKey::generate(b"wrong key"), &[]) {
+            Err(MacaroonError::InvalidSignature) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
     #[test]
     fn create_invalid_macaroon() {
         let key: &[u8; 32] = b"this is a super duper secret key";
@@ -377,12 +1754,14 @@ mod tests {
         let mut macaroon = Macaroon::create("location", key, "identifier").unwrap();
         macaroon.add_first_party_caveat("predicate");
         assert_eq!(1, macaroon.caveats.len());
+        // Held inline, without allocating
+        assert!(!macaroon.caveats.spilled());
         let caveat = &macaroon.caveats[0];
         assert_eq!("predicate", caveat.as_first_party().unwrap().predicate());
-        assert_eq!(signature.to_vec(), macaroon.signature);
+        assert_eq!(signature, macaroon.signature.0);
         assert_eq!(
-            *caveat.as_first_party().unwrap(),
-            macaroon.first_party_caveats()[0]
+            caveat.as_first_party().unwrap(),
+            macaroon.first_party_caveats().next().unwrap()
         );
     }
 
@@ -399,8 +1778,28 @@ mod tests {
         assert_eq!(location, caveat.location());
         assert_eq!(id, caveat.id());
         assert_eq!(
-            *caveat.as_third_party().unwrap(),
-            macaroon.third_party_caveats()[0]
+            caveat.as_third_party().unwrap(),
+            macaroon.third_party_caveats().next().unwrap()
+        );
+    }
+
+    #[cfg(not(feature = "unredacted_debug"))]
+    #[test]
+    fn test_debug_redacted() {
+        let mut macaroon = Macaroon::create("location", b"key", "identifier").unwrap();
+        macaroon.add_third_party_caveat("https://auth.mybank.com", b"My key", "My Caveat");
+        let debug = format!("{:?}", macaroon);
+        assert!(debug.contains("identifier") && debug.contains("My Caveat"));
+        let signature = format!("{:?}", macaroon.signature.0);
+        let verifier_id = format!(
+            "{:?}",
+            macaroon.third_party_caveats().next().unwrap().verifier_id
+        );
+        assert!(!debug.contains(&signature[1..signature.len() - 1]));
+        assert!(!debug.contains(&verifier_id[1..verifier_id.len() - 1]));
+        assert_eq!(
+            "MacaroonKey(..)",
+            format!("{:?}", MacaroonKey::generate(b"key"))
         );
     }
 }
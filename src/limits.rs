@@ -0,0 +1,169 @@
+//! Limits on the size of macaroons
+//!
+//! Anyone holding a macaroon can add caveats to it, so a service which accepts macaroons from
+//! untrusted clients may be sent ones with a very large number of caveats, each of which costs
+//! an HMAC (and perhaps a callback) to verify. A limit can be given wherever caveats are added:
+//! `DeserializeOptions::max_caveats` when macaroons are deserialized (or
+//! `MacaroonRef::deserialize_with_max_caveats`), and the limit passed to
+//! `Macaroon::try_add_first_party_caveat` and `Macaroon::try_add_third_party_caveat`. There's no
+//! limit otherwise.
+//!
+//! Macaroons are often carried somewhere with a limit on size, such as a cookie (typically 4KB).
+//! A `SizeBudget` lets the code minting or attenuating them find out that a caveat won't fit
+//! when it's added, rather than when the token is rejected further along: see
+//! `Macaroon::add_first_party_caveat_within` and `Macaroon::add_third_party_caveat_within`.
+//!
+//! The verifier has limits of its own, which apply whatever the macaroons were built from: see
+//! `VerifierBuilder::set_max_caveats` and `VerifierBuilder::set_max_discharge_depth`.
+use crate::{error::MacaroonError, Format, Macaroon};
+
+/// Check that a macaroon may have this many caveats, given the limit, if there is one
+pub(crate) fn check_caveat_count(
+    count: usize,
+    max_caveats: Option<usize>,
+) -> Result<(), MacaroonError> {
+    match max_caveats {
+        Some(max_caveats) if count > max_caveats => {
+            Err(MacaroonError::BadMacaroon("macaroon has too many caveats"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A maximum serialized size for macaroons
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeBudget {
+    format: Format,
+    max_len: usize,
+}
+
+impl SizeBudget {
+    /// A budget of `max_len` bytes, when serialized in the given format
+    pub fn new(format: Format, max_len: usize) -> SizeBudget {
+        SizeBudget { format, max_len }
+    }
+
+    /// The format the macaroon's size is measured in
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// The maximum serialized size, in bytes
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// How many more bytes the macaroon could grow by, or 0 if it's already over budget
+    pub fn remaining(&self, macaroon: &Macaroon) -> Result<usize, MacaroonError> {
+        Ok(self
+            .max_len
+            .saturating_sub(macaroon.serialized_len(self.format)?))
+    }
+
+    /// Check that the macaroon is within budget
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::BadMacaroon` if it isn't, or any error serializing it.
+    pub fn check(&self, macaroon: &Macaroon) -> Result<(), MacaroonError> {
+        match macaroon.serialized_len(self.format)? <= self.max_len {
+            true => Ok(()),
+            false => Err(MacaroonError::BadMacaroon(
+                "macaroon is larger than its size budget",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SizeBudget;
+    use crate::{
+        error::MacaroonError, DeserializeOptions, Format, Macaroon, MacaroonRef, ParseMode,
+    };
+
+    #[test]
+    fn test_max_caveats() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        for i in 0..3 {
+            macaroon.add_first_party_caveat(&format!("caveat {}", i));
+        }
+        let options = DeserializeOptions {
+            max_caveats: Some(3),
+            ..DeserializeOptions::new(ParseMode::Lenient)
+        };
+        let serialized = macaroon.serialize(Format::V2).unwrap();
+        assert_eq!(
+            macaroon,
+            Macaroon::deserialize_with(&serialized, &options).unwrap()
+        );
+        let mut attenuated = macaroon.clone();
+        match attenuated.try_add_first_party_caveat("caveat 3", 3) {
+            Err(MacaroonError::BadMacaroon(reason)) => {
+                assert_eq!("macaroon has too many caveats", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert!(attenuated
+            .try_add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat", 3)
+            .is_err());
+        assert_eq!(macaroon, attenuated);
+        attenuated
+            .try_add_first_party_caveat("caveat 3", 4)
+            .unwrap();
+
+        let serialized = attenuated.serialize(Format::V2).unwrap();
+        assert!(Macaroon::deserialize_with(&serialized, &options).is_err());
+        assert!(MacaroonRef::deserialize_with_max_caveats(&serialized, 3).is_err());
+        assert!(MacaroonRef::deserialize_with_max_caveats(&serialized, 4).is_ok());
+        let formats = [
+            Format::V2,
+            #[cfg(feature = "v1")]
+            Format::V1,
+            #[cfg(feature = "v2j")]
+            Format::V2J,
+            #[cfg(feature = "msgpack")]
+            Format::V2MsgPack,
+        ];
+        for format in formats {
+            let serialized = attenuated.serialize(format).unwrap();
+            assert!(
+                Macaroon::deserialize_with(&serialized, &options).is_err(),
+                "{:?}",
+                format
+            );
+            // The limit only applies where it's given
+            assert_eq!(attenuated, Macaroon::deserialize(&serialized).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_size_budget() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        let len = macaroon.serialized_len(Format::V2).unwrap();
+        let budget = SizeBudget::new(Format::V2, len + 30);
+        assert_eq!(30, budget.remaining(&macaroon).unwrap());
+        assert!(budget.check(&macaroon).is_ok());
+
+        // 2 bytes for the tag and length, 20 for the predicate and 1 for the end of the section
+        macaroon
+            .add_first_party_caveat_within("account = 3735928559", &budget)
+            .unwrap();
+        assert_eq!(len + 23, macaroon.serialized_len(Format::V2).unwrap());
+        let before = macaroon.clone();
+        match macaroon.add_first_party_caveat_within("user = alice", &budget) {
+            Err(MacaroonError::BadMacaroon(reason)) => {
+                assert_eq!("macaroon is larger than its size budget", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert!(macaroon
+            .add_third_party_caveat_within("http://auth.mybank/", b"bank key", "bank", &budget)
+            .is_err());
+        assert_eq!(before, macaroon);
+        assert_eq!(7, budget.remaining(&macaroon).unwrap());
+
+        macaroon.add_first_party_caveat("user = alice");
+        assert_eq!(0, budget.remaining(&macaroon).unwrap());
+        assert!(budget.check(&macaroon).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Checks for risky macaroon constructions
+//!
+//! This is intended to be run (e.g. in CI) against macaroons minted by a service, to catch
+//! macaroons which are valid but grant more than they probably should.
+use crate::{
+    error::MacaroonError, migration::condition_name, serialization::Format, std_caveats,
+    std_caveats::now, Macaroon,
+};
+use std::time::Duration;
+
+/// Policy used by `check`
+///
+/// The default policy requires an expiry of at most a day, a location, an `allow` caveat
+/// bounding the operations permitted, and a serialized size of at most 4KB (a common limit for
+/// cookies).
+#[derive(Clone, Debug, PartialEq)]
+pub struct LintPolicy {
+    /// Flag macaroons which don't have a `time-before` caveat
+    pub require_expiry: bool,
+    /// Flag macaroons whose expiry is further in the future than this
+    pub max_ttl: Option<Duration>,
+    /// Flag macaroons which don't have a location (their intended audience)
+    pub require_location: bool,
+    /// Condition names of caveats which bound the operations a macaroon may be used for. If this
+    /// isn't empty, macaroons without any caveat with one of these conditions are flagged.
+    pub operation_conditions: Vec<String>,
+    /// Flag macaroons whose version 2 serialization is larger than this many bytes
+    pub max_size: Option<usize>,
+}
+
+impl Default for LintPolicy {
+    fn default() -> LintPolicy {
+        LintPolicy {
+            require_expiry: true,
+            max_ttl: Some(Duration::from_secs(24 * 60 * 60)),
+            require_location: true,
+            operation_conditions: vec![String::from(std_caveats::ALLOW)],
+            max_size: Some(4096),
+        }
+    }
+}
+
+/// A risky construction found by `check`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lint {
+    /// The macaroon never expires
+    NoExpiry,
+    /// The macaroon expires further in the future than the policy allows
+    TtlTooLong(Duration),
+    /// The macaroon has no location
+    NoLocation,
+    /// The macaroon can be used for any operation
+    UnboundedOperations,
+    /// The macaroon's serialized size, which is larger than the policy allows
+    TooLarge(usize),
+}
+
+/// Check a macaroon against a lint policy
+///
+/// Returns all the problems found, or an empty list if there are none. Only the macaroon
+/// itself is checked, not any discharge macaroons it needs.
+pub fn check(macaroon: &Macaroon, policy: &LintPolicy) -> Result<Vec<Lint>, MacaroonError> {
+    let mut lints: Vec<Lint> = Vec::new();
+    match macaroon.expiry() {
+        Some(expiry) => {
+            if let (Some(max_ttl), Ok(ttl)) = (policy.max_ttl, expiry.duration_since(now())) {
+                if ttl > max_ttl {
+                    lints.push(Lint::TtlTooLong(ttl));
+                }
+            }
+        }
+        None => {
+            if policy.require_expiry {
+                lints.push(Lint::NoExpiry);
+            }
+        }
+    }
+    if policy.require_location && macaroon.location().is_none_or(|l| l.is_empty()) {
+        lints.push(Lint::NoLocation);
+    }
+    if !policy.operation_conditions.is_empty()
+        && !macaroon.first_party_caveats().any(|c| {
+            let predicate = c.predicate();
+            policy
+                .operation_conditions
+                .iter()
+                .any(|condition| condition == condition_name(&predicate))
+        })
+    {
+        lints.push(Lint::UnboundedOperations);
+    }
+    if let Some(max_size) = policy.max_size {
+        let size = macaroon.serialize(Format::V2)?.len();
+        if size > max_size {
+            lints.push(Lint::TooLarge(size));
+        }
+    }
+    debug!("lint::check: {:?}: {:?}", macaroon, lints);
+
+    Ok(lints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, Lint, LintPolicy};
+    use crate::{std_caveats, Macaroon};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_check_clean() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat(&std_caveats::time_before(
+            SystemTime::now() + Duration::from_secs(3600),
+        ));
+        macaroon.add_first_party_caveat("allow read");
+        assert_eq!(
+            Vec::<Lint>::new(),
+            check(&macaroon, &LintPolicy::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_check_risky() {
+        let mut macaroon = Macaroon::create("", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat(&"x".repeat(5000));
+        assert_eq!(
+            vec![
+                Lint::NoExpiry,
+                Lint::NoLocation,
+                Lint::UnboundedOperations,
+                Lint::TooLarge(5050)
+            ],
+            check(&macaroon, &LintPolicy::default()).unwrap()
+        );
+        let policy = LintPolicy {
+            require_expiry: false,
+            max_ttl: None,
+            require_location: false,
+            operation_conditions: Vec::new(),
+            max_size: None,
+        };
+        assert_eq!(Vec::<Lint>::new(), check(&macaroon, &policy).unwrap());
+    }
+
+    #[test]
+    fn test_check_ttl_too_long() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat(&std_caveats::time_before(
+            SystemTime::now() + Duration::from_secs(7 * 24 * 3600),
+        ));
+        macaroon.add_first_party_caveat("allow read");
+        match check(&macaroon, &LintPolicy::default()).unwrap().as_slice() {
+            [Lint::TtlTooLong(ttl)] => assert!(*ttl > Duration::from_secs(6 * 24 * 3600)),
+            lints => panic!("Unexpected lints {:?}", lints),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Comparing macaroon locations
+//!
+//! A macaroon's location says where it's meant to be used (for a root macaroon, the service
+//! which minted it; for a discharge, the third party which discharged the caveat). Nothing
+//! stops a holder presenting a macaroon somewhere else, so verifiers can check locations with
+//! `VerifierBuilder::add_location`, and discharges' locations are then checked against their
+//! third-party caveats' too.
+//!
+//! Locations are usually URLs, and the same URL can be written in more than one way, so
+//! they're compared once normalized: the scheme and host are lowercased, default ports
+//! (80 for `http` and `ws`, 443 for `https` and `wss`) are dropped, and so are trailing
+//! slashes. Locations which aren't URLs only have their trailing slashes dropped.
+
+/// Normalize a location for comparison
+pub fn normalize(location: &str) -> String {
+    let location = location.trim();
+    let (scheme, rest) = match location.find("://") {
+        Some(index) => (&location[..index], &location[index + 3..]),
+        None => return String::from(location.trim_end_matches('/')),
+    };
+    let scheme = scheme.to_ascii_lowercase();
+    let authority_len = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_len);
+    // Any user info is kept as it is, and only the host lowercased
+    let (user_info, host_port) = match authority.rfind('@') {
+        Some(index) => authority.split_at(index + 1),
+        None => ("", authority),
+    };
+    // The port follows the last colon, unless that's inside an IPv6 address
+    let (host, port) = match host_port.rfind(':') {
+        Some(index) if !host_port[index..].contains(']') => host_port.split_at(index),
+        _ => (host_port, ""),
+    };
+    let default_port = match scheme.as_str() {
+        "http" | "ws" => ":80",
+        "https" | "wss" => ":443",
+        _ => "",
+    };
+    let port = if port == default_port || port == ":" {
+        ""
+    } else {
+        port
+    };
+    let path = match path.find(['?', '#']) {
+        Some(index) => format!("{}{}", path[..index].trim_end_matches('/'), &path[index..]),
+        None => String::from(path.trim_end_matches('/')),
+    };
+    format!(
+        "{}://{}{}{}{}",
+        scheme,
+        user_info,
+        host.to_ascii_lowercase(),
+        port,
+        path
+    )
+}
+
+/// Whether two locations are the same, once normalized
+pub fn same_location(first: &str, second: &str) -> bool {
+    normalize(first) == normalize(second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, same_location};
+
+    #[test]
+    fn test_normalize() {
+        for (location, expected) in [
+            ("https://example.org/", "https://example.org"),
+            ("HTTPS://Example.ORG:443", "https://example.org"),
+            ("http://example.org:80/api/", "http://example.org/api"),
+            ("http://example.org:8080/", "http://example.org:8080"),
+            ("https://example.org:80", "https://example.org:80"),
+            ("https://[::1]:443/", "https://[::1]"),
+            ("https://[::1]", "https://[::1]"),
+            ("https://User@Example.org/", "https://User@example.org"),
+            ("https://example.org/a/?q=1", "https://example.org/a?q=1"),
+            ("https://example.org?q=A", "https://example.org?q=A"),
+            ("https://example.org/Path", "https://example.org/Path"),
+            ("auth service/", "auth service"),
+            ("", ""),
+        ] {
+            assert_eq!(expected, normalize(location), "{}", location);
+        }
+        assert!(same_location(
+            "http://example.org/",
+            "HTTP://example.org:80"
+        ));
+        assert!(!same_location(
+            "http://example.org/",
+            "https://example.org/"
+        ));
+    }
+}
@@ -0,0 +1,256 @@
+use crate::{
+    caveat::CaveatBuilder,
+    crypto::Signature,
+    error::MacaroonError,
+    redaction::Secret,
+    serialization::{
+        macaroon_builder::MacaroonBuilder,
+        v2::{self, UnknownFieldRef},
+    },
+    Macaroon,
+};
+use std::borrow::Cow;
+use std::fmt;
+
+/// A version 2 serialized macaroon, parsed in place
+///
+/// The fields borrow from the serialized macaroon rather than being copied out of it, so
+/// parsing one allocates nothing beyond the list of caveats. This suits services which
+/// deserialize a macaroon for every request, and only need to look at its fields, or which
+/// only need a full `Macaroon` (with `to_owned`) some of the time. With the `arena` feature,
+/// `arena::MacaroonArena` parses them without allocating at all.
+///
+/// Only the version 2 binary format can be parsed this way, since the fields of the other
+/// formats are encoded.
+#[derive(Clone, PartialEq)]
+pub struct MacaroonRef<'a> {
+    data: &'a [u8],
+    location: Option<&'a str>,
+    identifier: &'a str,
+    caveats: Cow<'a, [CaveatRef<'a>]>,
+    signature: Signature,
+    unknown_fields: Vec<UnknownFieldRef<'a>>,
+}
+
+/// A caveat of a `MacaroonRef`
+#[derive(Clone, Copy, PartialEq)]
+pub struct CaveatRef<'a> {
+    pub(crate) id: &'a str,
+    pub(crate) location: Option<&'a str>,
+    pub(crate) verifier_id: Option<&'a [u8]>,
+}
+
+impl fmt::Debug for CaveatRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CaveatRef")
+            .field("id", &self.id)
+            .field("location", &self.location)
+            .field("verifier_id", &self.verifier_id.map(Secret))
+            .finish()
+    }
+}
+
+// The serialized macaroon is left out, since it includes the signature
+impl fmt::Debug for MacaroonRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MacaroonRef")
+            .field("location", &self.location)
+            .field("identifier", &self.identifier)
+            .field("caveats", &self.caveats)
+            .field("signature", &self.signature)
+            .field("unknown_fields", &self.unknown_fields)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> MacaroonRef<'a> {
+    /// Parse a version 2 serialized macaroon
+    ///
+    /// This is as lenient as `Macaroon::deserialize`.
+    pub fn deserialize(data: &'a [u8]) -> Result<MacaroonRef<'a>, MacaroonError> {
+        v2::parse_v2(data, None)
+    }
+
+    /// Parse a version 2 serialized macaroon, rejecting it as it's parsed if it has more than
+    /// `max_caveats` caveats
+    pub fn deserialize_with_max_caveats(
+        data: &'a [u8],
+        max_caveats: usize,
+    ) -> Result<MacaroonRef<'a>, MacaroonError> {
+        v2::parse_v2(data, Some(max_caveats))
+    }
+
+    pub(crate) fn from_parts(
+        data: &'a [u8],
+        location: Option<&'a str>,
+        identifier: &'a str,
+        caveats: Cow<'a, [CaveatRef<'a>]>,
+        signature: Signature,
+        unknown_fields: Vec<UnknownFieldRef<'a>>,
+    ) -> MacaroonRef<'a> {
+        MacaroonRef {
+            data,
+            location,
+            identifier,
+            caveats,
+            signature,
+            unknown_fields,
+        }
+    }
+
+    /// Returns the serialized macaroon
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns the location, if there is one
+    pub fn location(&self) -> Option<&'a str> {
+        self.location
+    }
+
+    /// Returns the identifier
+    pub fn identifier(&self) -> &'a str {
+        self.identifier
+    }
+
+    /// Returns the caveats, first- and third-party, in order
+    pub fn caveats(&self) -> &[CaveatRef<'a>] {
+        &self.caveats
+    }
+
+    /// Returns the signature
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    pub(crate) fn unknown_fields(&self) -> &[UnknownFieldRef<'a>] {
+        &self.unknown_fields
+    }
+
+    /// Copy the fields out into a `Macaroon`
+    pub fn to_owned(&self) -> Result<Macaroon, MacaroonError> {
+        self.build()?.validate()
+    }
+
+    // As `to_owned`, without validating the result
+    pub(crate) fn build(&self) -> Result<Macaroon, MacaroonError> {
+        let mut builder = MacaroonBuilder::new();
+        if let Some(location) = self.location {
+            builder.set_location(location);
+        }
+        builder.set_identifier(self.identifier);
+        for caveat in self.caveats.iter() {
+            let mut caveat_builder = CaveatBuilder::new();
+            caveat_builder.add_id(String::from(caveat.id));
+            if let Some(location) = caveat.location {
+                caveat_builder.add_location(String::from(location));
+            }
+            if let Some(verifier_id) = caveat.verifier_id {
+                caveat_builder.add_verifier_id(verifier_id.to_vec());
+            }
+            builder.add_caveat(caveat_builder.build()?);
+        }
+        for field in &self.unknown_fields {
+            builder.add_unknown_field(field.to_unknown_field());
+        }
+        builder.set_signature(self.signature.as_ref())?;
+        builder.build()
+    }
+}
+
+impl<'a> CaveatRef<'a> {
+    /// Returns the caveat's identifier, which is the predicate of a first-party caveat
+    pub fn id(&self) -> &'a str {
+        self.id
+    }
+
+    /// Returns the location, if there is one (only third-party caveats have locations)
+    pub fn location(&self) -> Option<&'a str> {
+        self.location
+    }
+
+    /// Returns the verifier id of a third-party caveat
+    pub fn verifier_id(&self) -> Option<&'a [u8]> {
+        self.verifier_id
+    }
+
+    /// Whether this is a third-party caveat
+    pub fn is_third_party(&self) -> bool {
+        self.verifier_id.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MacaroonRef;
+    use crate::{error::MacaroonError, serialization::Format, Macaroon};
+
+    #[test]
+    fn test_deserialize() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("https://auth.mybank.com", b"caveat key", "caveat");
+        let serialized = macaroon.serialize(Format::V2).unwrap();
+
+        let view = MacaroonRef::deserialize(&serialized).unwrap();
+        assert_eq!(Some("http://example.org/"), view.location());
+        assert_eq!("keyid", view.identifier());
+        assert_eq!(macaroon.signature(), view.signature());
+        assert_eq!(2, view.caveats().len());
+        assert_eq!("account = 3735928559", view.caveats()[0].id());
+        assert!(!view.caveats()[0].is_third_party());
+        let third_party = view.caveats()[1];
+        assert_eq!("caveat", third_party.id());
+        assert_eq!(Some("https://auth.mybank.com"), third_party.location());
+        assert_eq!(
+            Some(
+                macaroon
+                    .third_party_caveats()
+                    .next()
+                    .unwrap()
+                    .verifier_id()
+                    .as_slice()
+            ),
+            third_party.verifier_id()
+        );
+
+        // The fields point into the serialized macaroon
+        let range = serialized.as_ptr_range();
+        assert!(range.contains(&view.identifier().as_ptr()));
+        assert!(range.contains(&third_party.id().as_ptr()));
+
+        assert_eq!(macaroon, view.to_owned().unwrap());
+        assert_eq!(serialized.as_slice(), view.as_bytes());
+    }
+
+    #[test]
+    #[cfg(not(feature = "unredacted_debug"))]
+    fn test_debug() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        let serialized = macaroon.serialize(Format::V2).unwrap();
+        let debug = format!("{:?}", MacaroonRef::deserialize(&serialized).unwrap());
+        assert!(debug.contains("keyid"));
+        assert!(debug.contains("account = 3735928559"));
+        // The signature's bytes don't appear, as they would in the serialized macaroon
+        let signature = format!("{:?}", macaroon.signature().as_bytes());
+        assert!(!debug.contains(signature.trim_matches(|c| c == '[' || c == ']')));
+    }
+
+    #[test]
+    fn test_deserialize_errors() {
+        let macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        let serialized = macaroon.serialize(Format::V2).unwrap();
+        match MacaroonRef::deserialize(&serialized[..serialized.len() - 1]) {
+            Err(MacaroonError::Truncated { .. }) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let mut bad_utf8 = serialized.clone();
+        let offset = serialized.windows(5).position(|w| w == b"keyid").unwrap();
+        bad_utf8[offset] = 0xff;
+        match MacaroonRef::deserialize(&bad_utf8) {
+            Err(MacaroonError::Utf8 { field, .. }) => assert_eq!("identifier", field),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
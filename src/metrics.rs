@@ -0,0 +1,302 @@
+//! Counting verifications
+//!
+//! A `Metrics` implementation set with `VerifierBuilder::set_metrics` is told the outcome of
+//! every verification the verifier does, and whether each discharge looked for was among those
+//! supplied, so that services can see how many tokens are failing, and why, without an
+//! observer (see the `observer` module) picking through every step.
+//!
+//! Applications implement the trait over whatever metrics framework they already use.
+//! `CountingMetrics` just keeps counts in memory, and with the `prometheus` feature,
+//! `PrometheusMetrics` registers counters with a Prometheus registry.
+//!
+//! Every way of verifying reports here (including each token of a batch, and
+//! `Verifier::evaluate_caveats`), except for `Verifier::dry_run`, which is for debugging rather
+//! than authorizing anything.
+use crate::error::MacaroonError;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Why a verification failed, broadly
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FailureReason {
+    /// The signature of the macaroon or one of its discharges was wrong
+    InvalidSignature,
+    /// A caveat wasn't satisfied
+    CaveatNotSatisfied,
+    /// A caveat wasn't in any namespace with a checker (see `VerifierBuilder::register_prefix`)
+    UnknownCaveat,
+    /// A discharge wasn't supplied, bound or used as it should have been
+    Discharge,
+    /// The macaroon broke one of the verifier's policies (its maximum age or number of caveats,
+    /// its locations, or revocation)
+    Policy,
+    /// The macaroon, or one of its discharges, was malformed
+    Malformed,
+    /// Anything else
+    Other,
+}
+
+impl FailureReason {
+    /// The reason for an error from verification
+    pub fn of(error: &MacaroonError) -> FailureReason {
+        match error {
+            MacaroonError::InvalidSignature => FailureReason::InvalidSignature,
+            MacaroonError::CaveatNotSatisfied { .. } => FailureReason::CaveatNotSatisfied,
+            MacaroonError::UnknownCaveat(_) => FailureReason::UnknownCaveat,
+            MacaroonError::DischargeError(_)
+            | MacaroonError::DischargeNotUsed(_)
+            | MacaroonError::DischargeNotBound { .. }
+            | MacaroonError::CaveatKeyDecryption { .. } => FailureReason::Discharge,
+            MacaroonError::BadMacaroon(_) => FailureReason::Policy,
+            error if error.is_malformed() => FailureReason::Malformed,
+            _ => FailureReason::Other,
+        }
+    }
+
+    /// A short name for the reason, for use as a metric label
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailureReason::InvalidSignature => "invalid_signature",
+            FailureReason::CaveatNotSatisfied => "caveat_not_satisfied",
+            FailureReason::UnknownCaveat => "unknown_caveat",
+            FailureReason::Discharge => "discharge",
+            FailureReason::Policy => "policy",
+            FailureReason::Malformed => "malformed",
+            FailureReason::Other => "other",
+        }
+    }
+}
+
+/// Receives the outcome of each verification
+///
+/// As with observers, implementations are shared by every verification using the verifier, so
+/// must be thread-safe, and verification carries on exactly as it would without them.
+pub trait Metrics: Send + Sync {
+    /// A macaroon was verified, and either authorized or not, for the reason given
+    ///
+    /// `caveats` is the number of caveats the macaroon has, not counting its discharges'.
+    fn verification_finished(&self, failure: Option<FailureReason>, caveats: usize) {
+        let _ = (failure, caveats);
+    }
+
+    /// A discharge was looked for, to verify a third-party caveat, and either found among the
+    /// discharges supplied or not
+    fn discharge_looked_up(&self, found: bool) {
+        let _ = found;
+    }
+}
+
+/// Metrics kept in memory
+#[derive(Debug, Default)]
+pub struct CountingMetrics {
+    succeeded: AtomicU64,
+    failed: Mutex<HashMap<FailureReason, u64>>,
+    caveats: AtomicU64,
+    discharges_found: AtomicU64,
+    discharges_missing: AtomicU64,
+}
+
+impl CountingMetrics {
+    /// Start counting from zero
+    pub fn new() -> CountingMetrics {
+        Default::default()
+    }
+
+    /// The number of verifications done
+    pub fn attempted(&self) -> u64 {
+        self.succeeded() + self.failed_total()
+    }
+
+    /// The number of macaroons authorized
+    pub fn succeeded(&self) -> u64 {
+        self.succeeded.load(Ordering::Relaxed)
+    }
+
+    /// The number of macaroons not authorized, for the reason given
+    pub fn failed(&self, reason: FailureReason) -> u64 {
+        let failed = self.failed.lock().unwrap();
+        failed.get(&reason).copied().unwrap_or_default()
+    }
+
+    /// The number of macaroons not authorized, for any reason
+    pub fn failed_total(&self) -> u64 {
+        self.failed.lock().unwrap().values().sum()
+    }
+
+    /// The average number of caveats of the macaroons verified, or zero if there were none
+    pub fn average_caveats(&self) -> f64 {
+        match self.attempted() {
+            0 => 0.0,
+            attempted => self.caveats.load(Ordering::Relaxed) as f64 / attempted as f64,
+        }
+    }
+
+    /// The number of discharges looked for and found among those supplied
+    pub fn discharges_found(&self) -> u64 {
+        self.discharges_found.load(Ordering::Relaxed)
+    }
+
+    /// The number of discharges looked for but not supplied
+    pub fn discharges_missing(&self) -> u64 {
+        self.discharges_missing.load(Ordering::Relaxed)
+    }
+}
+
+impl Metrics for CountingMetrics {
+    fn verification_finished(&self, failure: Option<FailureReason>, caveats: usize) {
+        self.caveats.fetch_add(caveats as u64, Ordering::Relaxed);
+        match failure {
+            Some(reason) => *self.failed.lock().unwrap().entry(reason).or_default() += 1,
+            None => {
+                self.succeeded.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn discharge_looked_up(&self, found: bool) {
+        let counter = match found {
+            true => &self.discharges_found,
+            false => &self.discharges_missing,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Metrics registered with a Prometheus registry
+///
+/// These are:
+///
+/// - `macaroon_verifications_total`, labelled with the `outcome`: `success`, or the failure
+///   reason (see `FailureReason::as_str`)
+/// - `macaroon_verification_caveats`, a histogram of the number of caveats of the macaroons
+///   verified
+/// - `macaroon_discharge_lookups_total`, labelled with whether the discharge was `found`
+#[cfg(feature = "prometheus")]
+#[derive(Clone, Debug)]
+pub struct PrometheusMetrics {
+    verifications: prometheus::IntCounterVec,
+    caveats: prometheus::Histogram,
+    discharge_lookups: prometheus::IntCounterVec,
+}
+
+#[cfg(feature = "prometheus")]
+impl PrometheusMetrics {
+    /// Create the metrics, and register them with the registry
+    ///
+    /// # Errors
+    /// Returns Prometheus's error if the registry already has metrics with these names.
+    pub fn register(registry: &prometheus::Registry) -> prometheus::Result<PrometheusMetrics> {
+        let metrics = PrometheusMetrics {
+            verifications: prometheus::IntCounterVec::new(
+                prometheus::Opts::new(
+                    "macaroon_verifications_total",
+                    "Macaroons verified, by outcome",
+                ),
+                &["outcome"],
+            )?,
+            caveats: prometheus::Histogram::with_opts(
+                prometheus::HistogramOpts::new(
+                    "macaroon_verification_caveats",
+                    "The number of caveats of the macaroons verified",
+                )
+                .buckets(vec![0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0]),
+            )?,
+            discharge_lookups: prometheus::IntCounterVec::new(
+                prometheus::Opts::new(
+                    "macaroon_discharge_lookups_total",
+                    "Discharges looked for, by whether they were supplied",
+                ),
+                &["found"],
+            )?,
+        };
+        registry.register(Box::new(metrics.verifications.clone()))?;
+        registry.register(Box::new(metrics.caveats.clone()))?;
+        registry.register(Box::new(metrics.discharge_lookups.clone()))?;
+        Ok(metrics)
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl Metrics for PrometheusMetrics {
+    fn verification_finished(&self, failure: Option<FailureReason>, caveats: usize) {
+        let outcome = failure.map_or("success", FailureReason::as_str);
+        self.verifications.with_label_values(&[outcome]).inc();
+        self.caveats.observe(caveats as f64);
+    }
+
+    fn discharge_looked_up(&self, found: bool) {
+        let found = if found { "true" } else { "false" };
+        self.discharge_lookups.with_label_values(&[found]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CountingMetrics, FailureReason};
+    use crate::{Macaroon, MacaroonKey, VerifierBuilder};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_counting_metrics() {
+        let key = MacaroonKey::generate(b"root key");
+        let mut root = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        root.add_first_party_caveat("account = 3735928559");
+        root.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        root.bind(&mut discharge);
+
+        let metrics = Arc::new(CountingMetrics::new());
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.set_metrics(metrics.clone());
+        let verifier = builder.build();
+
+        assert!(verifier
+            .verify(&root, &key, std::slice::from_ref(&discharge))
+            .unwrap());
+        assert!(!verifier.verify(&root, &key, &[]).unwrap());
+        let wrong_key = MacaroonKey::generate(b"wrong key");
+        assert!(!verifier.verify(&root, &wrong_key, &[]).unwrap());
+        let unused = Macaroon::create("http://auth.mybank/", b"bank key", "unused").unwrap();
+        assert!(verifier.verify(&root, &key, &[discharge, unused]).is_err());
+        // Dry runs aren't counted
+        assert_eq!(1, verifier.dry_run(&root, &[]).len());
+
+        assert_eq!(4, metrics.attempted());
+        assert_eq!(1, metrics.succeeded());
+        assert_eq!(1, metrics.failed(FailureReason::CaveatNotSatisfied));
+        assert_eq!(1, metrics.failed(FailureReason::InvalidSignature));
+        assert_eq!(1, metrics.failed(FailureReason::Discharge));
+        assert_eq!(0, metrics.failed(FailureReason::Policy));
+        assert_eq!(3, metrics.failed_total());
+        assert_eq!(2.0, metrics.average_caveats());
+        assert_eq!(2, metrics.discharges_found());
+        assert_eq!(1, metrics.discharges_missing());
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_prometheus_metrics() {
+        use super::{Metrics, PrometheusMetrics};
+
+        let registry = prometheus::Registry::new();
+        let metrics = PrometheusMetrics::register(&registry).unwrap();
+        metrics.verification_finished(None, 3);
+        metrics.verification_finished(Some(FailureReason::InvalidSignature), 1);
+        metrics.discharge_looked_up(true);
+        let families = registry.gather();
+        let names: Vec<&str> = families.iter().map(|family| family.get_name()).collect();
+        assert_eq!(
+            vec![
+                "macaroon_discharge_lookups_total",
+                "macaroon_verification_caveats",
+                "macaroon_verifications_total"
+            ],
+            names
+        );
+        assert_eq!(2, families[2].get_metric().len());
+        assert!(PrometheusMetrics::register(&registry).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{
+    caveat::{self, Caveat, CaveatType, Caveats},
+    crypto::{self, Signature},
+    error::MacaroonError,
+    Macaroon,
+};
+use std::collections::HashMap;
+
+/// Returns the condition name of a first-party caveat predicate
+///
+/// By convention a predicate starts with a condition name, separated by a space from its
+/// arguments (e.g. `time-before 2030-01-01T00:00:00Z`). A predicate without a space is all
+/// condition name.
+pub fn condition_name(predicate: &str) -> &str {
+    match predicate.find(' ') {
+        Some(index) => &predicate[..index],
+        None => predicate,
+    }
+}
+
+/// Re-mint a macaroon, renaming the conditions of its first-party caveats
+///
+/// `renames` maps old condition names to new ones. Any first-party caveat whose condition name
+/// appears in the map is rewritten with the new name, keeping its arguments unchanged. All
+/// other caveats, including third-party caveats, are carried over as-is, so the new macaroon
+/// grants exactly the same authority as the old one under the new vocabulary.
+///
+/// `key` is the root key the macaroon was created with (as passed to `Macaroon::create`). The
+/// macaroon's signature is checked against it before anything is re-minted. Any discharge
+/// macaroons bound to the old macaroon will need to be bound again to the new one.
+///
+/// # Errors
+/// Returns `MacaroonError::InvalidSignature` if the macaroon's signature doesn't match the key
+pub fn rename_caveats(
+    macaroon: &Macaroon,
+    key: &[u8],
+    renames: &HashMap<&str, &str>,
+) -> Result<Macaroon, MacaroonError> {
+    let derived_key = crypto::generate_derived_key(key);
+    if !macaroon.verify_signature_with(&derived_key) {
+        info!(
+            "migration::rename_caveats: Macaroon {:?} failed signature verification",
+            macaroon
+        );
+        return Err(MacaroonError::InvalidSignature);
+    }
+
+    let mut migrated = macaroon.clone();
+    migrated.caveats = Caveats::new();
+    let mut signature = crypto::generate_signature(&derived_key, macaroon.identifier());
+    let mut old_signature = signature;
+    for c in macaroon.caveats() {
+        let renamed: Box<dyn Caveat> = match c.get_type() {
+            CaveatType::FirstParty => {
+                let predicate = c.as_first_party().unwrap().predicate();
+                let name = condition_name(&predicate);
+                match renames.get(name) {
+                    Some(new_name) => Box::new(caveat::new_first_party(&format!(
+                        "{}{}",
+                        new_name,
+                        &predicate[name.len()..]
+                    ))),
+                    None => c.clone(),
+                }
+            }
+            CaveatType::ThirdParty => {
+                // The caveat key is encrypted using the signature so far, which may have
+                // changed due to renamed caveats earlier in the chain
+                let third_party = c.as_third_party().unwrap();
+                let caveat_key = third_party.decrypt_key(old_signature)?;
+                Box::new(caveat::new_third_party(
+                    &third_party.id(),
+                    crypto::encrypt(signature, &caveat_key),
+                    &third_party.location(),
+                ))
+            }
+        };
+        old_signature = c.sign(&old_signature);
+        signature = renamed.sign(&signature);
+        migrated.caveats.push(renamed);
+    }
+    migrated.signature = Signature(signature);
+    debug!("migration::rename_caveats: {:?}", migrated);
+
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{condition_name, rename_caveats};
+    use crate::{crypto, Macaroon, VerifierBuilder};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_condition_name() {
+        assert_eq!("account", condition_name("account = 12345678"));
+        assert_eq!("admin", condition_name("admin"));
+    }
+
+    #[test]
+    fn test_rename_caveats() {
+        let key = b"this is the key";
+        let mut macaroon = Macaroon::create("http://example.org/", key, "keyid").unwrap();
+        macaroon.add_first_party_caveat("acct = 12345678");
+        macaroon.add_first_party_caveat("user = alice");
+        let mut renames = HashMap::new();
+        renames.insert("acct", "account");
+        let migrated = rename_caveats(&macaroon, key, &renames).unwrap();
+        assert_eq!(macaroon.identifier(), migrated.identifier());
+        assert_eq!(macaroon.location(), migrated.location());
+        let predicates: Vec<String> = migrated
+            .first_party_caveats()
+            .map(|c| c.predicate())
+            .collect();
+        assert_eq!(vec!["account = 12345678", "user = alice"], predicates);
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 12345678");
+        builder.satisfy_exact("user = alice");
+        let derived_key = crypto::generate_derived_key(key);
+        let verifier = builder.build();
+        assert!(migrated.verify(&derived_key, &verifier).unwrap());
+    }
+
+    #[test]
+    fn test_rename_caveats_with_third_party() {
+        let key = b"this is the key";
+        let mut macaroon = Macaroon::create("http://example.org/", key, "keyid").unwrap();
+        macaroon.add_first_party_caveat("acct = 12345678");
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"caveat key", "caveat id");
+        let mut renames = HashMap::new();
+        renames.insert("acct", "account");
+        let migrated = rename_caveats(&macaroon, key, &renames).unwrap();
+
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"caveat key", "caveat id").unwrap();
+        migrated.bind(&mut discharge);
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 12345678");
+        builder.add_discharge_macaroons(&[discharge]);
+        let derived_key = crypto::generate_derived_key(key);
+        let verifier = builder.build();
+        assert!(migrated.verify(&derived_key, &verifier).unwrap());
+    }
+
+    #[test]
+    fn test_rename_caveats_wrong_key() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("acct = 12345678");
+        let renames = HashMap::new();
+        assert!(rename_caveats(&macaroon, b"this is not the key", &renames).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Observing verification as it happens
+//!
+//! Verification only says whether a macaroon is authorized (and, with `Verifier::authorize`, the
+//! first reason it isn't). A `VerificationObserver` set with `VerifierBuilder::set_observer` is
+//! told about each step along the way (every signature checked, caveat evaluated and discharge
+//! looked for), so that services can write audit logs or record metrics about why tokens pass or
+//! fail with whatever framework they already use.
+//!
+//! Events carry identifiers and predicates as they are, not redacted as they are in the crate's
+//! own logging, so observers which write them anywhere sensitive should use the `redaction`
+//! module.
+
+/// Something which happened while verifying a macaroon
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerificationEvent<'a> {
+    /// The signature of the root macaroon, or of a discharge, was checked
+    SignatureChecked {
+        /// The identifier of the macaroon
+        identifier: &'a str,
+        /// Whether the macaroon is a discharge
+        discharge: bool,
+        /// Whether the signature was valid
+        valid: bool,
+    },
+    /// A first-party caveat was evaluated
+    CaveatEvaluated {
+        /// The caveat's predicate
+        predicate: &'a str,
+        /// Whether the caveat was satisfied
+        satisfied: bool,
+    },
+    /// A discharge was looked for, to verify a third-party caveat
+    DischargeMatched {
+        /// The third-party caveat's id
+        caveat_id: &'a str,
+        /// Whether a discharge with that identifier was supplied
+        found: bool,
+    },
+}
+
+/// Receives events during verification
+///
+/// Observers are shared by every verification using the verifier, so must be thread-safe.
+/// Events are delivered in the order they happen, and verification carries on (or stops)
+/// exactly as it would without an observer.
+pub trait VerificationObserver: Send + Sync {
+    /// Called for each event
+    fn observe(&self, event: &VerificationEvent);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VerificationEvent, VerificationObserver};
+    use crate::{Macaroon, MacaroonKey, VerifierBuilder};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl VerificationObserver for Recorder {
+        fn observe(&self, event: &VerificationEvent) {
+            self.events.lock().unwrap().push(format!("{:?}", event));
+        }
+    }
+
+    impl Recorder {
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.events.lock().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let key = MacaroonKey::generate(b"root key");
+        let mut root = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        root.add_first_party_caveat("account = 3735928559");
+        root.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        discharge.add_first_party_caveat("user = alice");
+        root.bind(&mut discharge);
+
+        let recorder = Arc::new(Recorder::default());
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.set_observer(recorder.clone());
+        let verifier = builder.build();
+
+        assert!(!verifier
+            .verify(&root, &key, std::slice::from_ref(&discharge))
+            .unwrap());
+        assert_eq!(
+            vec![
+                "SignatureChecked { identifier: \"root\", discharge: false, valid: true }",
+                "CaveatEvaluated { predicate: \"account = 3735928559\", satisfied: true }",
+                "DischargeMatched { caveat_id: \"bank caveat\", found: true }",
+                "SignatureChecked { identifier: \"bank caveat\", discharge: true, valid: true }",
+                "CaveatEvaluated { predicate: \"user = alice\", satisfied: false }",
+            ],
+            recorder.take()
+        );
+
+        assert!(!verifier.verify(&root, &key, &[]).unwrap());
+        assert_eq!(
+            vec![
+                "SignatureChecked { identifier: \"root\", discharge: false, valid: true }",
+                "CaveatEvaluated { predicate: \"account = 3735928559\", satisfied: true }",
+                "DischargeMatched { caveat_id: \"bank caveat\", found: false }",
+            ],
+            recorder.take()
+        );
+
+        let wrong_key = MacaroonKey::generate(b"wrong key");
+        assert!(!verifier.verify(&root, &wrong_key, &[discharge]).unwrap());
+        assert_eq!(
+            vec!["SignatureChecked { identifier: \"root\", discharge: false, valid: false }"],
+            recorder.take()
+        );
+    }
+}
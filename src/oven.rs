@@ -0,0 +1,609 @@
+//! Minting and verifying macaroons with rotating root keys
+//!
+//! An `Oven` mints macaroons whose identifiers record, with an `IdentifierCodec`, which root key
+//! they were minted with, and uses that to find the key again when verifying them, so that
+//! root keys can be rotated without invalidating the macaroons already minted. Root keys are
+//! kept in a `RootKeyStore`: `MemoryRootKeyStore` holds them in memory, and services which
+//! share keys between instances can implement the trait over their own storage.
+//!
+//! If the codec has a payload key, the payloads of identifiers are encrypted when minting, and
+//! decrypted transparently when verifying.
+//!
+//! With `Oven::set_session_keys`, each macaroon is instead minted with a fresh random root key
+//! of its own, a session key, which is wrapped (encrypted) under the store's current key and
+//! carried in its identifier. Verifying it then needs only the store's long-lived keys, however
+//! many macaroons have been minted, and nothing per macaroon has to be stored or shared between
+//! instances. Either kind of macaroon is verified whichever mode the oven is in, so the mode can
+//! be switched without invalidating macaroons already minted.
+//!
+//! A `MintPolicy` set with `Oven::set_mint_policy` is consulted before each macaroon is
+//! minted, so that organizational policy (a maximum lifetime, caveats every macaroon must have,
+//! the locations macaroons may be minted for) is enforced in one place rather than by every
+//! caller remembering to attenuate what it mints.
+use crate::{
+    crypto,
+    error::MacaroonError,
+    identifier::{IdentifierCodec, IdentifierMetadata, ThirdPartyCaveatId},
+    location, std_caveats, Macaroon, MacaroonKey, Verifier, VerifyContext,
+};
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+// The domain root keys are personalized with to wrap session keys
+const SESSION_KEY_DOMAIN: &str = "macaroon session key";
+
+/// Where an `Oven` keeps its root keys
+pub trait RootKeyStore {
+    /// The id of the key to mint new macaroons with, and the key
+    ///
+    /// # Errors
+    /// Any error the store has, or `MacaroonError::MissingField` if it has no keys.
+    fn current(&self) -> Result<(String, Vec<u8>), MacaroonError>;
+
+    /// The key with the given id, if the store has it
+    fn get(&self, key_id: &str) -> Option<Vec<u8>>;
+}
+
+/// Root keys held in memory
+#[derive(Clone, Default)]
+pub struct MemoryRootKeyStore {
+    keys: HashMap<String, Vec<u8>>,
+    current: Option<String>,
+}
+
+impl MemoryRootKeyStore {
+    /// An empty store
+    pub fn new() -> MemoryRootKeyStore {
+        Default::default()
+    }
+
+    /// Add a key, which becomes the one new macaroons are minted with
+    pub fn insert(&mut self, key_id: &str, key: &[u8]) {
+        self.keys.insert(String::from(key_id), key.to_vec());
+        self.current = Some(String::from(key_id));
+    }
+
+    /// Remove a key, so that macaroons minted with it no longer verify
+    ///
+    /// If it's the current key, no new macaroons can be minted until another is added.
+    pub fn remove(&mut self, key_id: &str) {
+        self.keys.remove(key_id);
+        if self.current.as_deref() == Some(key_id) {
+            self.current = None;
+        }
+    }
+}
+
+impl RootKeyStore for MemoryRootKeyStore {
+    fn current(&self) -> Result<(String, Vec<u8>), MacaroonError> {
+        let key_id = self
+            .current
+            .as_ref()
+            .ok_or(MacaroonError::MissingField("root key"))?;
+        Ok((key_id.clone(), self.keys[key_id].clone()))
+    }
+
+    fn get(&self, key_id: &str) -> Option<Vec<u8>> {
+        self.keys.get(key_id).cloned()
+    }
+}
+
+/// Policy applied to every macaroon an `Oven` mints
+///
+/// Every method defaults to allowing anything.
+pub trait MintPolicy: Send + Sync {
+    /// The longest macaroons may be valid for, which the oven enforces by adding a
+    /// `time-before` caveat (see `std_caveats::time_before`)
+    fn max_lifetime(&self) -> Option<Duration> {
+        None
+    }
+
+    /// First-party caveats every macaroon is minted with
+    fn required_caveats(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Whether macaroons may be minted with the location
+    fn allows_location(&self, _location: &str) -> bool {
+        true
+    }
+}
+
+/// A `MintPolicy` with a fixed lifetime, caveats and locations
+#[derive(Clone, Debug, Default)]
+pub struct StandardMintPolicy {
+    max_lifetime: Option<Duration>,
+    required_caveats: Vec<String>,
+    locations: Vec<String>,
+}
+
+impl StandardMintPolicy {
+    /// A policy allowing anything, until set up otherwise
+    pub fn new() -> StandardMintPolicy {
+        Default::default()
+    }
+
+    /// Limit how long macaroons may be valid for
+    pub fn set_max_lifetime(&mut self, max_lifetime: Duration) {
+        self.max_lifetime = Some(max_lifetime);
+    }
+
+    /// Mint every macaroon with this first-party caveat
+    pub fn add_required_caveat(&mut self, predicate: &str) {
+        self.required_caveats.push(String::from(predicate));
+    }
+
+    /// Allow macaroons to be minted with this location
+    ///
+    /// Locations are compared once normalized (see the `location` module). Until one is
+    /// added, any location is allowed.
+    pub fn add_location(&mut self, location: &str) {
+        self.locations.push(location::normalize(location));
+    }
+}
+
+impl MintPolicy for StandardMintPolicy {
+    fn max_lifetime(&self) -> Option<Duration> {
+        self.max_lifetime
+    }
+
+    fn required_caveats(&self) -> Vec<String> {
+        self.required_caveats.clone()
+    }
+
+    fn allows_location(&self, location: &str) -> bool {
+        self.locations.is_empty() || self.locations.contains(&location::normalize(location))
+    }
+}
+
+/// Mints macaroons with the current root key, and verifies them with whichever key they were
+/// minted with
+///
+/// The key derived from each root key is kept by the root key's id, so that it's derived once
+/// rather than every time a macaroon is minted or verified.
+pub struct Oven<S: RootKeyStore> {
+    location: String,
+    store: S,
+    codec: IdentifierCodec,
+    version: u32,
+    policy: Option<Box<dyn MintPolicy>>,
+    session_keys: bool,
+    // Keys derived from the root keys, by id, along with the root key each was derived from
+    derived: Mutex<HashMap<String, (Vec<u8>, MacaroonKey)>>,
+}
+
+impl<S: RootKeyStore> Oven<S> {
+    /// An oven minting macaroons with the given location, and keys from the store
+    ///
+    /// Identifiers use the binary encoding, with no payload key, unless `set_codec` is used.
+    pub fn new(location: &str, store: S) -> Oven<S> {
+        Oven {
+            location: String::from(location),
+            store,
+            codec: IdentifierCodec::default(),
+            version: 0,
+            policy: None,
+            session_keys: false,
+            derived: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Consult this policy before minting each macaroon
+    pub fn set_mint_policy<P: MintPolicy + 'static>(&mut self, policy: P) {
+        self.policy = Some(Box::new(policy));
+    }
+
+    /// Use this codec for identifiers
+    pub fn set_codec(&mut self, codec: IdentifierCodec) {
+        self.codec = codec;
+    }
+
+    /// Mint each macaroon with a random session key, wrapped under the current root key in its
+    /// identifier, rather than with the root key itself
+    pub fn set_session_keys(&mut self, session_keys: bool) {
+        self.session_keys = session_keys;
+    }
+
+    /// Record this schema version in the identifiers of macaroons minted from now on
+    pub fn set_schema_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    /// The root key store
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// The root key store, for adding and removing keys
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Mint a macaroon with the current root key, recording the payload in its identifier
+    ///
+    /// The macaroon has whatever caveats the mint policy requires, and callers add whatever
+    /// other caveats they need.
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::BadMacaroon` if the mint policy doesn't allow the oven's
+    /// location, as well as any error getting the current key or encoding the identifier.
+    pub fn mint(&self, payload: Option<&[u8]>) -> Result<Macaroon, MacaroonError> {
+        if let Some(policy) = &self.policy {
+            if !policy.allows_location(&self.location) {
+                return Err(MacaroonError::BadMacaroon(
+                    "location isn't allowed by the mint policy",
+                ));
+            }
+        }
+        let (key_id, key) = self.store.current()?;
+        let now = std_caveats::now();
+        let mut metadata = IdentifierMetadata::new(&key_id, now);
+        metadata.version = self.version;
+        metadata.payload = payload.map(<[u8]>::to_vec);
+        let mut key = self.derive(&key_id, &key);
+        if self.session_keys {
+            let session_key = crypto::gen_key();
+            metadata.wrapped_key = Some(crypto::wrap_key(&wrapping_key(&key), &session_key));
+            key = session_key;
+        }
+        let mut macaroon =
+            Macaroon::create_with_key(&self.location, &key, &self.codec.encode(&metadata)?)?;
+        if let Some(policy) = &self.policy {
+            for predicate in policy.required_caveats() {
+                macaroon.add_first_party_caveat(&predicate);
+            }
+            if let Some(max_lifetime) = policy.max_lifetime() {
+                macaroon.add_first_party_caveat(&std_caveats::time_before(now + max_lifetime));
+            }
+        }
+        Ok(macaroon)
+    }
+
+    /// A new id for a third-party caveat, with a random nonce and the payload (see
+    /// `ThirdPartyCaveatId`)
+    ///
+    /// Unlike identifier payloads, the payload isn't encrypted with the codec's payload key,
+    /// since it's for the third party to read.
+    pub fn new_caveat_id(&self, payload: Option<&[u8]>) -> String {
+        ThirdPartyCaveatId::with_payload(payload.unwrap_or_default()).encode()
+    }
+
+    /// Verify a macaroon minted by this oven, and its discharges
+    ///
+    /// The root key is looked up by the id in the macaroon's identifier, and the macaroon is
+    /// verified with it by `Verifier::verify_with_context`. If the identifier records an expiry
+    /// time, that is checked too. Returns the identifier's metadata, with the payload
+    /// decrypted, if the macaroon is authorized, and `None` if it isn't (which includes its key
+    /// having been removed from the store).
+    ///
+    /// # Errors
+    /// Returns an error if the identifier can't be decoded, as well as any error verifying the
+    /// macaroon.
+    pub fn verify(
+        &self,
+        verifier: &Verifier,
+        macaroon: &Macaroon,
+        discharges: &[Macaroon],
+        context: &VerifyContext,
+    ) -> Result<Option<IdentifierMetadata>, MacaroonError> {
+        let (metadata, key) = match self.root_key(macaroon, context)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        match verifier.verify_with_context(macaroon, &key, discharges, context)? {
+            true => Ok(Some(metadata)),
+            false => Ok(None),
+        }
+    }
+
+    // The metadata in a macaroon's identifier, and the root key it names, unless the key has
+    // been removed or the identifier records an expiry time which has passed
+    pub(crate) fn root_key(
+        &self,
+        macaroon: &Macaroon,
+        context: &VerifyContext,
+    ) -> Result<Option<(IdentifierMetadata, MacaroonKey)>, MacaroonError> {
+        let metadata = self.codec.decode(macaroon.identifier())?;
+        let mut key = match self.store.get(&metadata.key_id) {
+            Some(key) => self.derive(&metadata.key_id, &key),
+            None => {
+                debug!(
+                    "Oven::verify: Macaroon {:?} has unknown root key {:?}",
+                    macaroon, metadata.key_id
+                );
+                return Ok(None);
+            }
+        };
+        if let Some(expires_at) = metadata.expires_at {
+            if context.time() >= expires_at {
+                debug!("Oven::verify: Macaroon {:?} has expired", macaroon);
+                return Ok(None);
+            }
+        }
+        if let Some(wrapped_key) = &metadata.wrapped_key {
+            key = match crypto::unwrap_key(&wrapping_key(&key), wrapped_key) {
+                Ok(session_key) => session_key,
+                Err(error) => {
+                    debug!(
+                        "Oven::verify: Macaroon {:?} has a session key which doesn't unwrap: {}",
+                        macaroon, error
+                    );
+                    return Ok(None);
+                }
+            };
+        }
+        Ok(Some((metadata, key)))
+    }
+
+    // The key derived from a root key, derived again only if the store has a different key
+    // under the id than last time
+    fn derive(&self, key_id: &str, key: &[u8]) -> MacaroonKey {
+        // The map is never left half-updated, so it's still usable if another thread panicked
+        let mut derived = self.derived.lock().unwrap_or_else(PoisonError::into_inner);
+        match derived.get(key_id) {
+            Some((root_key, derived_key)) if root_key[..] == *key => *derived_key,
+            _ => {
+                let derived_key = MacaroonKey::generate(key);
+                derived.insert(String::from(key_id), (key.to_vec(), derived_key));
+                derived_key
+            }
+        }
+    }
+}
+
+// Session keys are wrapped with a key personalized from the root key, so that the root key
+// itself is only ever used for HMAC
+fn wrapping_key(key: &MacaroonKey) -> MacaroonKey {
+    key.with_domain(SESSION_KEY_DOMAIN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryRootKeyStore, Oven, RootKeyStore, StandardMintPolicy};
+    use crate::{
+        error::MacaroonError,
+        identifier::{IdentifierCodec, IdentifierMetadata, ThirdPartyCaveatId},
+        std_caveats, Macaroon, MacaroonKey, Verifier, VerifierBuilder, VerifyContext,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn oven() -> Oven<MemoryRootKeyStore> {
+        let mut store = MemoryRootKeyStore::new();
+        store.insert("2023-10", b"october key");
+        store.insert("2023-11", b"november key");
+        Oven::new("http://example.org/", store)
+    }
+
+    #[test]
+    fn test_oven_key_rotation() {
+        let mut oven = oven();
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        let verifier = builder.build();
+        let context = VerifyContext::new();
+
+        let mut macaroon = oven.mint(Some(b"user=alice")).unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        let metadata = oven
+            .verify(&verifier, &macaroon, &[], &context)
+            .unwrap()
+            .unwrap();
+        assert_eq!("2023-11", metadata.key_id);
+        assert_eq!(Some(b"user=alice".to_vec()), metadata.payload);
+        assert!(verifier
+            .verify(&macaroon, &MacaroonKey::generate(b"november key"), &[])
+            .unwrap());
+
+        // Still verifies once the key is rotated, but not once it's removed
+        oven.store_mut().insert("2023-12", b"december key");
+        assert_eq!("2023-12", oven.store().current().unwrap().0);
+        assert!(oven
+            .verify(&verifier, &macaroon, &[], &context)
+            .unwrap()
+            .is_some());
+        oven.store_mut().remove("2023-11");
+        assert!(oven
+            .verify(&verifier, &macaroon, &[], &context)
+            .unwrap()
+            .is_none());
+
+        oven.store_mut().remove("2023-12");
+        match oven.mint(None) {
+            Err(MacaroonError::MissingField(field)) => assert_eq!("root key", field),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let forged = Macaroon::create("http://example.org/", b"key", "not metadata").unwrap();
+        assert!(oven.verify(&verifier, &forged, &[], &context).is_err());
+    }
+
+    #[test]
+    fn test_oven_derived_keys() {
+        let mut oven = oven();
+        let verifier = Verifier::default();
+        let context = VerifyContext::new();
+        let macaroon = oven.mint(None).unwrap();
+        assert_eq!(1, oven.derived.lock().unwrap().len());
+        assert!(oven
+            .verify(&verifier, &macaroon, &[], &context)
+            .unwrap()
+            .is_some());
+        assert_eq!(1, oven.derived.lock().unwrap().len());
+
+        // A key replaced under the same id is derived afresh
+        oven.store_mut().insert("2023-11", b"replacement key");
+        assert!(oven
+            .verify(&verifier, &macaroon, &[], &context)
+            .unwrap()
+            .is_none());
+        let macaroon = oven.mint(None).unwrap();
+        assert!(macaroon.verify_signature(MacaroonKey::generate(b"replacement key").as_ref()));
+        assert!(oven
+            .verify(&verifier, &macaroon, &[], &context)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_oven_mint_policy() {
+        let mut oven = oven();
+        let mut policy = StandardMintPolicy::new();
+        policy.set_max_lifetime(Duration::from_secs(3600));
+        policy.add_required_caveat("environment = production");
+        policy.add_location("HTTP://example.org");
+        oven.set_mint_policy(policy.clone());
+        let macaroon = oven.mint(None).unwrap();
+        let predicates: Vec<String> = macaroon
+            .first_party_caveats()
+            .map(|caveat| caveat.predicate())
+            .collect();
+        assert_eq!("environment = production", predicates[0]);
+        let minted_at = IdentifierCodec::default()
+            .decode(macaroon.identifier())
+            .unwrap()
+            .minted_at;
+        let expiry = std_caveats::parse_time_before(&predicates[1]).unwrap();
+        assert!(expiry > minted_at + Duration::from_secs(3599));
+        assert!(expiry <= minted_at + Duration::from_secs(3600));
+        assert_eq!(Some(expiry), macaroon.expiry());
+
+        // The caveats are checked like any others
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("environment = production");
+        builder.satisfy_general(std_caveats::check_time_before);
+        let verifier = builder.build();
+        let mut context = VerifyContext::new();
+        assert!(oven
+            .verify(&verifier, &macaroon, &[], &context)
+            .unwrap()
+            .is_some());
+        context.set_time(expiry);
+        assert!(oven
+            .verify(&verifier, &macaroon, &[], &context)
+            .unwrap()
+            .is_none());
+
+        let mut elsewhere = Oven::new("http://example.com/", oven.store().clone());
+        elsewhere.set_mint_policy(policy);
+        match elsewhere.mint(None) {
+            Err(MacaroonError::BadMacaroon(reason)) => {
+                assert_eq!("location isn't allowed by the mint policy", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_oven_new_caveat_id() {
+        let oven = oven();
+        let id = oven.new_caveat_id(Some(b"user = alice"));
+        assert_ne!(id, oven.new_caveat_id(Some(b"user = alice")));
+        assert_eq!(
+            b"user = alice".to_vec(),
+            ThirdPartyCaveatId::decode(&id).unwrap().payload
+        );
+        assert!(ThirdPartyCaveatId::decode(&oven.new_caveat_id(None))
+            .unwrap()
+            .payload
+            .is_empty());
+    }
+
+    #[test]
+    fn test_oven_encrypted_payload() {
+        let mut oven = oven();
+        let mut codec = IdentifierCodec::default();
+        codec.set_payload_key(MacaroonKey::generate(b"payload key"));
+        oven.set_codec(codec);
+        oven.set_schema_version(2);
+        let macaroon = oven.mint(Some(b"account=3735928559")).unwrap();
+        assert!(!macaroon.identifier().contains("MzczNTkyODU1OQ"));
+
+        // The holder can read the rest of the metadata, but not the payload
+        assert!(IdentifierCodec::default()
+            .decode(macaroon.identifier())
+            .is_err());
+        let metadata = oven
+            .verify(&Verifier::default(), &macaroon, &[], &VerifyContext::new())
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, metadata.version);
+        assert_eq!(Some(b"account=3735928559".to_vec()), metadata.payload);
+
+        let mut wrong_key = IdentifierCodec::default();
+        wrong_key.set_payload_key(MacaroonKey::generate(b"another key"));
+        assert!(wrong_key.decode(macaroon.identifier()).is_err());
+    }
+
+    #[test]
+    fn test_oven_session_keys() {
+        let mut minting = oven();
+        minting.set_session_keys(true);
+        let macaroon = minting.mint(None).unwrap();
+        let metadata = IdentifierCodec::default()
+            .decode(macaroon.identifier())
+            .unwrap();
+        assert!(metadata.wrapped_key.is_some());
+
+        // Any oven with the same store can verify it, in either mode
+        let verifier = Verifier::default();
+        let context = VerifyContext::new();
+        assert_eq!(
+            Some(metadata),
+            oven().verify(&verifier, &macaroon, &[], &context).unwrap()
+        );
+        assert!(minting
+            .verify(&verifier, &minting.mint(None).unwrap(), &[], &context)
+            .unwrap()
+            .is_some());
+        minting.set_session_keys(false);
+        assert!(minting
+            .verify(&verifier, &macaroon, &[], &context)
+            .unwrap()
+            .is_some());
+
+        // It wasn't minted with the root key, and the root key alone doesn't verify it
+        let forged = Macaroon::create(
+            "http://example.org/",
+            b"november key",
+            macaroon.identifier(),
+        )
+        .unwrap();
+        assert_ne!(macaroon.signature(), forged.signature());
+        assert_eq!(
+            None,
+            minting.verify(&verifier, &forged, &[], &context).unwrap()
+        );
+
+        // Nor does an oven whose key has the same id
+        let mut store = MemoryRootKeyStore::new();
+        store.insert("2023-11", b"another key");
+        let foreign = Oven::new("http://example.org/", store);
+        assert_eq!(
+            None,
+            foreign.verify(&verifier, &macaroon, &[], &context).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_oven_expiry() {
+        let oven = oven();
+        let minted_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut metadata = IdentifierMetadata::new("2023-11", minted_at);
+        metadata.expires_at = Some(minted_at + Duration::from_secs(3600));
+        let identifier = IdentifierCodec::default().encode(&metadata).unwrap();
+        let macaroon =
+            Macaroon::create("http://example.org/", b"november key", &identifier).unwrap();
+        let mut context = VerifyContext::new();
+        context.set_time(minted_at + Duration::from_secs(60));
+        let verifier = Verifier::default();
+        assert_eq!(
+            Some(metadata),
+            oven.verify(&verifier, &macaroon, &[], &context).unwrap()
+        );
+        context.set_time(minted_at + Duration::from_secs(3600));
+        assert_eq!(
+            None,
+            oven.verify(&verifier, &macaroon, &[], &context).unwrap()
+        );
+    }
+}
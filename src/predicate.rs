@@ -0,0 +1,306 @@
+//! Helpers for first-party caveat predicates
+//!
+//! Predicates are free text, but most are comparisons of a key with a value, as `comparison`
+//! writes them and `parse_comparison` reads them:
+//!
+//! ```text
+//! comparison = key operator value
+//! key        = one or more characters, none of them whitespace or an operator character
+//! operator   = "=" | "!=" | "<" | "<=" | ">" | ">="
+//! value      = any text, without leading or trailing whitespace
+//! ```
+//!
+//! Whitespace around the operator is optional, so `account<1000` and `account < 1000` are the
+//! same comparison. `=` and `!=` compare values as integers if both are, and as text otherwise,
+//! while the ordering operators only compare integers (in the range of `i64`). Verifiers can
+//! satisfy comparisons with `VerifierBuilder::satisfy_key_value`, rather than having to match
+//! predicates to the byte.
+
+/// Type of function used to canonicalize first-party caveat predicates
+pub type Canonicalizer = fn(&str) -> String;
+
+/// Condition name for disjunctive caveats (see `any_of`)
+pub const ANY_OF: &str = "any-of";
+
+const OPERATOR_CHARS: &[char] = &['=', '!', '<', '>'];
+
+/// Canonicalize the whitespace around the first operator in a predicate
+///
+/// The first operator (a run of `=`, `!`, `<` and `>` characters) is surrounded by exactly one
+/// space on either side, so that `account=1`, `account =1` and `account  =  1` all become
+/// `account = 1`. Predicates without an operator are returned unchanged.
+pub fn canonicalize_whitespace(predicate: &str) -> String {
+    let start = match predicate.find(OPERATOR_CHARS) {
+        Some(start) => start,
+        None => return String::from(predicate),
+    };
+    let end = predicate[start..]
+        .find(|c| !OPERATOR_CHARS.contains(&c))
+        .map_or(predicate.len(), |len| start + len);
+    [
+        predicate[..start].trim_end(),
+        &predicate[start..end],
+        predicate[end..].trim_start(),
+    ]
+    .iter()
+    .filter(|part| !part.is_empty())
+    .cloned()
+    .collect::<Vec<&str>>()
+    .join(" ")
+}
+
+/// The operators of comparison predicates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    /// `=`
+    Eq,
+    /// `!=`
+    Ne,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
+}
+
+impl Operator {
+    /// The operator as it's written in predicates
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operator::Eq => "=",
+            Operator::Ne => "!=",
+            Operator::Lt => "<",
+            Operator::Le => "<=",
+            Operator::Gt => ">",
+            Operator::Ge => ">=",
+        }
+    }
+
+    fn parse(operator: &str) -> Option<Operator> {
+        Some(match operator {
+            "=" => Operator::Eq,
+            "!=" => Operator::Ne,
+            "<" => Operator::Lt,
+            "<=" => Operator::Le,
+            ">" => Operator::Gt,
+            ">=" => Operator::Ge,
+            _ => return None,
+        })
+    }
+}
+
+/// A comparison predicate, as parsed by `parse_comparison`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Comparison<'a> {
+    pub key: &'a str,
+    pub operator: Operator,
+    pub value: &'a str,
+}
+
+impl Comparison<'_> {
+    /// Whether the comparison holds of an actual value for the key
+    ///
+    /// For example, `account < 1000` holds of `999`, but not of `1000` or `alice`.
+    pub fn holds_for(&self, actual: &str) -> bool {
+        let numbers = match (actual.parse::<i64>(), self.value.parse::<i64>()) {
+            (Ok(actual), Ok(value)) => Some((actual, value)),
+            _ => None,
+        };
+        match (self.operator, numbers) {
+            (Operator::Eq, Some((actual, value))) => actual == value,
+            (Operator::Ne, Some((actual, value))) => actual != value,
+            (Operator::Eq, None) => actual == self.value,
+            (Operator::Ne, None) => actual != self.value,
+            (Operator::Lt, Some((actual, value))) => actual < value,
+            (Operator::Le, Some((actual, value))) => actual <= value,
+            (Operator::Gt, Some((actual, value))) => actual > value,
+            (Operator::Ge, Some((actual, value))) => actual >= value,
+            (_, None) => false,
+        }
+    }
+}
+
+/// Encode a comparison predicate, such as `account < 1000`
+pub fn comparison(key: &str, operator: Operator, value: &str) -> String {
+    format!("{} {} {}", key, operator.as_str(), value)
+}
+
+/// Encode a comparison predicate with the `=` operator, such as `user = alice`
+pub fn key_value(key: &str, value: &str) -> String {
+    comparison(key, Operator::Eq, value)
+}
+
+/// Decode a comparison predicate
+///
+/// Returns `None` if the predicate isn't a comparison, by the grammar in the module
+/// documentation.
+pub fn parse_comparison(predicate: &str) -> Option<Comparison<'_>> {
+    let start = predicate.find(OPERATOR_CHARS)?;
+    let end = predicate[start..]
+        .find(|c| !OPERATOR_CHARS.contains(&c))
+        .map_or(predicate.len(), |len| start + len);
+    let key = predicate[..start].trim();
+    let value = predicate[end..].trim();
+    if key.is_empty() || key.contains(char::is_whitespace) || value.is_empty() {
+        return None;
+    }
+    Some(Comparison {
+        key,
+        operator: Operator::parse(&predicate[start..end])?,
+        value,
+    })
+}
+
+/// Encode a disjunctive ("either/or") predicate
+///
+/// The resulting predicate is satisfied if any one of the alternatives is, e.g.
+/// `any_of(&["user = alice", "role = admin"])` gives `any-of "user = alice" "role = admin"`.
+/// Each alternative is quoted, with any `"` or `\` in it escaped by a backslash, so the
+/// alternatives may contain any text (including other `any-of` predicates).
+pub fn any_of(alternatives: &[&str]) -> String {
+    let mut predicate = String::from(ANY_OF);
+    for alternative in alternatives {
+        predicate.push_str(" \"");
+        for c in alternative.chars() {
+            if c == '"' || c == '\\' {
+                predicate.push('\\');
+            }
+            predicate.push(c);
+        }
+        predicate.push('"');
+    }
+    predicate
+}
+
+/// Decode a disjunctive predicate created by `any_of`
+///
+/// Returns the alternatives, or `None` if the predicate isn't a well-formed `any-of` predicate.
+pub fn parse_any_of(predicate: &str) -> Option<Vec<String>> {
+    let mut chars = predicate.strip_prefix(ANY_OF)?.chars();
+    let mut alternatives: Vec<String> = Vec::new();
+    loop {
+        match chars.next() {
+            None => return Some(alternatives),
+            Some(' ') => (),
+            Some(_) => return None,
+        }
+        if chars.next() != Some('"') {
+            return None;
+        }
+        let mut alternative = String::new();
+        loop {
+            match chars.next()? {
+                '"' => break,
+                '\\' => alternative.push(chars.next()?),
+                c => alternative.push(c),
+            }
+        }
+        alternatives.push(alternative);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        any_of, canonicalize_whitespace, comparison, key_value, parse_any_of, parse_comparison,
+        Comparison, Operator,
+    };
+
+    #[test]
+    fn test_comparison() {
+        assert_eq!("user = alice", key_value("user", "alice"));
+        assert_eq!(
+            "account < 1000",
+            comparison("account", Operator::Lt, "1000")
+        );
+        for operator in [
+            Operator::Eq,
+            Operator::Ne,
+            Operator::Lt,
+            Operator::Le,
+            Operator::Gt,
+            Operator::Ge,
+        ] {
+            assert_eq!(
+                Some(Comparison {
+                    key: "account",
+                    operator,
+                    value: "1000"
+                }),
+                parse_comparison(&comparison("account", operator, "1000"))
+            );
+        }
+        assert_eq!(
+            parse_comparison("account < 1000"),
+            parse_comparison("account<1000")
+        );
+        assert_eq!(
+            Some("two words"),
+            parse_comparison("note =  two words ").map(|c| c.value)
+        );
+        for predicate in [
+            "admin",
+            "= 1",
+            "account =",
+            "my account = 1",
+            "a => 1",
+            "a == 1",
+        ] {
+            assert_eq!(None, parse_comparison(predicate), "{}", predicate);
+        }
+
+        let holds =
+            |predicate: &str, actual: &str| parse_comparison(predicate).unwrap().holds_for(actual);
+        assert!(holds("account < 1000", "999"));
+        assert!(!holds("account < 1000", "1000"));
+        assert!(holds("account <= 1000", "1000"));
+        assert!(holds("account > -5", "0"));
+        assert!(holds("account >= 5", "5"));
+        assert!(!holds("account < 1000", "alice"));
+        assert!(!holds("user < bob", "alice"));
+        assert!(holds("account = 0042", "42"));
+        assert!(holds("user = alice", "alice"));
+        assert!(!holds("user = alice", "bob"));
+        assert!(holds("user != alice", "bob"));
+        assert!(!holds("account != 42", "42"));
+    }
+
+    #[test]
+    fn test_canonicalize_whitespace() {
+        assert_eq!("account = 1", canonicalize_whitespace("account=1"));
+        assert_eq!("account = 1", canonicalize_whitespace("account = 1"));
+        assert_eq!("account = 1", canonicalize_whitespace("account  =\t1"));
+        assert_eq!("time >= 5", canonicalize_whitespace("time>=5"));
+        assert_eq!("a = b=c", canonicalize_whitespace("a=b=c"));
+        assert_eq!("admin", canonicalize_whitespace("admin"));
+        assert_eq!("= 1", canonicalize_whitespace("=1"));
+    }
+
+    #[test]
+    fn test_any_of() {
+        let predicate = any_of(&["user = alice", "role = admin"]);
+        assert_eq!("any-of \"user = alice\" \"role = admin\"", predicate);
+        assert_eq!(
+            vec!["user = alice", "role = admin"],
+            parse_any_of(&predicate).unwrap()
+        );
+
+        let nested = any_of(&[&predicate, "quote = \"\\\""]);
+        assert_eq!(
+            vec![predicate.as_str(), "quote = \"\\\""],
+            parse_any_of(&nested).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_any_of_malformed() {
+        assert_eq!(None, parse_any_of("user = alice"));
+        assert_eq!(None, parse_any_of("any-of user = alice"));
+        assert_eq!(None, parse_any_of("any-of \"user = alice"));
+        assert_eq!(None, parse_any_of("any-of \"a\"\"b\""));
+        assert_eq!(Some(Vec::new()), parse_any_of("any-of"));
+    }
+}
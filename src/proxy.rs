@@ -0,0 +1,237 @@
+//! Token exchange for proxies
+//!
+//! A proxy in front of internal services often shouldn't pass the macaroons its clients present
+//! straight through: they are minted with a key the internal services shouldn't need to know,
+//! and may grant far more than any one upstream request needs. A `TokenExchanger` implements
+//! the token-exchange pattern instead. It verifies the inbound macaroon (and its discharges),
+//! and mints a new macaroon for the upstream service with a different root key, narrowed to
+//! the operations the upstream request needs, and expiring shortly.
+//!
+//! The inbound macaroon's first-party caveats are carried over to the internal macaroon, since
+//! the upstream service may check them again, but they can be translated or dropped with a
+//! `CaveatMapper`. Third-party caveats are never carried over: they were discharged at the
+//! proxy. The caller is responsible for removing the inbound macaroon from the request, and
+//! attaching the internal one in whatever way the upstream service expects.
+use crate::{error::MacaroonError, std_caveats, Macaroon, MacaroonKey, Verifier, VerifyContext};
+use std::time::Duration;
+
+/// Hook to translate the inbound macaroon's first-party caveats for the upstream service
+///
+/// Called with the predicate of each caveat, and returns the predicate to add to the internal
+/// macaroon, or `None` to leave it off.
+pub type CaveatMapper = fn(&str) -> Option<String>;
+
+/// Exchanges inbound macaroons for internal ones
+pub struct TokenExchanger {
+    verifier: Verifier,
+    inbound_key: MacaroonKey,
+    upstream_location: String,
+    upstream_key: Vec<u8>,
+    ttl: Duration,
+    operations: Vec<String>,
+    mapper: Option<CaveatMapper>,
+}
+
+impl TokenExchanger {
+    /// Create a token exchanger
+    ///
+    /// Inbound macaroons are verified with the verifier and inbound key. Internal macaroons
+    /// are minted with the upstream location and root key, and expire `ttl` after they are
+    /// minted.
+    pub fn new(
+        verifier: Verifier,
+        inbound_key: MacaroonKey,
+        upstream_location: &str,
+        upstream_key: &[u8],
+        ttl: Duration,
+    ) -> TokenExchanger {
+        TokenExchanger {
+            verifier,
+            inbound_key,
+            upstream_location: String::from(upstream_location),
+            upstream_key: upstream_key.to_vec(),
+            ttl,
+            operations: Vec::new(),
+            mapper: None,
+        }
+    }
+
+    /// Restrict internal macaroons to the given operations
+    ///
+    /// This adds an `allow` caveat (see `std_caveats::allow`) to every internal macaroon.
+    pub fn set_operations(&mut self, operations: &[&str]) {
+        self.operations = operations.iter().map(|&op| String::from(op)).collect();
+    }
+
+    /// Sets the hook used to translate caveats (by default they are carried over unchanged)
+    pub fn set_caveat_mapper(&mut self, mapper: CaveatMapper) {
+        self.mapper = Some(mapper);
+    }
+
+    /// Verify an inbound macaroon, and mint an internal macaroon in its place
+    ///
+    /// The internal macaroon has the same identifier as the inbound one, so requests can be
+    /// correlated across the proxy.
+    ///
+    /// # Errors
+    /// Returns the error from `Verifier::authorize` if the inbound macaroon isn't authorized.
+    pub fn exchange(
+        &self,
+        inbound: &Macaroon,
+        discharges: &[Macaroon],
+        context: &VerifyContext,
+    ) -> Result<Macaroon, MacaroonError> {
+        self.verifier
+            .authorize(inbound, &self.inbound_key, discharges, context)?;
+        let mut internal = Macaroon::create(
+            &self.upstream_location,
+            &self.upstream_key,
+            inbound.identifier(),
+        )?;
+        for caveat in inbound.first_party_caveats() {
+            let predicate = caveat.predicate();
+            // The inbound expiry is replaced by the internal macaroon's own, shorter one
+            if std_caveats::parse_time_before(&predicate).is_some() {
+                continue;
+            }
+            let mapped = match self.mapper {
+                Some(mapper) => mapper(&predicate),
+                None => Some(predicate),
+            };
+            if let Some(mapped) = mapped {
+                internal.add_first_party_caveat(&mapped);
+            }
+        }
+        if !self.operations.is_empty() {
+            let operations: Vec<&str> = self.operations.iter().map(String::as_str).collect();
+            internal.add_first_party_caveat(&std_caveats::allow(&operations));
+        }
+        let expiry = context.time() + self.ttl;
+        let expiry = match inbound.expiry() {
+            Some(inbound_expiry) if inbound_expiry < expiry => inbound_expiry,
+            _ => expiry,
+        };
+        internal.add_first_party_caveat(&std_caveats::time_before(expiry));
+        Ok(internal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenExchanger;
+    use crate::{
+        error::MacaroonError, std_caveats, Macaroon, MacaroonKey, Verifier, VerifyContext,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const NOW: u64 = 1_483_228_800;
+
+    fn exchanger() -> TokenExchanger {
+        let mut verifier = Verifier::builder();
+        verifier.satisfy_exact("account = 3735928559");
+        verifier.satisfy_exact("tier = gold");
+        verifier.satisfy_general(std_caveats::check_time_before);
+        TokenExchanger::new(
+            verifier.build(),
+            MacaroonKey::generate(b"inbound key"),
+            "http://internal/",
+            b"upstream key",
+            Duration::from_secs(60),
+        )
+    }
+
+    fn context() -> VerifyContext {
+        let mut context = VerifyContext::new();
+        context.set_time(UNIX_EPOCH + Duration::from_secs(NOW));
+        context
+    }
+
+    fn inbound() -> Macaroon {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"inbound key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_first_party_caveat("tier = gold");
+        macaroon.add_first_party_caveat(&std_caveats::time_before(
+            UNIX_EPOCH + Duration::from_secs(NOW + 3600),
+        ));
+        macaroon
+    }
+
+    fn predicates(macaroon: &Macaroon) -> Vec<String> {
+        macaroon
+            .first_party_caveats()
+            .map(|caveat| caveat.predicate())
+            .collect()
+    }
+
+    #[test]
+    fn test_exchange() {
+        let mut exchanger = exchanger();
+        exchanger.set_operations(&["read"]);
+        let internal = exchanger.exchange(&inbound(), &[], &context()).unwrap();
+        assert_eq!("keyid", internal.identifier());
+        assert_eq!("http://internal/", internal.location().unwrap());
+        assert_eq!(
+            vec![
+                String::from("account = 3735928559"),
+                String::from("tier = gold"),
+                std_caveats::allow(&["read"]),
+                std_caveats::time_before(UNIX_EPOCH + Duration::from_secs(NOW + 60)),
+            ],
+            predicates(&internal)
+        );
+
+        // The internal macaroon is minted with the upstream key
+        let mut verifier = Verifier::builder();
+        verifier.satisfy_general(|_, _| true);
+        let verifier = verifier.build();
+        assert!(verifier
+            .verify(&internal, &MacaroonKey::generate(b"upstream key"), &[])
+            .unwrap());
+        assert!(!verifier
+            .verify(&internal, &MacaroonKey::generate(b"inbound key"), &[])
+            .unwrap());
+    }
+
+    #[test]
+    fn test_exchange_caveat_mapper() {
+        let mut exchanger = exchanger();
+        exchanger.set_caveat_mapper(|predicate| match predicate {
+            "tier = gold" => None,
+            _ => Some(predicate.replace("account", "internal-account")),
+        });
+        let internal = exchanger.exchange(&inbound(), &[], &context()).unwrap();
+        assert_eq!(
+            vec![
+                String::from("internal-account = 3735928559"),
+                std_caveats::time_before(UNIX_EPOCH + Duration::from_secs(NOW + 60)),
+            ],
+            predicates(&internal)
+        );
+    }
+
+    #[test]
+    fn test_exchange_keeps_earlier_expiry() {
+        let exchanger = exchanger();
+        let mut context = context();
+        context.set_time(UNIX_EPOCH + Duration::from_secs(NOW + 3590));
+        let internal = exchanger.exchange(&inbound(), &[], &context).unwrap();
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(NOW + 3600)),
+            internal.expiry()
+        );
+    }
+
+    #[test]
+    fn test_exchange_unauthorized() {
+        let exchanger = exchanger();
+        let mut macaroon = inbound();
+        macaroon.add_first_party_caveat("tier = platinum");
+        match exchanger.exchange(&macaroon, &[], &context()) {
+            Err(MacaroonError::CaveatNotSatisfied { caveat, .. }) => {
+                assert_eq!("tier = platinum", caveat)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
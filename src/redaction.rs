@@ -0,0 +1,148 @@
+//! Control over how much token content appears in error messages
+//!
+//! By default, errors include the fields of the macaroons involved (identifiers, locations and
+//! so on), which is convenient for debugging but may not be acceptable where errors are logged
+//! somewhere subject to a data-handling policy. `set_policy` changes this for the whole crate.
+//!
+//! Secrets are another matter: the `Debug` output of signatures and third-party caveats' verifier
+//! ids only shows a hash of them, whatever the policy, and that of keys shows nothing at all.
+//! The `unredacted_debug` feature shows signatures and verifier ids in full, for local debugging.
+use sodiumoxide::crypto::hash::sha256;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+static POLICY: AtomicU8 = AtomicU8::new(RedactionPolicy::Full as u8);
+
+const HASH_PREFIX_LEN: usize = 8;
+
+/// How much token content to include in error messages
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RedactionPolicy {
+    /// Include token content as-is (the default)
+    #[default]
+    Full = 0,
+    /// Include identifiers as-is, but replace any other content with a hash of it
+    IdentifiersOnly = 1,
+    /// Replace all token content, including identifiers, with a hash of it
+    HashesOnly = 2,
+}
+
+impl RedactionPolicy {
+    /// Returns a macaroon or caveat identifier as it should appear in an error message under
+    /// this policy
+    pub fn identifier(self, identifier: &str) -> String {
+        match self {
+            RedactionPolicy::Full | RedactionPolicy::IdentifiersOnly => String::from(identifier),
+            RedactionPolicy::HashesOnly => hash(identifier),
+        }
+    }
+
+    /// Returns any other token content (locations, predicates and the like) as it should appear
+    /// in an error message under this policy
+    pub fn content(self, content: &str) -> String {
+        match self {
+            RedactionPolicy::Full => String::from(content),
+            RedactionPolicy::IdentifiersOnly | RedactionPolicy::HashesOnly => hash(content),
+        }
+    }
+
+    fn from_u8(value: u8) -> RedactionPolicy {
+        match value {
+            1 => RedactionPolicy::IdentifiersOnly,
+            2 => RedactionPolicy::HashesOnly,
+            _ => RedactionPolicy::Full,
+        }
+    }
+}
+
+/// Sets the redaction policy for all errors created from now on
+pub fn set_policy(policy: RedactionPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Returns the current redaction policy
+pub fn policy() -> RedactionPolicy {
+    RedactionPolicy::from_u8(POLICY.load(Ordering::Relaxed))
+}
+
+/// Format an identifier for an error message under the current policy
+pub(crate) fn identifier(identifier: &str) -> String {
+    policy().identifier(identifier)
+}
+
+/// Format other token content for an error message under the current policy
+pub(crate) fn content(content: &str) -> String {
+    policy().content(content)
+}
+
+/// `Debug` formatting of secret bytes (signatures and verifier ids)
+pub(crate) struct Secret<'a>(pub(crate) &'a [u8]);
+
+impl fmt::Debug for Secret<'_> {
+    #[cfg(not(feature = "unredacted_debug"))]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} bytes)", hash_bytes(self.0), self.0.len())
+    }
+
+    #[cfg(feature = "unredacted_debug")]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+fn hash(content: &str) -> String {
+    hash_bytes(content.as_bytes())
+}
+
+// A truncated hash is enough to correlate errors with tokens without revealing their content
+fn hash_bytes(content: &[u8]) -> String {
+    let sha256::Digest(digest) = sha256::hash(content);
+    let hex: String = digest[..HASH_PREFIX_LEN]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256:{}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RedactionPolicy;
+
+    #[test]
+    fn test_full() {
+        let policy = RedactionPolicy::Full;
+        assert_eq!("keyid", policy.identifier("keyid"));
+        assert_eq!("http://example.org/", policy.content("http://example.org/"));
+    }
+
+    #[test]
+    fn test_identifiers_only() {
+        let policy = RedactionPolicy::IdentifiersOnly;
+        assert_eq!("keyid", policy.identifier("keyid"));
+        let location = policy.content("http://example.org/");
+        assert!(location.starts_with("sha256:"));
+        assert!(!location.contains("example"));
+        assert_eq!(location, policy.content("http://example.org/"));
+    }
+
+    #[test]
+    fn test_hashes_only() {
+        let policy = RedactionPolicy::HashesOnly;
+        let identifier = policy.identifier("keyid");
+        assert_eq!("sha256:".len() + 16, identifier.len());
+        assert!(!identifier.contains("keyid"));
+        assert_ne!(identifier, policy.identifier("other keyid"));
+        assert_eq!(identifier, policy.content("keyid"));
+    }
+
+    #[cfg(not(feature = "unredacted_debug"))]
+    #[test]
+    fn test_secret() {
+        use super::Secret;
+
+        let debug = format!("{:?}", Secret(&[0xab; 32]));
+        assert!(debug.starts_with("sha256:"));
+        assert!(debug.ends_with(" (32 bytes)"));
+        assert!(!debug.contains("171"));
+    }
+}
@@ -0,0 +1,118 @@
+//! Revoking macaroons before they expire
+//!
+//! A macaroon is valid until its caveats say otherwise, so one which is stolen stays usable
+//! until it expires. A `RevocationChecker` set with `VerifierBuilder::set_revocation_checker`
+//! is asked about the root macaroon and each discharge used to verify it, and any which it says
+//! has been revoked fails verification with `MacaroonError::BadMacaroon`.
+//!
+//! `DenyList` revokes macaroons by identifier, which, since the holder can't change the
+//! identifier, covers every macaroon derived from the one revoked. Services which record a root
+//! key id or a nonce in their identifiers (see the `identifier` module) can implement the trait
+//! to revoke by those instead.
+use crate::Macaroon;
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+/// Decides whether macaroons have been revoked
+///
+/// Checkers are shared by every verification using the verifier, so must be thread-safe.
+pub trait RevocationChecker: Send + Sync {
+    /// Whether the macaroon (the root or a discharge) has been revoked
+    fn is_revoked(&self, macaroon: &Macaroon) -> bool;
+}
+
+/// An in-memory list of revoked macaroon identifiers
+///
+/// Identifiers can be added while the list is in use by a verifier.
+#[derive(Debug, Default)]
+pub struct DenyList {
+    identifiers: RwLock<HashSet<String>>,
+}
+
+impl DenyList {
+    /// An empty deny list
+    pub fn new() -> DenyList {
+        Default::default()
+    }
+
+    /// Revoke the macaroons with this identifier
+    pub fn revoke(&self, identifier: &str) {
+        self.identifiers
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(String::from(identifier));
+    }
+
+    /// Reinstate the macaroons with this identifier
+    pub fn reinstate(&self, identifier: &str) {
+        self.identifiers
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(identifier);
+    }
+
+    /// Whether the macaroons with this identifier are revoked
+    pub fn is_revoked_identifier(&self, identifier: &str) -> bool {
+        self.identifiers
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .contains(identifier)
+    }
+
+    /// The number of identifiers in the list
+    pub fn len(&self) -> usize {
+        self.identifiers
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+
+    /// Whether the list is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl RevocationChecker for DenyList {
+    fn is_revoked(&self, macaroon: &Macaroon) -> bool {
+        self.is_revoked_identifier(macaroon.identifier())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DenyList;
+    use crate::{error::MacaroonError, Macaroon, MacaroonKey, VerifierBuilder, VerifyContext};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_deny_list() {
+        let key = MacaroonKey::generate(b"root key");
+        let mut root = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        root.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        root.bind(&mut discharge);
+        let discharges = [discharge];
+
+        let deny_list = Arc::new(DenyList::new());
+        let mut builder = VerifierBuilder::new();
+        builder.set_revocation_checker(deny_list.clone());
+        let verifier = builder.build();
+        assert!(verifier.verify(&root, &key, &discharges).unwrap());
+
+        for identifier in &["root", "bank caveat"] {
+            deny_list.revoke(identifier);
+            assert_eq!(1, deny_list.len());
+            match verifier.authorize(&root, &key, &discharges, &VerifyContext::new()) {
+                Err(MacaroonError::BadMacaroon(reason)) => {
+                    assert_eq!("macaroon has been revoked", reason)
+                }
+                result => panic!("Unexpected result {:?}", result),
+            }
+            deny_list.reinstate(identifier);
+            assert!(deny_list.is_empty());
+            assert!(verifier.verify(&root, &key, &discharges).unwrap());
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Macaroons which can only be attenuated
+//!
+//! A `Macaroon` can have its signature replaced, when it's bound as a discharge. A
+//! `SealedMacaroon` can't: once a macaroon is minted or deserialized as one, the only changes
+//! the type allows are adding caveats, each of which extends the signature chain. So code which
+//! is handed a `SealedMacaroon` can narrow what it authorizes, but can't produce one which no
+//! longer verifies, and this is checked at compile time.
+use crate::{error::MacaroonError, Format, Macaroon, ParseMode};
+use std::ops::Deref;
+
+/// A macaroon which can only have caveats added to it
+///
+/// All of `Macaroon`'s read-only methods are available through `Deref`.
+///
+/// ```compile_fail
+/// # use macaroon::{Macaroon, SealedMacaroon};
+/// let root = Macaroon::create("http://example.org/", b"key", "root").unwrap();
+/// let mut discharge = SealedMacaroon::create("http://auth.mybank/", b"bank key", "id").unwrap();
+/// root.bind(&mut discharge);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SealedMacaroon(Macaroon);
+
+impl SealedMacaroon {
+    /// Mint a macaroon, as `Macaroon::create`
+    pub fn create(location: &str, key: &[u8], identifier: &str) -> Result<Self, MacaroonError> {
+        Ok(SealedMacaroon(Macaroon::create(location, key, identifier)?))
+    }
+
+    /// Deserialize a macaroon, as `Macaroon::deserialize`
+    pub fn deserialize(data: &[u8]) -> Result<Self, MacaroonError> {
+        SealedMacaroon::deserialize_with_mode(data, ParseMode::Lenient)
+    }
+
+    /// Deserialize a macaroon, as `Macaroon::deserialize_with_mode`
+    pub fn deserialize_with_mode(data: &[u8], mode: ParseMode) -> Result<Self, MacaroonError> {
+        Ok(SealedMacaroon(Macaroon::deserialize_with_mode(data, mode)?))
+    }
+
+    /// Add a first-party caveat, as `Macaroon::add_first_party_caveat`
+    pub fn add_first_party_caveat(&mut self, predicate: &str) {
+        self.0.add_first_party_caveat(predicate)
+    }
+
+    /// Add a third-party caveat, as `Macaroon::add_third_party_caveat`
+    pub fn add_third_party_caveat(&mut self, location: &str, key: &[u8], id: &str) {
+        self.0.add_third_party_caveat(location, key, id)
+    }
+
+    /// Serialize the macaroon, as `Macaroon::serialize`
+    pub fn serialize(&self, format: Format) -> Result<Vec<u8>, MacaroonError> {
+        self.0.serialize(format)
+    }
+
+    /// The sealed macaroon
+    pub fn as_macaroon(&self) -> &Macaroon {
+        &self.0
+    }
+
+    /// Unseal the macaroon
+    ///
+    /// This is needed to bind the macaroon as a discharge, which replaces its signature.
+    pub fn into_inner(self) -> Macaroon {
+        self.0
+    }
+}
+
+impl Deref for SealedMacaroon {
+    type Target = Macaroon;
+
+    fn deref(&self) -> &Macaroon {
+        &self.0
+    }
+}
+
+impl From<Macaroon> for SealedMacaroon {
+    fn from(macaroon: Macaroon) -> SealedMacaroon {
+        SealedMacaroon(macaroon)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SealedMacaroon;
+    use crate::{Format, Macaroon, MacaroonKey, Verifier};
+
+    #[test]
+    fn test_sealed_macaroon() {
+        let mut sealed =
+            SealedMacaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        sealed.add_first_party_caveat("account = 3735928559");
+        sealed.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        sealed.bind(&mut discharge);
+        let mut verifier = Verifier::builder();
+        verifier.satisfy_exact("account = 3735928559");
+        let key = MacaroonKey::generate(b"this is the key");
+        assert!(verifier
+            .build()
+            .verify(&sealed, &key, &[discharge])
+            .unwrap());
+
+        let serialized = sealed.serialize(Format::V2).unwrap();
+        let deserialized = SealedMacaroon::deserialize(&serialized).unwrap();
+        assert_eq!(sealed, deserialized);
+        assert_eq!(*sealed.as_macaroon(), deserialized.into_inner());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{error::MacaroonError, serialization::Compression};
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+use std::io::{Read, Write};
+
+// Compressed macaroons start with these bytes, which no other format can start with (0xc1 is
+// the one byte MessagePack never uses), then a byte for the compression method
+pub const MAGIC: &[u8] = b"\xc1m";
+#[cfg(feature = "deflate")]
+const DEFLATE: u8 = b'd';
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = b'z';
+
+// However well it compressed, no legitimate macaroon is anywhere near this large, so anything
+// which decompresses to more is rejected rather than being decompressed in full
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+const MAX_DECOMPRESSED_LEN: u64 = 1 << 20;
+
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// Compress a serialized macaroon, adding the magic prefix
+//
+// With neither compression feature, there are no methods to compress with
+#[cfg_attr(
+    not(any(feature = "deflate", feature = "zstd")),
+    allow(unused_variables)
+)]
+pub fn compress(data: &[u8], method: Compression) -> Result<Vec<u8>, MacaroonError> {
+    match method {
+        #[cfg(feature = "deflate")]
+        Compression::Deflate => {
+            let mut encoder =
+                flate2::write::DeflateEncoder::new(prefix(DEFLATE), flate2::Compression::best());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(prefix(ZSTD), 19)?;
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+    }
+}
+
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+fn prefix(method: u8) -> Vec<u8> {
+    let mut prefix = MAGIC.to_vec();
+    prefix.push(method);
+    prefix
+}
+
+// Decompress a macaroon with the magic prefix, giving its serialization
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, MacaroonError> {
+    let method = match data.get(MAGIC.len()) {
+        Some(&method) => method,
+        None => return Err(MacaroonError::Truncated { offset: data.len() }),
+    };
+    match method {
+        #[cfg(feature = "deflate")]
+        DEFLATE => read_limited(flate2::read::DeflateDecoder::new(&data[MAGIC.len() + 1..])),
+        #[cfg(feature = "zstd")]
+        ZSTD => zstd::stream::read::Decoder::new(&data[MAGIC.len() + 1..])
+            .map_err(|_| decompression_failed())
+            .and_then(read_limited),
+        _ => Err(MacaroonError::Format {
+            offset: MAGIC.len(),
+            expected: "supported compression method",
+            found: format!("byte {:#04x}", method),
+        }),
+    }
+}
+
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+fn read_limited<R: Read>(decoder: R) -> Result<Vec<u8>, MacaroonError> {
+    let mut decompressed = Vec::new();
+    decoder
+        .take(MAX_DECOMPRESSED_LEN + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|_| decompression_failed())?;
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_LEN {
+        return Err(MacaroonError::InvalidField {
+            field: "compressed macaroon",
+            reason: "too large when decompressed",
+        });
+    }
+    Ok(decompressed)
+}
+
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+fn decompression_failed() -> MacaroonError {
+    MacaroonError::InvalidField {
+        field: "compressed macaroon",
+        reason: "can't be decompressed",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Compression, Format, ParseMode, SerializeOptions};
+    use crate::{error::MacaroonError, Macaroon};
+
+    fn methods() -> Vec<Compression> {
+        vec![
+            #[cfg(feature = "deflate")]
+            Compression::Deflate,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ]
+    }
+
+    fn audit_scoped() -> Macaroon {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        for i in 0..40 {
+            macaroon.add_first_party_caveat(&format!(
+                "audit-scope = {{\"resource\": \"projects/{}/logs\", \"actions\": [\"read\", \
+                   \"list\"]}}",
+                i
+            ));
+        }
+        macaroon
+    }
+
+    #[test]
+    fn test_compression() {
+        let macaroon = audit_scoped();
+        let uncompressed = macaroon.serialize(Format::V2).unwrap();
+        assert_eq!(
+            uncompressed,
+            macaroon
+                .serialize_with(&SerializeOptions::new(Format::V2))
+                .unwrap()
+        );
+        for method in methods() {
+            let options = SerializeOptions {
+                compress: Some(method),
+                ..SerializeOptions::new(Format::V2)
+            };
+            let compressed = macaroon.serialize_with(&options).unwrap();
+            assert!(super::is_compressed(&compressed));
+            assert!(compressed.len() < uncompressed.len() / 4, "{:?}", method);
+            assert_eq!(macaroon, Macaroon::deserialize(&compressed).unwrap());
+
+            #[cfg(feature = "rustc-serialize")]
+            {
+                let options = SerializeOptions {
+                    base64: Some(crate::Base64Encoding::URL_SAFE_NO_PAD),
+                    ..options
+                };
+                let text = macaroon.serialize_with(&options).unwrap();
+                assert_eq!(macaroon, Macaroon::deserialize(&text).unwrap());
+            }
+
+            // There's more than one compressed encoding of any macaroon
+            assert!(Macaroon::deserialize_with_mode(&compressed, ParseMode::Strict).is_err());
+
+            let mut corrupt = compressed.clone();
+            corrupt.truncate(compressed.len() / 2);
+            match Macaroon::deserialize(&corrupt) {
+                Err(MacaroonError::InvalidField { reason, .. }) => {
+                    assert_eq!("can't be decompressed", reason)
+                }
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decompression_errors() {
+        match Macaroon::deserialize(b"\xc1m") {
+            Err(MacaroonError::Truncated { offset }) => assert_eq!(2, offset),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match Macaroon::deserialize(b"\xc1mx") {
+            Err(MacaroonError::Format { offset, .. }) => assert_eq!(2, offset),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // Nor can a compressed macaroon hold another
+        for method in methods() {
+            let compressed = super::compress(b"\xc1md", method).unwrap();
+            assert!(Macaroon::deserialize(&compressed).is_err());
+            let bomb = super::compress(&vec![0; 2 << 20], method).unwrap();
+            match Macaroon::deserialize(&bomb) {
+                Err(MacaroonError::InvalidField { reason, .. }) => {
+                    assert_eq!("too large when decompressed", reason)
+                }
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+    }
+}
@@ -1,11 +1,19 @@
-use crate::{caveat::Caveat, error::MacaroonError, Macaroon};
+use crate::{
+    caveat::{Caveat, Caveats},
+    crypto::Signature,
+    error::MacaroonError,
+    serialization::UnknownField,
+    Macaroon,
+};
+use std::convert::TryFrom;
 
 #[derive(Default)]
 pub struct MacaroonBuilder {
     identifier: String,
     location: Option<String>,
-    signature: [u8; 32],
-    caveats: Vec<Box<dyn Caveat>>,
+    signature: Option<Signature>,
+    caveats: Caveats,
+    unknown_fields: Vec<UnknownField>,
 }
 
 impl MacaroonBuilder {
@@ -21,31 +29,35 @@ impl MacaroonBuilder {
         self.location = Some((*location).to_string());
     }
 
-    pub fn has_location(&self) -> bool {
-        self.location.is_some()
-    }
-
-    pub fn set_signature(&mut self, signature: &[u8]) {
-        self.signature.clone_from_slice(signature);
+    pub fn set_signature(&mut self, signature: &[u8]) -> Result<(), MacaroonError> {
+        self.signature = Some(Signature::try_from(signature)?);
+        Ok(())
     }
 
     pub fn add_caveat(&mut self, caveat: Box<dyn Caveat>) {
         self.caveats.push(caveat);
     }
 
+    pub fn add_unknown_field(&mut self, field: UnknownField) {
+        self.unknown_fields.push(field);
+    }
+
     pub fn build(&self) -> Result<Macaroon, MacaroonError> {
         if self.identifier.is_empty() {
-            return Err(MacaroonError::BadMacaroon("No identifier found"));
-        }
-        if self.signature.is_empty() {
-            return Err(MacaroonError::BadMacaroon("No signature found"));
+            return Err(MacaroonError::MissingField("identifier"));
         }
+        let signature = match self.signature {
+            Some(signature) => signature,
+            None => return Err(MacaroonError::MissingField("signature")),
+        };
 
         Ok(Macaroon {
             identifier: self.identifier.clone(),
             location: self.location.clone(),
-            signature: self.signature,
+            signature,
             caveats: self.caveats.clone(),
+            unknown_fields: self.unknown_fields.clone(),
+            unknown_json_fields: Vec::new(),
         })
     }
 }
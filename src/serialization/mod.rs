@@ -1,10 +1,314 @@
+#[cfg(feature = "rustc-serialize")]
+use crate::error::base64_field;
+use crate::{error::MacaroonError, redaction::Secret};
+#[cfg(feature = "rustc-serialize")]
+use rustc_serialize::base64::{self, Config, ToBase64};
+use std::fmt;
+
+pub mod compression;
 pub mod macaroon_builder;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+#[cfg(feature = "v1")]
 pub mod v1;
 pub mod v2;
+#[cfg(feature = "v2j")]
 pub mod v2j;
 
+/// Serialization formats
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Format {
+    #[cfg(feature = "v1")]
     V1,
     V2,
+    #[cfg(feature = "v2j")]
     V2J,
+    /// The version 2J fields, encoded as MessagePack rather than JSON, with binary fields
+    /// as MessagePack binary
+    #[cfg(feature = "msgpack")]
+    V2MsgPack,
+}
+
+/// Methods of compressing serialized macaroons
+///
+/// Each method needs the feature of the same name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    /// Raw deflate (RFC 1951)
+    #[cfg(feature = "deflate")]
+    Deflate,
+    /// Zstandard
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Base64 alphabets
+#[cfg(feature = "rustc-serialize")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Base64Alphabet {
+    /// The standard alphabet, with `+` and `/` (RFC 4648 section 4)
+    Standard,
+    /// The URL and filename safe alphabet, with `-` and `_` (RFC 4648 section 5)
+    UrlSafe,
+}
+
+/// A base64 encoding, for serialized macaroons which need to be text
+#[cfg(feature = "rustc-serialize")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Base64Encoding {
+    /// The alphabet to encode with
+    pub alphabet: Base64Alphabet,
+    /// Whether to pad the encoding with `=`
+    pub pad: bool,
+}
+
+#[cfg(feature = "rustc-serialize")]
+impl Base64Encoding {
+    /// The standard alphabet, with padding (as `Format::V1` is encoded by default)
+    pub const STANDARD: Base64Encoding = Base64Encoding {
+        alphabet: Base64Alphabet::Standard,
+        pad: true,
+    };
+    /// The URL-safe alphabet, without padding (as libmacaroons encodes macaroons)
+    pub const URL_SAFE_NO_PAD: Base64Encoding = Base64Encoding {
+        alphabet: Base64Alphabet::UrlSafe,
+        pad: false,
+    };
+
+    pub(crate) fn config(self) -> Config {
+        let config = match self.alphabet {
+            Base64Alphabet::Standard => base64::STANDARD,
+            Base64Alphabet::UrlSafe => base64::URL_SAFE,
+        };
+        Config {
+            pad: self.pad,
+            ..config
+        }
+    }
+}
+
+/// How to serialize a macaroon, for `Macaroon::serialize_with`
+///
+/// The macaroon is serialized in the format, then compressed, then encoded as base64 text, each
+/// of the last two only if asked for. Version 2 macaroons, say, can be stored as raw bytes in a
+/// binary column, or as base64 text in a header.
+///
+/// Compressed macaroons start with a prefix which no other format can start with, so they're
+/// recognized by `Macaroon::deserialize` like any other. Compression is only worth it for
+/// macaroons carrying many long caveats, and since the result is binary, it needs encoding as
+/// text for use in a header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SerializeOptions {
+    /// The format to serialize in
+    pub format: Format,
+    /// How to compress the serialization, if at all
+    pub compress: Option<Compression>,
+    /// How to encode the result as base64 text, if at all
+    ///
+    /// Version 1 macaroons are base64 text already, so without compression, this sets the
+    /// alphabet and padding they're encoded with, rather than encoding them twice.
+    #[cfg(feature = "rustc-serialize")]
+    pub base64: Option<Base64Encoding>,
+}
+
+impl SerializeOptions {
+    /// Serialize in the given format, without compression or base64 encoding
+    pub fn new(format: Format) -> SerializeOptions {
+        SerializeOptions {
+            format,
+            compress: None,
+            #[cfg(feature = "rustc-serialize")]
+            base64: None,
+        }
+    }
+}
+
+impl Default for SerializeOptions {
+    fn default() -> SerializeOptions {
+        SerializeOptions::new(Format::V2)
+    }
+}
+
+/// A field of a version 2 macaroon which this crate doesn't know
+///
+/// Version 2 fields are tagged and length-prefixed, so parsers can skip fields added to the
+/// format after they were written. `Macaroon::deserialize` keeps them (except in strict mode,
+/// which rejects them), and version 2 serialization writes them back among the known fields by
+/// tag, so a macaroon passed through this crate doesn't lose them. The other formats have
+/// nowhere to put them, and leave them out.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct UnknownField {
+    /// The index of the caveat whose section the field is in, or `None` if it's in the
+    /// macaroon's own
+    pub caveat: Option<usize>,
+    /// The field's tag
+    pub tag: u8,
+    /// The field's value
+    pub value: Vec<u8>,
+}
+
+impl fmt::Debug for UnknownField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnknownField")
+            .field("caveat", &self.caveat)
+            .field("tag", &self.tag)
+            .field("value", &Secret(&self.value))
+            .finish()
+    }
+}
+
+/// A field of a version 2J macaroon which isn't in the format
+///
+/// Like `UnknownField`, these are kept by `Macaroon::deserialize` (except in strict mode) and
+/// written back by version 2J serialization, after the known fields of the object they were in,
+/// in the order they were read. Only `Format::V2J` writes them, not the canonical form.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct UnknownJsonField {
+    /// The index of the caveat whose object the field is in, or `None` if it's in the
+    /// macaroon's own
+    pub caveat: Option<usize>,
+    /// The field's name
+    pub key: String,
+    /// The field's value, as JSON text
+    pub value: String,
+}
+
+impl fmt::Debug for UnknownJsonField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnknownJsonField")
+            .field("caveat", &self.caveat)
+            .field("key", &self.key)
+            .field("value", &Secret(self.value.as_bytes()))
+            .finish()
+    }
+}
+
+/// How strictly serialized macaroons are checked when they are deserialized
+///
+/// The lenient mode accepts anything which can be understood as a macaroon, so the same
+/// macaroon may have any number of accepted encodings. The strict mode only accepts a fixed set
+/// of them, those this crate and libmacaroons write:
+///
+/// - version 2 and MessagePack macaroons must be exactly as this crate would serialize them
+///   (one encoding each), which rules out trailing bytes, duplicate, out-of-order or unknown
+///   fields, and non-minimal lengths
+/// - version 1 macaroons must be base64 of exactly the packets this crate would write, in
+///   either alphabet, with or without padding (up to four encodings)
+/// - version 2J macaroons must be exactly as `Macaroon::canonical_bytes` (as libmacaroons) or
+///   `Format::V2J` would serialize them (up to two encodings), which rules out whitespace,
+///   unknown fields, other field orders, and base64 (`i64`, `l64`) where text would do
+/// - macaroons in the other formats encoded as base64 text must also be in either alphabet,
+///   with or without padding, so each of the encodings above may be wrapped four ways
+/// - compressed macaroons aren't accepted at all, since compressors differ in their output
+///
+/// So even in strict mode, a macaroon may be accepted in several encodings, within a format
+/// and across formats. Services which need one value per macaroon (as a cache key, say) should
+/// use `Macaroon::canonical_bytes` of the deserialized macaroon rather than the token itself.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ParseMode {
+    /// Accept any encoding which can be understood (the default)
+    #[default]
+    Lenient,
+    /// Only accept the encodings listed above
+    Strict,
+}
+
+/// Options for `Macaroon::deserialize_with`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeserializeOptions {
+    /// How strictly the serialization is checked
+    pub mode: ParseMode,
+    /// The most caveats the macaroon may have, if there's a limit
+    ///
+    /// Macaroons with more are rejected before their caveats are copied out (see the `limits`
+    /// module).
+    pub max_caveats: Option<usize>,
+}
+
+impl DeserializeOptions {
+    /// Deserialize in the given mode, with no limit on caveats
+    pub fn new(mode: ParseMode) -> DeserializeOptions {
+        DeserializeOptions {
+            mode,
+            max_caveats: None,
+        }
+    }
+}
+
+impl From<ParseMode> for DeserializeOptions {
+    fn from(mode: ParseMode) -> DeserializeOptions {
+        DeserializeOptions::new(mode)
+    }
+}
+
+// Check that the data is the canonical serialization, given what that is
+pub(crate) fn check_canonical(data: &[u8], canonical: &[u8]) -> Result<(), MacaroonError> {
+    if data == canonical {
+        return Ok(());
+    }
+    match data
+        .iter()
+        .zip(canonical)
+        .position(|(byte, expected)| byte != expected)
+    {
+        Some(offset) => Err(MacaroonError::Format {
+            offset,
+            expected: "canonical encoding",
+            found: format!("byte {:#04x}", data[offset]),
+        }),
+        None if data.len() > canonical.len() => Err(MacaroonError::Format {
+            offset: canonical.len(),
+            expected: "end of data",
+            found: format!("{} trailing bytes", data.len() - canonical.len()),
+        }),
+        None => Err(MacaroonError::Truncated { offset: data.len() }),
+    }
+}
+
+// Both alphabets are in use (libmacaroons uses the URL-safe one, without padding), but within
+// an alphabet there's only one canonical encoding
+#[cfg(feature = "rustc-serialize")]
+pub(crate) fn check_canonical_base64(text: &[u8], data: &[u8]) -> Result<(), MacaroonError> {
+    let canonical = [base64::STANDARD, base64::URL_SAFE].iter().any(|&config| {
+        [true, false].iter().any(|&pad| {
+            let config = Config { pad, ..config };
+            data.to_base64(config).as_bytes() == text
+        })
+    });
+    match canonical {
+        true => Ok(()),
+        false => Err(MacaroonError::Format {
+            offset: 0,
+            expected: "canonical base64",
+            found: String::from("non-canonical base64"),
+        }),
+    }
+}
+
+// Decode a macaroon in one of the binary formats (or version 2J) which has been encoded as
+// base64 text, such as a version 2 macaroon starting "Ag". Returns `None` for anything else,
+// including version 1 macaroons, which are base64 text themselves but decode to packets
+// starting with hex digits.
+#[cfg(feature = "rustc-serialize")]
+pub(crate) fn decode_base64_wrapped(
+    text: &[u8],
+    mode: ParseMode,
+) -> Result<Option<Vec<u8>>, MacaroonError> {
+    let decoded = match base64_field("macaroon", text) {
+        Ok(decoded) => decoded,
+        Err(_) => return Ok(None),
+    };
+    let wrapped = match decoded.first() {
+        Some(0x02) | Some(b'{') => true,
+        #[cfg(feature = "msgpack")]
+        Some(&first) if msgpack::is_msgpack(first) => true,
+        _ => compression::is_compressed(&decoded),
+    };
+    if !wrapped {
+        return Ok(None);
+    }
+    if mode == ParseMode::Strict {
+        check_canonical_base64(text, &decoded)?;
+    }
+    Ok(Some(decoded))
 }
@@ -0,0 +1,191 @@
+use crate::{
+    caveat::{CaveatBuilder, CaveatType},
+    error::MacaroonError,
+    limits,
+    serialization::{
+        check_canonical, macaroon_builder::MacaroonBuilder, v2j::LenCounter, DeserializeOptions,
+        ParseMode,
+    },
+    Macaroon,
+};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+// The version 2J field names, with the signature and verifier ids as MessagePack binary rather
+// than base64. Fields are borrowed from the macaroon when serializing, and from the input where
+// possible when deserializing.
+#[derive(Debug, Deserialize, Serialize)]
+struct CaveatMsgPack<'a> {
+    i: Cow<'a, str>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    l: Option<Cow<'a, str>>,
+    #[serde(
+        borrow,
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_bytes"
+    )]
+    v: Option<Cow<'a, [u8]>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct MsgPackSerialization<'a> {
+    v: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    l: Option<Cow<'a, str>>,
+    i: Cow<'a, str>,
+    c: Vec<CaveatMsgPack<'a>>,
+    #[serde(borrow, with = "serde_bytes")]
+    s: Cow<'a, [u8]>,
+}
+
+impl<'a> MsgPackSerialization<'a> {
+    fn from_macaroon(macaroon: &'a Macaroon) -> MsgPackSerialization<'a> {
+        let caveats = macaroon
+            .caveats()
+            .iter()
+            .map(|caveat| match caveat.get_type() {
+                CaveatType::FirstParty => CaveatMsgPack {
+                    i: Cow::Borrowed(&caveat.as_first_party().unwrap().predicate),
+                    l: None,
+                    v: None,
+                },
+                CaveatType::ThirdParty => {
+                    let third_party = caveat.as_third_party().unwrap();
+                    CaveatMsgPack {
+                        i: Cow::Borrowed(&third_party.id),
+                        l: Some(Cow::Borrowed(&third_party.location)),
+                        v: Some(Cow::Borrowed(&third_party.verifier_id)),
+                    }
+                }
+            })
+            .collect();
+        MsgPackSerialization {
+            v: 2,
+            l: macaroon.location.as_deref().map(Cow::Borrowed),
+            i: Cow::Borrowed(&macaroon.identifier),
+            c: caveats,
+            s: Cow::Borrowed(macaroon.signature().as_bytes()),
+        }
+    }
+}
+
+impl Macaroon {
+    fn from_msgpack(ser: MsgPackSerialization) -> Result<Macaroon, MacaroonError> {
+        let mut builder: MacaroonBuilder = MacaroonBuilder::new();
+        builder.set_identifier(&ser.i);
+        if let Some(location) = ser.l {
+            builder.set_location(&location);
+        }
+        builder.set_signature(&ser.s)?;
+        for c in ser.c {
+            let mut caveat_builder: CaveatBuilder = CaveatBuilder::new();
+            caveat_builder.add_id(c.i.into_owned());
+            if let Some(location) = c.l {
+                caveat_builder.add_location(location.into_owned());
+            }
+            if let Some(vid) = c.v {
+                caveat_builder.add_verifier_id(vid.into_owned());
+            }
+            builder.add_caveat(caveat_builder.build()?);
+        }
+        builder.build()
+    }
+}
+
+// The output is always the same for the same macaroon, so this is also the canonical form
+pub fn serialize_msgpack(macaroon: &Macaroon) -> Result<Vec<u8>, MacaroonError> {
+    Ok(rmp_serde::to_vec_named(
+        &MsgPackSerialization::from_macaroon(macaroon),
+    )?)
+}
+
+// The length of `serialize_msgpack`'s output, without keeping it
+pub fn serialized_len_msgpack(macaroon: &Macaroon) -> Result<usize, MacaroonError> {
+    let mut counter = LenCounter(0);
+    rmp_serde::encode::write_named(&mut counter, &MsgPackSerialization::from_macaroon(macaroon))?;
+    Ok(counter.0)
+}
+
+// Whether the data starts with a MessagePack map header (a fixmap, map 16 or map 32)
+pub fn is_msgpack(first: u8) -> bool {
+    matches!(first, 0x80..=0x8f | 0xde | 0xdf)
+}
+
+pub fn deserialize_msgpack(
+    data: &[u8],
+    options: impl Into<DeserializeOptions>,
+) -> Result<Macaroon, MacaroonError> {
+    let DeserializeOptions { mode, max_caveats } = options.into();
+    let ser: MsgPackSerialization = rmp_serde::from_slice(data)?;
+    limits::check_caveat_count(ser.c.len(), max_caveats)?;
+    let macaroon = Macaroon::from_msgpack(ser)?;
+    // Rules out unknown fields, other versions, other field orders and non-minimal encodings
+    if mode == ParseMode::Strict {
+        check_canonical(data, &serialize_msgpack(&macaroon)?)?;
+    }
+    Ok(macaroon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Format, ParseMode};
+    use crate::{error::MacaroonError, Macaroon};
+
+    const SIGNATURE_V2: [u8; 32] = [
+        75, 233, 103, 205, 30, 160, 198, 178, 107, 175, 106, 74, 148, 238, 155, 5, 177, 88, 134,
+        218, 11, 168, 94, 140, 66, 169, 60, 141, 14, 18, 94, 252,
+    ];
+
+    fn macaroon() -> Macaroon {
+        let mut macaroon = Macaroon::create("http://example.org/", &SIGNATURE_V2, "keyid").unwrap();
+        macaroon.add_first_party_caveat("user = alice");
+        macaroon.add_third_party_caveat("https://auth.mybank.com/", b"my key", "keyid");
+        macaroon
+    }
+
+    #[test]
+    fn test_serialize_deserialize_msgpack() {
+        let macaroon = macaroon();
+        let serialized = macaroon.serialize(Format::V2MsgPack).unwrap();
+        assert_eq!(0x85, serialized[0]);
+        assert_eq!(
+            macaroon,
+            super::deserialize_msgpack(&serialized, ParseMode::Strict).unwrap()
+        );
+        assert_eq!(macaroon, Macaroon::deserialize(&serialized).unwrap());
+        assert_eq!(
+            serialized.len(),
+            macaroon.serialized_len(Format::V2MsgPack).unwrap()
+        );
+
+        // Smaller than the JSON, even before that's base64-encoded
+        assert!(serialized.len() < macaroon.serialize(Format::V2J).unwrap().len());
+    }
+
+    #[test]
+    fn test_deserialize_msgpack_fields() {
+        let macaroon = Macaroon::create("http://example.org/", &SIGNATURE_V2, "keyid").unwrap();
+        // {"v": 2, "i": "keyid", "c": [], "s": <signature>, "x": 1}, with no location and an
+        // unknown field
+        let mut data = vec![0x85, 0xa1, b'v', 0x02, 0xa1, b'i', 0xa5];
+        data.extend_from_slice(b"keyid");
+        data.extend_from_slice(&[0xa1, b'c', 0x90, 0xa1, b's', 0xc4, 0x20]);
+        data.extend_from_slice(macaroon.signature().as_bytes());
+        data.extend_from_slice(&[0xa1, b'x', 0x01]);
+
+        let deserialized = super::deserialize_msgpack(&data, ParseMode::Lenient).unwrap();
+        assert_eq!(None, deserialized.location());
+        assert_eq!("keyid", deserialized.identifier());
+        assert_eq!(macaroon.signature(), deserialized.signature());
+        assert!(matches!(
+            super::deserialize_msgpack(&data, ParseMode::Strict),
+            Err(MacaroonError::Format { offset: 0, .. })
+        ));
+
+        // Without the signature
+        data.truncate(15);
+        data[0] = 0x83;
+        assert!(super::deserialize_msgpack(&data, ParseMode::Lenient).is_err());
+    }
+}
@@ -1,10 +1,14 @@
 use crate::{
     caveat::{CaveatBuilder, CaveatType},
-    error::MacaroonError,
-    serialization::macaroon_builder::MacaroonBuilder,
+    error::{base64_field, utf8_field, MacaroonError},
+    limits,
+    serialization::{
+        check_canonical, check_canonical_base64, macaroon_builder::MacaroonBuilder,
+        DeserializeOptions, ParseMode,
+    },
     Macaroon,
 };
-use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::base64::{Config, ToBase64, STANDARD, URL_SAFE};
 use std::str;
 
 // Version 1 fields
@@ -17,16 +21,33 @@ const CL: &str = "cl";
 
 const HEADER_SIZE: usize = 4;
 
-fn serialize_as_packet<'r>(tag: &'r str, value: &'r [u8]) -> Vec<u8> {
+// The packet length is 4 hex digits, and includes the header itself
+const MAX_PACKET_SIZE: usize = 0xffff;
+
+// As with libmacaroons, a field too large to fit in a packet is an error, since the format has
+// no way of splitting one across packets
+fn serialize_as_packet(tag: &'static str, value: &[u8]) -> Result<Vec<u8>, MacaroonError> {
+    let size = packet_len(tag, value)?;
     let mut packet: Vec<u8> = Vec::new();
-    let size = HEADER_SIZE + 2 + tag.len() + value.len();
     packet.extend(packet_header(size));
     packet.extend_from_slice(tag.as_bytes());
     packet.extend_from_slice(b" ");
     packet.extend_from_slice(value);
     packet.extend_from_slice(b"\n");
 
-    packet
+    Ok(packet)
+}
+
+// The length of a packet, checked as `serialize_as_packet` does
+fn packet_len(tag: &'static str, value: &[u8]) -> Result<usize, MacaroonError> {
+    let size = HEADER_SIZE + 2 + tag.len() + value.len();
+    if size > MAX_PACKET_SIZE {
+        return Err(MacaroonError::InvalidField {
+            field: tag,
+            reason: "too large for a version 1 packet",
+        });
+    }
+    Ok(size)
 }
 
 fn to_hex_char(value: u8) -> u8 {
@@ -35,137 +56,231 @@ fn to_hex_char(value: u8) -> u8 {
 }
 
 fn packet_header(size: usize) -> Vec<u8> {
-    let mut header: Vec<u8> = Vec::new();
-    header.push(to_hex_char(((size >> 12) & 15) as u8));
-    header.push(to_hex_char(((size >> 8) & 15) as u8));
-    header.push(to_hex_char(((size >> 4) & 15) as u8));
-    header.push(to_hex_char((size & 15) as u8));
-
-    header
+    vec![
+        to_hex_char(((size >> 12) & 15) as u8),
+        to_hex_char(((size >> 8) & 15) as u8),
+        to_hex_char(((size >> 4) & 15) as u8),
+        to_hex_char((size & 15) as u8),
+    ]
 }
 
 pub fn serialize_v1(macaroon: &Macaroon) -> Result<Vec<u8>, MacaroonError> {
+    serialize_v1_with(macaroon, STANDARD)
+}
+
+// Serialize with another base64 alphabet or padding
+pub fn serialize_v1_with(macaroon: &Macaroon, base64: Config) -> Result<Vec<u8>, MacaroonError> {
+    Ok(serialize_packets(macaroon)?
+        .to_base64(base64)
+        .as_bytes()
+        .to_vec())
+}
+
+fn serialize_packets(macaroon: &Macaroon) -> Result<Vec<u8>, MacaroonError> {
     let mut serialized: Vec<u8> = Vec::new();
-    if let Some(ref location) = macaroon.location() {
-        serialized.extend(serialize_as_packet(LOCATION, location.as_bytes()));
+    if let Some(location) = &macaroon.location {
+        serialized.extend(serialize_as_packet(LOCATION, location.as_bytes())?);
     };
     serialized.extend(serialize_as_packet(
         IDENTIFIER,
         macaroon.identifier().as_bytes(),
-    ));
+    )?);
     for caveat in macaroon.caveats() {
         match caveat.get_type() {
             CaveatType::FirstParty => {
                 let first_party = caveat.as_first_party().unwrap();
-                serialized.extend(serialize_as_packet(CID, first_party.predicate().as_bytes()));
+                serialized.extend(serialize_as_packet(CID, first_party.predicate.as_bytes())?);
             }
             CaveatType::ThirdParty => {
                 let third_party = caveat.as_third_party().unwrap();
-                serialized.extend(serialize_as_packet(CID, third_party.id().as_bytes()));
-                serialized.extend(serialize_as_packet(
-                    VID,
-                    third_party.verifier_id().as_slice(),
-                ));
-                serialized.extend(serialize_as_packet(CL, third_party.location().as_bytes()))
+                serialized.extend(serialize_as_packet(CID, third_party.id.as_bytes())?);
+                serialized.extend(serialize_as_packet(VID, &third_party.verifier_id)?);
+                serialized.extend(serialize_as_packet(CL, third_party.location.as_bytes())?)
             }
         }
     }
-    serialized.extend(serialize_as_packet(SIGNATURE, macaroon.signature()));
-    Ok(serialized.to_base64(STANDARD).as_bytes().to_vec())
+    serialized.extend(serialize_as_packet(
+        SIGNATURE,
+        macaroon.signature().as_ref(),
+    )?);
+    Ok(serialized)
+}
+
+// The length of `serialize_v1`'s output, without serializing the macaroon
+pub fn serialized_len_v1(macaroon: &Macaroon) -> Result<usize, MacaroonError> {
+    let mut len = 0;
+    if let Some(location) = &macaroon.location {
+        len += packet_len(LOCATION, location.as_bytes())?;
+    }
+    len += packet_len(IDENTIFIER, macaroon.identifier().as_bytes())?;
+    for caveat in macaroon.caveats() {
+        match caveat.get_type() {
+            CaveatType::FirstParty => {
+                let first_party = caveat.as_first_party().unwrap();
+                len += packet_len(CID, first_party.predicate.as_bytes())?;
+            }
+            CaveatType::ThirdParty => {
+                let third_party = caveat.as_third_party().unwrap();
+                len += packet_len(CID, third_party.id.as_bytes())?;
+                len += packet_len(VID, &third_party.verifier_id)?;
+                len += packet_len(CL, third_party.location.as_bytes())?;
+            }
+        }
+    }
+    len += packet_len(SIGNATURE, macaroon.signature().as_ref())?;
+    // Padded base64
+    Ok(len.div_ceil(3) * 4)
+}
+
+pub fn serialize_v1_canonical(macaroon: &Macaroon) -> Result<Vec<u8>, MacaroonError> {
+    let base64 = Config {
+        pad: false,
+        ..URL_SAFE
+    };
+    serialize_v1_with(macaroon, base64)
 }
 
 fn base64_decode(base64: &str) -> Result<Vec<u8>, MacaroonError> {
-    Ok(base64.from_base64()?)
+    base64_field("macaroon", base64)
 }
 
 struct Packet {
+    offset: usize,
     key: String,
     value: Vec<u8>,
 }
 
 fn deserialize_as_packets(
-    data: &[u8],
+    mut data: &[u8],
+    mut offset: usize,
     mut packets: Vec<Packet>,
 ) -> Result<Vec<Packet>, MacaroonError> {
-    if data.is_empty() {
-        return Ok(packets);
-    }
-    let hex: &str = str::from_utf8(&data[..4])?;
-    let size: usize = usize::from_str_radix(hex, 16)?;
-    let packet_data = &data[4..size];
-    let index = split_index(packet_data)?;
-    let (key_slice, value_slice) = packet_data.split_at(index);
-    packets.push(Packet {
-        key: String::from_utf8(key_slice.to_vec())?,
+    while !data.is_empty() {
+        let truncated = MacaroonError::Truncated {
+            offset: offset + data.len(),
+        };
+        let header = data.get(..HEADER_SIZE).ok_or(truncated)?;
+        let size = packet_size(header, offset)?;
+        if size <= HEADER_SIZE {
+            return Err(MacaroonError::Format {
+                offset,
+                expected: "packet length",
+                found: size.to_string(),
+            });
+        }
+        let packet_data = data
+            .get(HEADER_SIZE..size)
+            .ok_or(MacaroonError::Truncated {
+                offset: offset + data.len(),
+            })?;
+        let index = split_index(packet_data, offset + HEADER_SIZE)?;
+        let (key_slice, value_slice) = packet_data.split_at(index);
         // skip beginning space and terminating \n
-        value: value_slice[1..value_slice.len() - 1].to_vec(),
-    });
-    deserialize_as_packets(&data[size..], packets)
+        let value = match value_slice[1..].strip_suffix(b"\n") {
+            Some(value) => value,
+            None => {
+                return Err(MacaroonError::Format {
+                    offset: offset + size - 1,
+                    expected: "packet terminator",
+                    found: format!("{:?}", value_slice.last().map(|&byte| byte as char)),
+                })
+            }
+        };
+        packets.push(Packet {
+            offset,
+            key: utf8_field("packet key", key_slice.to_vec())?,
+            value: value.to_vec(),
+        });
+        data = &data[size..];
+        offset += size;
+    }
+    Ok(packets)
+}
+
+fn packet_size(header: &[u8], offset: usize) -> Result<usize, MacaroonError> {
+    let invalid = || MacaroonError::Format {
+        offset,
+        expected: "packet length",
+        found: String::from_utf8_lossy(header).into_owned(),
+    };
+    let hex = str::from_utf8(header).map_err(|_| invalid())?;
+    usize::from_str_radix(hex, 16).map_err(|_| invalid())
 }
 
-fn split_index(packet: &[u8]) -> Result<usize, MacaroonError> {
+fn split_index(packet: &[u8], offset: usize) -> Result<usize, MacaroonError> {
     match packet.iter().position(|&r| r == b' ') {
         Some(index) => Ok(index),
-        None => Err(MacaroonError::DeserializationError(String::from(
-            "Key/value error",
-        ))),
+        None => Err(MacaroonError::Format {
+            offset,
+            expected: "packet key and value",
+            found: String::from("no separator"),
+        }),
     }
 }
 
-pub fn deserialize_v1(base64: &[u8]) -> Result<Macaroon, MacaroonError> {
-    let data = base64_decode(&String::from_utf8(base64.to_vec())?)?;
+pub fn deserialize_v1(
+    base64: &[u8],
+    options: impl Into<DeserializeOptions>,
+) -> Result<Macaroon, MacaroonError> {
+    let DeserializeOptions { mode, max_caveats } = options.into();
+    let base64 = utf8_field("macaroon", base64.to_vec())?;
+    let data = base64_decode(&base64)?;
+    if mode == ParseMode::Strict {
+        check_canonical_base64(base64.as_bytes(), &data)?;
+    }
     let mut builder: MacaroonBuilder = MacaroonBuilder::new();
     let mut caveat_builder: CaveatBuilder = CaveatBuilder::new();
-    for packet in deserialize_as_packets(data.as_slice(), Vec::new())? {
+    let packets = deserialize_as_packets(data.as_slice(), 0, Vec::new())?;
+    let caveats = packets.iter().filter(|packet| packet.key == CID).count();
+    limits::check_caveat_count(caveats, max_caveats)?;
+    for packet in packets {
         match packet.key.as_str() {
             LOCATION => {
-                builder.set_location(&String::from_utf8(packet.value)?);
+                builder.set_location(&utf8_field(LOCATION, packet.value)?);
             }
             IDENTIFIER => {
-                builder.set_identifier(&String::from_utf8(packet.value)?);
+                builder.set_identifier(&utf8_field(IDENTIFIER, packet.value)?);
             }
             SIGNATURE => {
                 if caveat_builder.has_id() {
                     builder.add_caveat(caveat_builder.build()?);
                     caveat_builder = CaveatBuilder::new();
                 }
-                if packet.value.len() != 32 {
-                    error!(
-                        "deserialize_v1: Deserialization error - signature length is {}",
-                        packet.value.len()
-                    );
-                    return Err(MacaroonError::DeserializationError(String::from(
-                        "Illegal signature length in packet",
-                    )));
-                }
-                builder.set_signature(&packet.value);
+                builder.set_signature(&packet.value)?;
             }
             CID => {
                 if caveat_builder.has_id() {
                     builder.add_caveat(caveat_builder.build()?);
                     caveat_builder = CaveatBuilder::new();
-                    caveat_builder.add_id(String::from_utf8(packet.value)?);
+                    caveat_builder.add_id(utf8_field(CID, packet.value)?);
                 } else {
-                    caveat_builder.add_id(String::from_utf8(packet.value)?);
+                    caveat_builder.add_id(utf8_field(CID, packet.value)?);
                 }
             }
             VID => {
                 caveat_builder.add_verifier_id(packet.value);
             }
-            CL => caveat_builder.add_location(String::from_utf8(packet.value)?),
-            _ => {
-                return Err(MacaroonError::DeserializationError(String::from(
-                    "Unknown key",
-                )))
+            CL => caveat_builder.add_location(utf8_field(CL, packet.value)?),
+            key => {
+                return Err(MacaroonError::Format {
+                    offset: packet.offset + HEADER_SIZE,
+                    expected: "packet key",
+                    found: String::from(key),
+                })
             }
         };
     }
-    Ok(builder.build()?)
+    let macaroon = builder.build()?;
+    if mode == ParseMode::Strict {
+        check_canonical(&data, &serialize_packets(&macaroon)?)?;
+    }
+    Ok(macaroon)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Macaroon;
+    use crate::{error::MacaroonError, serialization::ParseMode, Macaroon, MacaroonKey};
+    use rustc_serialize::base64::{ToBase64, URL_SAFE};
 
     #[test]
     fn test_deserialize_v1() {
@@ -174,17 +289,17 @@ mod tests {
             124, 222, 231, 146, 81, 28, 91, 198, 245, 40, 72, 88, 5, 223, 233, 178, 78, 120, 94,
             40, 226, 169, 147, 1, 249, 215, 17, 198, 9, 227, 142, 247,
         ];
-        let macaroon = super::deserialize_v1(&serialized.as_bytes().to_vec()).unwrap();
+        let macaroon = super::deserialize_v1(serialized.as_bytes(), ParseMode::Lenient).unwrap();
         assert!(macaroon.location().is_some());
         assert_eq!("http://example.org/", &macaroon.location().unwrap());
         assert_eq!("keyid", macaroon.identifier());
-        assert_eq!(signature.to_vec(), macaroon.signature());
+        assert_eq!(&signature, macaroon.signature().as_bytes());
         serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDJmc2lnbmF0dXJlIPVIB_bcbt-Ivw9zBrOCJWKjYlM9v3M5umF2XaS9JZ2HCg";
         signature = [
             245, 72, 7, 246, 220, 110, 223, 136, 191, 15, 115, 6, 179, 130, 37, 98, 163, 98, 83,
             61, 191, 115, 57, 186, 97, 118, 93, 164, 189, 37, 157, 135,
         ];
-        let macaroon = super::deserialize_v1(&serialized.as_bytes().to_vec()).unwrap();
+        let macaroon = super::deserialize_v1(serialized.as_bytes(), ParseMode::Lenient).unwrap();
         assert!(macaroon.location().is_some());
         assert_eq!("http://example.org/", &macaroon.location().unwrap());
         assert_eq!("keyid", macaroon.identifier());
@@ -193,7 +308,145 @@ mod tests {
             "account = 3735928559",
             macaroon.caveats()[0].as_first_party().unwrap().predicate()
         );
-        assert_eq!(signature.to_vec(), macaroon.signature());
+        assert_eq!(&signature, macaroon.signature().as_bytes());
+    }
+
+    #[test]
+    fn test_deserialize_v1_errors() {
+        let serialized =
+            super::serialize_v1(&Macaroon::create("http://example.org/", b"key", "keyid").unwrap())
+                .unwrap();
+        let data = super::base64_decode(std::str::from_utf8(&serialized).unwrap()).unwrap();
+        match super::deserialize_as_packets(&data[..data.len() - 4], 0, Vec::new()) {
+            Err(MacaroonError::Truncated { offset }) => assert_eq!(data.len() - 4, offset),
+            result => panic!("Unexpected result {:?}", result.map(|p| p.len())),
+        }
+        match super::deserialize_as_packets(b"zzzzkey value\n", 0, Vec::new()) {
+            Err(MacaroonError::Format {
+                offset, expected, ..
+            }) => {
+                assert_eq!(0, offset);
+                assert_eq!("packet length", expected);
+            }
+            result => panic!("Unexpected result {:?}", result.map(|p| p.len())),
+        }
+        assert!(super::deserialize_v1(b"not base64!", ParseMode::Lenient)
+            .unwrap_err()
+            .is_malformed());
+        match super::deserialize_as_packets(b"0006k\n", 0, Vec::new()) {
+            Err(MacaroonError::Format { expected, .. }) => {
+                assert_eq!("packet key and value", expected)
+            }
+            result => panic!("Unexpected result {:?}", result.map(|p| p.len())),
+        }
+        match super::deserialize_as_packets(b"0006k ", 0, Vec::new()) {
+            Err(MacaroonError::Format { expected, .. }) => {
+                assert_eq!("packet terminator", expected)
+            }
+            result => panic!("Unexpected result {:?}", result.map(|p| p.len())),
+        }
+        let unsigned = b"0021location http://example.org/\n0015identifier keyid\n";
+        match super::deserialize_v1(unsigned.to_base64(URL_SAFE).as_bytes(), ParseMode::Lenient) {
+            Err(MacaroonError::MissingField(field)) => assert_eq!("signature", field),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let short = b"0015identifier keyid\n0016signature 0123456\n";
+        match super::deserialize_v1(short.to_base64(URL_SAFE).as_bytes(), ParseMode::Lenient) {
+            Err(MacaroonError::InvalidField { field, .. }) => assert_eq!("signature", field),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_v1_many_packets() {
+        // Packets are read in a loop, so a token of many tiny packets can't exhaust the stack
+        let data = b"0008k v\n".repeat(500_000);
+        let packets = super::deserialize_as_packets(&data, 0, Vec::new()).unwrap();
+        assert_eq!(500_000, packets.len());
+    }
+
+    #[test]
+    fn test_serialize_v1_packet_limit() {
+        // The largest identifier which fits, allowing for the header, tag, space and newline
+        let largest = "i".repeat(0xffff - 4 - "identifier".len() - 2);
+        let macaroon = Macaroon::create("http://example.org/", b"key", &largest).unwrap();
+        let serialized = super::serialize_v1(&macaroon).unwrap();
+        assert_eq!(
+            macaroon,
+            super::deserialize_v1(&serialized, ParseMode::Lenient).unwrap()
+        );
+
+        let too_large = format!("{}i", largest);
+        let macaroon = Macaroon::create("http://example.org/", b"key", &too_large).unwrap();
+        match super::serialize_v1(&macaroon) {
+            Err(MacaroonError::InvalidField { field, .. }) => assert_eq!("identifier", field),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_v1_strict() {
+        // As serialized by libmacaroons, with the URL-safe alphabet and no padding
+        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAyZnNpZ25hdHVyZSB83ueSURxbxvUoSFgF3-myTnheKOKpkwH51xHGCeOO9wo";
+        let macaroon = super::deserialize_v1(serialized.as_bytes(), ParseMode::Strict).unwrap();
+        let ours = super::serialize_v1(&macaroon).unwrap();
+        assert_eq!(
+            macaroon,
+            super::deserialize_v1(&ours, ParseMode::Strict).unwrap()
+        );
+
+        let with_newline = format!("{}\n", serialized);
+        assert!(super::deserialize_v1(with_newline.as_bytes(), ParseMode::Lenient).is_ok());
+        assert!(super::deserialize_v1(with_newline.as_bytes(), ParseMode::Strict).is_err());
+
+        // The identifier before the location
+        let mut packets = super::serialize_as_packet(super::IDENTIFIER, b"keyid").unwrap();
+        packets
+            .extend(super::serialize_as_packet(super::LOCATION, b"http://example.org/").unwrap());
+        packets.extend(
+            super::serialize_as_packet(super::SIGNATURE, macaroon.signature().as_ref()).unwrap(),
+        );
+        let reordered = packets.to_base64(URL_SAFE);
+        assert_eq!(
+            macaroon,
+            super::deserialize_v1(reordered.as_bytes(), ParseMode::Lenient).unwrap()
+        );
+        match super::deserialize_v1(reordered.as_bytes(), ParseMode::Strict) {
+            Err(MacaroonError::Format { offset, .. }) => assert_eq!(2, offset),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_v1_untrimmed() {
+        // Leading and trailing whitespace in every field, serialized as libmacaroons does: only
+        // the newline terminating each packet is removed
+        let serialized = "MDAyMmxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8gCjAwMTZpZGVudGlmaWVyIGtleWlkCgowMDFl\
+                          Y2lkIGFjY291bnQgPSAzNzM1OTI4NTU5IAowMDE2Y2lkIHVzZXIgPSBhbGljZQoKMDAxMmNpZCAgIHBh\
+                          ZGRlZAkKMDAyZnNpZ25hdHVyZSBu_7sz3i5BWilbBAqmVul6eUtDD7JcBk1QZu6TGt0eOQo";
+        let macaroon = super::deserialize_v1(serialized.as_bytes(), ParseMode::Strict).unwrap();
+        assert_eq!(Some("http://example.org/ "), macaroon.location().as_deref());
+        assert_eq!("keyid\n", macaroon.identifier());
+        let predicates: Vec<String> = macaroon
+            .first_party_caveats()
+            .map(|c| c.predicate())
+            .collect();
+        assert_eq!(
+            vec!["account = 3735928559 ", "user = alice\n", "  padded\t"],
+            predicates
+        );
+        assert!(macaroon.verify_signature(MacaroonKey::generate(b"this is the key").as_ref()));
+        assert_eq!(
+            serialized.as_bytes(),
+            &super::serialize_v1_canonical(&macaroon).unwrap()[..]
+        );
+
+        let mut minted =
+            Macaroon::create("http://example.org/ ", b"this is the key", "keyid\n").unwrap();
+        for predicate in &predicates {
+            minted.add_first_party_caveat(predicate);
+        }
+        assert_eq!(macaroon, minted);
     }
 
     #[test]
@@ -203,11 +456,11 @@ mod tests {
             75, 233, 103, 205, 30, 160, 198, 178, 107, 175, 106, 74, 148, 238, 155, 5, 177, 88,
             134, 218, 11, 168, 94, 140, 66, 169, 60, 141, 14, 18, 94, 252,
         ];
-        let macaroon = super::deserialize_v1(&serialized.as_bytes().to_vec()).unwrap();
+        let macaroon = super::deserialize_v1(serialized.as_bytes(), ParseMode::Lenient).unwrap();
         assert!(macaroon.location().is_some());
         assert_eq!("http://example.org/", &macaroon.location().unwrap());
         assert_eq!("keyid", macaroon.identifier());
-        assert_eq!(signature.to_vec(), macaroon.signature());
+        assert_eq!(&signature, macaroon.signature().as_bytes());
         assert_eq!(2, macaroon.caveats().len());
         assert_eq!(
             "account = 3735928559",
@@ -1,9 +1,17 @@
 use crate::{
-    caveat::{CaveatBuilder, CaveatType},
-    error::MacaroonError,
-    serialization::macaroon_builder::MacaroonBuilder,
+    caveat::{CaveatType, INLINE_CAVEATS},
+    crypto::Signature,
+    error::{utf8_str, MacaroonError},
+    limits,
+    macaroon_ref::{CaveatRef, MacaroonRef},
+    redaction::Secret,
+    serialization::{check_canonical, DeserializeOptions, ParseMode, UnknownField},
     Macaroon,
 };
+use smallvec::SmallVec;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt;
 
 // Version 2 fields
 const EOS_V2: u8 = 0;
@@ -12,61 +20,170 @@ const IDENTIFIER_V2: u8 = 2;
 const VID_V2: u8 = 4;
 const SIGNATURE_V2: u8 = 6;
 
+// Fields with any other tag are skipped when parsing, and kept as unknown fields
+fn is_known_tag(tag: u8) -> bool {
+    matches!(
+        tag,
+        EOS_V2 | LOCATION_V2 | IDENTIFIER_V2 | VID_V2 | SIGNATURE_V2
+    )
+}
+
 const VARINT_PACK_SIZE: usize = 128;
 
+// Largest field we'll accept. This is far beyond any real macaroon, and is only here so a
+// corrupt or hostile size can't be used to make us allocate without limit.
+const MAX_FIELD_SIZE: u64 = 1 << 24;
+
+#[cfg(test)]
 fn varint_size(size: usize) -> Vec<u8> {
     let mut buffer: Vec<u8> = Vec::new();
+    write_varint(size, &mut buffer);
+    buffer
+}
+
+fn write_varint(size: usize, buffer: &mut Vec<u8>) {
     let mut my_size: usize = size;
     while my_size >= VARINT_PACK_SIZE {
         buffer.push(((my_size & (VARINT_PACK_SIZE - 1)) | VARINT_PACK_SIZE) as u8);
         my_size >>= 7;
     }
     buffer.push(my_size as u8);
-
-    buffer
 }
 
 fn serialize_field_v2(tag: u8, value: &[u8], buffer: &mut Vec<u8>) {
     buffer.push(tag);
-    buffer.extend(varint_size(value.len()));
-    buffer.extend(value);
+    write_varint(value.len(), buffer);
+    buffer.extend_from_slice(value);
+}
+
+// The length of a serialized field: the tag, the varint length and the value
+fn field_len_v2(len: usize) -> usize {
+    let mut varint_len = 1;
+    let mut remaining = len;
+    while remaining >= VARINT_PACK_SIZE {
+        varint_len += 1;
+        remaining >>= 7;
+    }
+    1 + varint_len + len
+}
+
+// Write the unknown fields of a section (the macaroon's own, or a caveat's) whose tags are in
+// the range, in tag order, so that they go back among the known fields where they belong
+fn serialize_unknown_v2<R>(
+    macaroon: &Macaroon,
+    caveat: Option<usize>,
+    tags: R,
+    buffer: &mut Vec<u8>,
+) where
+    R: std::ops::RangeBounds<u8>,
+{
+    if macaroon.unknown_fields.is_empty() {
+        return;
+    }
+    let mut fields: Vec<&UnknownField> = macaroon
+        .unknown_fields
+        .iter()
+        .filter(|field| field.caveat == caveat && tags.contains(&field.tag))
+        .collect();
+    fields.sort_by_key(|field| field.tag);
+    for field in fields {
+        serialize_field_v2(field.tag, &field.value, buffer);
+    }
+}
+
+// The length of `write_v2`'s output, without writing it
+pub(crate) fn serialized_len_v2(macaroon: &Macaroon) -> usize {
+    let mut len = 1; // version
+    for field in &macaroon.unknown_fields {
+        len += field_len_v2(field.value.len());
+    }
+    if let Some(location) = &macaroon.location {
+        len += field_len_v2(location.len());
+    }
+    len += field_len_v2(macaroon.identifier().len()) + 1;
+    for caveat in macaroon.caveats() {
+        len += match caveat.get_type() {
+            CaveatType::FirstParty => {
+                field_len_v2(caveat.as_first_party().unwrap().predicate.len()) + 1
+            }
+            CaveatType::ThirdParty => {
+                let third_party = caveat.as_third_party().unwrap();
+                field_len_v2(third_party.location.len())
+                    + field_len_v2(third_party.id.len())
+                    + field_len_v2(third_party.verifier_id.len())
+                    + 1
+            }
+        };
+    }
+    len + 1 + field_len_v2(macaroon.signature().as_ref().len())
 }
 
 pub fn serialize_v2(macaroon: &Macaroon) -> Result<Vec<u8>, MacaroonError> {
+    Ok(write_v2(macaroon))
+}
+
+// Unlike the other formats, this can't fail, since there are no limits on field sizes
+pub(crate) fn write_v2(macaroon: &Macaroon) -> Vec<u8> {
     let mut buffer: Vec<u8> = Vec::new();
     buffer.push(2); // version
-    if let Some(ref location) = macaroon.location() {
-        serialize_field_v2(LOCATION_V2, &location.as_bytes().to_vec(), &mut buffer);
+    if let Some(location) = &macaroon.location {
+        serialize_field_v2(LOCATION_V2, location.as_bytes(), &mut buffer);
     };
-    serialize_field_v2(
-        IDENTIFIER_V2,
-        &macaroon.identifier().as_bytes().to_vec(),
-        &mut buffer,
-    );
+    serialize_field_v2(IDENTIFIER_V2, macaroon.identifier().as_bytes(), &mut buffer);
+    serialize_unknown_v2(macaroon, None, .., &mut buffer);
     buffer.push(EOS_V2);
-    for caveat in macaroon.caveats() {
+    for (index, caveat) in macaroon.caveats().iter().enumerate() {
+        let section = Some(index);
         match caveat.get_type() {
             CaveatType::FirstParty => {
                 let first_party = caveat.as_first_party().unwrap();
-                serialize_field_v2(
-                    IDENTIFIER_V2,
-                    &first_party.predicate().as_bytes().to_vec(),
-                    &mut buffer,
-                );
+                serialize_field_v2(IDENTIFIER_V2, first_party.predicate.as_bytes(), &mut buffer);
+                serialize_unknown_v2(macaroon, section, .., &mut buffer);
                 buffer.push(EOS_V2);
             }
             CaveatType::ThirdParty => {
                 let third_party = caveat.as_third_party().unwrap();
-                serialize_field_v2(LOCATION_V2, third_party.location().as_bytes(), &mut buffer);
-                serialize_field_v2(IDENTIFIER_V2, third_party.id().as_bytes(), &mut buffer);
-                serialize_field_v2(VID_V2, third_party.verifier_id().as_slice(), &mut buffer);
+                serialize_field_v2(LOCATION_V2, third_party.location.as_bytes(), &mut buffer);
+                serialize_field_v2(IDENTIFIER_V2, third_party.id.as_bytes(), &mut buffer);
+                serialize_unknown_v2(macaroon, section, ..VID_V2, &mut buffer);
+                serialize_field_v2(VID_V2, &third_party.verifier_id, &mut buffer);
+                serialize_unknown_v2(macaroon, section, VID_V2.., &mut buffer);
                 buffer.push(EOS_V2);
             }
         }
     }
     buffer.push(EOS_V2);
-    serialize_field_v2(SIGNATURE_V2, macaroon.signature(), &mut buffer);
-    Ok(buffer)
+    serialize_field_v2(SIGNATURE_V2, macaroon.signature().as_ref(), &mut buffer);
+    buffer
+}
+
+// A field with an unknown tag, as parsed, with the offset of its tag
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct UnknownFieldRef<'a> {
+    pub(crate) offset: usize,
+    pub(crate) caveat: Option<usize>,
+    pub(crate) tag: u8,
+    pub(crate) value: &'a [u8],
+}
+
+impl UnknownFieldRef<'_> {
+    pub(crate) fn to_unknown_field(self) -> UnknownField {
+        UnknownField {
+            caveat: self.caveat,
+            tag: self.tag,
+            value: self.value.to_vec(),
+        }
+    }
+}
+
+impl fmt::Debug for UnknownFieldRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnknownFieldRef")
+            .field("caveat", &self.caveat)
+            .field("tag", &self.tag)
+            .field("value", &Secret(self.value))
+            .finish()
+    }
 }
 
 struct V2Deserializer<'r> {
@@ -75,15 +192,13 @@ struct V2Deserializer<'r> {
 }
 
 impl<'r> V2Deserializer<'r> {
-    pub fn new(data: &[u8]) -> V2Deserializer<'_> {
+    pub fn new(data: &'r [u8]) -> V2Deserializer<'r> {
         V2Deserializer { data, index: 0 }
     }
 
     fn get_byte(&mut self) -> Result<u8, MacaroonError> {
-        if self.index > self.data.len() - 1 {
-            return Err(MacaroonError::DeserializationError(String::from(
-                "Buffer overrun",
-            )));
+        if self.index >= self.data.len() {
+            return Err(MacaroonError::Truncated { offset: self.index });
         }
         let byte = self.data[self.index];
         self.index += 1;
@@ -94,150 +209,213 @@ impl<'r> V2Deserializer<'r> {
         self.get_byte()
     }
 
-    pub fn get_eos(&mut self) -> Result<u8, MacaroonError> {
-        let eos = self.get_byte()?;
+    // The next known tag in the section, skipping over any unknown fields before it
+    pub fn next_tag(
+        &mut self,
+        caveat: Option<usize>,
+        unknown_fields: &mut Vec<UnknownFieldRef<'r>>,
+    ) -> Result<u8, MacaroonError> {
+        loop {
+            let offset = self.index;
+            let tag = self.get_tag()?;
+            if is_known_tag(tag) {
+                return Ok(tag);
+            }
+            let value = self.get_field()?;
+            unknown_fields.push(UnknownFieldRef {
+                offset,
+                caveat,
+                tag,
+                value,
+            });
+        }
+    }
+
+    pub fn end_section(
+        &mut self,
+        caveat: Option<usize>,
+        unknown_fields: &mut Vec<UnknownFieldRef<'r>>,
+    ) -> Result<(), MacaroonError> {
+        let eos = self.next_tag(caveat, unknown_fields)?;
         match eos {
-            EOS_V2 => Ok(eos),
-            _ => Err(MacaroonError::DeserializationError(String::from(
-                "Expected EOS",
-            ))),
+            EOS_V2 => Ok(()),
+            _ => Err(self.unexpected_tag("end of section", eos)),
         }
     }
 
-    pub fn get_field(&mut self) -> Result<Vec<u8>, MacaroonError> {
-        let size: usize = self.get_field_size()?;
-        if size + self.index > self.data.len() {
-            return Err(MacaroonError::DeserializationError(String::from(
-                "Unexpected end of field",
-            )));
+    pub fn get_field(&mut self) -> Result<&'r [u8], MacaroonError> {
+        let size = self.get_field_size()?;
+        if size > self.data.len() - self.index {
+            return Err(MacaroonError::Truncated {
+                offset: self.data.len(),
+            });
         }
 
-        let field: Vec<u8> = self.data[self.index..self.index + size].to_vec();
+        let field = &self.data[self.index..self.index + size];
         self.index += size;
         Ok(field)
     }
 
     fn get_field_size(&mut self) -> Result<usize, MacaroonError> {
-        let mut size: usize = 0;
-        let mut shift: usize = 0;
-        let mut byte: u8;
-        while shift <= 63 {
-            byte = self.get_byte()?;
-            if byte & 128 != 0 {
-                size |= ((byte & 127) << shift) as usize;
-            } else {
-                size |= (byte << shift) as usize;
-                return Ok(size);
+        let mut size: u64 = 0;
+        let mut shift: u32 = 0;
+        loop {
+            let byte = self.get_byte()?;
+            let bits = u64::from(byte & 127);
+            // Anything which would be shifted out of a u64 is an overflow
+            if shift >= 64 || (bits << shift) >> shift != bits {
+                return Err(MacaroonError::InvalidField {
+                    field: "field size",
+                    reason: "varint overflows 64 bits",
+                });
+            }
+            size |= bits << shift;
+            if byte & 128 == 0 {
+                break;
             }
             shift += 7;
         }
-        Err(MacaroonError::DeserializationError(String::from(
-            "Error in field size",
-        )))
+        if size > MAX_FIELD_SIZE {
+            return Err(MacaroonError::InvalidField {
+                field: "field size",
+                reason: "field too large",
+            });
+        }
+        Ok(size as usize)
     }
-}
 
-pub fn deserialize_v2(data: &[u8]) -> Result<Macaroon, MacaroonError> {
-    let mut builder = MacaroonBuilder::new();
-    let mut deserializer = V2Deserializer::new(data);
-    if deserializer.get_byte()? != 2 {
-        return Err(MacaroonError::DeserializationError(String::from(
-            "Wrong version number",
-        )));
-    }
-    let mut tag: u8 = deserializer.get_tag()?;
-    match tag {
-        LOCATION_V2 => builder.set_location(&String::from_utf8(deserializer.get_field()?)?),
-        IDENTIFIER_V2 => builder.set_identifier(&String::from_utf8(deserializer.get_field()?)?),
-        _ => {
-            return Err(MacaroonError::DeserializationError(String::from(
-                "Identifier not found",
-            )))
+    // Error for the tag just read
+    fn unexpected_tag(&self, expected: &'static str, tag: u8) -> MacaroonError {
+        MacaroonError::Format {
+            offset: self.index - 1,
+            expected,
+            found: format!("tag {}", tag),
         }
     }
-    if builder.has_location() {
-        tag = deserializer.get_tag()?;
-        match tag {
-            IDENTIFIER_V2 => {
-                builder.set_identifier(&String::from_utf8(deserializer.get_field()?)?);
-            }
-            _ => {
-                return Err(MacaroonError::DeserializationError(String::from(
-                    "Identifier not found",
-                )))
-            }
+}
+
+pub fn deserialize_v2(
+    data: &[u8],
+    options: impl Into<DeserializeOptions>,
+) -> Result<Macaroon, MacaroonError> {
+    let DeserializeOptions { mode, max_caveats } = options.into();
+    let parsed = parse_v2(data, max_caveats)?;
+    if mode == ParseMode::Strict {
+        if let Some(field) = parsed.unknown_fields().first() {
+            return Err(MacaroonError::Format {
+                offset: field.offset,
+                expected: "known field",
+                found: format!("tag {}", field.tag),
+            });
         }
     }
-    deserializer.get_eos()?;
-    tag = deserializer.get_tag()?;
+    let macaroon = parsed.build()?;
+    if mode == ParseMode::Strict {
+        check_canonical(data, &serialize_v2(&macaroon)?)?;
+    }
+    Ok(macaroon)
+}
+
+pub(crate) fn parse_v2(
+    data: &[u8],
+    max_caveats: Option<usize>,
+) -> Result<MacaroonRef<'_>, MacaroonError> {
+    parse_v2_with(data, max_caveats, |caveats| Cow::Owned(caveats.to_vec()))
+}
+
+// Parse a version 2 macaroon, with the caveats stored as the caller chooses once they've all
+// been found (in an arena, say: see `arena::MacaroonArena`)
+pub(crate) fn parse_v2_with<'a, F>(
+    data: &'a [u8],
+    max_caveats: Option<usize>,
+    store: F,
+) -> Result<MacaroonRef<'a>, MacaroonError>
+where
+    F: FnOnce(&[CaveatRef<'a>]) -> Cow<'a, [CaveatRef<'a>]>,
+{
+    let mut deserializer = V2Deserializer::new(data);
+    let version = deserializer.get_byte()?;
+    if version != 2 {
+        return Err(MacaroonError::Format {
+            offset: 0,
+            expected: "version 2",
+            found: format!("version {}", version),
+        });
+    }
+    let mut unknown_fields = Vec::new();
+    let mut location = None;
+    let mut tag: u8 = deserializer.next_tag(None, &mut unknown_fields)?;
+    if tag == LOCATION_V2 {
+        location = Some(utf8_str("location", deserializer.get_field()?)?);
+        tag = deserializer.next_tag(None, &mut unknown_fields)?;
+    }
+    let identifier = match tag {
+        IDENTIFIER_V2 => utf8_str("identifier", deserializer.get_field()?)?,
+        _ => return Err(deserializer.unexpected_tag("identifier", tag)),
+    };
+    deserializer.end_section(None, &mut unknown_fields)?;
+    let mut caveats: SmallVec<[CaveatRef; INLINE_CAVEATS]> = SmallVec::new();
+    tag = deserializer.next_tag(Some(0), &mut unknown_fields)?;
     while tag != EOS_V2 {
-        let mut caveat_builder: CaveatBuilder = CaveatBuilder::new();
-        match tag {
-            LOCATION_V2 => {
-                let field: Vec<u8> = deserializer.get_field()?;
-                caveat_builder.add_location(String::from_utf8(field)?);
-            }
-            IDENTIFIER_V2 => caveat_builder.add_id(String::from_utf8(deserializer.get_field()?)?),
-            _ => {
-                return Err(MacaroonError::DeserializationError(String::from(
-                    "Caveat identifier not found",
-                )))
-            }
-        }
-        if caveat_builder.has_location() {
-            tag = deserializer.get_tag()?;
-            match tag {
-                IDENTIFIER_V2 => {
-                    let field: Vec<u8> = deserializer.get_field()?;
-                    caveat_builder.add_id(String::from_utf8(field)?);
-                }
-                _ => {
-                    return Err(MacaroonError::DeserializationError(String::from(
-                        "Caveat identifier not found",
-                    )))
-                }
-            }
+        let section = Some(caveats.len());
+        let mut caveat_location = None;
+        if tag == LOCATION_V2 {
+            caveat_location = Some(utf8_str("caveat location", deserializer.get_field()?)?);
+            tag = deserializer.next_tag(section, &mut unknown_fields)?;
         }
-        tag = deserializer.get_tag()?;
-        match tag {
+        let id = match tag {
+            IDENTIFIER_V2 => utf8_str("caveat identifier", deserializer.get_field()?)?,
+            _ => return Err(deserializer.unexpected_tag("caveat identifier", tag)),
+        };
+        tag = deserializer.next_tag(section, &mut unknown_fields)?;
+        let verifier_id = match tag {
             VID_V2 => {
-                let field: Vec<u8> = deserializer.get_field()?;
-                caveat_builder.add_verifier_id(field);
-                builder.add_caveat(caveat_builder.build()?);
-                deserializer.get_eos()?;
-                tag = deserializer.get_tag()?;
-            }
-            EOS_V2 => {
-                builder.add_caveat(caveat_builder.build()?);
-                tag = deserializer.get_tag()?;
+                let field = deserializer.get_field()?;
+                deserializer.end_section(section, &mut unknown_fields)?;
+                Some(field)
             }
+            EOS_V2 => None,
             _ => {
-                return Err(MacaroonError::DeserializationError(String::from(
-                    "Unexpected caveat tag found",
-                )))
+                return Err(deserializer.unexpected_tag("caveat verifier id or end of section", tag))
             }
+        };
+        limits::check_caveat_count(caveats.len() + 1, max_caveats)?;
+        caveats.push(CaveatRef {
+            id,
+            location: caveat_location,
+            verifier_id,
+        });
+        tag = deserializer.next_tag(Some(caveats.len()), &mut unknown_fields)?;
+    }
+    // Unknown fields with no caveat to go with them
+    if let Some(field) = unknown_fields.last() {
+        if field.caveat == Some(caveats.len()) {
+            return Err(deserializer.unexpected_tag("caveat identifier", tag));
         }
     }
     tag = deserializer.get_tag()?;
-    if tag == SIGNATURE_V2 {
-        let sig: Vec<u8> = deserializer.get_field()?;
-        if sig.len() != 32 {
-            return Err(MacaroonError::DeserializationError(String::from(
-                "Bad signature length",
-            )));
-        }
-        builder.set_signature(&sig);
-    } else {
-        return Err(MacaroonError::DeserializationError(String::from(
-            "Unexpected tag found",
-        )));
+    if tag != SIGNATURE_V2 {
+        return Err(deserializer.unexpected_tag("signature", tag));
     }
-    Ok(builder.build()?)
+    let signature = Signature::try_from(deserializer.get_field()?)?;
+    Ok(MacaroonRef::from_parts(
+        data,
+        location,
+        identifier,
+        store(&caveats),
+        signature,
+        unknown_fields,
+    ))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{caveat, serialization::macaroon_builder::MacaroonBuilder, Macaroon};
+    use crate::{
+        caveat,
+        error::MacaroonError,
+        serialization::{macaroon_builder::MacaroonBuilder, ParseMode},
+        Macaroon,
+    };
     use rustc_serialize::base64::FromBase64;
 
     #[test]
@@ -248,7 +426,7 @@ mod tests {
             134, 218, 11, 168, 94, 140, 66, 169, 60, 141, 14, 18, 94, 252,
         ];
         let serialized: Vec<u8> = SERIALIZED.from_base64().unwrap();
-        let macaroon = super::deserialize_v2(&serialized).unwrap();
+        let macaroon = super::deserialize_v2(&serialized, ParseMode::Lenient).unwrap();
         assert_eq!("http://example.org/", &macaroon.location().unwrap());
         assert_eq!("keyid", macaroon.identifier());
         assert_eq!(2, macaroon.caveats().len());
@@ -260,7 +438,7 @@ mod tests {
             "user = alice",
             macaroon.caveats()[1].as_first_party().unwrap().predicate()
         );
-        assert_eq!(SIGNATURE.to_vec(), macaroon.signature());
+        assert_eq!(&SIGNATURE, macaroon.signature().as_bytes());
     }
 
     #[test]
@@ -275,11 +453,198 @@ mod tests {
         builder.add_caveat(Box::new(caveat::new_first_party("user = alice")));
         builder.set_location("http://example.org/");
         builder.set_identifier("keyid");
-        builder.set_signature(&SIGNATURE);
+        builder.set_signature(&SIGNATURE).unwrap();
         let serialized = super::serialize_v2(&builder.build().unwrap()).unwrap();
         assert_eq!(SERIALIZED.from_base64().unwrap(), serialized);
     }
 
+    #[test]
+    fn test_deserialize_v2_errors() {
+        let macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        let serialized = super::serialize_v2(&macaroon).unwrap();
+        match super::deserialize_v2(&serialized[..serialized.len() - 10], ParseMode::Lenient) {
+            Err(MacaroonError::Truncated { offset }) => assert!(offset <= serialized.len()),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let mut bad_version = serialized.clone();
+        bad_version[0] = 3;
+        match super::deserialize_v2(&bad_version, ParseMode::Lenient) {
+            Err(MacaroonError::Format { offset, .. }) => assert_eq!(0, offset),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_varint_round_trip() {
+        for &size in &[0, 1, 127, 128, 255, 300, 16_383, 16_384, 1 << 20] {
+            let encoded = super::varint_size(size);
+            let mut deserializer = super::V2Deserializer::new(&encoded);
+            assert_eq!(size, deserializer.get_field_size().unwrap());
+            assert_eq!(encoded.len(), deserializer.index);
+        }
+    }
+
+    #[test]
+    fn test_varint_overflow() {
+        let mut encoded = vec![0xff; 9];
+        encoded.push(0x02);
+        match super::V2Deserializer::new(&encoded).get_field_size() {
+            Err(MacaroonError::InvalidField { reason, .. }) => {
+                assert_eq!("varint overflows 64 bits", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let encoded = super::varint_size(1 << 25);
+        match super::V2Deserializer::new(&encoded).get_field_size() {
+            Err(MacaroonError::InvalidField { reason, .. }) => {
+                assert_eq!("field too large", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_serialize_deserialize_v2_long_fields() {
+        let identifier = "i".repeat(200);
+        let predicate = format!("data = {}", "x".repeat(20_000));
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", &identifier).unwrap();
+        macaroon.add_first_party_caveat(&predicate);
+        macaroon.add_third_party_caveat("https://auth.mybank.com", b"caveat key", &identifier);
+        let serialized = super::serialize_v2(&macaroon).unwrap();
+        let deserialized = super::deserialize_v2(&serialized, ParseMode::Lenient).unwrap();
+        assert_eq!(&identifier, deserialized.identifier());
+        assert_eq!(
+            predicate,
+            deserialized.caveats()[0]
+                .as_first_party()
+                .unwrap()
+                .predicate()
+        );
+        assert_eq!(macaroon, deserialized);
+    }
+
+    #[test]
+    fn test_deserialize_v2_strict() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        let serialized = super::serialize_v2(&macaroon).unwrap();
+        assert_eq!(
+            macaroon,
+            super::deserialize_v2(&serialized, ParseMode::Strict).unwrap()
+        );
+
+        let mut trailing = serialized.clone();
+        trailing.push(0);
+        assert!(super::deserialize_v2(&trailing, ParseMode::Lenient).is_ok());
+        match super::deserialize_v2(&trailing, ParseMode::Strict) {
+            Err(MacaroonError::Format { offset, .. }) => assert_eq!(serialized.len(), offset),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // The location's length, as a two-byte varint rather than one
+        let mut non_minimal = serialized[..2].to_vec();
+        non_minimal.extend_from_slice(&[serialized[2] | 0x80, 0]);
+        non_minimal.extend_from_slice(&serialized[3..]);
+        assert_eq!(
+            macaroon,
+            super::deserialize_v2(&non_minimal, ParseMode::Lenient).unwrap()
+        );
+        match super::deserialize_v2(&non_minimal, ParseMode::Strict) {
+            Err(MacaroonError::Format { offset, .. }) => assert_eq!(2, offset),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_v2_unknown_fields() {
+        use super::{serialize_field_v2, EOS_V2, IDENTIFIER_V2, LOCATION_V2, SIGNATURE_V2, VID_V2};
+        use crate::UnknownField;
+
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("https://auth.mybank.com/", b"my key", "keyid");
+        let third_party = macaroon.caveats()[1].as_third_party().unwrap().clone();
+
+        // Extensions (tag 3) and fields with tags from later versions of the format, in the
+        // macaroon's section and in both caveats'
+        let mut data = vec![2];
+        serialize_field_v2(LOCATION_V2, b"http://example.org/", &mut data);
+        serialize_field_v2(IDENTIFIER_V2, b"keyid", &mut data);
+        serialize_field_v2(5, b"header", &mut data);
+        data.push(EOS_V2);
+        serialize_field_v2(IDENTIFIER_V2, b"account = 3735928559", &mut data);
+        serialize_field_v2(3, b"", &mut data);
+        data.push(EOS_V2);
+        serialize_field_v2(LOCATION_V2, b"https://auth.mybank.com/", &mut data);
+        serialize_field_v2(IDENTIFIER_V2, b"keyid", &mut data);
+        serialize_field_v2(3, b"extension", &mut data);
+        serialize_field_v2(VID_V2, &third_party.verifier_id(), &mut data);
+        serialize_field_v2(200, b"later", &mut data);
+        data.push(EOS_V2);
+        data.push(EOS_V2);
+        serialize_field_v2(SIGNATURE_V2, macaroon.signature().as_bytes(), &mut data);
+
+        let deserialized = super::deserialize_v2(&data, ParseMode::Lenient).unwrap();
+        assert_eq!(macaroon.caveats(), deserialized.caveats());
+        assert_eq!(macaroon.signature(), deserialized.signature());
+        assert_eq!(
+            &[
+                UnknownField {
+                    caveat: None,
+                    tag: 5,
+                    value: b"header".to_vec()
+                },
+                UnknownField {
+                    caveat: Some(0),
+                    tag: 3,
+                    value: Vec::new()
+                },
+                UnknownField {
+                    caveat: Some(1),
+                    tag: 3,
+                    value: b"extension".to_vec()
+                },
+                UnknownField {
+                    caveat: Some(1),
+                    tag: 200,
+                    value: b"later".to_vec()
+                },
+            ],
+            deserialized.unknown_fields()
+        );
+        // They're written back where they were
+        assert_eq!(data, super::serialize_v2(&deserialized).unwrap());
+        assert_eq!(data.len(), super::serialized_len_v2(&deserialized));
+
+        // And kept when the macaroon is attenuated and passed on
+        let mut attenuated = deserialized.clone();
+        attenuated.add_first_party_caveat("time < 2030-01-01T00:00");
+        let serialized = super::serialize_v2(&attenuated).unwrap();
+        assert_eq!(
+            deserialized.unknown_fields(),
+            super::deserialize_v2(&serialized, ParseMode::Lenient)
+                .unwrap()
+                .unknown_fields()
+        );
+
+        match super::deserialize_v2(&data, ParseMode::Strict) {
+            Err(MacaroonError::Format { offset, found, .. }) => {
+                assert_eq!(29, offset);
+                assert_eq!("tag 5", found);
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // Unknown fields must belong to a caveat, or come before the end of the header
+        let mut stray = vec![2];
+        serialize_field_v2(IDENTIFIER_V2, b"keyid", &mut stray);
+        stray.push(EOS_V2);
+        serialize_field_v2(3, b"extension", &mut stray);
+        stray.push(EOS_V2);
+        serialize_field_v2(SIGNATURE_V2, macaroon.signature().as_bytes(), &mut stray);
+        assert!(super::deserialize_v2(&stray, ParseMode::Lenient).is_err());
+    }
+
     #[test]
     fn test_serialize_deserialize_v2() {
         let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
@@ -287,7 +652,7 @@ mod tests {
         macaroon.add_first_party_caveat("user = alice");
         macaroon.add_third_party_caveat("https://auth.mybank.com", b"caveat key", "caveat");
         let serialized = super::serialize_v2(&macaroon).unwrap();
-        macaroon = super::deserialize_v2(&serialized).unwrap();
+        macaroon = super::deserialize_v2(&serialized, ParseMode::Lenient).unwrap();
         assert_eq!("http://example.org/", &macaroon.location().unwrap());
         assert_eq!("keyid", macaroon.identifier());
         assert_eq!(3, macaroon.caveats().len());
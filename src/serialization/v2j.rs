@@ -1,75 +1,173 @@
 use crate::{
     caveat::{CaveatBuilder, CaveatType},
-    error::MacaroonError,
-    serialization::macaroon_builder::MacaroonBuilder,
+    error::{base64_field, utf8_field, MacaroonError},
+    limits, redaction,
+    serialization::{
+        check_canonical, macaroon_builder::MacaroonBuilder, DeserializeOptions, ParseMode,
+        UnknownJsonField,
+    },
     Macaroon,
 };
-use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
-use serde::{Deserialize, Serialize};
+use rustc_serialize::base64::{Config, ToBase64, STANDARD, URL_SAFE};
+use serde::{
+    de::{MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::{value::RawValue, Value};
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
 use std::str;
 
+// The fields of a macaroon, and of its caveats, for checking for unknown fields
+const FIELDS: &[&str] = &["v", "i", "i64", "l", "l64", "c", "s", "s64"];
+const CAVEAT_FIELDS: &[&str] = &["i", "i64", "l", "l64", "v", "v64"];
+
+// Fields which aren't in the format, written after the known fields of their object
+#[derive(Debug, Default)]
+struct ExtraFields<'a>(Vec<(&'a str, &'a RawValue)>);
+
+impl Serialize for ExtraFields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in &self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+// The fields of a JSON object in the order they appear, with their values left as JSON
+struct RawObject<'a>(Vec<(String, &'a RawValue)>);
+
+impl<'de> Deserialize<'de> for RawObject<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RawObjectVisitor;
+
+        impl<'de> Visitor<'de> for RawObjectVisitor {
+            type Value = RawObject<'de>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut fields = Vec::new();
+                while let Some(field) = map.next_entry()? {
+                    fields.push(field);
+                }
+                Ok(RawObject(fields))
+            }
+        }
+
+        deserializer.deserialize_map(RawObjectVisitor)
+    }
+}
+
+// Fields are borrowed from the macaroon when serializing, and owned when deserializing
 #[derive(Debug, Default, Deserialize, Serialize)]
-struct CaveatV2J {
-    i: Option<String>,
+struct CaveatV2J<'a> {
+    i: Option<Cow<'a, str>>,
     i64: Option<String>,
-    l: Option<String>,
+    l: Option<Cow<'a, str>>,
     l64: Option<String>,
-    v: Option<Vec<u8>>,
-    v64: Option<Vec<u8>>,
+    v: Option<Cow<'a, [u8]>>,
+    v64: Option<String>,
+    #[serde(flatten, skip_deserializing)]
+    extra: ExtraFields<'a>,
+}
+
+// The canonical form, in a fixed field order, leaving out absent fields, and with binary
+// fields in URL-safe base64 without padding (as libmacaroons serializes them)
+#[derive(Serialize)]
+struct CanonicalCaveatV2J<'a> {
+    i: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    l: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v64: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CanonicalV2J<'a> {
+    v: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    l: Option<&'a str>,
+    i: &'a str,
+    c: Vec<CanonicalCaveatV2J<'a>>,
+    s64: String,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
-struct V2JSerialization {
+struct V2JSerialization<'a> {
     v: u8,
-    i: Option<String>,
+    i: Option<Cow<'a, str>>,
     i64: Option<String>,
-    l: Option<String>,
+    l: Option<Cow<'a, str>>,
     l64: Option<String>,
-    c: Vec<CaveatV2J>,
+    c: Vec<CaveatV2J<'a>>,
     s: Option<Vec<u8>>,
     s64: Option<String>,
+    #[serde(flatten, skip_deserializing)]
+    extra: ExtraFields<'a>,
 }
 
-impl V2JSerialization {
-    fn from_macaroon(macaroon: Macaroon) -> Result<V2JSerialization, MacaroonError> {
+impl<'a> V2JSerialization<'a> {
+    fn from_macaroon(macaroon: &'a Macaroon) -> Result<V2JSerialization<'a>, MacaroonError> {
         let mut serialized: V2JSerialization = V2JSerialization {
             v: 2,
-            i: Some(macaroon.identifier().to_owned()),
+            i: Some(Cow::Borrowed(&macaroon.identifier)),
             i64: None,
-            l: macaroon.location(),
+            l: macaroon.location.as_deref().map(Cow::Borrowed),
             l64: None,
-            c: Vec::new(),
+            c: Vec::with_capacity(macaroon.caveats.len()),
             s: None,
-            s64: Some(macaroon.signature().to_base64(STANDARD)),
+            s64: Some(macaroon.signature().as_bytes().to_base64(STANDARD)),
+            extra: ExtraFields::default(),
         };
         for caveat in macaroon.caveats() {
             match caveat.get_type() {
                 CaveatType::FirstParty => {
                     let first_party = caveat.as_first_party().unwrap();
                     let serialized_caveat: CaveatV2J = CaveatV2J {
-                        i: Some(first_party.predicate()),
+                        i: Some(Cow::Borrowed(&first_party.predicate)),
                         i64: None,
                         l: None,
                         l64: None,
                         v: None,
                         v64: None,
+                        extra: ExtraFields::default(),
                     };
                     serialized.c.push(serialized_caveat);
                 }
                 CaveatType::ThirdParty => {
                     let third_party = caveat.as_third_party().unwrap();
                     let serialized_caveat: CaveatV2J = CaveatV2J {
-                        i: Some(third_party.id()),
+                        i: Some(Cow::Borrowed(&third_party.id)),
                         i64: None,
-                        l: Some(third_party.location()),
+                        l: Some(Cow::Borrowed(&third_party.location)),
                         l64: None,
-                        v: Some(third_party.verifier_id()),
+                        v: Some(Cow::Borrowed(&third_party.verifier_id)),
                         v64: None,
+                        extra: ExtraFields::default(),
                     };
                     serialized.c.push(serialized_caveat);
                 }
             }
         }
+        // Any for caveats the macaroon doesn't have are left out
+        for field in &macaroon.unknown_json_fields {
+            let value: &RawValue = serde_json::from_str(&field.value)?;
+            let extra = match field.caveat {
+                None => &mut serialized.extra,
+                Some(index) => match serialized.c.get_mut(index) {
+                    Some(caveat) => &mut caveat.extra,
+                    None => continue,
+                },
+            };
+            extra.0.push((&field.key, value));
+        }
 
         Ok(serialized)
     }
@@ -78,31 +176,21 @@ impl V2JSerialization {
 impl Macaroon {
     fn from_v2j(ser: V2JSerialization) -> Result<Macaroon, MacaroonError> {
         if ser.i.is_some() && ser.i64.is_some() {
-            return Err(MacaroonError::DeserializationError(String::from(
-                "Found i and i64 fields",
-            )));
+            return Err(MacaroonError::DuplicateField("identifier"));
         }
         if ser.l.is_some() && ser.l64.is_some() {
-            return Err(MacaroonError::DeserializationError(String::from(
-                "Found l and l64 fields",
-            )));
+            return Err(MacaroonError::DuplicateField("location"));
         }
         if ser.s.is_some() && ser.s64.is_some() {
-            return Err(MacaroonError::DeserializationError(String::from(
-                "Found s and s64 fields",
-            )));
+            return Err(MacaroonError::DuplicateField("signature"));
         }
 
         let mut builder: MacaroonBuilder = MacaroonBuilder::new();
         builder.set_identifier(&match ser.i {
-            Some(id) => id,
+            Some(id) => id.into_owned(),
             None => match ser.i64 {
-                Some(id) => String::from_utf8(id.from_base64()?)?,
-                None => {
-                    return Err(MacaroonError::DeserializationError(String::from(
-                        "No identifier found",
-                    )))
-                }
+                Some(id) => utf8_field("identifier", base64_field("identifier", id.as_str())?)?,
+                None => return Err(MacaroonError::MissingField("identifier")),
             },
         });
 
@@ -110,49 +198,52 @@ impl Macaroon {
             Some(loc) => builder.set_location(&loc),
             None => {
                 if let Some(loc) = ser.l64 {
-                    builder.set_location(&String::from_utf8(loc.from_base64()?)?)
+                    builder.set_location(&utf8_field(
+                        "location",
+                        base64_field("location", loc.as_str())?,
+                    )?)
                 }
             }
         };
 
-        builder.set_signature(&match ser.s {
+        let signature = match ser.s {
             Some(sig) => sig,
             None => match ser.s64 {
-                Some(sig) => sig.from_base64()?,
-                None => {
-                    return Err(MacaroonError::DeserializationError(String::from(
-                        "No signature found",
-                    )))
-                }
+                Some(sig) => base64_field("signature", sig.as_str())?,
+                None => return Err(MacaroonError::MissingField("signature")),
             },
-        });
+        };
+        builder.set_signature(&signature)?;
 
         let mut caveat_builder: CaveatBuilder = CaveatBuilder::new();
         for c in ser.c {
             caveat_builder.add_id(match c.i {
-                Some(id) => id,
+                Some(id) => id.into_owned(),
                 None => match c.i64 {
-                    Some(id64) => String::from_utf8(id64.from_base64()?)?,
-                    None => {
-                        return Err(MacaroonError::DeserializationError(String::from(
-                            "No caveat ID found",
-                        )))
-                    }
+                    Some(id64) => utf8_field(
+                        "caveat identifier",
+                        base64_field("caveat identifier", id64.as_str())?,
+                    )?,
+                    None => return Err(MacaroonError::MissingField("caveat identifier")),
                 },
             });
             match c.l {
-                Some(loc) => caveat_builder.add_location(loc),
+                Some(loc) => caveat_builder.add_location(loc.into_owned()),
                 None => {
                     if let Some(loc64) = c.l64 {
-                        caveat_builder.add_location(String::from_utf8(loc64.from_base64()?)?)
+                        caveat_builder.add_location(utf8_field(
+                            "caveat location",
+                            base64_field("caveat location", loc64.as_str())?,
+                        )?)
                     }
                 }
             };
             match c.v {
-                Some(vid) => caveat_builder.add_verifier_id(vid),
+                Some(vid) => caveat_builder.add_verifier_id(vid.into_owned()),
                 None => {
                     if let Some(vid64) = c.v64 {
-                        caveat_builder.add_verifier_id(vid64.from_base64()?)
+                        caveat_builder
+                            .add_verifier_id(base64_field("caveat verifier id", vid64.as_str())?)
                     }
                 }
             };
@@ -160,25 +251,195 @@ impl Macaroon {
             caveat_builder = CaveatBuilder::new();
         }
 
-        Ok(builder.build()?)
+        builder.build()
     }
 }
 
 pub fn serialize_v2j(macaroon: &Macaroon) -> Result<Vec<u8>, MacaroonError> {
-    let serialized: String =
-        serde_json::to_string(&V2JSerialization::from_macaroon(macaroon.clone())?)?;
+    let serialized: String = serde_json::to_string(&V2JSerialization::from_macaroon(macaroon)?)?;
     Ok(serialized.into_bytes())
 }
 
-pub fn deserialize_v2j(data: &[u8]) -> Result<Macaroon, MacaroonError> {
+// Counts what's written to it
+pub(super) struct LenCounter(pub(super) usize);
+
+impl io::Write for LenCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The length of `serialize_v2j`'s output, without keeping it
+pub fn serialized_len_v2j(macaroon: &Macaroon) -> Result<usize, MacaroonError> {
+    let mut counter = LenCounter(0);
+    serde_json::to_writer(&mut counter, &V2JSerialization::from_macaroon(macaroon)?)?;
+    Ok(counter.0)
+}
+
+pub fn serialize_v2j_canonical(macaroon: &Macaroon) -> Result<Vec<u8>, MacaroonError> {
+    let base64 = Config {
+        pad: false,
+        ..URL_SAFE
+    };
+    let caveats = macaroon
+        .caveats()
+        .iter()
+        .map(|caveat| match caveat.get_type() {
+            CaveatType::FirstParty => CanonicalCaveatV2J {
+                i: &caveat.as_first_party().unwrap().predicate,
+                l: None,
+                v64: None,
+            },
+            CaveatType::ThirdParty => {
+                let third_party = caveat.as_third_party().unwrap();
+                CanonicalCaveatV2J {
+                    i: &third_party.id,
+                    l: Some(&third_party.location),
+                    v64: Some(third_party.verifier_id.to_base64(base64)),
+                }
+            }
+        })
+        .collect();
+    let canonical = CanonicalV2J {
+        v: 2,
+        l: macaroon.location.as_deref(),
+        i: &macaroon.identifier,
+        c: caveats,
+        s64: macaroon.signature().as_bytes().to_base64(base64),
+    };
+    Ok(serde_json::to_vec(&canonical)?)
+}
+
+pub fn deserialize_v2j(
+    data: &[u8],
+    options: impl Into<DeserializeOptions>,
+) -> Result<Macaroon, MacaroonError> {
+    let DeserializeOptions { mode, max_caveats } = options.into();
     let v2j: V2JSerialization = serde_json::from_slice(data)?;
-    Macaroon::from_v2j(v2j)
+    limits::check_caveat_count(v2j.c.len(), max_caveats)?;
+    if mode == ParseMode::Strict {
+        check_strict(data, &v2j)?;
+    }
+    let mut macaroon = Macaroon::from_v2j(v2j)?;
+    match mode {
+        ParseMode::Lenient => macaroon.unknown_json_fields = unknown_json_fields(data)?,
+        ParseMode::Strict => check_reserialized(data, &macaroon)?,
+    }
+    Ok(macaroon)
+}
+
+// A JSON array of version 2J serializations, as go-macaroon-bakery's `macaroon.Slice`
+pub fn serialize_v2j_slice(macaroons: &[Macaroon]) -> Result<Vec<u8>, MacaroonError> {
+    let mut serialized = vec![b'['];
+    for (i, macaroon) in macaroons.iter().enumerate() {
+        if i > 0 {
+            serialized.push(b',');
+        }
+        serialized.extend(serialize_v2j(macaroon)?);
+    }
+    serialized.push(b']');
+    Ok(serialized)
+}
+
+pub fn deserialize_v2j_slice(data: &[u8], mode: ParseMode) -> Result<Vec<Macaroon>, MacaroonError> {
+    let macaroons: Vec<&RawValue> = serde_json::from_slice(data)?;
+    macaroons
+        .iter()
+        .map(|macaroon| deserialize_v2j(macaroon.get().as_bytes(), mode)?.validate())
+        .collect()
+}
+
+// The fields which aren't in the format, to keep them on the macaroon, the macaroon's own
+// first and then each caveat's (as they're written back out)
+fn unknown_json_fields(data: &[u8]) -> Result<Vec<UnknownJsonField>, MacaroonError> {
+    let object: RawObject = serde_json::from_slice(data)?;
+    let mut unknown = Vec::new();
+    let mut unknown_in_caveats = Vec::new();
+    for (key, value) in object.0 {
+        if key == "c" {
+            let caveats: Vec<RawObject> = serde_json::from_str(value.get())?;
+            for (index, caveat) in caveats.into_iter().enumerate() {
+                for (key, value) in caveat.0 {
+                    if !CAVEAT_FIELDS.contains(&key.as_str()) {
+                        unknown_in_caveats.push(UnknownJsonField {
+                            caveat: Some(index),
+                            key,
+                            value: String::from(value.get()),
+                        });
+                    }
+                }
+            }
+        } else if !FIELDS.contains(&key.as_str()) {
+            unknown.push(UnknownJsonField {
+                caveat: None,
+                key,
+                value: String::from(value.get()),
+            });
+        }
+    }
+    unknown.append(&mut unknown_in_caveats);
+    Ok(unknown)
+}
+
+// Duplicate fields are always rejected (by serde). This checks the version and looks for unknown
+// fields, so they're reported as such; the rest (whitespace, field order, `i64` where `i` would
+// do) is left to `check_reserialized`
+fn check_strict(data: &[u8], v2j: &V2JSerialization) -> Result<(), MacaroonError> {
+    if v2j.v != 2 {
+        return Err(MacaroonError::InvalidField {
+            field: "version",
+            reason: "must be 2",
+        });
+    }
+    let value: Value = serde_json::from_slice(data)?;
+    check_fields(data, &value, FIELDS)?;
+    if let Some(caveats) = value.get("c").and_then(Value::as_array) {
+        for caveat in caveats {
+            check_fields(data, caveat, CAVEAT_FIELDS)?;
+        }
+    }
+    Ok(())
+}
+
+// The data must be the macaroon serialized again, either as libmacaroons serializes it (what
+// `Macaroon::canonical_bytes` gives) or as this crate does (`serialize_v2j`)
+fn check_reserialized(data: &[u8], macaroon: &Macaroon) -> Result<(), MacaroonError> {
+    if data == serialize_v2j_canonical(macaroon)? {
+        return Ok(());
+    }
+    check_canonical(data, &serialize_v2j(macaroon)?)
+}
+
+fn check_fields(data: &[u8], value: &Value, known: &[&str]) -> Result<(), MacaroonError> {
+    let unknown = value
+        .as_object()
+        .and_then(|object| object.keys().find(|key| !known.contains(&key.as_str())));
+    match unknown {
+        Some(key) => {
+            let quoted = format!("\"{}\"", key);
+            let offset = data
+                .windows(quoted.len())
+                .position(|window| window == quoted.as_bytes())
+                .unwrap_or(0);
+            Err(MacaroonError::Format {
+                offset,
+                expected: "known field",
+                found: redaction::content(key),
+            })
+        }
+        None => Ok(()),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::Format;
-    use crate::Macaroon;
+    use super::super::{Format, ParseMode};
+    use crate::{error::MacaroonError, Macaroon, UnknownJsonField};
 
     const SERIALIZED_V2J: &str = "{\"v\":2,\"l\":\"http://example.org/\",\"i\":\"keyid\",\
                                   \"c\":[{\"i\":\"account = 3735928559\"},{\"i\":\"user = \
@@ -192,7 +453,7 @@ mod tests {
     #[test]
     fn test_deserialize_v2j() {
         let serialized_v2j: Vec<u8> = SERIALIZED_V2J.as_bytes().to_vec();
-        let macaroon = super::deserialize_v2j(&serialized_v2j).unwrap();
+        let macaroon = super::deserialize_v2j(&serialized_v2j, ParseMode::Lenient).unwrap();
         assert_eq!("http://example.org/", &macaroon.location().unwrap());
         assert_eq!("keyid", macaroon.identifier());
         assert_eq!(2, macaroon.caveats().len());
@@ -204,7 +465,7 @@ mod tests {
             "user = alice",
             macaroon.caveats()[1].as_first_party().unwrap().predicate()
         );
-        assert_eq!(SIGNATURE_V2.to_vec(), macaroon.signature());
+        assert_eq!(&SIGNATURE_V2, macaroon.signature().as_bytes());
     }
 
     #[test]
@@ -216,4 +477,139 @@ mod tests {
         let other = Macaroon::deserialize(&serialized).unwrap();
         assert_eq!(macaroon, other);
     }
+
+    #[test]
+    fn test_deserialize_v2j_strict() {
+        assert!(super::deserialize_v2j(SERIALIZED_V2J.as_bytes(), ParseMode::Strict).is_ok());
+
+        let unknown = SERIALIZED_V2J.replace("\"v\":2,", "\"v\":2,\"x\":1,");
+        assert!(super::deserialize_v2j(unknown.as_bytes(), ParseMode::Lenient).is_ok());
+        match super::deserialize_v2j(unknown.as_bytes(), ParseMode::Strict) {
+            Err(MacaroonError::Format { offset, found, .. }) => {
+                assert_eq!(7, offset);
+                assert_eq!("x", found);
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let unknown = SERIALIZED_V2J.replace("{\"i\":\"user", "{\"x\":1,\"i\":\"user");
+        assert!(super::deserialize_v2j(unknown.as_bytes(), ParseMode::Strict).is_err());
+
+        let version = SERIALIZED_V2J.replace("\"v\":2", "\"v\":3");
+        assert!(super::deserialize_v2j(version.as_bytes(), ParseMode::Lenient).is_ok());
+        assert!(super::deserialize_v2j(version.as_bytes(), ParseMode::Strict).is_err());
+
+        let duplicate =
+            SERIALIZED_V2J.replace("\"i\":\"keyid\"", "\"i\":\"keyid\",\"i\":\"other\"");
+        assert!(super::deserialize_v2j(duplicate.as_bytes(), ParseMode::Lenient).is_err());
+
+        let whitespace = SERIALIZED_V2J.replace("\"v\":2,", "\"v\": 2,");
+        assert!(super::deserialize_v2j(whitespace.as_bytes(), ParseMode::Lenient).is_ok());
+        match super::deserialize_v2j(whitespace.as_bytes(), ParseMode::Strict) {
+            Err(MacaroonError::Format { offset, .. }) => assert_eq!(5, offset),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let trailing = format!("{}\n", SERIALIZED_V2J);
+        assert!(super::deserialize_v2j(trailing.as_bytes(), ParseMode::Lenient).is_ok());
+        assert!(super::deserialize_v2j(trailing.as_bytes(), ParseMode::Strict).is_err());
+
+        // The identifier as base64, which it needn't be
+        let base64 = SERIALIZED_V2J.replace("\"i\":\"keyid\"", "\"i64\":\"a2V5aWQ\"");
+        assert_eq!(
+            super::deserialize_v2j(SERIALIZED_V2J.as_bytes(), ParseMode::Strict).unwrap(),
+            super::deserialize_v2j(base64.as_bytes(), ParseMode::Lenient).unwrap()
+        );
+        assert!(super::deserialize_v2j(base64.as_bytes(), ParseMode::Strict).is_err());
+    }
+
+    #[test]
+    fn test_v2j_slice() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"caveat key", "caveat");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"caveat key", "caveat").unwrap();
+        macaroon.bind(&mut discharge);
+        let stack = vec![macaroon, discharge];
+        let serialized = Macaroon::serialize_slice(&stack).unwrap();
+        assert_eq!(
+            format!(
+                "[{},{}]",
+                String::from_utf8(stack[0].serialize(Format::V2J).unwrap()).unwrap(),
+                String::from_utf8(stack[1].serialize(Format::V2J).unwrap()).unwrap()
+            )
+            .into_bytes(),
+            serialized
+        );
+        assert_eq!(stack, Macaroon::deserialize_slice(&serialized).unwrap());
+        assert_eq!(
+            stack,
+            Macaroon::deserialize_slice_with_mode(&serialized, ParseMode::Strict).unwrap()
+        );
+        assert_eq!(b"[]".to_vec(), Macaroon::serialize_slice(&[]).unwrap());
+        assert!(Macaroon::deserialize_slice(b" [ ] ").unwrap().is_empty());
+
+        // As go-macaroon-bakery writes it, indented, and with an unknown field
+        let bakery = format!(
+            " [\n  {}\n]\n",
+            SERIALIZED_V2J.replace("\"v\":2,", "\"v\":2,\"x\":1,")
+        );
+        let deserialized = Macaroon::deserialize_slice(bakery.as_bytes()).unwrap();
+        assert_eq!(
+            Macaroon::deserialize(SERIALIZED_V2J.as_bytes()).unwrap(),
+            deserialized[0]
+        );
+        assert!(
+            Macaroon::deserialize_slice_with_mode(bakery.as_bytes(), ParseMode::Strict).is_err()
+        );
+
+        for data in [&b"{}"[..], b"[\"AgE\"]", b"[{\"v\":2}]", b"["] {
+            assert!(Macaroon::deserialize_slice(data).is_err(), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn test_v2j_unknown_fields() {
+        let unknown = SERIALIZED_V2J
+            .replace("\"v\":2,", "\"v\":2,\"x\":{\"y\": [1, 2]},")
+            .replace("{\"i\":\"user", "{\"e\":\"ext\",\"i\":\"user")
+            .replace("}],", "}],\"z\":null,");
+        let mut macaroon = Macaroon::deserialize(unknown.as_bytes()).unwrap();
+        let fields = vec![
+            UnknownJsonField {
+                caveat: None,
+                key: String::from("x"),
+                value: String::from("{\"y\": [1, 2]}"),
+            },
+            UnknownJsonField {
+                caveat: None,
+                key: String::from("z"),
+                value: String::from("null"),
+            },
+            UnknownJsonField {
+                caveat: Some(1),
+                key: String::from("e"),
+                value: String::from("\"ext\""),
+            },
+        ];
+        assert_eq!(fields, macaroon.unknown_json_fields());
+
+        // Passing an attenuated macaroon on keeps them
+        macaroon.add_first_party_caveat("time < 2030-01-01T00:00");
+        let serialized = String::from_utf8(macaroon.serialize(Format::V2J).unwrap()).unwrap();
+        assert!(serialized.contains("\"x\":{\"y\": [1, 2]}"));
+        assert!(serialized.contains("\"e\":\"ext\""));
+        assert_eq!(
+            serialized.len(),
+            macaroon.serialized_len(Format::V2J).unwrap()
+        );
+        let other = Macaroon::deserialize(serialized.as_bytes()).unwrap();
+        assert_eq!(macaroon, other);
+        assert_eq!(fields, other.unknown_json_fields());
+
+        // The canonical form leaves them out
+        let canonical = macaroon.canonical_bytes(Format::V2J).unwrap();
+        assert!(Macaroon::deserialize(&canonical)
+            .unwrap()
+            .unknown_json_fields()
+            .is_empty());
+    }
 }
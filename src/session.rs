@@ -0,0 +1,316 @@
+//! Web sessions backed by macaroons
+//!
+//! This puts together the pieces most services need to use macaroons as session cookies: a
+//! `SessionManager` mints a macaroon for a session with an expiry caveat, encodes it as a
+//! cookie value, and verifies cookies presented back to it. Sessions use a sliding window:
+//! when a session which is still valid gets close to expiry, a replacement cookie with a fresh
+//! expiry is minted, to be set on the response.
+//!
+//! The session's own expiry is always its first caveat, so that it's the only one replaced when
+//! the session is refreshed. Caveats added after it, including shorter expiries added by a
+//! holder narrowing the session before handing it on, are carried over unchanged, so
+//! refreshing never lengthens them.
+//!
+//! Cookies are the version 2 serialization, base64-encoded with the URL-safe alphabet and no
+//! padding, so they can be used as cookie values as-is.
+use crate::{
+    error::{base64_field, MacaroonError},
+    std_caveats, Format, Macaroon, MacaroonKey, Verifier, VerifierBuilder, VerifyContext,
+};
+use rustc_serialize::base64::{ToBase64, URL_SAFE};
+use std::time::{Duration, SystemTime};
+
+/// A session which has been verified
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    id: String,
+    refreshed_cookie: Option<String>,
+}
+
+impl Session {
+    /// The session identifier passed to `SessionManager::mint`
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// A replacement cookie, if the session was close to expiry
+    ///
+    /// If this is set, it should be sent back to the client in place of the cookie it
+    /// presented.
+    pub fn refreshed_cookie(&self) -> Option<&str> {
+        self.refreshed_cookie.as_deref()
+    }
+}
+
+/// Mints and verifies session cookies
+pub struct SessionManager {
+    location: String,
+    root_key: Vec<u8>,
+    key: MacaroonKey,
+    ttl: Duration,
+    refresh_threshold: Duration,
+    verifier: Verifier,
+}
+
+impl SessionManager {
+    /// Create a session manager
+    ///
+    /// Sessions are minted with the given location and root key, and last for `ttl` after they
+    /// are minted or last refreshed. The verifier is used to check any caveats added to
+    /// sessions besides their expiry, which the session manager checks itself.
+    ///
+    /// By default, a session is refreshed once less than half its lifetime remains (see
+    /// `set_refresh_threshold`).
+    pub fn new(
+        location: &str,
+        root_key: &[u8],
+        ttl: Duration,
+        mut verifier: VerifierBuilder,
+    ) -> SessionManager {
+        verifier.satisfy_general(std_caveats::check_time_before);
+        SessionManager {
+            location: String::from(location),
+            root_key: root_key.to_vec(),
+            key: MacaroonKey::generate(root_key),
+            ttl,
+            refresh_threshold: ttl / 2,
+            verifier: verifier.build(),
+        }
+    }
+
+    /// Sets how close to expiry a session must be before it is refreshed
+    ///
+    /// A threshold of zero turns off refreshing, so sessions expire `ttl` after they are minted.
+    pub fn set_refresh_threshold(&mut self, threshold: Duration) {
+        self.refresh_threshold = threshold;
+    }
+
+    /// Mint a session cookie
+    ///
+    /// The session id becomes the macaroon's identifier. Any caveats are added to the session,
+    /// along with its expiry, and are carried over when it is refreshed.
+    pub fn mint(&self, id: &str, caveats: &[&str]) -> Result<String, MacaroonError> {
+        self.mint_at(id, caveats, std_caveats::now())
+    }
+
+    /// Verify a session cookie
+    ///
+    /// Returns `Ok(None)` if the session isn't valid, including if it has expired. Otherwise
+    /// returns the session, with a replacement cookie if it has been refreshed.
+    ///
+    /// # Errors
+    /// Returns an error if the cookie can't be decoded.
+    pub fn verify(
+        &self,
+        cookie: &str,
+        context: &VerifyContext,
+    ) -> Result<Option<Session>, MacaroonError> {
+        let macaroon = decode_cookie(cookie)?;
+        if !self
+            .verifier
+            .verify_with_context(&macaroon, &self.key, &[], context)?
+        {
+            return Ok(None);
+        }
+        let now = context.time();
+        // Sessions whose first caveat isn't the session's expiry weren't minted here, and aren't
+        // refreshed
+        let caveats: Vec<String> = macaroon
+            .first_party_caveats()
+            .map(|caveat| caveat.predicate())
+            .collect();
+        let expiry = macaroon
+            .caveats()
+            .first()
+            .and_then(|caveat| caveat.as_first_party().ok())
+            .and_then(|caveat| std_caveats::parse_time_before(&caveat.predicate));
+        let refreshed_cookie = match expiry {
+            Some(expiry)
+                if expiry.duration_since(now).unwrap_or_default() < self.refresh_threshold =>
+            {
+                let caveats: Vec<&str> = caveats[1..].iter().map(String::as_str).collect();
+                Some(self.mint_at(macaroon.identifier(), &caveats, now)?)
+            }
+            _ => None,
+        };
+        Ok(Some(Session {
+            id: macaroon.identifier().clone(),
+            refreshed_cookie,
+        }))
+    }
+
+    fn mint_at(
+        &self,
+        id: &str,
+        caveats: &[&str],
+        now: SystemTime,
+    ) -> Result<String, MacaroonError> {
+        let mut macaroon = Macaroon::create(&self.location, &self.root_key, id)?;
+        macaroon.add_first_party_caveat(&std_caveats::time_before(now + self.ttl));
+        for caveat in caveats {
+            macaroon.add_first_party_caveat(caveat);
+        }
+        encode_cookie(&macaroon)
+    }
+}
+
+/// Encode a macaroon as a cookie value
+pub fn encode_cookie(macaroon: &Macaroon) -> Result<String, MacaroonError> {
+    Ok(macaroon.serialize(Format::V2)?.to_base64(URL_SAFE))
+}
+
+/// Decode a cookie value produced by `encode_cookie`
+pub fn decode_cookie(cookie: &str) -> Result<Macaroon, MacaroonError> {
+    Macaroon::deserialize(&base64_field("cookie", cookie)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_cookie, encode_cookie, SessionManager};
+    use crate::{std_caveats, Macaroon, VerifierBuilder, VerifyContext};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn session_manager() -> SessionManager {
+        let mut verifier = VerifierBuilder::new();
+        verifier.satisfy_exact("role = admin");
+        SessionManager::new(
+            "http://example.org/",
+            b"session key",
+            Duration::from_secs(3600),
+            verifier,
+        )
+    }
+
+    fn context_at(secs: u64) -> VerifyContext {
+        let mut context = VerifyContext::new();
+        context.set_time(UNIX_EPOCH + Duration::from_secs(secs));
+        context
+    }
+
+    #[test]
+    fn test_cookie_round_trip() {
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "session").unwrap();
+        macaroon.add_first_party_caveat("role = admin");
+        let cookie = encode_cookie(&macaroon).unwrap();
+        assert!(cookie
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(macaroon, decode_cookie(&cookie).unwrap());
+        assert!(decode_cookie("not a cookie").is_err());
+    }
+
+    #[test]
+    fn test_session_lifecycle() {
+        let manager = session_manager();
+        let start = 1_483_228_800;
+        let cookie = manager
+            .mint_at(
+                "alice",
+                &["role = admin"],
+                UNIX_EPOCH + Duration::from_secs(start),
+            )
+            .unwrap();
+
+        // Early in its lifetime, the session is valid as-is
+        let session = manager
+            .verify(&cookie, &context_at(start + 60))
+            .unwrap()
+            .unwrap();
+        assert_eq!("alice", session.id());
+        assert_eq!(None, session.refreshed_cookie());
+
+        // Later on, it is refreshed, and the new cookie outlives the old one
+        let session = manager
+            .verify(&cookie, &context_at(start + 3000))
+            .unwrap()
+            .unwrap();
+        let refreshed = session.refreshed_cookie().unwrap();
+        let macaroon = decode_cookie(refreshed).unwrap();
+        assert_eq!(
+            vec![
+                &std_caveats::time_before(UNIX_EPOCH + Duration::from_secs(start + 6600)),
+                "role = admin"
+            ],
+            macaroon
+                .first_party_caveats()
+                .map(|caveat| caveat.predicate())
+                .collect::<Vec<String>>()
+        );
+        assert_eq!(
+            None,
+            manager.verify(&cookie, &context_at(start + 3600)).unwrap()
+        );
+        assert!(manager
+            .verify(refreshed, &context_at(start + 3600))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_session_attenuated_expiry() {
+        let manager = session_manager();
+        let start = 1_483_228_800;
+        let cookie = manager
+            .mint_at(
+                "alice",
+                &["role = admin"],
+                UNIX_EPOCH + Duration::from_secs(start),
+            )
+            .unwrap();
+
+        // The holder narrows the session to 55 minutes before handing it on
+        let mut macaroon = decode_cookie(&cookie).unwrap();
+        let narrowed = std_caveats::time_before(UNIX_EPOCH + Duration::from_secs(start + 3300));
+        macaroon.add_first_party_caveat(&narrowed);
+        let cookie = encode_cookie(&macaroon).unwrap();
+
+        // Refreshing replaces the session's own expiry, but keeps the narrower one
+        let session = manager
+            .verify(&cookie, &context_at(start + 3000))
+            .unwrap()
+            .unwrap();
+        let refreshed = session.refreshed_cookie().unwrap();
+        assert_eq!(
+            vec![
+                &std_caveats::time_before(UNIX_EPOCH + Duration::from_secs(start + 6600)),
+                "role = admin",
+                &narrowed
+            ],
+            decode_cookie(refreshed)
+                .unwrap()
+                .first_party_caveats()
+                .map(|caveat| caveat.predicate())
+                .collect::<Vec<String>>()
+        );
+        assert!(manager
+            .verify(refreshed, &context_at(start + 3200))
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            None,
+            manager
+                .verify(refreshed, &context_at(start + 3300))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_session_rejected() {
+        let mut manager = session_manager();
+        manager.set_refresh_threshold(Duration::from_secs(0));
+        let now = std_caveats::now();
+        let cookie = manager.mint("bob", &["role = guest"]).unwrap();
+        let mut context = VerifyContext::new();
+        context.set_time(now);
+        assert_eq!(None, manager.verify(&cookie, &context).unwrap());
+
+        let other = SessionManager::new(
+            "http://example.org/",
+            b"other key",
+            Duration::from_secs(3600),
+            VerifierBuilder::new(),
+        );
+        let cookie = other.mint("bob", &[]).unwrap();
+        assert_eq!(None, manager.verify(&cookie, &context).unwrap());
+    }
+}
@@ -0,0 +1,95 @@
+//! Computing the root signature outside the process
+//!
+//! Minting a macaroon starts with an HMAC of its identifier keyed with the root key. Everything
+//! after that (adding caveats, binding discharges) only needs the signature so far, so a
+//! `Signer` can compute that first HMAC wherever the root key is kept, such as a KMS or an HSM
+//! holding an HMAC-SHA256 key, and the key never enters the process. `MacaroonKey` is the
+//! in-process implementation.
+//!
+//! The signer must use the key as `Macaroon::create_with_key` does, that is the derived key
+//! (see `MacaroonKey`), for the macaroons to verify.
+use crate::{crypto, error::MacaroonError, MacaroonKey, Signature};
+
+/// Computes the first signature of a macaroon, from its identifier
+///
+/// Signers are shared by everything minting with them, so must be thread-safe.
+pub trait Signer: Send + Sync {
+    /// The HMAC-SHA256 of the identifier, keyed with the root key
+    ///
+    /// # Errors
+    /// Whatever error the signer gives when it can't sign, such as `MacaroonError::Io` when a
+    /// remote signer can't be reached.
+    fn sign(&self, identifier: &str) -> Result<Signature, MacaroonError>;
+}
+
+impl Signer for MacaroonKey {
+    fn sign(&self, identifier: &str) -> Result<Signature, MacaroonError> {
+        Ok(Signature(crypto::generate_signature(&self.0, identifier)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Signer;
+    use crate::{error::MacaroonError, Macaroon, MacaroonKey, Signature, Verifier};
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Stands in for a KMS, which holds the key and only hands out HMACs
+    struct Remote {
+        key: MacaroonKey,
+        calls: AtomicUsize,
+        available: bool,
+    }
+
+    impl Signer for Remote {
+        fn sign(&self, identifier: &str) -> Result<Signature, MacaroonError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match self.available {
+                true => self.key.sign(identifier),
+                false => Err(MacaroonError::Io(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "KMS unavailable",
+                ))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_signer() {
+        let key = MacaroonKey::generate(b"root key");
+        let remote = Remote {
+            key,
+            calls: AtomicUsize::new(0),
+            available: true,
+        };
+        let mut macaroon =
+            Macaroon::create_with_signer("http://example.org/", &remote, "keyid").unwrap();
+        assert_eq!(
+            Macaroon::create("http://example.org/", b"root key", "keyid").unwrap(),
+            macaroon
+        );
+        // Only the first signature is remote
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        assert_eq!(1, remote.calls.load(Ordering::SeqCst));
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        macaroon.bind(&mut discharge);
+        let mut builder = Verifier::builder();
+        builder.satisfy_exact("account = 3735928559");
+        assert!(builder
+            .build()
+            .verify(&macaroon, &key, &[discharge])
+            .unwrap());
+
+        let unavailable = Remote {
+            available: false,
+            ..remote
+        };
+        assert!(matches!(
+            Macaroon::create_with_signer("http://example.org/", &unavailable, "keyid"),
+            Err(MacaroonError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,910 @@
+//! Standard first-party caveats
+//!
+//! These follow the conventions used by go-macaroon-bakery and pymacaroons, so macaroons minted
+//! with them can be verified by those libraries and vice versa. Each caveat has a constructor
+//! which returns the predicate to pass to `Macaroon::add_first_party_caveat`, and a checker
+//! which can be passed to `VerifierBuilder::satisfy_general`.
+use crate::{migration::condition_name, Macaroon, VerifyContext};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::randombytes::randombytes;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Condition name for expiry caveats (see `time_before`)
+pub const TIME_BEFORE: &str = "time-before";
+
+/// Condition name for issue time caveats (see `issued_at`)
+pub const ISSUED_AT: &str = "issued-at";
+
+/// Condition name for nonce caveats (see `nonce`)
+pub const NONCE: &str = "nonce";
+
+/// Condition name for declared attribute caveats (see `declared`)
+pub const DECLARED: &str = "declared";
+
+/// Condition name for caveats restricting the permitted operations (see `allow`)
+pub const ALLOW: &str = "allow";
+
+/// Condition name for caveats forbidding operations (see `deny`)
+pub const DENY: &str = "deny";
+
+/// Condition name for caveats binding a macaroon to a request body (see `body_sha256`)
+pub const BODY_SHA256: &str = "body-sha256";
+
+/// Condition name for caveats confining a macaroon to a client address (see `client_ip_addr`)
+pub const CLIENT_IP_ADDR: &str = "client-ip-addr";
+
+/// Condition name for caveats confining a macaroon to a client network (see `client_cidr`)
+pub const CLIENT_CIDR: &str = "client-cidr";
+
+/// Condition name for caveats binding a macaroon to a TLS connection (see `tls_exporter`)
+pub const TLS_EXPORTER: &str = "tls-exporter";
+
+/// Condition name for caveats binding a macaroon to a client certificate (see
+/// `tls_client_cert`)
+pub const TLS_CLIENT_CERT: &str = "tls-client-cert-sha256";
+
+/// Prefix of vendor-specific experimental caveats (see `experimental`)
+pub const EXPERIMENTAL_PREFIX: &str = "x-";
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Create an expiry caveat predicate, e.g. `time-before 2030-01-01T00:00:00Z`
+///
+/// The caveat is satisfied if verification takes place before the given time. Sub-second
+/// precision is dropped, so the macaroon may expire up to a second early.
+pub fn time_before(time: SystemTime) -> String {
+    format!("{} {}", TIME_BEFORE, format_timestamp(time))
+}
+
+/// Parse the expiry time from a `time-before` predicate
+///
+/// Returns `None` if the predicate isn't a `time-before` caveat or its time is malformed.
+pub fn parse_time_before(predicate: &str) -> Option<SystemTime> {
+    if condition_name(predicate) != TIME_BEFORE {
+        return None;
+    }
+    parse_timestamp(predicate[TIME_BEFORE.len()..].trim_start())
+}
+
+/// Checker for `time-before` caveats, using the time of the request (see `VerifyContext::time`)
+pub fn check_time_before(predicate: &str, context: &VerifyContext) -> bool {
+    match parse_time_before(predicate) {
+        Some(expiry) => context.time() < expiry,
+        None => false,
+    }
+}
+
+/// Create an issue time caveat predicate, e.g. `issued-at 2017-01-01T00:00:00Z`
+///
+/// This records when the macaroon was minted, for `VerifierBuilder::set_max_age`. Since anyone
+/// holding a macaroon can add caveats to it, only the earliest issue time counts.
+pub fn issued_at(time: SystemTime) -> String {
+    format!("{} {}", ISSUED_AT, format_timestamp(time))
+}
+
+/// Parse the issue time from an `issued-at` predicate
+///
+/// Returns `None` if the predicate isn't an `issued-at` caveat or its time is malformed.
+pub fn parse_issued_at(predicate: &str) -> Option<SystemTime> {
+    if condition_name(predicate) != ISSUED_AT {
+        return None;
+    }
+    parse_timestamp(predicate[ISSUED_AT.len()..].trim_start())
+}
+
+/// Create a nonce caveat predicate with 16 random bytes, e.g.
+/// `nonce 6f1ed002ab5595859014ebf0951522d9`
+///
+/// A nonce makes each macaroon minted unique, even when it's minted with the same identifier
+/// and caveats at the same second as another, so that it can be told apart in logs or revoked on
+/// its own. Verifiers only satisfy nonce caveats if told to, with
+/// `VerifierBuilder::satisfy_nonces`.
+pub fn nonce() -> String {
+    let hex: String = randombytes(16)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{} {}", NONCE, hex)
+}
+
+/// Parse the nonce from a `nonce` predicate
+///
+/// Returns `None` if the predicate isn't a `nonce` caveat or has no nonce.
+pub fn parse_nonce(predicate: &str) -> Option<&str> {
+    if condition_name(predicate) != NONCE {
+        return None;
+    }
+    match predicate[NONCE.len()..].trim_start() {
+        "" => None,
+        nonce => Some(nonce),
+    }
+}
+
+/// Create a declared attribute caveat predicate, e.g. `declared username alice`
+///
+/// Declarations are used by third parties to pass authenticated attributes (such as a user
+/// name) back to the target service in discharge macaroons. The key must not contain spaces;
+/// the value may contain anything. See `Verifier::verify_declarations` for how they are
+/// verified.
+pub fn declared(key: &str, value: &str) -> String {
+    format!("{} {} {}", DECLARED, key, value)
+}
+
+/// Parse the key and value from a `declared` predicate
+///
+/// Returns `None` if the predicate isn't a well-formed `declared` caveat.
+pub fn parse_declared(predicate: &str) -> Option<(&str, &str)> {
+    if condition_name(predicate) != DECLARED {
+        return None;
+    }
+    let declaration = predicate[DECLARED.len()..].strip_prefix(' ')?;
+    match declaration.find(' ') {
+        Some(index) if index > 0 => Some((&declaration[..index], &declaration[index + 1..])),
+        _ => None,
+    }
+}
+
+/// Create a caveat predicate allowing only the given operations, e.g. `allow read write`
+///
+/// The caveat is satisfied only if every operation being attempted is in the list. Operation
+/// names must not contain spaces.
+pub fn allow(operations: &[&str]) -> String {
+    operations_predicate(ALLOW, operations)
+}
+
+/// Create a caveat predicate forbidding the given operations, e.g. `deny delete`
+///
+/// The caveat is satisfied only if none of the operations being attempted is in the list.
+/// Operation names must not contain spaces.
+pub fn deny(operations: &[&str]) -> String {
+    operations_predicate(DENY, operations)
+}
+
+fn operations_predicate(condition: &str, operations: &[&str]) -> String {
+    let mut predicate = String::from(condition);
+    for operation in operations {
+        predicate.push(' ');
+        predicate.push_str(operation);
+    }
+    predicate
+}
+
+/// Check an `allow` or `deny` caveat against the operations being attempted
+///
+/// Returns `None` if the predicate isn't an `allow` or `deny` caveat.
+pub fn check_operations(predicate: &str, attempted: &[String]) -> Option<bool> {
+    let condition = condition_name(predicate);
+    let mut operations = predicate[condition.len()..]
+        .split(' ')
+        .filter(|op| !op.is_empty());
+    match condition {
+        ALLOW => Some(
+            !attempted.is_empty()
+                && attempted
+                    .iter()
+                    .all(|attempt| operations.clone().any(|op| op == attempt)),
+        ),
+        DENY => Some(!operations.any(|op| attempted.iter().any(|attempt| op == attempt))),
+        _ => None,
+    }
+}
+
+/// Create a caveat predicate binding a macaroon to a request body, e.g. `body-sha256 <hex>`
+///
+/// The caveat is satisfied only if the SHA-256 digest of the body of the request (see
+/// `VerifyContext::set_body`) matches that of `body`. This makes upload or download
+/// authorizations which can't be replayed with different content. Nothing stops them being
+/// replayed with the same content, so they aren't single-use unless combined with a nonce
+/// checked against a replay store.
+pub fn body_sha256(body: &[u8]) -> String {
+    let sha256::Digest(digest) = sha256::hash(body);
+    digest_predicate(BODY_SHA256, &digest)
+}
+
+/// Parse the digest from a `body-sha256` predicate
+///
+/// Returns `None` if the predicate isn't a `body-sha256` caveat or its digest is malformed.
+pub fn parse_body_sha256(predicate: &str) -> Option<[u8; 32]> {
+    parse_digest_predicate(BODY_SHA256, predicate)
+}
+
+/// Checker for `body-sha256` caveats, using the body digest from the context
+///
+/// Fails if the context doesn't have a body digest.
+pub fn check_body_sha256(predicate: &str, context: &VerifyContext) -> bool {
+    match (parse_body_sha256(predicate), context.body_sha256()) {
+        (Some(expected), Some(actual)) => expected == actual,
+        _ => false,
+    }
+}
+
+/// Create a caveat predicate binding a macaroon to a TLS connection, e.g. `tls-exporter <hex>`
+///
+/// `exporter` is keying material exported from the TLS session the macaroon is handed over on
+/// (RFC 5705, or RFC 9266's `EXPORTER-Channel-Binding` label for TLS 1.3), so the caveat is
+/// satisfied only on that connection (see `VerifyContext::set_tls_exporter`), and a macaroon
+/// stolen from it can't be replayed on another. Only the SHA-256 digest of the exporter value
+/// goes in the caveat.
+pub fn tls_exporter(exporter: &[u8]) -> String {
+    let sha256::Digest(digest) = sha256::hash(exporter);
+    digest_predicate(TLS_EXPORTER, &digest)
+}
+
+/// Parse the exporter digest from a `tls-exporter` predicate
+///
+/// Returns `None` if the predicate isn't a `tls-exporter` caveat or its digest is malformed.
+pub fn parse_tls_exporter(predicate: &str) -> Option<[u8; 32]> {
+    parse_digest_predicate(TLS_EXPORTER, predicate)
+}
+
+/// Checker for `tls-exporter` caveats, using the exporter value from the context
+///
+/// Fails if the context doesn't have an exporter value.
+pub fn check_tls_exporter(predicate: &str, context: &VerifyContext) -> bool {
+    match (parse_tls_exporter(predicate), context.tls_exporter_sha256()) {
+        (Some(expected), Some(actual)) => expected == actual,
+        _ => false,
+    }
+}
+
+/// Create a caveat predicate binding a macaroon to a client certificate, e.g.
+/// `tls-client-cert-sha256 <hex>`
+///
+/// `fingerprint` is the SHA-256 digest of the DER encoding of the certificate, as reported by
+/// `openssl x509 -fingerprint -sha256` or passed on by a TLS-terminating proxy. The caveat is
+/// satisfied only if the request was made with the same certificate (see
+/// `VerifyContext::set_client_cert_sha256`), so a stolen macaroon is useless without the
+/// certificate's private key.
+pub fn tls_client_cert(fingerprint: [u8; 32]) -> String {
+    digest_predicate(TLS_CLIENT_CERT, &fingerprint)
+}
+
+/// Parse the fingerprint from a `tls-client-cert-sha256` predicate
+///
+/// Returns `None` if the predicate isn't a `tls-client-cert-sha256` caveat or its fingerprint is
+/// malformed.
+pub fn parse_tls_client_cert(predicate: &str) -> Option<[u8; 32]> {
+    parse_digest_predicate(TLS_CLIENT_CERT, predicate)
+}
+
+/// Checker for `tls-client-cert-sha256` caveats, using the certificate fingerprint from the
+/// context
+///
+/// Fails if the context doesn't have a client certificate.
+pub fn check_tls_client_cert(predicate: &str, context: &VerifyContext) -> bool {
+    match (
+        parse_tls_client_cert(predicate),
+        context.client_cert_sha256(),
+    ) {
+        (Some(expected), Some(actual)) => expected == actual,
+        _ => false,
+    }
+}
+
+/// Check a TLS channel binding caveat (`tls-exporter` or `tls-client-cert-sha256`)
+///
+/// Returns `None` if the predicate isn't a channel binding caveat. `Verifier` checks these
+/// caveats itself, without a checker being registered.
+pub fn check_channel_binding(predicate: &str, context: &VerifyContext) -> Option<bool> {
+    match condition_name(predicate) {
+        TLS_EXPORTER => Some(check_tls_exporter(predicate, context)),
+        TLS_CLIENT_CERT => Some(check_tls_client_cert(predicate, context)),
+        _ => None,
+    }
+}
+
+/// Create a caveat predicate confining a macaroon to a client address, e.g.
+/// `client-ip-addr 192.0.2.1`
+///
+/// The caveat is satisfied only if the peer address of the request (see
+/// `VerifyContext::set_peer_addr`) is the given address. IPv4 peers connecting over IPv6, with
+/// IPv4-mapped addresses (`::ffff:192.0.2.1`), match the IPv4 address.
+pub fn client_ip_addr(addr: IpAddr) -> String {
+    format!("{} {}", CLIENT_IP_ADDR, addr)
+}
+
+/// Parse the address from a `client-ip-addr` predicate
+///
+/// Returns `None` if the predicate isn't a `client-ip-addr` caveat or its address is malformed.
+pub fn parse_client_ip_addr(predicate: &str) -> Option<IpAddr> {
+    if condition_name(predicate) != CLIENT_IP_ADDR {
+        return None;
+    }
+    predicate[CLIENT_IP_ADDR.len()..]
+        .strip_prefix(' ')?
+        .parse()
+        .ok()
+}
+
+/// Checker for `client-ip-addr` caveats, using the peer address from the context
+///
+/// Fails if the context doesn't have a peer address.
+pub fn check_client_ip_addr(predicate: &str, context: &VerifyContext) -> bool {
+    match (parse_client_ip_addr(predicate), context.peer_addr()) {
+        (Some(expected), Some(actual)) => unmap(expected) == unmap(actual),
+        _ => false,
+    }
+}
+
+/// Create a caveat predicate confining a macaroon to a client network, e.g.
+/// `client-cidr 192.0.2.0/24` or `client-cidr 2001:db8::/32`
+///
+/// The caveat is satisfied only if the peer address of the request is in the network given by
+/// the address and prefix length. As with `client_ip_addr`, IPv4-mapped IPv6 peers match IPv4
+/// networks (but IPv4 peers don't match IPv6 networks). Bits of the address beyond the prefix
+/// are ignored.
+pub fn client_cidr(addr: IpAddr, prefix_len: u8) -> String {
+    format!("{} {}/{}", CLIENT_CIDR, addr, prefix_len)
+}
+
+/// Parse the network address and prefix length from a `client-cidr` predicate
+///
+/// Returns `None` if the predicate isn't a `client-cidr` caveat, or its address is malformed or
+/// its prefix length is too long for the address.
+pub fn parse_client_cidr(predicate: &str) -> Option<(IpAddr, u8)> {
+    if condition_name(predicate) != CLIENT_CIDR {
+        return None;
+    }
+    let cidr = predicate[CLIENT_CIDR.len()..].strip_prefix(' ')?;
+    let (addr, prefix_len) = cidr.split_at(cidr.find('/')?);
+    let addr: IpAddr = addr.parse().ok()?;
+    let prefix_len = &prefix_len[1..];
+    if prefix_len.is_empty() || !prefix_len.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let prefix_len: u8 = prefix_len.parse().ok()?;
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix_len > max_len {
+        return None;
+    }
+    Some((addr, prefix_len))
+}
+
+/// Checker for `client-cidr` caveats, using the peer address from the context
+///
+/// Fails if the context doesn't have a peer address.
+pub fn check_client_cidr(predicate: &str, context: &VerifyContext) -> bool {
+    match (parse_client_cidr(predicate), context.peer_addr()) {
+        (Some((network, prefix_len)), Some(peer)) => in_network(network, prefix_len, peer),
+        _ => false,
+    }
+}
+
+// A predicate with a SHA-256 digest in hex as its argument
+fn digest_predicate(name: &str, digest: &[u8; 32]) -> String {
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{} {}", name, hex)
+}
+
+fn parse_digest_predicate(name: &str, predicate: &str) -> Option<[u8; 32]> {
+    if condition_name(predicate) != name {
+        return None;
+    }
+    let hex = predicate[name.len()..].strip_prefix(' ')?.as_bytes();
+    // `from_str_radix` would take a sign, so `+a` for `0a`
+    if hex.len() != 64 || !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let mut digest = [0; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.chunks(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(digest)
+}
+
+// IPv4-mapped IPv6 addresses as the IPv4 addresses they are
+fn unmap(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => addr,
+        },
+        v4 => v4,
+    }
+}
+
+fn in_network(network: IpAddr, prefix_len: u8, addr: IpAddr) -> bool {
+    // Comparing as 128-bit numbers, with IPv4 addresses in the top 32 bits, so that the same
+    // mask works for both
+    let bits = |addr: IpAddr| match addr {
+        IpAddr::V4(v4) => u128::from(u32::from(v4)) << 96,
+        IpAddr::V6(v6) => u128::from(v6),
+    };
+    let addr = if network.is_ipv4() { unmap(addr) } else { addr };
+    if network.is_ipv4() != addr.is_ipv4() {
+        return false;
+    }
+    let mask = match prefix_len {
+        0 => 0,
+        len => u128::MAX << (128 - u32::from(len)),
+    };
+    bits(network) & mask == bits(addr) & mask
+}
+
+/// Create a vendor-specific experimental caveat predicate, e.g. `x-acme:region eu-west`
+///
+/// Experimental caveats have condition names of the form `x-<vendor>:<name>`, so they can never
+/// collide with standard caveats or those of other vendors. They are verified by checkers
+/// registered for the vendor with `VerifierBuilder::register_experimental`. The vendor and name must
+/// not contain spaces, and the vendor must not contain `:`.
+pub fn experimental(vendor: &str, name: &str, args: &str) -> String {
+    if args.is_empty() {
+        format!("{}{}:{}", EXPERIMENTAL_PREFIX, vendor, name)
+    } else {
+        format!("{}{}:{} {}", EXPERIMENTAL_PREFIX, vendor, name, args)
+    }
+}
+
+/// Returns the vendor of an experimental caveat
+///
+/// Returns `None` if the predicate isn't an experimental caveat.
+pub fn experimental_vendor(predicate: &str) -> Option<&str> {
+    let condition = condition_name(predicate).strip_prefix(EXPERIMENTAL_PREFIX)?;
+    match condition.find(':') {
+        Some(index) if index > 0 && index < condition.len() - 1 => Some(&condition[..index]),
+        _ => None,
+    }
+}
+
+/// Returns the earliest expiry time of any macaroon in a stack
+///
+/// The stack is the root macaroon plus its discharge macaroons, in any order. The root and its
+/// discharges are only usable until the earliest of their `time-before` caveats, so this is
+/// useful for setting cookie or cache lifetimes. Returns `None` if none of them expire.
+pub fn stack_expiry(stack: &[Macaroon]) -> Option<SystemTime> {
+    stack.iter().filter_map(|macaroon| macaroon.expiry()).min()
+}
+
+pub(crate) fn now() -> SystemTime {
+    SystemTime::now()
+}
+
+/// Format a time as an RFC 3339 timestamp in UTC, to the second (`2030-01-01T00:00:00Z`)
+pub fn format_timestamp(time: SystemTime) -> String {
+    let seconds = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(error) => -(error.duration().as_secs_f64().ceil() as i64),
+    };
+    let days = seconds.div_euclid(SECONDS_PER_DAY);
+    let seconds = seconds.rem_euclid(SECONDS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Parse an RFC 3339 timestamp, such as `2030-01-01T00:00:00Z` or
+/// `2029-12-31T19:00:00.5-05:00`
+pub fn parse_timestamp(timestamp: &str) -> Option<SystemTime> {
+    let bytes = timestamp.as_bytes();
+    if !timestamp.is_ascii()
+        || bytes.len() < 20
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !(bytes[10] == b'T' || bytes[10] == b't')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
+    }
+    let year = parse_digits(&timestamp[0..4])?;
+    let month = parse_digits(&timestamp[5..7])?;
+    let day = parse_digits(&timestamp[8..10])?;
+    let hour = parse_digits(&timestamp[11..13])?;
+    let minute = parse_digits(&timestamp[14..16])?;
+    let second = parse_digits(&timestamp[17..19])?;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let mut rest = &timestamp[19..];
+    let mut nanos: u32 = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(fraction.len());
+        if len == 0 {
+            return None;
+        }
+        for (i, digit) in fraction[..len.min(9)].bytes().enumerate() {
+            nanos += u32::from(digit - b'0') * 10u32.pow(8 - i as u32);
+        }
+        rest = &fraction[len..];
+    }
+    let offset: i64 = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let bytes = rest.as_bytes();
+            if bytes.len() != 6 || bytes[3] != b':' {
+                return None;
+            }
+            let sign = match bytes[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let offset_hours = parse_digits(&rest[1..3])?;
+            let offset_minutes = parse_digits(&rest[4..6])?;
+            if offset_hours > 23 || offset_minutes > 59 {
+                return None;
+            }
+            sign * (offset_hours * 3600 + offset_minutes * 60)
+        }
+    };
+
+    let seconds =
+        days_from_civil(year, month, day) * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second
+            - offset;
+    if seconds >= 0 {
+        UNIX_EPOCH.checked_add(Duration::new(seconds as u64, nanos))
+    } else {
+        UNIX_EPOCH
+            .checked_sub(Duration::from_secs(seconds.unsigned_abs()))?
+            .checked_add(Duration::new(0, nanos))
+    }
+}
+
+fn parse_digits(digits: &str) -> Option<i64> {
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar - see
+// http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        allow, body_sha256, check_body_sha256, check_channel_binding, check_client_cidr,
+        check_client_ip_addr, check_operations, check_time_before, check_tls_client_cert,
+        check_tls_exporter, client_cidr, client_ip_addr, declared, deny, experimental,
+        experimental_vendor, format_timestamp, issued_at, nonce, parse_body_sha256,
+        parse_client_cidr, parse_client_ip_addr, parse_declared, parse_issued_at, parse_nonce,
+        parse_time_before, parse_timestamp, parse_tls_client_cert, parse_tls_exporter,
+        stack_expiry, time_before, tls_client_cert, tls_exporter,
+    };
+    use crate::{Macaroon, VerifyContext};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!("1970-01-01T00:00:00Z", format_timestamp(UNIX_EPOCH));
+        assert_eq!(
+            "2017-01-01T00:00:00Z",
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(1_483_228_800))
+        );
+        assert_eq!(
+            "2000-02-29T23:59:59Z",
+            format_timestamp(UNIX_EPOCH + Duration::from_millis(951_868_799_500))
+        );
+        assert_eq!(
+            "1969-12-31T23:59:59Z",
+            format_timestamp(UNIX_EPOCH - Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_483_228_800);
+        assert_eq!(Some(time), parse_timestamp("2017-01-01T00:00:00Z"));
+        assert_eq!(Some(time), parse_timestamp("2016-12-31T19:00:00-05:00"));
+        assert_eq!(Some(time), parse_timestamp("2017-01-01T01:30:00+01:30"));
+        assert_eq!(
+            Some(time + Duration::from_millis(250)),
+            parse_timestamp("2017-01-01T00:00:00.250Z")
+        );
+        assert_eq!(
+            Some(UNIX_EPOCH - Duration::from_secs(86_400)),
+            parse_timestamp("1969-12-31T00:00:00Z")
+        );
+        assert_eq!(None, parse_timestamp("2017-01-01T00:00:00"));
+        assert_eq!(None, parse_timestamp("2017-02-29T00:00:00Z"));
+        assert_eq!(None, parse_timestamp("2017-01-01T24:00:00Z"));
+        assert_eq!(None, parse_timestamp("2017-01-01T00:00:00.Z"));
+        assert_eq!(None, parse_timestamp("2017-01-01 00:00:00Z"));
+        assert_eq!(None, parse_timestamp("+017-01-01T00:00:00Z"));
+        assert_eq!(None, parse_timestamp("2017-01-01T00:00:0\u{e9}Z"));
+    }
+
+    #[test]
+    fn test_time_before() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_483_228_800);
+        let predicate = time_before(time);
+        assert_eq!("time-before 2017-01-01T00:00:00Z", predicate);
+        assert_eq!(Some(time), parse_time_before(&predicate));
+        assert_eq!(None, parse_time_before("time-beforex 2017-01-01T00:00:00Z"));
+        assert_eq!(None, parse_time_before("account = 12345678"));
+        let context = VerifyContext::new();
+        assert!(!check_time_before(&predicate, &context));
+        assert!(check_time_before(
+            &time_before(SystemTime::now() + Duration::from_secs(60)),
+            &context
+        ));
+        assert!(!check_time_before("account = 12345678", &context));
+
+        let mut context = VerifyContext::new();
+        context.set_time(time - Duration::from_secs(1));
+        assert!(check_time_before(&predicate, &context));
+        context.set_time(time);
+        assert!(!check_time_before(&predicate, &context));
+    }
+
+    #[test]
+    fn test_issued_at() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_483_228_800);
+        let predicate = issued_at(time);
+        assert_eq!("issued-at 2017-01-01T00:00:00Z", predicate);
+        assert_eq!(Some(time), parse_issued_at(&predicate));
+        assert_eq!(None, parse_issued_at(&time_before(time)));
+        assert_eq!(None, parse_issued_at("issued-at yesterday"));
+    }
+
+    #[test]
+    fn test_nonce() {
+        let predicate = nonce();
+        let value = parse_nonce(&predicate).unwrap();
+        assert_eq!(32, value.len());
+        assert!(value.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_ne!(predicate, nonce());
+        assert_eq!(None, parse_nonce("nonce"));
+        assert_eq!(None, parse_nonce("nonces 1234"));
+    }
+
+    #[test]
+    fn test_body_sha256() {
+        let predicate = body_sha256(b"hello");
+        assert_eq!(
+            "body-sha256 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            predicate
+        );
+        assert_eq!(
+            Some(sodiumoxide::crypto::hash::sha256::hash(b"hello").0),
+            parse_body_sha256(&predicate)
+        );
+        assert_eq!(None, parse_body_sha256("body-sha256 2cf24dba"));
+        assert_eq!(None, parse_body_sha256(&predicate.replace('2', "g")));
+        assert_eq!(None, parse_body_sha256(&predicate.replace('c', "\u{e9}")));
+        assert_eq!(None, parse_body_sha256(&predicate.replacen("2c", "+c", 1)));
+
+        let mut context = VerifyContext::new();
+        assert!(!check_body_sha256(&predicate, &context));
+        context.set_body(b"hello");
+        assert!(check_body_sha256(&predicate, &context));
+        context.set_body(b"goodbye");
+        assert!(!check_body_sha256(&predicate, &context));
+        assert!(!check_body_sha256("account = 12345678", &context));
+    }
+
+    #[test]
+    fn test_tls_exporter() {
+        let predicate = tls_exporter(b"exported keying material");
+        assert_eq!(
+            Some(sodiumoxide::crypto::hash::sha256::hash(b"exported keying material").0),
+            parse_tls_exporter(&predicate)
+        );
+        assert_eq!(
+            None,
+            parse_tls_exporter(&body_sha256(b"exported keying material"))
+        );
+        // A sign isn't a hex digit
+        let signed = format!("tls-exporter +{}", &predicate["tls-exporter ".len() + 1..]);
+        assert_eq!(None, parse_tls_exporter(&signed));
+
+        let mut context = VerifyContext::new();
+        assert!(!check_tls_exporter(&predicate, &context));
+        context.set_tls_exporter(b"exported keying material");
+        assert!(check_tls_exporter(&predicate, &context));
+        assert_eq!(Some(true), check_channel_binding(&predicate, &context));
+        context.set_tls_exporter(b"other keying material");
+        assert!(!check_tls_exporter(&predicate, &context));
+        assert_eq!(None, check_channel_binding("account = 12345678", &context));
+    }
+
+    #[test]
+    fn test_tls_client_cert() {
+        let predicate = tls_client_cert([0xab; 32]);
+        assert_eq!(
+            format!("tls-client-cert-sha256 {}", "ab".repeat(32)),
+            predicate
+        );
+        assert_eq!(Some([0xab; 32]), parse_tls_client_cert(&predicate));
+        assert_eq!(None, parse_tls_client_cert("tls-client-cert-sha256 abab"));
+
+        let mut context = VerifyContext::new();
+        assert!(!check_tls_client_cert(&predicate, &context));
+        context.set_client_cert_sha256([0xab; 32]);
+        assert!(check_tls_client_cert(&predicate, &context));
+        assert_eq!(Some(true), check_channel_binding(&predicate, &context));
+        context.set_client_cert_sha256([0xcd; 32]);
+        assert_eq!(Some(false), check_channel_binding(&predicate, &context));
+    }
+
+    #[test]
+    fn test_client_ip_addr() {
+        let predicate = client_ip_addr("192.0.2.1".parse().unwrap());
+        assert_eq!("client-ip-addr 192.0.2.1", predicate);
+        assert_eq!(
+            Some("192.0.2.1".parse().unwrap()),
+            parse_client_ip_addr(&predicate)
+        );
+        assert_eq!(None, parse_client_ip_addr("client-ip-addr 192.0.2"));
+
+        let mut context = VerifyContext::new();
+        assert!(!check_client_ip_addr(&predicate, &context));
+        for (peer, expected) in [
+            ("192.0.2.1", true),
+            ("::ffff:192.0.2.1", true),
+            ("192.0.2.2", false),
+            ("::1", false),
+        ] {
+            context.set_peer_addr(peer.parse().unwrap());
+            assert_eq!(
+                expected,
+                check_client_ip_addr(&predicate, &context),
+                "{}",
+                peer
+            );
+        }
+        context.set_peer_addr("2001:db8::1".parse().unwrap());
+        assert!(check_client_ip_addr(
+            "client-ip-addr 2001:db8:0:0::1",
+            &context
+        ));
+    }
+
+    #[test]
+    fn test_client_cidr() {
+        let predicate = client_cidr("192.0.2.0".parse().unwrap(), 24);
+        assert_eq!("client-cidr 192.0.2.0/24", predicate);
+        assert_eq!(
+            Some(("192.0.2.0".parse().unwrap(), 24)),
+            parse_client_cidr(&predicate)
+        );
+        for malformed in &[
+            "client-cidr 192.0.2.0",
+            "client-cidr 192.0.2.0/",
+            "client-cidr 192.0.2.0/33",
+            "client-cidr 192.0.2.0/+24",
+            "client-cidr 2001:db8::/129",
+        ] {
+            assert_eq!(None, parse_client_cidr(malformed), "{}", malformed);
+        }
+
+        let mut context = VerifyContext::new();
+        assert!(!check_client_cidr(&predicate, &context));
+        for (cidr, peer, expected) in [
+            ("192.0.2.0/24", "192.0.2.200", true),
+            ("192.0.2.0/24", "::ffff:192.0.2.200", true),
+            ("192.0.2.0/24", "192.0.3.1", false),
+            ("192.0.2.77/24", "192.0.2.1", true),
+            ("0.0.0.0/0", "203.0.113.9", true),
+            ("0.0.0.0/0", "2001:db8::1", false),
+            ("192.0.2.1/32", "192.0.2.1", true),
+            ("2001:db8::/32", "2001:db8:ffff::1", true),
+            ("2001:db8::/32", "2001:db9::1", false),
+            ("2001:db8::/32", "192.0.2.1", false),
+            ("::/0", "::1", true),
+            ("2001:db8::1/128", "2001:db8::1", true),
+        ] {
+            context.set_peer_addr(peer.parse().unwrap());
+            let predicate = format!("client-cidr {}", cidr);
+            assert_eq!(
+                expected,
+                check_client_cidr(&predicate, &context),
+                "{} {}",
+                cidr,
+                peer
+            );
+        }
+    }
+
+    #[test]
+    fn test_declared() {
+        let predicate = declared("username", "alice smith");
+        assert_eq!("declared username alice smith", predicate);
+        assert_eq!(
+            Some(("username", "alice smith")),
+            parse_declared(&predicate)
+        );
+        assert_eq!(Some(("username", "")), parse_declared("declared username "));
+        assert_eq!(None, parse_declared("declared username"));
+        assert_eq!(None, parse_declared("declared  alice"));
+        assert_eq!(None, parse_declared("username = alice"));
+    }
+
+    #[test]
+    fn test_allow_deny() {
+        let allow_read = allow(&["read", "list"]);
+        assert_eq!("allow read list", allow_read);
+        let deny_delete = deny(&["delete"]);
+        assert_eq!("deny delete", deny_delete);
+
+        let read = vec![String::from("read")];
+        let read_delete = vec![String::from("read"), String::from("delete")];
+        assert_eq!(Some(true), check_operations(&allow_read, &read));
+        assert_eq!(Some(false), check_operations(&allow_read, &read_delete));
+        assert_eq!(Some(false), check_operations(&allow_read, &[]));
+        assert_eq!(Some(true), check_operations(&deny_delete, &read));
+        assert_eq!(Some(false), check_operations(&deny_delete, &read_delete));
+        assert_eq!(Some(true), check_operations(&deny_delete, &[]));
+        assert_eq!(Some(false), check_operations("allow", &read));
+        assert_eq!(None, check_operations("allowed read", &read));
+    }
+
+    #[test]
+    fn test_experimental() {
+        let predicate = experimental("acme", "region", "eu-west");
+        assert_eq!("x-acme:region eu-west", predicate);
+        assert_eq!(Some("acme"), experimental_vendor(&predicate));
+        assert_eq!("x-acme:beta", experimental("acme", "beta", ""));
+        assert_eq!(Some("acme"), experimental_vendor("x-acme:beta"));
+        assert_eq!(None, experimental_vendor("x-acme region"));
+        assert_eq!(None, experimental_vendor("x-:region"));
+        assert_eq!(None, experimental_vendor("x-acme: region"));
+        assert_eq!(None, experimental_vendor("acme:region"));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let earlier = UNIX_EPOCH + Duration::from_secs(1_483_228_800);
+        let later = earlier + Duration::from_secs(3600);
+        let mut macaroon = Macaroon::create("http://example.org/", b"key", "keyid").unwrap();
+        assert_eq!(None, macaroon.expiry());
+        macaroon.add_first_party_caveat(&time_before(later));
+        macaroon.add_first_party_caveat("account = 12345678");
+        assert_eq!(Some(later), macaroon.expiry());
+
+        let mut discharge = Macaroon::create("http://auth.mybank/", b"key", "caveat").unwrap();
+        assert_eq!(
+            Some(later),
+            stack_expiry(&[macaroon.clone(), discharge.clone()])
+        );
+        discharge.add_first_party_caveat(&time_before(earlier));
+        assert_eq!(Some(earlier), stack_expiry(&[macaroon, discharge]));
+        assert_eq!(None, stack_expiry(&[]));
+    }
+}
@@ -0,0 +1,206 @@
+//! Archives of serialized macaroons
+//!
+//! A token archive is a sequence of records, each of which is a serialized macaroon (in any
+//! format) preceded by its length as a 4-byte big-endian integer. Archives can be scanned in place
+//! (e.g. from a memory-mapped file) with `scan` or `scan_raw`, or streamed with `read_token`, so
+//! none of them requires loading the whole archive into memory.
+use crate::{error::MacaroonError, macaroon_ref::MacaroonRef, serialization::Format, Macaroon};
+use std::io::{Read, Write};
+
+const LENGTH_SIZE: usize = 4;
+
+/// Append a macaroon to an archive
+pub fn write_token<W: Write>(
+    writer: &mut W,
+    macaroon: &Macaroon,
+    format: Format,
+) -> Result<(), MacaroonError> {
+    let serialized = macaroon.serialize(format)?;
+    if serialized.len() > u32::MAX as usize {
+        return Err(MacaroonError::BadMacaroon("Serialized macaroon too large"));
+    }
+    writer.write_all(&(serialized.len() as u32).to_be_bytes())?;
+    writer.write_all(&serialized)?;
+    Ok(())
+}
+
+/// Read the next serialized macaroon from an archive
+///
+/// Returns `Ok(None)` at the end of the archive. If the archive is truncated, the offset in the
+/// error is relative to the start of the entry being read.
+pub fn read_token<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, MacaroonError> {
+    let mut length = [0; LENGTH_SIZE];
+    let mut read = 0;
+    while read < LENGTH_SIZE {
+        match reader.read(&mut length[read..])? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(truncated(read)),
+            count => read += count,
+        }
+    }
+    let mut token = Vec::new();
+    let length = u64::from(u32::from_be_bytes(length));
+    let read = reader.take(length).read_to_end(&mut token)?;
+    if read as u64 != length {
+        return Err(truncated(LENGTH_SIZE + read));
+    }
+    Ok(Some(token))
+}
+
+/// Iterate over the macaroons in an archive held in memory
+///
+/// Each macaroon is parsed in place with `MacaroonRef::deserialize`, borrowing from the archive,
+/// so this works well with memory-mapped files. That only parses version 2 macaroons, so use
+/// `scan_raw` for archives written in other formats. A token which doesn't parse gives an error
+/// and the scan goes on to the next one, but iteration stops if the archive is truncated.
+pub fn scan(data: &[u8]) -> TokenScanner<'_> {
+    TokenScanner {
+        tokens: scan_raw(data),
+    }
+}
+
+/// Iterator returned by `scan`
+pub struct TokenScanner<'a> {
+    tokens: RawTokenScanner<'a>,
+}
+
+impl<'a> Iterator for TokenScanner<'a> {
+    type Item = Result<MacaroonRef<'a>, MacaroonError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.tokens
+            .next()
+            .map(|token| token.and_then(MacaroonRef::deserialize))
+    }
+}
+
+/// Iterate over the serialized macaroons in an archive held in memory, without parsing them
+///
+/// Each token is a slice of the archive, in whatever format it was written, to be deserialized
+/// with `Macaroon::deserialize`. Iteration stops after the first error.
+pub fn scan_raw(data: &[u8]) -> RawTokenScanner<'_> {
+    RawTokenScanner {
+        data,
+        offset: 0,
+        failed: false,
+    }
+}
+
+/// Iterator returned by `scan_raw`
+pub struct RawTokenScanner<'a> {
+    data: &'a [u8],
+    offset: usize,
+    failed: bool,
+}
+
+impl<'a> Iterator for RawTokenScanner<'a> {
+    type Item = Result<&'a [u8], MacaroonError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() || self.failed {
+            return None;
+        }
+        let token = match self.data.get(..LENGTH_SIZE) {
+            Some(length) => {
+                let mut bytes = [0; LENGTH_SIZE];
+                bytes.copy_from_slice(length);
+                let length = u32::from_be_bytes(bytes) as usize;
+                self.data
+                    .get(LENGTH_SIZE..)
+                    .and_then(|rest| rest.get(..length))
+            }
+            None => None,
+        };
+        match token {
+            Some(token) => {
+                self.data = &self.data[LENGTH_SIZE + token.len()..];
+                self.offset += LENGTH_SIZE + token.len();
+                Some(Ok(token))
+            }
+            None => {
+                self.failed = true;
+                Some(Err(truncated(self.offset + self.data.len())))
+            }
+        }
+    }
+}
+
+fn truncated(offset: usize) -> MacaroonError {
+    MacaroonError::Truncated { offset }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_token, scan, scan_raw, write_token};
+    use crate::{serialization::Format, Macaroon};
+
+    fn archive() -> (Vec<Macaroon>, Vec<u8>) {
+        let mut macaroons = Vec::new();
+        let mut data = Vec::new();
+        for i in 0..3 {
+            let mut macaroon =
+                Macaroon::create("http://example.org/", b"key", &format!("keyid {}", i)).unwrap();
+            macaroon.add_first_party_caveat(&format!("account = {}", i));
+            write_token(&mut data, &macaroon, Format::V2).unwrap();
+            macaroons.push(macaroon);
+        }
+        (macaroons, data)
+    }
+
+    #[test]
+    fn test_scan() {
+        let (macaroons, data) = archive();
+        let scanned: Vec<Macaroon> = scan(&data)
+            .map(|macaroon| macaroon.unwrap().to_owned().unwrap())
+            .collect();
+        assert_eq!(macaroons, scanned);
+    }
+
+    #[test]
+    fn test_scan_raw() {
+        let (macaroons, mut data) = archive();
+        // A token in a format which can't be parsed in place (as far as `scan` is concerned)
+        data.extend_from_slice(&5u32.to_be_bytes());
+        data.extend_from_slice(b"hello");
+        write_token(&mut data, &macaroons[0], Format::V2).unwrap();
+
+        let tokens: Vec<&[u8]> = scan_raw(&data).map(|token| token.unwrap()).collect();
+        assert_eq!(5, tokens.len());
+        assert_eq!(b"hello", tokens[3]);
+        assert_eq!(macaroons[0], Macaroon::deserialize(tokens[4]).unwrap());
+        // scan goes on past what it can't parse
+        let results: Vec<_> = scan(&data).collect();
+        assert_eq!(5, results.len());
+        assert!(results[3].is_err());
+        assert_eq!(
+            macaroons[0],
+            results[4].as_ref().unwrap().to_owned().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_scan_truncated() {
+        let (_, data) = archive();
+        let results: Vec<_> = scan(&data[..data.len() - 1]).collect();
+        assert_eq!(3, results.len());
+        assert!(results[1].is_ok());
+        assert!(results[2].is_err());
+        assert_eq!(1, scan(&data[..2]).count());
+    }
+
+    #[test]
+    fn test_read_token() {
+        let (macaroons, data) = archive();
+        let mut reader = data.as_slice();
+        for macaroon in macaroons {
+            let token = read_token(&mut reader).unwrap().unwrap();
+            assert_eq!(macaroon, Macaroon::deserialize(&token).unwrap());
+        }
+        assert!(read_token(&mut reader).unwrap().is_none());
+
+        let mut truncated = &data[..data.len() - 1];
+        read_token(&mut truncated).unwrap();
+        read_token(&mut truncated).unwrap();
+        assert!(read_token(&mut truncated).is_err());
+    }
+}
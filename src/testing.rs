@@ -0,0 +1,377 @@
+//! Utilities for testing code which mints and verifies macaroons
+//!
+//! This module is only available with the `test-util` feature, and is intended to be used as a
+//! dev-dependency only. It provides fixed key fixtures, a controllable clock whose time can be
+//! passed to verification in a `VerifyContext`, and helpers for minting macaroons which have
+//! already expired or are about to, so expiry handling can be tested without sleeping. `DeterministicNonces` likewise adds reproducible third-party caveats, so
+//! that serialized macaroons can be compared with golden files.
+//!
+//! For property tests, `arbitrary_macaroon` (also `Macaroon`'s `proptest::arbitrary::Arbitrary`
+//! implementation, for `any::<Macaroon>()`) generates macaroons with random mixes of first and
+//! third-party caveats, so crates can test their own serialization and storage layers with
+//! realistic macaroons.
+use crate::{
+    error::MacaroonError, inspect, std_caveats, Macaroon, MacaroonKey, VerifyContext, NONCE_LEN,
+};
+use proptest::prelude::*;
+use sodiumoxide::crypto::hash::sha256;
+use std::cell::Cell;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Root key fixture, as passed to `Macaroon::create`
+pub const ROOT_KEY: &[u8] = b"this is the testing root key";
+
+/// Third-party caveat key fixture, as passed to `Macaroon::add_third_party_caveat`
+pub const THIRD_PARTY_KEY: &[u8] = b"this is the testing third-party key";
+
+/// Location used for macaroons minted by this module
+pub const LOCATION: &str = "http://example.org/";
+
+/// Location of the third party in `discharged_stack`
+pub const THIRD_PARTY_LOCATION: &str = "http://auth.mybank/";
+
+/// The derived key for `ROOT_KEY`, as used to verify macaroons minted by this module
+pub fn root_key() -> MacaroonKey {
+    MacaroonKey::generate(ROOT_KEY)
+}
+
+/// A controllable clock
+///
+/// Verification takes the time from the `VerifyContext`, so to verify at the clock's time, pass
+/// `MockClock::context` (or set the time of a context of your own to `MockClock::now`).
+#[derive(Debug)]
+pub struct MockClock {
+    time: Cell<SystemTime>,
+}
+
+impl MockClock {
+    /// Create a clock set to the given time
+    pub fn set(time: SystemTime) -> MockClock {
+        MockClock {
+            time: Cell::new(time),
+        }
+    }
+
+    /// Returns the clock's current time
+    pub fn now(&self) -> SystemTime {
+        self.time.get()
+    }
+
+    /// Move the clock forward
+    pub fn advance(&self, duration: Duration) {
+        self.time.set(self.time.get() + duration);
+    }
+
+    /// A verification context with the clock's current time
+    pub fn context(&self) -> VerifyContext {
+        let mut context = VerifyContext::new();
+        context.set_time(self.now());
+        context
+    }
+}
+
+/// A seeded source of nonces for encrypting third-party caveat keys
+///
+/// Third-party caveats added with `DeterministicNonces::add_third_party_caveat` use the nonces
+/// it generates, so that the same sequence of calls produces the same macaroons.
+/// `Macaroon::add_third_party_caveat` itself always uses random nonces. The nonces are derived
+/// from the seed by hashing, so different seeds give unrelated sequences.
+///
+/// Since the nonces are predictable, this must never be used outside tests.
+#[derive(Clone, Debug)]
+pub struct DeterministicNonces {
+    seed: u64,
+    // The number of nonces generated so far
+    count: u64,
+}
+
+impl DeterministicNonces {
+    /// Start generating nonces from the given seed
+    pub fn seed(seed: u64) -> DeterministicNonces {
+        DeterministicNonces { seed, count: 0 }
+    }
+
+    /// The next nonce in the sequence
+    pub fn next_nonce(&mut self) -> [u8; NONCE_LEN] {
+        let mut input = [0; 16];
+        input[..8].copy_from_slice(&self.seed.to_be_bytes());
+        input[8..].copy_from_slice(&self.count.to_be_bytes());
+        self.count += 1;
+        let sha256::Digest(digest) = sha256::hash(&input);
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&digest[..NONCE_LEN]);
+        nonce
+    }
+
+    /// Add a third-party caveat to a macaroon, as `Macaroon::add_third_party_caveat`, using the
+    /// next nonce in the sequence
+    pub fn add_third_party_caveat(
+        &mut self,
+        macaroon: &mut Macaroon,
+        location: &str,
+        key: &[u8],
+        id: &str,
+    ) {
+        macaroon.add_third_party_caveat_with_nonce(location, key, id, self.next_nonce());
+    }
+}
+
+/// A macaroon signed with `ROOT_KEY`, followed by a discharge bound to it
+///
+/// The macaroon has the first-party caveat `account = 3735928559` and a third-party caveat
+/// for `THIRD_PARTY_LOCATION` with the identifier `bank caveat`, which the discharge (minted
+/// with `THIRD_PARTY_KEY`) satisfies, so the stack verifies against `root_key()`.
+pub fn discharged_stack() -> Vec<Macaroon> {
+    let mut macaroon = Macaroon::create(LOCATION, ROOT_KEY, "root").unwrap();
+    macaroon.add_first_party_caveat("account = 3735928559");
+    macaroon.add_third_party_caveat(THIRD_PARTY_LOCATION, THIRD_PARTY_KEY, "bank caveat");
+    let mut discharge =
+        Macaroon::create(THIRD_PARTY_LOCATION, THIRD_PARTY_KEY, "bank caveat").unwrap();
+    macaroon.bind(&mut discharge);
+    vec![macaroon, discharge]
+}
+
+/// Mint a macaroon signed with `ROOT_KEY` which expires `ttl` after `now`
+pub fn mint_expiring(
+    identifier: &str,
+    now: SystemTime,
+    ttl: Duration,
+) -> Result<Macaroon, MacaroonError> {
+    let mut macaroon = Macaroon::create(LOCATION, ROOT_KEY, identifier)?;
+    macaroon.add_first_party_caveat(&std_caveats::time_before(now + ttl));
+    Ok(macaroon)
+}
+
+/// Mint a macaroon signed with `ROOT_KEY` which expired `age` before `now`
+pub fn mint_expired(
+    identifier: &str,
+    now: SystemTime,
+    age: Duration,
+) -> Result<Macaroon, MacaroonError> {
+    let mut macaroon = Macaroon::create(LOCATION, ROOT_KEY, identifier)?;
+    macaroon.add_first_party_caveat(&std_caveats::time_before(now - age));
+    Ok(macaroon)
+}
+
+/// Assert that a macaroon has the expected structure
+///
+/// The expected snapshot is in the format given by `inspect::snapshot`, and on failure the
+/// panic message is a line diff between it and the macaroon's actual snapshot (see
+/// `inspect::diff_snapshot`).
+#[track_caller]
+pub fn assert_snapshot(macaroon: &Macaroon, expected: &str) {
+    if let Some(diff) = inspect::diff_snapshot(macaroon, expected) {
+        panic!(
+            "Macaroon doesn't match snapshot (- expected, + actual):\n{}",
+            diff
+        );
+    }
+}
+
+/// A strategy for first-party caveat predicates
+///
+/// Predicates are a mix of the usual `account = ...` and `user = ...` kinds, `time <`
+/// expiries (see `std_caveats`), and arbitrary text.
+pub fn arbitrary_predicate() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "account = [0-9]{1,10}",
+        "user = [a-z]{1,12}",
+        (0u64..4_102_444_800)
+            .prop_map(|secs| { std_caveats::time_before(UNIX_EPOCH + Duration::from_secs(secs)) }),
+        "\\PC{0,64}",
+    ]
+}
+
+// A caveat to add: a first-party predicate, or a third-party location and id
+#[derive(Clone, Debug)]
+enum CaveatSpec {
+    FirstParty(String),
+    ThirdParty(String, String),
+}
+
+fn arbitrary_caveat() -> impl Strategy<Value = CaveatSpec> {
+    prop_oneof![
+        3 => arbitrary_predicate().prop_map(CaveatSpec::FirstParty),
+        1 => ("https://[a-z]{1,12}\\.example/", "[ -~]{1,32}")
+            .prop_map(|(location, id)| CaveatSpec::ThirdParty(location, id)),
+    ]
+}
+
+/// A strategy for macaroons signed with `ROOT_KEY`
+///
+/// Each has a location, a printable identifier and up to 8 caveats. Third-party
+/// caveats use `THIRD_PARTY_KEY`, so discharges can be minted for them, and their nonces come
+/// from a generated `DeterministicNonces` seed, so the same case always gives the same
+/// macaroon.
+pub fn arbitrary_macaroon() -> impl Strategy<Value = Macaroon> {
+    (
+        "https://[a-z]{1,12}\\.example/",
+        "[ -~]{1,32}",
+        proptest::collection::vec(arbitrary_caveat(), 0..=8),
+        any::<u64>(),
+    )
+        .prop_map(|(location, identifier, caveats, seed)| {
+            let mut nonces = DeterministicNonces::seed(seed);
+            let mut macaroon = Macaroon::create(&location, ROOT_KEY, &identifier).unwrap();
+            for caveat in caveats {
+                match caveat {
+                    CaveatSpec::FirstParty(predicate) => {
+                        macaroon.add_first_party_caveat(&predicate)
+                    }
+                    CaveatSpec::ThirdParty(location, id) => nonces.add_third_party_caveat(
+                        &mut macaroon,
+                        &location,
+                        THIRD_PARTY_KEY,
+                        &id,
+                    ),
+                }
+            }
+            macaroon
+        })
+}
+
+impl Arbitrary for Macaroon {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Macaroon>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        arbitrary_macaroon().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        arbitrary_macaroon, assert_snapshot, mint_expired, mint_expiring, root_key,
+        DeterministicNonces, MockClock, LOCATION, ROOT_KEY, THIRD_PARTY_KEY,
+    };
+    use crate::{std_caveats, Format, Macaroon, VerifierBuilder};
+    use proptest::prelude::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_mock_clock() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_483_228_800);
+        let clock = MockClock::set(time);
+        assert_eq!(time, clock.now());
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(time + Duration::from_secs(60), clock.now());
+        assert_eq!(time + Duration::from_secs(60), clock.context().time());
+
+        // The clock only affects the contexts it makes, not the standard checkers
+        let predicate = std_caveats::time_before(time + Duration::from_secs(30));
+        assert!(!std_caveats::check_time_before(
+            &predicate,
+            &clock.context()
+        ));
+        assert!(!std_caveats::check_time_before(
+            &predicate,
+            &crate::VerifyContext::new()
+        ));
+    }
+
+    #[test]
+    fn test_deterministic_nonces() {
+        let mint = |mut nonces: DeterministicNonces| {
+            let mut macaroon = Macaroon::create(LOCATION, ROOT_KEY, "keyid").unwrap();
+            for id in ["first", "second"] {
+                nonces.add_third_party_caveat(
+                    &mut macaroon,
+                    "http://auth.mybank/",
+                    THIRD_PARTY_KEY,
+                    id,
+                );
+            }
+            macaroon.serialize(Format::V2).unwrap()
+        };
+        let seeded = mint(DeterministicNonces::seed(1));
+        assert_eq!(seeded, mint(DeterministicNonces::seed(1)));
+        assert_ne!(seeded, mint(DeterministicNonces::seed(2)));
+
+        // The two caveats don't share a nonce
+        let mut nonces = DeterministicNonces::seed(1);
+        assert_ne!(nonces.next_nonce(), nonces.next_nonce());
+
+        // Nor do caveats added as usual pick up the sequence
+        let _nonces = DeterministicNonces::seed(1);
+        let mut random = Macaroon::create(LOCATION, ROOT_KEY, "keyid").unwrap();
+        random.add_third_party_caveat("http://auth.mybank/", THIRD_PARTY_KEY, "first");
+        random.add_third_party_caveat("http://auth.mybank/", THIRD_PARTY_KEY, "second");
+        assert_ne!(seeded, random.serialize(Format::V2).unwrap());
+
+        let macaroon = Macaroon::deserialize(&seeded).unwrap();
+        let mut discharges = Vec::new();
+        for id in &["first", "second"] {
+            let mut discharge =
+                Macaroon::create("http://auth.mybank/", THIRD_PARTY_KEY, id).unwrap();
+            macaroon.bind(&mut discharge);
+            discharges.push(discharge);
+        }
+        assert!(VerifierBuilder::new()
+            .build()
+            .verify(&macaroon, &root_key(), &discharges)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_mint_expiring() {
+        let clock = MockClock::set(UNIX_EPOCH + Duration::from_secs(1_483_228_800));
+        let macaroon = mint_expiring("keyid", clock.now(), Duration::from_secs(60)).unwrap();
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_general(std_caveats::check_time_before);
+        let verifier = builder.build();
+        assert!(verifier
+            .verify_with_context(&macaroon, &root_key(), &[], &clock.context())
+            .unwrap());
+        clock.advance(Duration::from_secs(60));
+        assert!(!verifier
+            .verify_with_context(&macaroon, &root_key(), &[], &clock.context())
+            .unwrap());
+        // Verified with the system clock, it expired long ago
+        assert!(!verifier.verify(&macaroon, &root_key(), &[]).unwrap());
+    }
+
+    #[test]
+    fn test_assert_snapshot() {
+        let macaroon = mint_expiring("keyid", SystemTime::now(), Duration::from_secs(60)).unwrap();
+        let expiry = std_caveats::time_before(macaroon.expiry().unwrap());
+        assert_snapshot(
+            &macaroon,
+            &format!(
+                "format 1\nlocation http://example.org/\nidentifier keyid\ncid {}\nsignature ...",
+                expiry
+            ),
+        );
+        let result =
+            panic::catch_unwind(AssertUnwindSafe(|| assert_snapshot(&macaroon, "format 1")));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_mint_expired() {
+        let macaroon = mint_expired("keyid", SystemTime::now(), Duration::from_secs(1)).unwrap();
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_general(std_caveats::check_time_before);
+        let verifier = builder.build();
+        assert!(!verifier.verify(&macaroon, &root_key(), &[]).unwrap());
+    }
+
+    proptest! {
+        #[test]
+        fn test_arbitrary_macaroon(macaroon in arbitrary_macaroon()) {
+            prop_assert!(macaroon.verify_signature(root_key().as_ref()));
+            let formats = [
+                Format::V2,
+                #[cfg(feature = "v1")]
+                Format::V1,
+                #[cfg(feature = "v2j")]
+                Format::V2J,
+            ];
+            for format in formats {
+                let serialized = macaroon.serialize(format).unwrap();
+                prop_assert_eq!(&macaroon, &Macaroon::deserialize(&serialized).unwrap());
+            }
+        }
+    }
+}
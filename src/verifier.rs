@@ -1,244 +1,3607 @@
-use crate::{caveat, crypto, error::MacaroonError, Macaroon};
+use crate::{
+    caveat, crypto,
+    crypto::{BindingMode, MacaroonKey, Signature},
+    error::MacaroonError,
+    limits, location,
+    metrics::{FailureReason, Metrics},
+    observer::{VerificationEvent, VerificationObserver},
+    predicate,
+    predicate::Canonicalizer,
+    redaction,
+    revocation::RevocationChecker,
+    std_caveats, Macaroon,
+};
+use sodiumoxide::crypto::hash::sha256;
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::net::IpAddr;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
 
-/// Type of callback for `Verifier::satisfy_general()`
-pub type VerifierCallback = fn(&str) -> bool;
+// Root keys personalized with the verifier's domain are kept for reuse, up to this many (they're
+// the service's own keys, so there are rarely more than a few)
+const MAX_PERSONALIZED_KEYS: usize = 64;
 
-/// Verifier struct
+/// Type of callback for `VerifierBuilder::satisfy_general()`
+///
+/// The callback is passed the predicate being verified and the context of the request.
+pub type VerifierCallback = fn(&str, &VerifyContext) -> bool;
+
+/// A check of a caveat's predicate, which can be combined with others
+///
+/// Checkers are built up from exact predicates and general callbacks, and combined with `any`
+/// and `all` to any depth, so that a policy such as "an admin, or both a team member and a
+/// project member" can be written out rather than buried in a single callback. They're added to
+/// a verifier with `VerifierBuilder::satisfy_any`, `satisfy_all` or `satisfy_checker`.
+///
+/// Combinations are evaluated over all of a macaroon's first-party caveats, the same way
+/// `Verifier::all_of` and `any_of` combine whole verifiers: `all` is only met if each of its
+/// checkers satisfies one of the caveats, and then satisfies each caveat any of its checkers
+/// does. So "team = core" and "project = macaroons" can be separate caveats, and neither is
+/// satisfied without the other.
+#[derive(Clone, Debug)]
+pub enum Checker {
+    /// Satisfied by exactly this predicate
+    Exact(String),
+    /// Satisfied by predicates the callback accepts
+    General(VerifierCallback),
+    /// Satisfied by comparisons of the key (see the `predicate` module) which hold for the
+    /// value
+    KeyValue { key: String, value: String },
+    /// Satisfied by predicates the regular expression matches in full
+    #[cfg(feature = "regex")]
+    Pattern(regex::Regex),
+    /// Satisfies a caveat any of the checkers satisfies (so none if there are none)
+    Any(Vec<Checker>),
+    /// Satisfies a caveat any of the checkers satisfies, provided every one of them satisfies
+    /// one of the macaroon's caveats (so none if there are none)
+    All(Vec<Checker>),
+}
+
+impl Checker {
+    /// Combine checkers so that any one of them satisfies a caveat
+    pub fn any<I, C>(checkers: I) -> Checker
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<Checker>,
+    {
+        Checker::Any(checkers.into_iter().map(Into::into).collect())
+    }
+
+    /// Combine checkers so that all of them must satisfy one of the macaroon's caveats
+    pub fn all<I, C>(checkers: I) -> Checker
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<Checker>,
+    {
+        Checker::All(checkers.into_iter().map(Into::into).collect())
+    }
+
+    /// Check a predicate, as a macaroon's only caveat
+    pub fn check(&self, predicate: &str, context: &VerifyContext) -> bool {
+        self.check_in(predicate, &[predicate], context)
+    }
+
+    /// Check a predicate, which is one of the macaroon's first-party caveats
+    pub fn check_in(&self, predicate: &str, caveats: &[&str], context: &VerifyContext) -> bool {
+        self.satisfies(predicate, caveats, context, None)
+    }
+
+    /// Whether the caveats between them meet the checker: for `all`, whether each of its
+    /// checkers satisfies one of them
+    pub fn is_met(&self, caveats: &[&str], context: &VerifyContext) -> bool {
+        self.met(caveats, context, None)
+    }
+
+    // Whether this combines other checkers, and so needs the macaroon's caveats
+    fn is_compound(&self) -> bool {
+        matches!(self, Checker::Any(_) | Checker::All(_))
+    }
+
+    // With a canonicalizer, the predicate has already been canonicalized, but the caveats
+    // haven't
+    fn satisfies(
+        &self,
+        predicate: &str,
+        caveats: &[&str],
+        context: &VerifyContext,
+        canonicalizer: Option<Canonicalizer>,
+    ) -> bool {
+        let satisfies = |c: &Checker| c.satisfies(predicate, caveats, context, canonicalizer);
+        match self {
+            Checker::Any(checkers) => checkers.iter().any(satisfies),
+            Checker::All(checkers) => {
+                self.met(caveats, context, canonicalizer) && checkers.iter().any(satisfies)
+            }
+            _ => self.check_leaf(predicate, context, canonicalizer),
+        }
+    }
+
+    fn met(
+        &self,
+        caveats: &[&str],
+        context: &VerifyContext,
+        canonicalizer: Option<Canonicalizer>,
+    ) -> bool {
+        let met = |c: &Checker| c.met(caveats, context, canonicalizer);
+        match self {
+            Checker::Any(checkers) => checkers.iter().any(met),
+            Checker::All(checkers) => !checkers.is_empty() && checkers.iter().all(met),
+            _ => caveats.iter().any(|caveat| match canonicalizer {
+                Some(canonicalize) => {
+                    self.check_leaf(&canonicalize(caveat), context, canonicalizer)
+                }
+                None => self.check_leaf(caveat, context, None),
+            }),
+        }
+    }
+
+    // Exact predicates are canonicalized to compare with the (canonical) predicate
+    fn check_leaf(
+        &self,
+        predicate: &str,
+        context: &VerifyContext,
+        canonicalizer: Option<Canonicalizer>,
+    ) -> bool {
+        match self {
+            Checker::Exact(expected) => match canonicalizer {
+                Some(canonicalize) => canonicalize(expected) == predicate,
+                None => expected == predicate,
+            },
+            Checker::General(callback) => callback(predicate, context),
+            Checker::KeyValue { key, value } => match predicate::parse_comparison(predicate) {
+                Some(comparison) => comparison.key == key && comparison.holds_for(value),
+                None => false,
+            },
+            #[cfg(feature = "regex")]
+            Checker::Pattern(pattern) => pattern.is_match(predicate),
+            Checker::Any(_) | Checker::All(_) => false,
+        }
+    }
+}
+
+impl From<VerifierCallback> for Checker {
+    fn from(callback: VerifierCallback) -> Checker {
+        Checker::General(callback)
+    }
+}
+
+impl From<&str> for Checker {
+    fn from(predicate: &str) -> Checker {
+        Checker::Exact(String::from(predicate))
+    }
+}
+
+/// Type of callback for `VerifierBuilder::set_issued_at_extractor()`
+///
+/// The callback is passed a macaroon's identifier, and returns the time it was issued, if the
+/// identifier records that.
+pub type IssuedAtExtractor = fn(&str) -> Option<SystemTime>;
+
+/// Type of callback for `VerifierBuilder::satisfy_general_async()`
+///
+/// As `VerifierCallback`, but returns a future, for checks which need to do I/O.
+#[cfg(feature = "async")]
+pub type AsyncVerifierCallback =
+    for<'a> fn(&'a str, &'a VerifyContext) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>>;
+
+/// The details of a particular request, against which caveats are verified
+///
+/// A `Verifier` holds what is true of every request (the predicates and checkers it satisfies),
+/// while a `VerifyContext` holds what is specific to one: when it was made, who made it and what
+/// they are trying to do, along with any other values a service's checkers need. The context is
+/// passed by reference to each general checker.
+#[derive(Clone, Debug, Default)]
+pub struct VerifyContext {
+    time: Option<SystemTime>,
+    peer_addr: Option<IpAddr>,
+    operations: Vec<String>,
+    body_sha256: Option<[u8; 32]>,
+    tls_exporter_sha256: Option<[u8; 32]>,
+    client_cert_sha256: Option<[u8; 32]>,
+    values: HashMap<String, String>,
+}
+
+impl VerifyContext {
+    /// Create a new, empty context
+    pub fn new() -> VerifyContext {
+        Default::default()
+    }
+
+    /// Sets the time at which the request was made
+    pub fn set_time(&mut self, time: SystemTime) {
+        self.time = Some(time);
+    }
+
+    /// The time at which the request was made, which is the current time if it hasn't been set
+    pub fn time(&self) -> SystemTime {
+        self.time.unwrap_or_else(std_caveats::now)
+    }
+
+    /// Sets the address of the peer making the request
+    pub fn set_peer_addr(&mut self, peer_addr: IpAddr) {
+        self.peer_addr = Some(peer_addr);
+    }
+
+    /// The address of the peer making the request, if known
+    pub fn peer_addr(&self) -> Option<IpAddr> {
+        self.peer_addr
+    }
+
+    /// Sets the operations being attempted, to check `allow` and `deny` caveats against
+    ///
+    /// Once set, `allow` and `deny` caveats (see `std_caveats::allow`) are checked
+    /// automatically: an `allow` caveat is satisfied if all of these operations are listed in
+    /// it, and a `deny` caveat if none of them are.
+    pub fn set_operations(&mut self, operations: &[&str]) {
+        self.operations = operations.iter().map(|op| String::from(*op)).collect();
+    }
+
+    /// The operations being attempted
+    pub fn operations(&self) -> &[String] {
+        &self.operations
+    }
+
+    /// Sets the body of the request, to check `body-sha256` caveats against
+    ///
+    /// Only the SHA-256 digest of the body is kept (see `std_caveats::body_sha256`).
+    pub fn set_body(&mut self, body: &[u8]) {
+        let sha256::Digest(digest) = sha256::hash(body);
+        self.body_sha256 = Some(digest);
+    }
+
+    /// The SHA-256 digest of the body of the request, if it has been set
+    pub fn body_sha256(&self) -> Option<[u8; 32]> {
+        self.body_sha256
+    }
+
+    /// Sets the keying material exported from the request's TLS connection, to check
+    /// `tls-exporter` caveats against
+    ///
+    /// Only the SHA-256 digest of the value is kept (see `std_caveats::tls_exporter`).
+    pub fn set_tls_exporter(&mut self, exporter: &[u8]) {
+        let sha256::Digest(digest) = sha256::hash(exporter);
+        self.tls_exporter_sha256 = Some(digest);
+    }
+
+    /// The SHA-256 digest of the TLS exporter value, if it has been set
+    pub fn tls_exporter_sha256(&self) -> Option<[u8; 32]> {
+        self.tls_exporter_sha256
+    }
+
+    /// Sets the SHA-256 fingerprint of the client certificate the request was made with, to
+    /// check `tls-client-cert-sha256` caveats against
+    pub fn set_client_cert_sha256(&mut self, fingerprint: [u8; 32]) {
+        self.client_cert_sha256 = Some(fingerprint);
+    }
+
+    /// The SHA-256 fingerprint of the client certificate, if it has been set
+    pub fn client_cert_sha256(&self) -> Option<[u8; 32]> {
+        self.client_cert_sha256
+    }
+
+    /// Sets an application-defined value, replacing any previous value for the key
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.values.insert(String::from(key), String::from(value));
+    }
+
+    /// Returns an application-defined value, if it has been set
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+}
+
+/// What to do about discharge macaroons which weren't needed to verify a macaroon
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnusedDischargePolicy {
+    /// Fail verification with `MacaroonError::DischargeNotUsed` (the default)
+    #[default]
+    Reject,
+    /// Log a warning, but otherwise ignore them
+    Warn,
+}
+
+#[derive(Clone)]
+enum ExperimentalHandler {
+    Check(VerifierCallback),
+    Ignore,
+}
+
+/// Builder for a `Verifier`
 ///
-/// Contains all information and maintains all state for the macaroon
-/// verification process
+/// Everything that is true of every request a service handles (the predicates and checkers it
+/// satisfies, and its policies) is set up here once. The resulting `Verifier` can't be changed,
+/// so it can be shared between threads and used for any number of verifications.
 #[derive(Default)]
+pub struct VerifierBuilder {
+    verifier: Verifier,
+}
+
+impl VerifierBuilder {
+    /// Create a new builder, for a verifier which satisfies nothing
+    pub fn new() -> VerifierBuilder {
+        Default::default()
+    }
+
+    /// Predicate to satisfy a caveat by exact string match
+    pub fn satisfy_exact(&mut self, predicate: &str) {
+        self.verifier.predicates.push(String::from(predicate));
+    }
+
+    /// Provides a callback function used to verify a caveat
+    pub fn satisfy_general(&mut self, callback: VerifierCallback) {
+        self.verifier.callbacks.push(callback);
+    }
+
+    /// Satisfy comparisons of the key which hold for the value
+    ///
+    /// Comparisons are predicates such as `account = 3735928559` or `account < 4000000000`,
+    /// written with any whitespace around the operator (see the `predicate` module for the
+    /// grammar). With the value `3735928559`, both of those would be satisfied, but not
+    /// `account = 1` or `user = alice`.
+    pub fn satisfy_key_value(&mut self, key: &str, value: &str) {
+        self.satisfy_checker(Checker::KeyValue {
+            key: String::from(key),
+            value: String::from(value),
+        });
+    }
+
+    /// Satisfy comparisons of the key which hold for the number, as `satisfy_key_value`
+    pub fn satisfy_number(&mut self, key: &str, value: i64) {
+        self.satisfy_key_value(key, &value.to_string());
+    }
+
+    /// Satisfy predicates which the regular expression matches in full
+    ///
+    /// This is for families of predicates too large to list, such as namespaced feature flags
+    /// (`myapp:feature:[a-z-]+`). The whole predicate has to match, not just part of it, so
+    /// `admin` doesn't satisfy `not admin`.
+    #[cfg(feature = "regex")]
+    pub fn satisfy_pattern(&mut self, pattern: &regex::Regex) {
+        let anchored = regex::Regex::new(&format!("^(?:{})$", pattern.as_str()))
+            .expect("anchoring a valid regular expression keeps it valid");
+        self.satisfy_checker(Checker::Pattern(anchored));
+    }
+
+    /// Satisfy caveats which any of the checkers satisfies
+    ///
+    /// Here a macaroon is authorized for an admin, or for a member of both the team and the
+    /// project, whose caveats are separate:
+    ///
+    /// ```
+    /// # use macaroon::{Checker, Macaroon, MacaroonKey, VerifierBuilder};
+    /// let mut builder = VerifierBuilder::new();
+    /// builder.satisfy_any(vec![
+    ///     Checker::from("role = admin"),
+    ///     Checker::all(["team = core", "project = macaroons"]),
+    /// ]);
+    /// let verifier = builder.build();
+    ///
+    /// let key = MacaroonKey::generate(b"key");
+    /// let mut macaroon = Macaroon::create_with_key("http://example.org/", &key, "keyid").unwrap();
+    /// macaroon.add_first_party_caveat("team = core");
+    /// assert!(!verifier.verify(&macaroon, &key, &[]).unwrap());
+    /// macaroon.add_first_party_caveat("project = macaroons");
+    /// assert!(verifier.verify(&macaroon, &key, &[]).unwrap());
+    /// ```
+    pub fn satisfy_any<I, C>(&mut self, checkers: I)
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<Checker>,
+    {
+        self.satisfy_checker(Checker::any(checkers));
+    }
+
+    /// Satisfy caveats which any of the checkers satisfies, provided every one of them satisfies
+    /// one of the macaroon's caveats
+    pub fn satisfy_all<I, C>(&mut self, checkers: I)
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<Checker>,
+    {
+        self.satisfy_checker(Checker::all(checkers));
+    }
+
+    /// Satisfy caveats which the checker satisfies
+    pub fn satisfy_checker(&mut self, checker: Checker) {
+        self.verifier.checkers.push(checker);
+    }
+
+    /// Route caveats starting with the prefix to the checker, and only to it
+    ///
+    /// A caveat starting with a registered prefix (the longest, if more than one matches) is
+    /// only satisfied by the checkers registered for that prefix, and never by the verifier's
+    /// other predicates and checkers. So `register_prefix("time ", ...)` keeps a checker for
+    /// expiry from seeing, or having to reject, anything else.
+    ///
+    /// Once any prefix is registered, a caveat which doesn't start with one, and which nothing
+    /// else satisfies, fails verification with `MacaroonError::UnknownCaveat`, naming the
+    /// caveat's first word. That tells a forgotten checker apart from a caveat a checker
+    /// rejected.
+    pub fn register_prefix<C: Into<Checker>>(&mut self, prefix: &str, checker: C) {
+        self.verifier
+            .routes
+            .push((String::from(prefix), checker.into()));
+    }
+
+    /// Provides an asynchronous callback function used to verify a caveat
+    ///
+    /// These are only used by `Verifier::verify_async`, and only for caveats which none of the
+    /// other predicates and callbacks satisfy.
+    #[cfg(feature = "async")]
+    pub fn satisfy_general_async(&mut self, callback: AsyncVerifierCallback) {
+        self.verifier.async_callbacks.push(callback);
+    }
+
+    /// Canonicalize predicates before matching them
+    ///
+    /// Both the predicates of the caveats being verified and those passed to `satisfy_exact` are
+    /// canonicalized before being compared, and callbacks are passed the canonical form. The
+    /// caveat signatures are always computed over the predicates as written.
+    pub fn set_canonicalizer(&mut self, canonicalizer: Canonicalizer) {
+        self.verifier.canonicalizer = Some(canonicalizer);
+    }
+
+    /// Sets what to do about discharge macaroons which weren't needed during verification
+    ///
+    /// By default, as with libmacaroons, verification fails if any of the discharge macaroons
+    /// supplied aren't used, since this hides client bugs and can mask token-splicing attacks.
+    pub fn set_unused_discharge_policy(&mut self, policy: UnusedDischargePolicy) {
+        self.verifier.unused_discharge_policy = policy;
+    }
+
+    /// Provides a callback function used to verify a vendor's experimental caveats
+    ///
+    /// All experimental caveats for the vendor (see `std_caveats::experimental`) are passed to
+    /// this callback, and only this callback.
+    pub fn register_experimental(&mut self, vendor: &str, callback: VerifierCallback) {
+        self.verifier
+            .experimental
+            .insert(String::from(vendor), ExperimentalHandler::Check(callback));
+    }
+
+    /// Treat all of a vendor's experimental caveats as satisfied
+    ///
+    /// This is intended for services which have decided, as a matter of policy, that a vendor's
+    /// experimental caveats don't restrict them. Experimental caveats for vendors which are
+    /// neither registered nor ignored are verified like any other caveat.
+    pub fn ignore_experimental(&mut self, vendor: &str) {
+        self.verifier
+            .experimental
+            .insert(String::from(vendor), ExperimentalHandler::Ignore);
+    }
+
+    /// Satisfy `declared` caveats
+    ///
+    /// In this mode, `declared key value` caveats (see `std_caveats::declared`) in the macaroon
+    /// and its discharges are satisfied automatically. The declared attributes can be collected
+    /// with `Verifier::verify_declarations`. If the same key is declared with two different
+    /// values, verification fails.
+    pub fn collect_declarations(&mut self) {
+        self.verifier.collect_declarations = true;
+    }
+
+    /// Satisfy `nonce` caveats
+    ///
+    /// Nonces (see `std_caveats::nonce`) only make each macaroon unique, so services which mint
+    /// them have nothing to check. Services which check nonces themselves, against replay,
+    /// should instead register a checker for them, or reject replayed macaroons with a
+    /// `RevocationChecker`. Without either, nonce caveats fail like any other unknown caveat.
+    pub fn satisfy_nonces(&mut self) {
+        self.verifier.satisfy_nonces = true;
+    }
+
+    /// Reject macaroons issued longer ago than this, whatever their expiry
+    ///
+    /// This protects against issuers which forget to add expiry caveats. The issue time is the
+    /// earliest of the macaroon's `issued-at` caveats (see `std_caveats::issued_at`) and the
+    /// time given by the identifier, if there is an extractor for it (see
+    /// `set_issued_at_extractor`). Macaroons whose issue time can't be found are rejected, as
+    /// are those issued in the future. With this set, `issued-at` caveats are satisfied
+    /// automatically.
+    ///
+    /// Since anyone holding a macaroon can add an `issued-at` caveat to it, the caveat is only a
+    /// reliable record if every macaroon is minted with one. An issue time from the identifier
+    /// doesn't have this problem.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.verifier.max_age = Some(max_age);
+    }
+
+    /// Reject macaroons, the root or any discharge, with more than this many caveats
+    pub fn set_max_caveats(&mut self, max_caveats: usize) {
+        self.verifier.max_caveats = Some(max_caveats);
+    }
+
+    /// Reject discharge macaroons nested more than this deeply
+    ///
+    /// The discharges of the root macaroon's third-party caveats are at depth 1, the discharges
+    /// of their third-party caveats at depth 2, and so on. Discharges can't be used twice in one
+    /// chain, so the depth is already limited by the number of discharges, but this keeps a
+    /// client from making the verifier do all the work of following a long chain.
+    pub fn set_max_discharge_depth(&mut self, max_depth: usize) {
+        self.verifier.max_discharge_depth = Some(max_depth);
+    }
+
+    /// Reject root macaroons whose location isn't this, or another added location
+    ///
+    /// Locations are compared once normalized (see the `location` module), and macaroons
+    /// without one are rejected. With any locations added, discharges must also have the
+    /// location of the third-party caveat they discharge.
+    pub fn add_location(&mut self, location: &str) {
+        self.verifier.locations.push(location::normalize(location));
+    }
+
+    /// Only accept macaroons minted with keys personalized with this domain
+    ///
+    /// Root keys given to `verify` (and the other verification methods) are personalized with
+    /// the domain, as `MacaroonKey::with_domain` does, before checking signatures, so
+    /// macaroons minted with the unpersonalized key, or one personalized for another domain,
+    /// fail verification. Discharges are minted by third parties with caveat keys, which
+    /// aren't personalized. Not interoperable with other implementations of macaroons: see the
+    /// `crypto` module.
+    pub fn set_domain(&mut self, domain: &str) {
+        self.verifier.domain = Some(String::from(domain));
+    }
+
+    /// Expect discharges to be bound to the root macaroon as the binding mode says
+    ///
+    /// This is the libmacaroons binding unless set otherwise, and has to match however the
+    /// client binds its discharges (see `BindingMode`).
+    pub fn set_binding_mode(&mut self, mode: BindingMode) {
+        self.verifier.binding_mode = mode;
+    }
+
+    /// Reject discharge macaroons which don't have a caveat the checker satisfies
+    ///
+    /// This is for policies about what the third parties must put in their discharges, so that
+    /// verification fails if a discharger mints one without the usual constraints. Each call
+    /// adds a requirement, and a discharge must meet all of them. The caveats still have to be
+    /// satisfied by the verifier as usual.
+    pub fn require_discharge_caveat<C: Into<Checker>>(&mut self, checker: C) {
+        self.verifier.discharge_requirements.push(checker.into());
+    }
+
+    /// Reject discharge macaroons which don't expire within this long of the request
+    ///
+    /// The expiry is the earliest of the discharge's `time <` caveats (see
+    /// `std_caveats::time_before`), and discharges without one are rejected. Satisfying the
+    /// caveats still needs `std_caveats::check_time_before`, as usual.
+    pub fn set_max_discharge_lifetime(&mut self, max_lifetime: Duration) {
+        self.verifier.max_discharge_lifetime = Some(max_lifetime);
+    }
+
+    /// Reject macaroons, the root or any discharge, which the checker says have been revoked
+    /// (see the `revocation` module)
+    pub fn set_revocation_checker(&mut self, checker: Arc<dyn RevocationChecker>) {
+        self.verifier.revocation_checker = Some(checker);
+    }
+
+    /// Report each step of verification to the observer (see the `observer` module)
+    pub fn set_observer(&mut self, observer: Arc<dyn VerificationObserver>) {
+        self.verifier.observer = Some(observer);
+    }
+
+    /// Report the outcome of each verification to the metrics (see the `metrics` module)
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.verifier.metrics = Some(metrics);
+    }
+
+    /// Provides a callback used to find the issue time of a macaroon from its identifier, for
+    /// `set_max_age`
+    pub fn set_issued_at_extractor(&mut self, extractor: IssuedAtExtractor) {
+        self.verifier.issued_at_extractor = Some(extractor);
+    }
+
+    /// Adds discharge macaroons to the verifier, to be used by `Macaroon::verify`
+    pub fn add_discharge_macaroons(&mut self, discharge_macaroons: &[Macaroon]) {
+        self.verifier
+            .discharge_macaroons
+            .extend_from_slice(discharge_macaroons);
+    }
+
+    /// Adds discharge macaroons to the verifier, after checking that they're bound to the root
+    /// macaroon (see `Macaroon::check_bindings`)
+    ///
+    /// Nothing is added if any of the discharges doesn't check out.
+    pub fn add_checked_discharge_macaroons(
+        &mut self,
+        root: &Macaroon,
+        key: &MacaroonKey,
+        discharge_macaroons: &[Macaroon],
+    ) -> Result<(), MacaroonError> {
+        root.check_bindings(key, discharge_macaroons)?;
+        self.add_discharge_macaroons(discharge_macaroons);
+        Ok(())
+    }
+
+    /// Build the verifier
+    pub fn build(self) -> Verifier {
+        self.verifier
+    }
+}
+
+/// Verifier struct
+///
+/// Contains all the criteria used to verify macaroons, and is created using a
+/// `VerifierBuilder`. A verifier holds no state from one verification to the next, so one
+/// instance can be shared between threads and reused across requests, with anything specific to
+/// a request passed in a `VerifyContext`.
+///
+/// Verifiers can also be combined with `all_of` and `any_of`, so that, for example, policy
+/// which applies to a whole platform and policy for a single endpoint can be kept separately.
+#[derive(Clone, Default)]
 pub struct Verifier {
     predicates: Vec<String>,
     callbacks: Vec<VerifierCallback>,
+    checkers: Vec<Checker>,
+    routes: Vec<(String, Checker)>,
+    #[cfg(feature = "async")]
+    async_callbacks: Vec<AsyncVerifierCallback>,
+    canonicalizer: Option<Canonicalizer>,
     discharge_macaroons: Vec<Macaroon>,
-    signature: [u8; 32],
-    id_chain: Vec<String>,
+    unused_discharge_policy: UnusedDischargePolicy,
+    experimental: HashMap<String, ExperimentalHandler>,
+    collect_declarations: bool,
+    satisfy_nonces: bool,
+    max_age: Option<Duration>,
+    issued_at_extractor: Option<IssuedAtExtractor>,
+    max_caveats: Option<usize>,
+    max_discharge_depth: Option<usize>,
+    discharge_requirements: Vec<Checker>,
+    max_discharge_lifetime: Option<Duration>,
+    locations: Vec<String>,
+    domain: Option<String>,
+    // Root keys personalized with the domain, by root key
+    personalized: Arc<Mutex<HashMap<[u8; 32], [u8; 32]>>>,
+    binding_mode: BindingMode,
+    revocation_checker: Option<Arc<dyn RevocationChecker>>,
+    observer: Option<Arc<dyn VerificationObserver>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    composition: Option<(Composition, Vec<Verifier>)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Composition {
+    AllOf,
+    AnyOf,
 }
 
-impl Verifier {
-    /// Create a new Verifier
-    pub fn new() -> Verifier {
-        Default::default()
+impl Verifier {
+    /// Create a builder for a verifier
+    pub fn builder() -> VerifierBuilder {
+        VerifierBuilder::new()
+    }
+
+    /// Combine verifiers so that a macaroon is only authorized if every one of them authorizes
+    /// it, with each caveat satisfied by any one of them
+    ///
+    /// Each verifier verifies the whole macaroon, with all of its policies (maximum age,
+    /// locations, revocation, discharge requirements and so on) and experimental handlers, but
+    /// a caveat it doesn't satisfy itself is still satisfied if another of the verifiers
+    /// satisfies it. So a platform-wide verifier which checks expiry and an endpoint's verifier
+    /// which checks accounts together authorize a macaroon with both kinds of caveat, while
+    /// caveats neither of them knows about still fail. An empty list authorizes nothing.
+    ///
+    /// The combined verifier has no settings of its own: anything set on it with a builder is
+    /// ignored, other than its asynchronous callbacks.
+    pub fn all_of<I>(verifiers: I) -> Verifier
+    where
+        I: IntoIterator<Item = Verifier>,
+    {
+        Verifier::compose(Composition::AllOf, verifiers)
+    }
+
+    /// Combine verifiers so that a macaroon is authorized if any one of them authorizes it on
+    /// its own
+    ///
+    /// Each verifier verifies the whole macaroon, with all of its policies, in turn, until one
+    /// authorizes it. If none do, the result (or error) is the first verifier's. An empty list
+    /// authorizes nothing.
+    pub fn any_of<I>(verifiers: I) -> Verifier
+    where
+        I: IntoIterator<Item = Verifier>,
+    {
+        Verifier::compose(Composition::AnyOf, verifiers)
+    }
+
+    fn compose<I>(composition: Composition, verifiers: I) -> Verifier
+    where
+        I: IntoIterator<Item = Verifier>,
+    {
+        Verifier {
+            composition: Some((composition, verifiers.into_iter().collect())),
+            ..Default::default()
+        }
+    }
+
+    /// Verify a macaroon along with the discharge macaroons for its third-party caveats
+    ///
+    /// This does the whole verification in one call: checks the root macaroon's signature,
+    /// verifies its first-party caveats using this verifier's predicates and callbacks, and for
+    /// each third-party caveat, finds the matching discharge, checks that it is bound to the
+    /// root macaroon, and verifies the discharge's own caveats in turn. Each discharge may only be
+    /// used once, which also rules out cycles between discharges.
+    ///
+    /// Only the discharges passed in are used, not any added with
+    /// `VerifierBuilder::add_discharge_macaroons`.
+    ///
+    /// Returns `Ok(true)` if authorized and `Ok(false)` if not.
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::DischargeNotUsed` if the macaroon was otherwise authorized but
+    /// one of the discharges wasn't needed to verify it (see `set_unused_discharge_policy`).
+    pub fn verify(
+        &self,
+        root: &Macaroon,
+        key: &MacaroonKey,
+        discharges: &[Macaroon],
+    ) -> Result<bool, MacaroonError> {
+        self.verify_with_context(root, key, discharges, &VerifyContext::new())
+    }
+
+    /// Verify a macaroon and its discharges against the details of a particular request
+    ///
+    /// The same as `verify`, but the context is passed to the general checkers.
+    pub fn verify_with_context(
+        &self,
+        root: &Macaroon,
+        key: &MacaroonKey,
+        discharges: &[Macaroon],
+        context: &VerifyContext,
+    ) -> Result<bool, MacaroonError> {
+        Verification::new(self, context, discharges).run(root, &key.0)
+    }
+
+    /// Verify many macaroons, each with its own discharges, all minted with the same root key
+    ///
+    /// This is for batch jobs checking stored tokens. Each result is as `verify` would give for
+    /// that token, except that every token is verified as of the same time, taken once at the
+    /// start, and the state of verification is reused from one token to the next rather than
+    /// being set up afresh.
+    ///
+    /// With the `rayon` feature, tokens are verified in parallel on rayon's global thread pool.
+    /// The results are still in the same order as the tokens, but an observer (see
+    /// `VerifierBuilder::set_observer`) sees the events of different tokens interleaved.
+    pub fn verify_batch(
+        &self,
+        tokens: &[(Macaroon, &[Macaroon])],
+        key: &MacaroonKey,
+    ) -> Vec<Result<bool, MacaroonError>> {
+        let mut context = VerifyContext::new();
+        context.set_time(std_caveats::now());
+        self.verify_batch_with_context(tokens, key, &context)
+    }
+
+    /// Verify many macaroons against the details of a particular request (or batch)
+    ///
+    /// The same as `verify_batch`, but the context is passed to the general checkers.
+    pub fn verify_batch_with_context(
+        &self,
+        tokens: &[(Macaroon, &[Macaroon])],
+        key: &MacaroonKey,
+        context: &VerifyContext,
+    ) -> Vec<Result<bool, MacaroonError>> {
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            tokens
+                .par_iter()
+                .map_init(
+                    || Verification::new(self, context, &[]),
+                    |verification, (root, discharges)| {
+                        verification.reset(discharges);
+                        verification.run(root, &key.0)
+                    },
+                )
+                .collect()
+        }
+        #[cfg(not(feature = "rayon"))]
+        {
+            let mut verification = Verification::new(self, context, &[]);
+            tokens
+                .iter()
+                .map(|(root, discharges)| {
+                    verification.reset(discharges);
+                    verification.run(root, &key.0)
+                })
+                .collect()
+        }
+    }
+
+    /// Verify a macaroon and its discharges against each of several candidate root keys
+    ///
+    /// This is for services which rotate their root keys, and have to accept macaroons minted
+    /// with the current key or any of the previous ones. The root macaroon's signature is
+    /// checked against every key, whichever matches, so the time taken doesn't depend on which
+    /// key it was minted with. As for `verify`, the keys are personalized with the domain given
+    /// to `VerifierBuilder::set_domain`, if any. The rest of verification is as `authorize`,
+    /// with the key which matched.
+    ///
+    /// Returns the index of that key in `keys`, along with what `authorize` gives with it: `Ok`
+    /// if the macaroon is authorized, or why it isn't.
+    ///
+    /// # Errors
+    /// `MacaroonError::InvalidSignature` if none of the keys matches.
+    pub fn verify_with_keys(
+        &self,
+        root: &Macaroon,
+        keys: &[MacaroonKey],
+        discharges: &[Macaroon],
+        context: &VerifyContext,
+    ) -> Result<(usize, Result<(), MacaroonError>), MacaroonError> {
+        let mut matched = None;
+        for (index, key) in keys.iter().enumerate() {
+            let key = match &self.domain {
+                Some(domain) => self.personalize(&key.0, domain),
+                None => key.0,
+            };
+            if root.verify_signature_with(&key) && matched.is_none() {
+                matched = Some(index);
+            }
+        }
+        let index = match matched {
+            Some(index) => index,
+            None => {
+                debug!(
+                    "Verifier::verify_with_keys: Macaroon {:?} doesn't match any of {} keys",
+                    root,
+                    keys.len()
+                );
+                return Err(MacaroonError::InvalidSignature);
+            }
+        };
+        Ok((
+            index,
+            self.authorize(root, &keys[index], discharges, context),
+        ))
+    }
+
+    /// Verify a macaroon and its discharges, returning why it isn't authorized if it isn't
+    ///
+    /// The same as `verify_with_context`, but rather than `Ok(false)`, an unauthorized macaroon
+    /// gives `MacaroonError::InvalidSignature` if the signature of the macaroon or one of its
+    /// discharges is wrong, or `MacaroonError::CaveatNotSatisfied` with the first caveat which
+    /// wasn't satisfied (or `MacaroonError::UnknownCaveat`, if no checker was registered for it
+    /// with `VerifierBuilder::register_prefix`).
+    pub fn authorize(
+        &self,
+        root: &Macaroon,
+        key: &MacaroonKey,
+        discharges: &[Macaroon],
+        context: &VerifyContext,
+    ) -> Result<(), MacaroonError> {
+        let mut verification = Verification::new(self, context, discharges);
+        match verification.run(root, &key.0)? {
+            true => Ok(()),
+            false => Err(verification
+                .failure
+                .unwrap_or(MacaroonError::BadMacaroon("macaroon isn't authorized"))),
+        }
+    }
+
+    /// Evaluate the caveats of a macaroon and its discharges, without checking any signatures
+    ///
+    /// This is the second half of verification, for services which are passed macaroons whose
+    /// signatures have already been checked (with `Macaroon::verify_integrity` or
+    /// `Macaroon::check_bindings`), by a relay which holds the root key, say. Caveats are
+    /// evaluated as by `verify_with_context`, and discharges are matched up with the third-party
+    /// caveats by identifier, but nothing stops a macaroon being forged, so this must never be
+    /// the only check.
+    pub fn evaluate_caveats(
+        &self,
+        root: &Macaroon,
+        discharges: &[Macaroon],
+        context: &VerifyContext,
+    ) -> Result<bool, MacaroonError> {
+        let mut verification = Verification::new(self, context, discharges);
+        verification.check_signatures = false;
+        verification.run(root, &[0; 32])
+    }
+
+    /// List everything which stops a macaroon being authorized, rather than just the first
+    ///
+    /// This is for working out why access was denied. Every caveat of the macaroon and its
+    /// discharges is evaluated, as by `evaluate_caveats`, without stopping at the first which
+    /// isn't satisfied, and the errors `authorize` would have given for each are returned in the
+    /// order they were found: `MacaroonError::CaveatNotSatisfied` with each caveat's text and
+    /// index, and any failed policy checks (the maximum age, say). A third-party caveat whose
+    /// discharge has unsatisfied caveats is listed after them. Nothing is returned if the
+    /// caveats would all be satisfied.
+    ///
+    /// No signatures are checked, so this says nothing about whether the macaroon is genuine.
+    pub fn dry_run(&self, root: &Macaroon, discharges: &[Macaroon]) -> Vec<MacaroonError> {
+        self.dry_run_with_context(root, discharges, &VerifyContext::new())
+    }
+
+    /// List everything which stops a macaroon being authorized for a particular request
+    ///
+    /// The same as `dry_run`, but the context is passed to the general checkers.
+    pub fn dry_run_with_context(
+        &self,
+        root: &Macaroon,
+        discharges: &[Macaroon],
+        context: &VerifyContext,
+    ) -> Vec<MacaroonError> {
+        let mut verification = Verification::new(self, context, discharges);
+        verification.check_signatures = false;
+        verification.failures = Some(Vec::new());
+        if let Err(error) = verification.run(root, &[0; 32]) {
+            verification.fail(error);
+        }
+        verification.failures.unwrap_or_default()
+    }
+
+    /// Verify a macaroon and its discharges, and collect the attributes they declare
+    ///
+    /// The same as `verify_with_context`, but the `declared` caveats are satisfied (as with
+    /// `VerifierBuilder::collect_declarations`) and collected. Returns `Ok(None)` if the
+    /// macaroon isn't authorized.
+    pub fn verify_declarations(
+        &self,
+        root: &Macaroon,
+        key: &MacaroonKey,
+        discharges: &[Macaroon],
+        context: &VerifyContext,
+    ) -> Result<Option<HashMap<String, String>>, MacaroonError> {
+        let mut verification = Verification::new(self, context, discharges);
+        verification.collect_declarations = true;
+        match verification.run(root, &key.0)? {
+            true => Ok(Some(verification.declarations)),
+            false => Ok(None),
+        }
+    }
+
+    /// Verify a macaroon and its discharges, using asynchronous callbacks where needed
+    ///
+    /// The same as `verify_with_context`, except that caveats which aren't satisfied any other
+    /// way are passed to the callbacks given to `VerifierBuilder::satisfy_general_async`. The
+    /// signatures and all the other caveats are checked first, so the asynchronous callbacks are
+    /// only called if those succeed.
+    #[cfg(feature = "async")]
+    pub async fn verify_async(
+        &self,
+        root: &Macaroon,
+        key: &MacaroonKey,
+        discharges: &[Macaroon],
+        context: &VerifyContext,
+    ) -> Result<bool, MacaroonError> {
+        let mut verification = Verification::new(self, context, discharges);
+        if self.has_async_callbacks() {
+            verification.deferred = Some(Vec::new());
+        }
+        let result = verification.run(root, &key.0);
+        let deferred = match verification.deferred.take() {
+            Some(deferred) => deferred,
+            // Without asynchronous callbacks, this was an ordinary verification, and recorded
+            None => return result,
+        };
+        // The asynchronous callbacks aren't called at all for a macaroon which is already
+        // rejected, since they may do I/O
+        if !matches!(result, Ok(true)) {
+            verification.record(root.caveats.len(), verification.outcome(&result));
+            return result;
+        }
+        for predicate in deferred {
+            let satisfied = self.verify_predicate_async(&predicate, context).await;
+            verification.observe(VerificationEvent::CaveatEvaluated {
+                predicate: &predicate,
+                satisfied,
+            });
+            if !satisfied {
+                debug!(
+                    "Verifier::verify_async: Caveat {:?} of macaroon {:?} failed verification",
+                    predicate, root
+                );
+                verification.record(root.caveats.len(), Some(FailureReason::CaveatNotSatisfied));
+                return Ok(false);
+            }
+        }
+        verification.record(root.caveats.len(), verification.outcome(&result));
+        result
+    }
+
+    #[cfg(feature = "async")]
+    async fn verify_predicate_async(&self, predicate: &str, context: &VerifyContext) -> bool {
+        for verifier in self.components() {
+            if Box::pin(verifier.verify_predicate_async(predicate, context)).await {
+                return true;
+            }
+        }
+        let mut candidates = Vec::new();
+        self.async_candidates(predicate, &mut candidates);
+        for candidate in &candidates {
+            for callback in &self.async_callbacks {
+                if callback(candidate, context).await {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // The forms of the predicate the asynchronous callbacks are given, in the same order the
+    // synchronous ones see them in `verify_predicate`
+    #[cfg(feature = "async")]
+    fn async_candidates(&self, predicate: &str, candidates: &mut Vec<String>) {
+        if let Some(alternatives) = predicate::parse_any_of(predicate) {
+            for alternative in &alternatives {
+                self.async_candidates(alternative, candidates);
+            }
+        }
+        candidates.push(match self.canonicalizer {
+            Some(canonicalize) => canonicalize(predicate),
+            None => String::from(predicate),
+        });
+    }
+
+    pub(crate) fn verify_macaroon(
+        &self,
+        root: &Macaroon,
+        key: &[u8; 32],
+        context: &VerifyContext,
+    ) -> Result<bool, MacaroonError> {
+        Verification::new(self, context, &self.discharge_macaroons).run(root, key)
+    }
+
+    pub fn verify_predicate(&self, predicate: &str, context: &VerifyContext) -> bool {
+        self.verify_predicate_in(predicate, &[predicate], context)
+    }
+
+    // Verify a predicate which is one of the macaroon's first-party caveats
+    fn verify_predicate_in(
+        &self,
+        predicate: &str,
+        caveats: &[&str],
+        context: &VerifyContext,
+    ) -> bool {
+        // Either way, a combination satisfies a caveat any of its verifiers does
+        if let Some((_, verifiers)) = &self.composition {
+            return verifiers
+                .iter()
+                .any(|verifier| verifier.verify_predicate_in(predicate, caveats, context));
+        }
+
+        if let Some(alternatives) = predicate::parse_any_of(predicate) {
+            if alternatives
+                .iter()
+                .any(|a| self.verify_predicate_in(a, caveats, context))
+            {
+                return true;
+            }
+        }
+
+        if let Some(prefix) = self.route(predicate) {
+            let mut routes = self.routes.iter().filter(|(p, _)| p == prefix);
+            return match self.canonicalizer {
+                Some(canonicalize) => {
+                    let predicate = canonicalize(predicate);
+                    routes
+                        .any(|(_, c)| c.satisfies(&predicate, caveats, context, Some(canonicalize)))
+                }
+                None => routes.any(|(_, c)| c.check_in(predicate, caveats, context)),
+            };
+        }
+
+        if let Some(canonicalize) = self.canonicalizer {
+            let predicate = canonicalize(predicate);
+            return self.predicates.iter().any(|p| canonicalize(p) == predicate)
+                || self
+                    .callbacks
+                    .iter()
+                    .any(|callback| callback(&predicate, context))
+                || self
+                    .checkers
+                    .iter()
+                    .any(|c| c.satisfies(&predicate, caveats, context, Some(canonicalize)));
+        }
+
+        let mut count = self.predicates.iter().filter(|&p| p == predicate).count();
+        if count > 0 {
+            return true;
+        }
+
+        count = self
+            .callbacks
+            .iter()
+            .filter(|&callback| callback(predicate, context))
+            .count();
+        if count > 0 {
+            return true;
+        }
+
+        self.checkers
+            .iter()
+            .any(|c| c.check_in(predicate, caveats, context))
+    }
+
+    // The root key personalized with the domain, derived again only for keys not seen before
+    fn personalize(&self, key: &[u8; 32], domain: &str) -> [u8; 32] {
+        // The map is never left half-updated, so it's still usable if another thread panicked
+        let mut personalized = self
+            .personalized
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(&personalized_key) = personalized.get(key) {
+            return personalized_key;
+        }
+        if personalized.len() >= MAX_PERSONALIZED_KEYS {
+            personalized.clear();
+        }
+        let personalized_key = crypto::personalize_key(key, domain);
+        personalized.insert(*key, personalized_key);
+        personalized_key
+    }
+
+    // The longest registered prefix the predicate starts with
+    fn route(&self, predicate: &str) -> Option<&str> {
+        self.routes
+            .iter()
+            .map(|(prefix, _)| prefix.as_str())
+            .filter(|prefix| predicate.starts_with(prefix))
+            .max_by_key(|prefix| prefix.len())
+    }
+
+    // Whether prefixes are registered (in any of the verifiers, if this is a composition)
+    fn has_routes(&self) -> bool {
+        !self.routes.is_empty() || self.components().iter().any(Verifier::has_routes)
+    }
+
+    // Whether the predicate starts with a registered prefix (in any of the verifiers, if this is
+    // a composition)
+    fn is_routed(&self, predicate: &str) -> bool {
+        self.route(predicate).is_some()
+            || self
+                .components()
+                .iter()
+                .any(|verifier| verifier.is_routed(predicate))
+    }
+
+    // Whether any checkers combine others (in any of the verifiers, if this is a composition),
+    // so that they need all of the macaroon's caveats
+    fn has_compound_checkers(&self) -> bool {
+        self.checkers.iter().any(Checker::is_compound)
+            || self.routes.iter().any(|(_, c)| c.is_compound())
+            || self
+                .components()
+                .iter()
+                .any(Verifier::has_compound_checkers)
+    }
+
+    #[cfg(feature = "async")]
+    fn has_async_callbacks(&self) -> bool {
+        !self.async_callbacks.is_empty()
+            || self.components().iter().any(Verifier::has_async_callbacks)
+    }
+
+    // The verifiers combined, if this is a composition
+    fn components(&self) -> &[Verifier] {
+        match &self.composition {
+            Some((_, verifiers)) => verifiers,
+            None => &[],
+        }
+    }
+}
+
+/// The state of a single verification
+///
+/// This is created afresh for each call to verify, so that the verifier itself can be shared.
+pub(crate) struct Verification<'a> {
+    verifier: &'a Verifier,
+    // The other verifiers whose checks also satisfy caveats, when this is one of the verifiers
+    // combined with `Verifier::all_of`
+    peers: Vec<&'a Verifier>,
+    // The first-party caveats of each macaroon being verified, innermost last, if any checkers
+    // combine others
+    caveat_sets: Vec<Option<Vec<String>>>,
+    context: &'a VerifyContext,
+    discharge_macaroons: &'a [Macaroon],
+    signature: Signature,
+    root_signature: Signature,
+    // Off when only the caveats are evaluated, the signatures having been checked elsewhere
+    check_signatures: bool,
+    id_chain: Vec<&'a str>,
+    // How deeply nested the discharge being verified is
+    depth: usize,
+    collect_declarations: bool,
+    declarations: HashMap<String, String>,
+    // Why verification failed, if it has
+    failure: Option<MacaroonError>,
+    // Every failure, in order, if this is a dry run
+    failures: Option<Vec<MacaroonError>>,
+    // Caveats left for the asynchronous callbacks, if we're verifying asynchronously
+    #[cfg(feature = "async")]
+    deferred: Option<Vec<String>>,
+}
+
+impl<'a> Verification<'a> {
+    fn new(
+        verifier: &'a Verifier,
+        context: &'a VerifyContext,
+        discharge_macaroons: &'a [Macaroon],
+    ) -> Verification<'a> {
+        Verification {
+            verifier,
+            peers: Vec::new(),
+            caveat_sets: Vec::new(),
+            context,
+            discharge_macaroons,
+            signature: Signature([0; 32]),
+            root_signature: Signature([0; 32]),
+            check_signatures: true,
+            id_chain: Vec::new(),
+            depth: 0,
+            collect_declarations: verifier.collect_declarations,
+            declarations: HashMap::new(),
+            failure: None,
+            failures: None,
+            #[cfg(feature = "async")]
+            deferred: None,
+        }
+    }
+
+    // Start again, with a new set of discharges, keeping what's been allocated
+    fn reset(&mut self, discharge_macaroons: &'a [Macaroon]) {
+        self.discharge_macaroons = discharge_macaroons;
+        self.id_chain.clear();
+        self.depth = 0;
+        self.declarations.clear();
+        self.failure = None;
+        #[cfg(feature = "async")]
+        {
+            self.deferred = None;
+        }
+    }
+
+    fn run(&mut self, root: &Macaroon, key: &[u8; 32]) -> Result<bool, MacaroonError> {
+        let result = self.run_checks(root, key);
+        // Asynchronous verification reports once the deferred caveats have been checked too
+        #[cfg(feature = "async")]
+        if self.deferred.is_some() {
+            return result;
+        }
+        let outcome = self.outcome(&result);
+        self.record(root.caveats.len(), outcome);
+        result
+    }
+
+    fn run_checks(&mut self, root: &Macaroon, key: &[u8; 32]) -> Result<bool, MacaroonError> {
+        trace_span!(
+            "Verifier::verify",
+            caveats = root.caveats.len(),
+            discharges = self.discharge_macaroons.len()
+        );
+        if let Some((composition, verifiers)) = &self.verifier.composition {
+            return self.run_composed(*composition, verifiers, root, key);
+        }
+        if !self.check_signatures {
+            return self.evaluate(root);
+        }
+        let personalized;
+        let key = match &self.verifier.domain {
+            Some(domain) => {
+                personalized = self.verifier.personalize(key, domain);
+                &personalized
+            }
+            None => key,
+        };
+        let valid = root.verify_signature_with(key);
+        self.observe(VerificationEvent::SignatureChecked {
+            identifier: root.identifier(),
+            discharge: false,
+            valid,
+        });
+        if !valid {
+            debug!(
+                "Verification::run: Macaroon {:?} failed signature verification",
+                root
+            );
+            self.fail(MacaroonError::InvalidSignature);
+            return Ok(false);
+        }
+        self.root_signature = root.signature;
+        self.signature = Signature(crypto::generate_signature(key, root.identifier()));
+        self.evaluate(root)
+    }
+
+    // Run each of the combined verifiers' own verification, and combine the results
+    fn run_composed(
+        &mut self,
+        composition: Composition,
+        verifiers: &'a [Verifier],
+        root: &Macaroon,
+        key: &[u8; 32],
+    ) -> Result<bool, MacaroonError> {
+        if verifiers.is_empty() {
+            self.fail(MacaroonError::BadMacaroon("no verifiers were combined"));
+            return Ok(false);
+        }
+        let mut first = None;
+        for verifier in verifiers {
+            let mut component = Verification::new(verifier, self.context, self.discharge_macaroons);
+            component.peers = match composition {
+                Composition::AllOf => verifiers.iter().chain(self.peers.iter().copied()).collect(),
+                Composition::AnyOf => self.peers.clone(),
+            };
+            component.check_signatures = self.check_signatures;
+            component.collect_declarations |= self.collect_declarations;
+            if self.is_dry_run() {
+                component.failures = Some(Vec::new());
+            }
+            #[cfg(feature = "async")]
+            if self.deferred.is_some() {
+                component.deferred = Some(Vec::new());
+            }
+            let result = component.run(root, key);
+            let authorized = matches!(result, Ok(true));
+            if authorized || composition == Composition::AllOf {
+                self.declarations.extend(component.declarations.drain());
+                #[cfg(feature = "async")]
+                if let (Some(deferred), Some(more)) = (&mut self.deferred, component.deferred) {
+                    deferred.extend(more);
+                }
+            }
+            match (composition, result) {
+                (Composition::AnyOf, Ok(true)) => {
+                    if let Some(failures) = &mut self.failures {
+                        failures.clear();
+                    }
+                    return Ok(true);
+                }
+                (Composition::AllOf, Ok(true)) => (),
+                (Composition::AllOf, Err(error)) if !self.is_dry_run() => return Err(error),
+                (_, result) => {
+                    // With `AllOf`, everything which stopped any of them authorizing the
+                    // macaroon, and with `AnyOf`, what stopped the first
+                    let counts = composition == Composition::AllOf || first.is_none();
+                    let result = match &mut self.failures {
+                        Some(failures) => {
+                            if counts {
+                                // Every one of them evaluates every caveat, but each caveat
+                                // which isn't satisfied is only listed once
+                                let found = component.failures.unwrap_or_default();
+                                for error in result.err().into_iter().chain(found) {
+                                    let message = error.to_string();
+                                    if !failures.iter().any(|f| f.to_string() == message) {
+                                        failures.push(error);
+                                    }
+                                }
+                            }
+                            Ok(false)
+                        }
+                        None => {
+                            if let Some(failure) = component.failure {
+                                self.fail(failure);
+                            }
+                            result
+                        }
+                    };
+                    if first.is_none() {
+                        first = Some(result);
+                    }
+                }
+            }
+        }
+        match first {
+            Some(Err(error)) => Err(error),
+            Some(Ok(_)) => Ok(false),
+            None => Ok(true),
+        }
+    }
+
+    fn evaluate(&mut self, root: &Macaroon) -> Result<bool, MacaroonError> {
+        if self.is_dry_run() {
+            return self.evaluate_all(root);
+        }
+        if !self.check_size(root)
+            || !self.check_revoked(root)
+            || !self.check_age(root)
+            || !self.check_location(root)
+        {
+            return Ok(false);
+        }
+        match root.verify_caveats(self) {
+            Ok(true) => self.check_discharges_used(root),
+            result => result,
+        }
+    }
+
+    // As `evaluate`, but carrying on past failures, each of which is recorded
+    fn evaluate_all(&mut self, root: &Macaroon) -> Result<bool, MacaroonError> {
+        let checked = [
+            self.check_size(root),
+            self.check_revoked(root),
+            self.check_age(root),
+            self.check_location(root),
+        ];
+        let satisfied = root.verify_caveats(self)?;
+        let used = self.check_discharges_used(root).unwrap_or_else(|error| {
+            self.fail(error);
+            false
+        });
+        Ok(checked.iter().all(|&passed| passed) && satisfied && used)
+    }
+
+    fn check_size(&mut self, macaroon: &Macaroon) -> bool {
+        let failure = match (self.verifier.max_caveats, self.verifier.max_discharge_depth) {
+            (Some(max_caveats), _) if macaroon.caveats.len() > max_caveats => {
+                "macaroon has too many caveats"
+            }
+            (_, Some(max_depth)) if self.depth > max_depth => "discharge chain is too deep",
+            _ => return true,
+        };
+        debug!(
+            "Verification::check_size: Macaroon {:?} rejected: {}",
+            macaroon, failure
+        );
+        self.fail(MacaroonError::BadMacaroon(failure));
+        false
+    }
+
+    fn check_location(&mut self, root: &Macaroon) -> bool {
+        if self.verifier.locations.is_empty() {
+            return true;
+        }
+        if let Some(actual) = root.location() {
+            if self
+                .verifier
+                .locations
+                .contains(&location::normalize(&actual))
+            {
+                return true;
+            }
+        }
+        debug!(
+            "Verification::check_location: Macaroon {:?} isn't for any of {:?}",
+            root, self.verifier.locations
+        );
+        self.fail(MacaroonError::BadMacaroon(
+            "macaroon has the wrong location",
+        ));
+        false
+    }
+
+    fn check_discharge_location(
+        &mut self,
+        caveat: &caveat::ThirdPartyCaveat,
+        discharge: &Macaroon,
+    ) -> bool {
+        if self.verifier.locations.is_empty() {
+            return true;
+        }
+        if let Some(actual) = discharge.location() {
+            if location::same_location(&actual, &caveat.location) {
+                return true;
+            }
+        }
+        debug!(
+            "Verification::check_discharge_location: Discharge macaroon {:?} isn't from {:?}",
+            discharge, caveat.location
+        );
+        self.fail(MacaroonError::BadMacaroon(
+            "discharge has the wrong location",
+        ));
+        false
+    }
+
+    fn check_discharge_requirements(&mut self, discharge: &Macaroon) -> bool {
+        let context = self.context;
+        let predicates: Vec<&str> = discharge.predicates().collect();
+        let failure = if !self
+            .verifier
+            .discharge_requirements
+            .iter()
+            .all(|checker| checker.is_met(&predicates, context))
+        {
+            "discharge is missing a required caveat"
+        } else {
+            match (self.verifier.max_discharge_lifetime, discharge.expiry()) {
+                (None, _) => return true,
+                (Some(_), None) => "discharge has no expiry",
+                (Some(max_lifetime), Some(expiry)) if expiry > context.time() + max_lifetime => {
+                    "discharge expires too late"
+                }
+                _ => return true,
+            }
+        };
+        debug!(
+            "Verification::check_discharge_requirements: Discharge macaroon {:?} rejected: {}",
+            discharge, failure
+        );
+        self.fail(MacaroonError::BadMacaroon(failure));
+        false
+    }
+
+    fn check_revoked(&mut self, macaroon: &Macaroon) -> bool {
+        match &self.verifier.revocation_checker {
+            Some(checker) if checker.is_revoked(macaroon) => {
+                debug!(
+                    "Verification::check_revoked: Macaroon {:?} has been revoked",
+                    macaroon
+                );
+                self.fail(MacaroonError::BadMacaroon("macaroon has been revoked"));
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn check_age(&mut self, root: &Macaroon) -> bool {
+        let max_age = match self.verifier.max_age {
+            Some(max_age) => max_age,
+            None => return true,
+        };
+        let from_identifier = self
+            .verifier
+            .issued_at_extractor
+            .and_then(|extractor| extractor(root.identifier()));
+        let issued_at = match (root.issued_at(), from_identifier) {
+            (Some(caveat), Some(identifier)) => Some(caveat.min(identifier)),
+            (caveat, identifier) => caveat.or(identifier),
+        };
+        let failure = match issued_at {
+            None => "macaroon has no issue time",
+            Some(issued_at) => match self.context.time().duration_since(issued_at) {
+                Ok(age) if age <= max_age => return true,
+                Ok(_) => "macaroon is older than the maximum age",
+                Err(_) => "macaroon was issued in the future",
+            },
+        };
+        debug!(
+            "Verification::check_age: Macaroon {:?} rejected: {}",
+            root, failure
+        );
+        self.fail(MacaroonError::BadMacaroon(failure));
+        false
+    }
+
+    // The root is only for logging, as the incremental verifier has no macaroon
+    fn check_discharges_used(&self, root: &dyn fmt::Debug) -> Result<bool, MacaroonError> {
+        let unused = match self
+            .discharge_macaroons
+            .iter()
+            .find(|dm| !self.id_chain.contains(&dm.identifier().as_str()))
+        {
+            Some(unused) => unused,
+            None => return Ok(true),
+        };
+        match self.verifier.unused_discharge_policy {
+            UnusedDischargePolicy::Reject => {
+                debug!(
+                    "Verification::check_discharges_used: Discharge macaroon {:?} not used to \
+                       verify {:?}",
+                    unused, root
+                );
+                Err(MacaroonError::DischargeNotUsed(redaction::identifier(
+                    unused.identifier(),
+                )))
+            }
+            UnusedDischargePolicy::Warn => {
+                warn!(
+                    "Verification::check_discharges_used: Discharge macaroon {:?} not used to \
+                       verify {:?}",
+                    unused, root
+                );
+                Ok(true)
+            }
+        }
+    }
+
+    // Why a verification with this result failed, if it did
+    fn outcome(&self, result: &Result<bool, MacaroonError>) -> Option<FailureReason> {
+        match result {
+            Ok(true) => None,
+            Ok(false) => Some(
+                self.failure
+                    .as_ref()
+                    .map_or(FailureReason::Other, FailureReason::of),
+            ),
+            Err(error) => Some(FailureReason::of(error)),
+        }
+    }
+
+    fn record(&self, caveats: usize, failure: Option<FailureReason>) {
+        if let Some(metrics) = self.metrics() {
+            metrics.verification_finished(failure, caveats);
+        }
+    }
+
+    // Dry runs aren't reported, since nothing is authorized by them
+    fn metrics(&self) -> Option<&dyn Metrics> {
+        match self.is_dry_run() {
+            true => None,
+            false => self.verifier.metrics.as_deref(),
+        }
+    }
+
+    /// Report an event to the verifier's observer, if it has one
+    pub fn observe(&self, event: VerificationEvent) {
+        if let Some(observer) = &self.verifier.observer {
+            observer.observe(&event);
+        }
+    }
+
+    /// Record why verification failed
+    ///
+    /// Only the first failure is kept, since that's the one which caused any later ones (a
+    /// discharge's caveat failing causes the third-party caveat it discharges to fail, say).
+    pub fn fail(&mut self, error: MacaroonError) {
+        if let Some(failures) = &mut self.failures {
+            failures.push(error);
+        } else if self.failure.is_none() {
+            self.failure = Some(error);
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.failures.is_some()
+    }
+
+    pub fn signature(&self) -> Signature {
+        self.signature
+    }
+
+    pub fn binding_mode(&self) -> BindingMode {
+        self.verifier.binding_mode
+    }
+
+    pub fn set_signature(&mut self, signature: Signature) {
+        self.signature = signature;
+    }
+
+    pub fn update_signature<F>(&mut self, generator: F)
+    where
+        F: Fn(&[u8; 32]) -> [u8; 32],
+    {
+        self.signature = Signature(generator(&self.signature.0));
+    }
+
+    pub fn verify_first_party(&mut self, predicate: &str) -> bool {
+        #[cfg(feature = "async")]
+        let deferred = self.deferred.as_ref().map_or(0, Vec::len);
+        let satisfied = self.check_first_party(predicate);
+        // Caveats left for the asynchronous callbacks are reported once they've been checked
+        #[cfg(feature = "async")]
+        if self.deferred.as_ref().map_or(0, Vec::len) > deferred {
+            return satisfied;
+        }
+        self.observe(VerificationEvent::CaveatEvaluated {
+            predicate,
+            satisfied,
+        });
+        satisfied
+    }
+
+    fn check_first_party(&mut self, predicate: &str) -> bool {
+        if self.collect_declarations {
+            if let Some((key, value)) = std_caveats::parse_declared(predicate) {
+                return self.declare(key, value);
+            }
+        }
+        let verifier = self.verifier;
+        let caveats: Vec<&str> = match self.caveat_sets.last() {
+            Some(Some(caveats)) => caveats.iter().map(String::as_str).collect(),
+            _ => vec![predicate],
+        };
+        if self.satisfies(verifier, predicate, &caveats)
+            || self.peers.iter().any(|&peer| {
+                !std::ptr::eq(peer, verifier) && self.satisfies(peer, predicate, &caveats)
+            })
+        {
+            return true;
+        }
+        #[cfg(feature = "async")]
+        if let Some(deferred) = &mut self.deferred {
+            deferred.push(String::from(predicate));
+            return true;
+        }
+        let verifiers = || std::iter::once(verifier).chain(self.peers.iter().copied());
+        if verifiers().any(Verifier::has_routes)
+            && !verifiers().any(|verifier| verifier.is_routed(predicate))
+        {
+            debug!(
+                "Verification::check_first_party: No checker for caveat {:?}",
+                predicate
+            );
+            let prefix = predicate.split_whitespace().next().unwrap_or_default();
+            self.fail(MacaroonError::UnknownCaveat(redaction::content(prefix)));
+        }
+        false
+    }
+
+    // Whether the verifier satisfies a first-party caveat, other than a declaration
+    fn satisfies(&self, verifier: &Verifier, predicate: &str, caveats: &[&str]) -> bool {
+        // The issue time was checked against the maximum age before any caveats
+        if verifier.max_age.is_some() && std_caveats::parse_issued_at(predicate).is_some() {
+            return true;
+        }
+        // A checker registered for nonces takes precedence over satisfying them all
+        if verifier.satisfy_nonces
+            && verifier.route(predicate).is_none()
+            && std_caveats::parse_nonce(predicate).is_some()
+        {
+            return true;
+        }
+        if let Some(vendor) = std_caveats::experimental_vendor(predicate) {
+            match verifier.experimental.get(vendor) {
+                Some(ExperimentalHandler::Check(callback)) => {
+                    return callback(predicate, self.context)
+                }
+                Some(ExperimentalHandler::Ignore) => return true,
+                None => (),
+            }
+        }
+        // Channel bindings are always checked, failing if the context has nothing to check them
+        // against
+        if let Some(result) = std_caveats::check_channel_binding(predicate, self.context) {
+            return result;
+        }
+        let operations = self.context.operations();
+        if !operations.is_empty() {
+            if let Some(result) = std_caveats::check_operations(predicate, operations) {
+                return result;
+            }
+        }
+        verifier.verify_predicate_in(predicate, caveats, self.context)
+    }
+
+    // Keep the macaroon's first-party caveats while its caveats are verified, if any checkers
+    // combine others
+    pub(crate) fn enter(&mut self, macaroon: &Macaroon) {
+        let compound = std::iter::once(self.verifier)
+            .chain(self.peers.iter().copied())
+            .any(Verifier::has_compound_checkers);
+        self.caveat_sets
+            .push(compound.then(|| macaroon.predicates().map(String::from).collect()));
+    }
+
+    pub(crate) fn leave(&mut self) {
+        self.caveat_sets.pop();
+    }
+
+    fn declare(&mut self, key: &str, value: &str) -> bool {
+        match self.declarations.get(key) {
+            Some(existing) if existing != value => {
+                debug!(
+                    "Verification::declare: Conflicting declarations for {:?}: {:?} and {:?}",
+                    key, existing, value
+                );
+                false
+            }
+            _ => {
+                self.declarations
+                    .insert(String::from(key), String::from(value));
+                true
+            }
+        }
+    }
+
+    fn verify_discharge(
+        &mut self,
+        caveat: &caveat::ThirdPartyCaveat,
+        dm: &Macaroon,
+    ) -> Result<bool, MacaroonError> {
+        if !self.check_size(dm)
+            || !self.check_revoked(dm)
+            || !self.check_discharge_requirements(dm)
+            || !self.check_discharge_location(caveat, dm)
+        {
+            return Ok(false);
+        }
+        if !self.check_signatures {
+            return dm.verify_caveats(self);
+        }
+        let key = caveat.decrypt_key(self.signature.0)?;
+        let root_signature = self.root_signature;
+        dm.verify_as_discharge(self, &root_signature, &key)
+    }
+
+    pub fn verify_caveat(
+        &mut self,
+        caveat: &caveat::ThirdPartyCaveat,
+    ) -> Result<bool, MacaroonError> {
+        let discharge_macaroons = self.discharge_macaroons;
+        let dm_opt = discharge_macaroons
+            .iter()
+            .find(|dm| *dm.identifier() == caveat.id);
+        self.observe(VerificationEvent::DischargeMatched {
+            caveat_id: &caveat.id,
+            found: dm_opt.is_some(),
+        });
+        if let Some(metrics) = self.metrics() {
+            metrics.discharge_looked_up(dm_opt.is_some());
+        }
+        match dm_opt {
+            Some(dm) => {
+                if self.id_chain.contains(&dm.identifier().as_str()) {
+                    debug!(
+                        "Verification::verify_caveat: caveat verification loop - id {:?} found \
+                           in id chain {:?}",
+                        dm.identifier(),
+                        self.id_chain
+                    );
+                    return Ok(false);
+                }
+                self.id_chain.push(dm.identifier());
+                self.depth += 1;
+                let result = self.verify_discharge(caveat, dm);
+                self.depth -= 1;
+                result
+            }
+            None => {
+                debug!(
+                    "Verification::verify_caveat: No discharge macaroon found matching caveat \
+                       id {:?}",
+                    caveat.id
+                );
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Verifies a macaroon as its caveats arrive, without a `Macaroon`
+///
+/// This is for embedded and FFI callers which parse macaroons themselves, and want to verify
+/// each caveat as it's parsed rather than building up the whole macaroon first. The caveats are
+/// fed in order, then `finalize` checks the macaroon's signature and gives the result, which is
+/// the same as `Verifier::verify_with_context` gives for the macaroon. Until then, nothing
+/// about the caveats can be trusted: a caveat which fails verification only means the macaroon
+/// isn't authorized if its signature turns out to be valid.
+///
+/// First-party caveats are checked as they're fed in. Third-party caveats are checked in
+/// `finalize`, since their discharges are bound to the signature it's given. The verifier's
+/// revocation checker, maximum age and locations need the whole macaroon, as do combinations of
+/// verifiers and of checkers, so a verifier with any of those authorizes nothing this way; its
+/// other policies apply as usual.
+pub struct IncrementalVerifier<'a> {
+    verification: Verification<'a>,
+    identifier: String,
+    caveats: usize,
+    satisfied: bool,
+    // Third-party caveats, with their index and the signature before them
+    third_party: Vec<(usize, Signature, caveat::ThirdPartyCaveat)>,
+}
+
+impl<'a> IncrementalVerifier<'a> {
+    /// Start verifying the macaroon with the identifier, signed with the root key, with the
+    /// discharges for its third-party caveats
+    pub fn new(
+        verifier: &'a Verifier,
+        key: &MacaroonKey,
+        identifier: &str,
+        discharges: &'a [Macaroon],
+        context: &'a VerifyContext,
+    ) -> IncrementalVerifier<'a> {
+        let mut verification = Verification::new(verifier, context, discharges);
+        let key = match &verifier.domain {
+            Some(domain) => verifier.personalize(&key.0, domain),
+            None => key.0,
+        };
+        verification.signature = Signature(crypto::generate_signature(&key, identifier));
+        IncrementalVerifier {
+            verification,
+            identifier: String::from(identifier),
+            caveats: 0,
+            satisfied: true,
+            third_party: Vec::new(),
+        }
+    }
+
+    /// Verify the next caveat, a first-party caveat with the predicate
+    ///
+    /// # Errors
+    /// Returns `MacaroonError::BadMacaroon` if the macaroon now has more caveats than
+    /// `VerifierBuilder::set_max_caveats` allows.
+    pub fn feed_caveat(&mut self, predicate: &str) -> Result<(), MacaroonError> {
+        let index = self.next_index()?;
+        if self.satisfied && !self.verification.verify_first_party(predicate) {
+            self.verification.fail(MacaroonError::CaveatNotSatisfied {
+                caveat: redaction::content(predicate),
+                index,
+            });
+            self.satisfied = false;
+        }
+        self.verification
+            .update_signature(|signature| crypto::hmac(signature, predicate.as_bytes()));
+        Ok(())
+    }
+
+    /// Verify the next caveat, a third-party caveat with the identifier, verifier id and
+    /// location
+    ///
+    /// # Errors
+    /// As `feed_caveat`.
+    pub fn feed_third_party_caveat(
+        &mut self,
+        id: &str,
+        verifier_id: &[u8],
+        location: &str,
+    ) -> Result<(), MacaroonError> {
+        let index = self.next_index()?;
+        let caveat = caveat::new_third_party(id, verifier_id.to_vec(), location);
+        let signature = self.verification.signature();
+        self.verification.update_signature(|signature| {
+            crypto::hmac_concat(signature, verifier_id, id.as_bytes())
+        });
+        self.third_party.push((index, signature, caveat));
+        Ok(())
+    }
+
+    /// Check the macaroon's signature, and give the result of verifying it
+    ///
+    /// Returns `Ok(true)` if it's authorized, `Ok(false)` if not, and `MacaroonError` as
+    /// `Verifier::verify_with_context` does.
+    pub fn finalize(mut self, signature: &Signature) -> Result<bool, MacaroonError> {
+        let result = self.check(signature);
+        let outcome = self.verification.outcome(&result);
+        self.verification.record(self.caveats, outcome);
+        result
+    }
+
+    fn next_index(&mut self) -> Result<usize, MacaroonError> {
+        limits::check_caveat_count(self.caveats + 1, self.verification.verifier.max_caveats)?;
+        self.caveats += 1;
+        Ok(self.caveats - 1)
+    }
+
+    fn check(&mut self, signature: &Signature) -> Result<bool, MacaroonError> {
+        let valid = self.verification.signature() == *signature;
+        self.verification
+            .observe(VerificationEvent::SignatureChecked {
+                identifier: &self.identifier,
+                discharge: false,
+                valid,
+            });
+        if !valid {
+            debug!(
+                "IncrementalVerifier::finalize: Macaroon {:?} failed signature verification",
+                redaction::identifier(&self.identifier)
+            );
+            self.verification.fail(MacaroonError::InvalidSignature);
+            return Ok(false);
+        }
+        let verifier = self.verification.verifier;
+        if verifier.revocation_checker.is_some()
+            || verifier.max_age.is_some()
+            || !verifier.locations.is_empty()
+            || verifier.composition.is_some()
+            || verifier.has_compound_checkers()
+        {
+            self.verification.fail(MacaroonError::BadMacaroon(
+                "verifier's policies need the whole macaroon",
+            ));
+            return Ok(false);
+        }
+        if !self.satisfied {
+            return Ok(false);
+        }
+        self.verification.root_signature = *signature;
+        for (index, before, caveat) in &self.third_party {
+            self.verification.set_signature(*before);
+            if !self.verification.verify_caveat(caveat)? {
+                self.verification.fail(MacaroonError::CaveatNotSatisfied {
+                    caveat: redaction::identifier(&caveat.id),
+                    index: *index,
+                });
+                return Ok(false);
+            }
+        }
+        self.verification
+            .check_discharges_used(&redaction::identifier(&self.identifier))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Checker, IncrementalVerifier, UnusedDischargePolicy, Verifier, VerifierBuilder,
+        VerifierCallback, VerifyContext,
+    };
+    use crate::{
+        caveat::{self, Caveat},
+        crypto::{self, BindingMode, Signature},
+        error::MacaroonError,
+        predicate,
+        predicate::canonicalize_whitespace,
+        std_caveats, Macaroon, MacaroonKey,
+    };
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+    #[cfg(feature = "async")]
+    use std::{future::Future, pin::Pin};
+
+    #[cfg(feature = "v1")]
+    #[test]
+    fn test_simple_macaroon() {
+        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAyZnNpZ25hdHVyZSB83ueSURxbxvUoSFgF3-myTnheKOKpkwH51xHGCeOO9wo";
+        let macaroon = Macaroon::deserialize(serialized.as_bytes()).unwrap();
+        let key = crypto::generate_derived_key(b"this is the key");
+        let verifier = VerifierBuilder::new().build();
+        assert!(macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[cfg(feature = "v1")]
+    #[test]
+    fn test_simple_macaroon_bad_verifier_key() {
+        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAyZnNpZ25hdHVyZSB83ueSURxbxvUoSFgF3-myTnheKOKpkwH51xHGCeOO9wo";
+        let macaroon = Macaroon::deserialize(serialized.as_bytes()).unwrap();
+        let key = crypto::generate_derived_key(b"this is not the key");
+        let verifier = VerifierBuilder::new().build();
+        assert!(!macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[cfg(feature = "v1")]
+    #[test]
+    fn test_macaroon_exact_caveat() {
+        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDJmc2lnbmF0dXJlIPVIB_bcbt-Ivw9zBrOCJWKjYlM9v3M5umF2XaS9JZ2HCg";
+        let macaroon = Macaroon::deserialize(serialized.as_bytes()).unwrap();
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        let key = crypto::generate_derived_key(b"this is the key");
+        let verifier = builder.build();
+        assert!(macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[cfg(feature = "v1")]
+    #[test]
+    fn test_macaroon_exact_caveat_wrong_verifier() {
+        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDJmc2lnbmF0dXJlIPVIB_bcbt-Ivw9zBrOCJWKjYlM9v3M5umF2XaS9JZ2HCg";
+        let macaroon = Macaroon::deserialize(serialized.as_bytes()).unwrap();
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 0000000000");
+        let key = crypto::generate_derived_key(b"this is the key");
+        let verifier = builder.build();
+        assert!(!macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[cfg(feature = "v1")]
+    #[test]
+    fn test_macaroon_exact_caveat_wrong_context() {
+        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDJmc2lnbmF0dXJlIPVIB_bcbt-Ivw9zBrOCJWKjYlM9v3M5umF2XaS9JZ2HCg";
+        let macaroon = Macaroon::deserialize(serialized.as_bytes()).unwrap();
+        let key = crypto::generate_derived_key(b"this is the key");
+        let verifier = VerifierBuilder::new().build();
+        assert!(!macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[cfg(feature = "v1")]
+    #[test]
+    fn test_macaroon_two_exact_caveats() {
+        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDE1Y2lkIHVzZXIgPSBhbGljZQowMDJmc2lnbmF0dXJlIEvpZ80eoMaya69qSpTumwWxWIbaC6hejEKpPI0OEl78Cg";
+        let macaroon = Macaroon::deserialize(serialized.as_bytes()).unwrap();
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("user = alice");
+        let key = crypto::generate_derived_key(b"this is the key");
+        let verifier = builder.build();
+        assert!(macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[cfg(feature = "v1")]
+    #[test]
+    fn test_macaroon_two_exact_caveats_incomplete_verifier() {
+        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDE1Y2lkIHVzZXIgPSBhbGljZQowMDJmc2lnbmF0dXJlIEvpZ80eoMaya69qSpTumwWxWIbaC6hejEKpPI0OEl78Cg";
+        let macaroon = Macaroon::deserialize(serialized.as_bytes()).unwrap();
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        let key = crypto::generate_derived_key(b"this is the key");
+        let verifier = builder.build();
+        assert!(!macaroon.verify(&key, &verifier).unwrap());
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("user = alice");
+        let key = crypto::generate_derived_key(b"this is the key");
+        let verifier = builder.build();
+        assert!(!macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[test]
+    fn test_macaroon_canonicalized_caveat() {
+        let key = b"this is the key";
+        let mut macaroon = Macaroon::create("http://example.org/", key, "keyid").unwrap();
+        macaroon.add_first_party_caveat("account=3735928559");
+        macaroon.add_canonical_first_party_caveat("user  =alice", canonicalize_whitespace);
+        assert_eq!(
+            "user = alice",
+            macaroon.first_party_caveats().nth(1).unwrap().predicate()
+        );
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("user=alice");
+        let key = crypto::generate_derived_key(key);
+        let verifier = builder.build();
+        assert!(!macaroon.verify(&key, &verifier).unwrap());
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("user=alice");
+        builder.set_canonicalizer(canonicalize_whitespace);
+        let verifier = builder.build();
+        assert!(macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[test]
+    fn test_macaroon_any_of_caveat() {
+        let key = b"this is the key";
+        let mut macaroon = Macaroon::create("http://example.org/", key, "keyid").unwrap();
+        macaroon.add_first_party_caveat(&predicate::any_of(&["user = alice", "role = admin"]));
+        let key = crypto::generate_derived_key(key);
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("role = admin");
+        let verifier = builder.build();
+        assert!(macaroon.verify(&key, &verifier).unwrap());
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("user = alice");
+        let verifier = builder.build();
+        assert!(macaroon.verify(&key, &verifier).unwrap());
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("user = bob");
+        let verifier = builder.build();
+        assert!(!macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[test]
+    fn test_macaroon_declarations() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat(&std_caveats::declared("service", "bank"));
+        macaroon.add_third_party_caveat("http://auth.id/", b"this is another key", "id caveat");
+        let mut discharge =
+            Macaroon::create("http://auth.id/", b"this is another key", "id caveat").unwrap();
+        discharge.add_first_party_caveat(&std_caveats::declared("username", "alice"));
+        discharge.add_first_party_caveat(&std_caveats::declared("service", "bank"));
+        macaroon.bind(&mut discharge);
+        let key = MacaroonKey::generate(b"this is the key");
+
+        let verifier = VerifierBuilder::new().build();
+        assert!(!verifier
+            .verify(&macaroon, &key, &[discharge.clone()])
+            .unwrap());
+
+        let mut builder = VerifierBuilder::new();
+        builder.collect_declarations();
+        let verifier = builder.build();
+        assert!(verifier
+            .verify(&macaroon, &key, &[discharge.clone()])
+            .unwrap());
+        let declarations = verifier
+            .verify_declarations(&macaroon, &key, &[discharge], &VerifyContext::new())
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, declarations.len());
+        assert_eq!("alice", declarations["username"]);
+        assert_eq!("bank", declarations["service"]);
+    }
+
+    #[test]
+    fn test_macaroon_conflicting_declarations() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat(&std_caveats::declared("username", "bob"));
+        macaroon.add_third_party_caveat("http://auth.id/", b"this is another key", "id caveat");
+        let mut discharge =
+            Macaroon::create("http://auth.id/", b"this is another key", "id caveat").unwrap();
+        discharge.add_first_party_caveat(&std_caveats::declared("username", "alice"));
+        macaroon.bind(&mut discharge);
+        let key = MacaroonKey::generate(b"this is the key");
+
+        let mut builder = VerifierBuilder::new();
+        builder.collect_declarations();
+        let verifier = builder.build();
+        assert!(!verifier.verify(&macaroon, &key, &[discharge]).unwrap());
+    }
+
+    #[test]
+    fn test_macaroon_operations() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat(&std_caveats::allow(&["read", "write"]));
+        macaroon.add_first_party_caveat(&std_caveats::deny(&["write"]));
+        let key = MacaroonKey::generate(b"this is the key");
+
+        let mut context = VerifyContext::new();
+        let verifier = VerifierBuilder::new().build();
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &context)
+            .unwrap());
+        context.set_operations(&["read"]);
+        assert!(verifier
+            .verify_with_context(&macaroon, &key, &[], &context)
+            .unwrap());
+        context.set_operations(&["read", "write"]);
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &context)
+            .unwrap());
+        context.set_operations(&["delete"]);
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &context)
+            .unwrap());
+    }
+
+    fn acme_region_verifier(caveat: &str, _context: &VerifyContext) -> bool {
+        caveat == "x-acme:region eu-west"
+    }
+
+    #[test]
+    fn test_macaroon_experimental_caveats() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat(&std_caveats::experimental("acme", "region", "eu-west"));
+        macaroon.add_first_party_caveat(&std_caveats::experimental("other", "beta", ""));
+        let key = MacaroonKey::generate(b"this is the key");
+
+        let mut builder = VerifierBuilder::new();
+        builder.register_experimental("acme", acme_region_verifier);
+        builder.satisfy_exact("x-other:beta");
+        let verifier = builder.build();
+        assert!(verifier.verify(&macaroon, &key, &[]).unwrap());
+
+        let mut builder = VerifierBuilder::new();
+        builder.register_experimental("acme", acme_region_verifier);
+        let verifier = builder.build();
+        assert!(!verifier.verify(&macaroon, &key, &[]).unwrap());
+
+        let mut builder = VerifierBuilder::new();
+        builder.register_experimental("acme", acme_region_verifier);
+        builder.ignore_experimental("other");
+        let verifier = builder.build();
+        assert!(verifier.verify(&macaroon, &key, &[]).unwrap());
+
+        let mut builder = VerifierBuilder::new();
+        builder.ignore_experimental("other");
+        builder.satisfy_general(|_, _| true);
+        builder.register_experimental("acme", |_, _| false);
+        let verifier = builder.build();
+        assert!(!verifier.verify(&macaroon, &key, &[]).unwrap());
+    }
+
+    fn tenant_verifier(caveat: &str, context: &VerifyContext) -> bool {
+        match caveat.strip_prefix("tenant = ") {
+            Some(tenant) => context.get("tenant") == Some(tenant),
+            None => false,
+        }
+    }
+
+    fn loopback_verifier(caveat: &str, context: &VerifyContext) -> bool {
+        caveat == "peer = loopback" && context.peer_addr().is_some_and(|a| a.is_loopback())
+    }
+
+    #[test]
+    fn test_macaroon_verify_with_context() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("tenant = acme");
+        macaroon.add_first_party_caveat("peer = loopback");
+        let key = MacaroonKey::generate(b"this is the key");
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_general(tenant_verifier);
+        builder.satisfy_general(loopback_verifier);
+        let verifier = builder.build();
+        assert!(!verifier.verify(&macaroon, &key, &[]).unwrap());
+
+        let mut context = VerifyContext::new();
+        context.insert("tenant", "acme");
+        context.set_peer_addr("127.0.0.1".parse().unwrap());
+        assert!(verifier
+            .verify_with_context(&macaroon, &key, &[], &context)
+            .unwrap());
+
+        let mut other = context.clone();
+        other.insert("tenant", "globex");
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &other)
+            .unwrap());
+        other.insert("tenant", "acme");
+        other.set_peer_addr("192.0.2.1".parse().unwrap());
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &other)
+            .unwrap());
+    }
+
+    #[cfg(feature = "async")]
+    fn async_tenant_verifier<'a>(
+        caveat: &'a str,
+        context: &'a VerifyContext,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        Box::pin(async move { tenant_verifier(caveat, context) })
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_verify_async() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_first_party_caveat(&predicate::any_of(&["role = admin", "tenant = acme"]));
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"this is another key", "bank");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"this is another key", "bank").unwrap();
+        discharge.add_first_party_caveat("tenant = acme");
+        macaroon.bind(&mut discharge);
+        let key = MacaroonKey::generate(b"this is the key");
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_general_async(async_tenant_verifier);
+        let verifier = builder.build();
+        let mut context = VerifyContext::new();
+        context.insert("tenant", "acme");
+        let discharges = [discharge];
+
+        let verify = verifier.verify_async(&macaroon, &key, &discharges, &context);
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&verify);
+        assert!(crate::block_on(verify).unwrap());
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &discharges, &context)
+            .unwrap());
+        assert!(!crate::block_on(verifier.verify_async(&macaroon, &key, &[], &context)).unwrap());
+
+        context.insert("tenant", "globex");
+        assert!(
+            !crate::block_on(verifier.verify_async(&macaroon, &key, &discharges, &context))
+                .unwrap()
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_verify_async_rejected_synchronously() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn counting_verifier<'a>(
+            caveat: &'a str,
+            context: &'a VerifyContext,
+        ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            async_tenant_verifier(caveat, context)
+        }
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_general_async(counting_verifier);
+        let verifier = builder.build();
+        let mut context = VerifyContext::new();
+        context.insert("tenant", "acme");
+        let key = MacaroonKey::generate(b"this is the key");
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("tenant = acme");
+        macaroon.add_first_party_caveat("account = 3735928559");
+
+        // A wrong key, and a discharge which isn't used
+        let other = MacaroonKey::generate(b"this is another key");
+        assert!(!crate::block_on(verifier.verify_async(&macaroon, &other, &[], &context)).unwrap());
+        let unused = Macaroon::create("http://auth.mybank/", b"other key", "other").unwrap();
+        assert!(
+            crate::block_on(verifier.verify_async(&macaroon, &key, &[unused], &context)).is_err()
+        );
+        assert_eq!(0, CALLS.load(Ordering::SeqCst));
+
+        assert!(crate::block_on(verifier.verify_async(&macaroon, &key, &[], &context)).unwrap());
+        assert_eq!(1, CALLS.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_verifier_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Verifier>();
+        assert_send_sync::<Macaroon>();
+
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("tenant = acme");
+        let key = MacaroonKey::generate(b"this is the key");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_general(tenant_verifier);
+        let verifier = builder.build();
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = ["acme", "globex", "acme"]
+                .iter()
+                .map(|tenant| {
+                    let (verifier, macaroon) = (&verifier, &macaroon);
+                    scope.spawn(move || {
+                        let mut context = VerifyContext::new();
+                        context.insert("tenant", tenant);
+                        verifier
+                            .verify_with_context(macaroon, &key, &[], &context)
+                            .unwrap()
+                    })
+                })
+                .collect();
+            let results: Vec<bool> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            assert_eq!(vec![true, false, true], results);
+        });
+    }
+
+    fn after_time_verifier(caveat: &str, _context: &VerifyContext) -> bool {
+        if !caveat.starts_with("time > ") {
+            return false;
+        }
+
+        match time::strptime(&caveat[7..], "%Y-%m-%dT%H:%M") {
+            Ok(compare) => time::now() > compare,
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_macaroon_two_exact_and_one_general_caveat() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_first_party_caveat("user = alice");
+        macaroon.add_first_party_caveat("time > 2010-01-01T00:00");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("user = alice");
+        builder.satisfy_general(after_time_verifier);
+        let key = crypto::generate_derived_key(b"this is the key");
+        let verifier = builder.build();
+        assert!(macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[test]
+    fn test_macaroon_two_exact_and_one_general_fails_general() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_first_party_caveat("user = alice");
+        macaroon.add_first_party_caveat("time > 3010-01-01T00:00");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("user = alice");
+        builder.satisfy_general(after_time_verifier);
+        let key = crypto::generate_derived_key(b"this is the key");
+        let verifier = builder.build();
+        assert!(!macaroon.verify(&key, &verifier).unwrap());
+    }
+
+    #[test]
+    fn test_macaroon_two_exact_and_one_general_incomplete_verifier() {
+        let key = b"this is the key";
+        let mut macaroon = Macaroon::create("http://example.org/", key, "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_first_party_caveat("user = alice");
+        macaroon.add_first_party_caveat("time > 2010-01-01T00:00");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("user = alice");
+        let verifier = builder.build();
+        assert!(!macaroon.verify(key, &verifier).unwrap());
+    }
+
+    #[test]
+    fn test_macaroon_third_party_caveat() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_third_party_caveat(
+            "http://auth.mybank/",
+            b"this is another key",
+            "other keyid",
+        );
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"this is another key", "other keyid").unwrap();
+        discharge.add_first_party_caveat("time > 2010-01-01T00:00");
+        macaroon.bind(&mut discharge);
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_general(after_time_verifier);
+        builder.add_discharge_macaroons(&[discharge]);
+        let root_key = crypto::generate_derived_key(b"this is the key");
+        let verifier = builder.build();
+        assert!(macaroon.verify(&root_key, &verifier).unwrap());
+    }
+
+    #[test]
+    fn test_add_checked_discharge_macaroons() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_third_party_caveat(
+            "http://auth.mybank/",
+            b"this is another key",
+            "other keyid",
+        );
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"this is another key", "other keyid").unwrap();
+        let key = MacaroonKey::generate(b"this is the key");
+        let mut builder = VerifierBuilder::new();
+        match builder.add_checked_discharge_macaroons(&macaroon, &key, &[discharge.clone()]) {
+            Err(MacaroonError::DischargeNotBound { id, .. }) => assert_eq!("other keyid", id),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        macaroon.bind(&mut discharge);
+        builder
+            .add_checked_discharge_macaroons(&macaroon, &key, &[discharge])
+            .unwrap();
+        let verifier = builder.build();
+        assert!(macaroon.verify(key.as_ref(), &verifier).unwrap());
+    }
+
+    #[test]
+    fn test_macaroon_third_party_caveat_undecryptable() {
+        // A caveat whose key is encrypted with something other than the signature chain, as a
+        // broken implementation adding third-party caveats might do
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        let caveat = caveat::new_third_party(
+            "other keyid",
+            crypto::encrypt([0; 32], b"this is another key"),
+            "http://auth.mybank/",
+        );
+        macaroon.signature = Signature(caveat.sign(&macaroon.signature.0));
+        macaroon.caveats.push(Box::new(caveat));
+        let discharge =
+            Macaroon::create("http://auth.mybank/", b"this is another key", "other keyid").unwrap();
+        let verifier = Verifier::default();
+        let key = MacaroonKey::generate(b"this is the key");
+        match verifier.verify(&macaroon, &key, &[discharge]) {
+            Err(MacaroonError::CaveatKeyDecryption {
+                id,
+                location,
+                reason,
+            }) => {
+                assert_eq!("other keyid", id);
+                assert_eq!("http://auth.mybank/", location);
+                assert_eq!("Unknown decryption error", reason);
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_macaroon_third_party_caveat_oversized_key() {
+        // Decrypts, but to a key too long to be an HMAC key
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        let caveat = caveat::new_third_party(
+            "other keyid",
+            crypto::encrypt(macaroon.signature.0, &[1; 64]),
+            "http://auth.mybank/",
+        );
+        macaroon.signature = Signature(caveat.sign(&macaroon.signature.0));
+        macaroon.caveats.push(Box::new(caveat));
+        let discharge =
+            Macaroon::create("http://auth.mybank/", b"this is another key", "other keyid").unwrap();
+        let verifier = Verifier::default();
+        let key = MacaroonKey::generate(b"this is the key");
+        match verifier.verify(&macaroon, &key, &[discharge]) {
+            Err(MacaroonError::CaveatKeyDecryption { reason, .. }) => {
+                assert_eq!("caveat key is longer than 32 bytes", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
     }
 
-    pub fn reset(&mut self) {
-        self.signature = [0; 32];
-        self.id_chain.clear();
+    #[test]
+    fn test_verify_with_discharges() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_third_party_caveat(
+            "http://auth.mybank/",
+            b"this is another key",
+            "other keyid",
+        );
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"this is another key", "other keyid").unwrap();
+        discharge.add_first_party_caveat("time > 2010-01-01T00:00");
+        macaroon.bind(&mut discharge);
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_general(after_time_verifier);
+        let key = MacaroonKey::generate(b"this is the key");
+        let verifier = builder.build();
+        assert!(verifier
+            .verify(&macaroon, &key, &[discharge.clone()])
+            .unwrap());
+        assert!(!verifier.verify(&macaroon, &key, &[]).unwrap());
+
+        let mut unused =
+            Macaroon::create("http://auth.mybank/", b"this is another key", "unused").unwrap();
+        macaroon.bind(&mut unused);
+        match verifier.verify(&macaroon, &key, &[discharge, unused]) {
+            Err(MacaroonError::DischargeNotUsed(id)) => assert_eq!("unused", id),
+            result => panic!("Unexpected result {:?}", result),
+        }
     }
 
-    /// Predicate to satisfy a caveat by exact string match
-    pub fn satisfy_exact(&mut self, predicate: &str) {
-        self.predicates.push(String::from(predicate));
+    #[test]
+    fn test_authorize() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_first_party_caveat("user = alice");
+        let key = MacaroonKey::generate(b"this is the key");
+        let context = VerifyContext::new();
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        let verifier = builder.build();
+        match verifier.authorize(&macaroon, &key, &[], &context) {
+            Err(MacaroonError::CaveatNotSatisfied { caveat, index }) => {
+                assert_eq!("user = alice", caveat);
+                assert_eq!(1, index);
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("user = alice");
+        let verifier = builder.build();
+        assert!(verifier.authorize(&macaroon, &key, &[], &context).is_ok());
+        match verifier.authorize(
+            &macaroon,
+            &MacaroonKey::generate(b"wrong key"),
+            &[],
+            &context,
+        ) {
+            Err(MacaroonError::InvalidSignature) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"caveat key", "other keyid");
+        match verifier.authorize(&macaroon, &key, &[], &context) {
+            Err(MacaroonError::CaveatNotSatisfied { caveat, index }) => {
+                assert_eq!("other keyid", caveat);
+                assert_eq!(2, index);
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
     }
 
-    /// Provides a callback function used to verify a caveat
-    pub fn satisfy_general(&mut self, callback: VerifierCallback) {
-        self.callbacks.push(callback);
+    #[test]
+    fn test_composed_verifiers() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_first_party_caveat("time > 2010-01-01T00:00");
+        let key = MacaroonKey::generate(b"this is the key");
+
+        // The platform checks times and locations, and the endpoint checks accounts
+        let mut baseline = VerifierBuilder::new();
+        baseline.satisfy_general(after_time_verifier);
+        baseline.add_location("http://example.org/");
+        let baseline = baseline.build();
+        let mut endpoint = VerifierBuilder::new();
+        endpoint.satisfy_exact("account = 3735928559");
+        endpoint.ignore_experimental("acme");
+        let endpoint = endpoint.build();
+        assert!(!baseline.verify(&macaroon, &key, &[]).unwrap());
+        assert!(!endpoint.verify(&macaroon, &key, &[]).unwrap());
+
+        // Together they satisfy every caveat, and each one's policies still apply
+        let verifier = Verifier::all_of(vec![baseline.clone(), endpoint.clone()]);
+        assert!(verifier.verify(&macaroon, &key, &[]).unwrap());
+        let mut experimental = macaroon.clone();
+        experimental.add_first_party_caveat(&std_caveats::experimental("acme", "region", "eu"));
+        assert!(verifier.verify(&experimental, &key, &[]).unwrap());
+        let moved = Macaroon::create("http://other.example/", b"this is the key", "keyid").unwrap();
+        match verifier.authorize(&moved, &key, &[], &VerifyContext::new()) {
+            Err(MacaroonError::BadMacaroon(reason)) => {
+                assert_eq!("macaroon has the wrong location", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let mut unknown = macaroon.clone();
+        unknown.add_first_party_caveat("user = alice");
+        match verifier.authorize(&unknown, &key, &[], &VerifyContext::new()) {
+            Err(MacaroonError::CaveatNotSatisfied { caveat, index }) => {
+                assert_eq!("user = alice", caveat);
+                assert_eq!(2, index);
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(1, verifier.dry_run(&unknown, &[]).len());
+
+        // Either one has to authorize the whole macaroon on its own
+        let verifier = Verifier::any_of(vec![baseline.clone(), endpoint.clone()]);
+        assert!(!verifier.verify(&macaroon, &key, &[]).unwrap());
+        let mut account_only =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        account_only.add_first_party_caveat("account = 3735928559");
+        assert!(verifier.verify(&account_only, &key, &[]).unwrap());
+        assert!(Verifier::any_of(vec![verifier.clone(), baseline.clone()])
+            .dry_run(&account_only, &[])
+            .is_empty());
+
+        // Combinations nest
+        let verifier = Verifier::all_of(vec![
+            Verifier::any_of(vec![endpoint.clone()]),
+            Verifier::all_of(vec![baseline]),
+        ]);
+        assert!(verifier.verify(&macaroon, &key, &[]).unwrap());
+        assert!(!Verifier::all_of(vec![])
+            .verify(&macaroon, &key, &[])
+            .unwrap());
+        assert!(!Verifier::any_of(vec![])
+            .verify(&macaroon, &key, &[])
+            .unwrap());
     }
 
-    /// Adds discharge macaroons to the verifier
-    pub fn add_discharge_macaroons(&mut self, discharge_macaroons: &[Macaroon]) {
-        self.discharge_macaroons
-            .extend(discharge_macaroons.to_vec());
+    #[test]
+    fn test_verify_integrity_then_evaluate_caveats() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        discharge.add_first_party_caveat("user = alice");
+        macaroon.bind(&mut discharge);
+        let key = MacaroonKey::generate(b"this is the key");
+
+        // At the relay
+        macaroon.verify_integrity(&key).unwrap();
+        match macaroon.verify_integrity(&MacaroonKey::generate(b"wrong key")) {
+            Err(MacaroonError::InvalidSignature) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // At the policy service
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("user = alice");
+        let verifier = builder.build();
+        let context = VerifyContext::new();
+        let discharges = [discharge];
+        assert!(verifier
+            .evaluate_caveats(&macaroon, &discharges, &context)
+            .unwrap());
+        assert!(!verifier.evaluate_caveats(&macaroon, &[], &context).unwrap());
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        assert!(!builder
+            .build()
+            .evaluate_caveats(&macaroon, &discharges, &context)
+            .unwrap());
+
+        // Signatures aren't checked at all, which is why the relay has to
+        let mut forged = Macaroon::create("http://example.org/", b"forged key", "keyid").unwrap();
+        forged.add_first_party_caveat("account = 3735928559");
+        assert!(verifier.evaluate_caveats(&forged, &[], &context).unwrap());
+        assert!(forged.verify_integrity(&key).is_err());
     }
 
-    pub fn set_signature(&mut self, signature: [u8; 32]) {
-        self.signature = signature;
+    #[test]
+    fn test_checker_combinators() {
+        let key = MacaroonKey::generate(b"this is the key");
+        let verify = |builder: &VerifierBuilder, predicates: &[&str]| {
+            let mut macaroon =
+                Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+            for predicate in predicates {
+                macaroon.add_first_party_caveat(predicate);
+            }
+            builder.verifier.verify(&macaroon, &key, &[]).unwrap()
+        };
+
+        // An admin, or both a team member and a project member, in separate caveats
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_any(vec![
+            Checker::from("role = admin"),
+            Checker::all(["team = core", "project = macaroons"]),
+        ]);
+        assert!(verify(&builder, &["role = admin"]));
+        assert!(verify(&builder, &["team = core", "project = macaroons"]));
+        assert!(verify(&builder, &["project = macaroons", "team = core"]));
+        assert!(!verify(&builder, &["team = core"]));
+        assert!(!verify(&builder, &["project = macaroons"]));
+        assert!(!verify(&builder, &["team = core", "project = other"]));
+        assert!(!verify(&builder, &["role = user"]));
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_all(["team = core", "project = macaroons"]);
+        assert!(verify(&builder, &["team = core", "project = macaroons"]));
+        assert!(!verify(&builder, &["team = core"]));
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_all(Vec::<Checker>::new());
+        builder.satisfy_any(Vec::<Checker>::new());
+        assert!(!verify(&builder, &["role = admin"]));
+
+        // Standing alone, a checker sees the predicate as the only caveat
+        let checker = Checker::all(["team = core", "project = macaroons"]);
+        let context = VerifyContext::new();
+        assert!(!checker.check("team = core", &context));
+        assert!(checker.check_in(
+            "team = core",
+            &["team = core", "project = macaroons"],
+            &context
+        ));
+        assert!(checker.is_met(&["project = macaroons", "team = core"], &context));
+
+        // Exact predicates are canonicalized along with the caveats'
+        let mut builder = VerifierBuilder::new();
+        builder.set_canonicalizer(predicate::canonicalize_whitespace);
+        builder.satisfy_any(["role  =  admin"]);
+        assert!(verify(&builder, &["role = admin"]));
+        let mut builder = VerifierBuilder::new();
+        builder.set_canonicalizer(predicate::canonicalize_whitespace);
+        builder.satisfy_all(["team = core", "project  =  macaroons"]);
+        assert!(verify(&builder, &["team  = core", "project = macaroons"]));
     }
 
-    pub fn update_signature<F>(&mut self, generator: F)
-    where
-        F: Fn(&[u8; 32]) -> [u8; 32],
-    {
-        self.signature = generator(&self.signature);
+    #[test]
+    fn test_incremental_verifier() {
+        let key = MacaroonKey::generate(b"root key");
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        macaroon.add_first_party_caveat("user = alice");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        discharge.add_first_party_caveat("time > 2010-01-01T00:00");
+        macaroon.bind(&mut discharge);
+        let discharges = [discharge];
+        let serialized = macaroon.serialize(crate::Format::V2).unwrap();
+
+        // Fed from a parser, as a streaming one would
+        let verify = |verifier: &Verifier,
+                      discharges: &[Macaroon],
+                      signature: Option<Signature>| {
+            let parsed = crate::MacaroonRef::deserialize(&serialized).unwrap();
+            let context = VerifyContext::new();
+            let mut incremental =
+                IncrementalVerifier::new(verifier, &key, parsed.identifier(), discharges, &context);
+            for caveat in parsed.caveats() {
+                match caveat.verifier_id() {
+                    Some(verifier_id) => incremental.feed_third_party_caveat(
+                        caveat.id(),
+                        verifier_id,
+                        caveat.location().unwrap(),
+                    ),
+                    None => incremental.feed_caveat(caveat.id()),
+                }
+                .unwrap();
+            }
+            incremental.finalize(&signature.unwrap_or(*parsed.signature()))
+        };
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("user = alice");
+        builder.satisfy_general(after_time_verifier);
+        let verifier = builder.build();
+        assert!(verifier.verify(&macaroon, &key, &discharges).unwrap());
+        assert!(verify(&verifier, &discharges, None).unwrap());
+        assert!(!verify(&verifier, &discharges, Some(Signature([0; 32]))).unwrap());
+        assert!(!verify(&verifier, &[], None).unwrap());
+        let unused = Macaroon::create("http://auth.mybank/", b"bank key", "unused").unwrap();
+        let extra = [discharges[0].clone(), unused];
+        assert!(matches!(
+            verify(&verifier, &extra, None),
+            Err(MacaroonError::DischargeNotUsed(_))
+        ));
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_general(after_time_verifier);
+        assert!(!verify(&builder.build(), &discharges, None).unwrap());
+
+        // Policies which need the whole macaroon authorize nothing
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("user = alice");
+        builder.satisfy_general(after_time_verifier);
+        builder.set_max_age(Duration::from_secs(3600));
+        assert!(!verify(&builder.build(), &discharges, None).unwrap());
+
+        // The verifier's limit on caveats is checked as they're fed in
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.set_max_caveats(1);
+        let verifier = builder.build();
+        let context = VerifyContext::new();
+        let mut incremental = IncrementalVerifier::new(&verifier, &key, "keyid", &[], &context);
+        incremental.feed_caveat("account = 3735928559").unwrap();
+        assert!(matches!(
+            incremental.feed_caveat("user = alice"),
+            Err(MacaroonError::BadMacaroon("macaroon has too many caveats"))
+        ));
     }
 
-    pub fn verify_predicate(&self, predicate: &str) -> bool {
-        let mut count = self.predicates.iter().filter(|&p| p == predicate).count();
-        if count > 0 {
-            return true;
-        }
+    #[test]
+    fn test_verify_with_keys() {
+        let keys = [
+            MacaroonKey::generate(b"previous key"),
+            MacaroonKey::generate(b"current key"),
+        ];
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        let verifier = builder.build();
+        let context = VerifyContext::new();
+        let mint = |key: &[u8], predicate: &str| {
+            let mut macaroon = Macaroon::create("http://example.org/", key, "keyid").unwrap();
+            macaroon.add_first_party_caveat(predicate);
+            macaroon
+        };
 
-        count = self
-            .callbacks
+        for (index, key) in [&b"previous key"[..], &b"current key"[..]]
             .iter()
-            .filter(|&callback| callback(predicate))
-            .count();
-        if count > 0 {
-            return true;
+            .enumerate()
+        {
+            let macaroon = mint(key, "account = 3735928559");
+            match verifier.verify_with_keys(&macaroon, &keys, &[], &context) {
+                Ok((matched, Ok(()))) => assert_eq!(index, matched),
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+        let unknown = mint(b"unknown key", "account = 3735928559");
+        assert!(matches!(
+            verifier.verify_with_keys(&unknown, &keys, &[], &context),
+            Err(MacaroonError::InvalidSignature)
+        ));
+        // The key matches, but the caveat isn't satisfied
+        let unauthorized = mint(b"current key", "account = 0");
+        match verifier.verify_with_keys(&unauthorized, &keys, &[], &context) {
+            Ok((1, Err(MacaroonError::CaveatNotSatisfied { index, .. }))) => assert_eq!(0, index),
+            result => panic!("Unexpected result {:?}", result),
         }
+        assert!(matches!(
+            verifier.verify_with_keys(&unauthorized, &[], &[], &context),
+            Err(MacaroonError::InvalidSignature)
+        ));
 
-        false
+        // The keys are personalized with the verifier's domain, as for verify
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.set_domain("a");
+        let verifier = builder.build();
+        let personalized =
+            Macaroon::create_with_key("http://example.org/", &keys[1].with_domain("a"), "keyid")
+                .unwrap();
+        assert!(verifier
+            .verify_with_context(&personalized, &keys[1], &[], &context)
+            .unwrap());
+        assert!(matches!(
+            verifier.verify_with_keys(&personalized, &keys, &[], &context),
+            Ok((1, Ok(())))
+        ));
+        assert!(matches!(
+            verifier.verify_with_keys(
+                &mint(b"current key", "account = 3735928559"),
+                &keys,
+                &[],
+                &context
+            ),
+            Err(MacaroonError::InvalidSignature)
+        ));
     }
 
-    pub fn verify_caveat(
-        &mut self,
-        caveat: &caveat::ThirdPartyCaveat,
-        macaroon: &Macaroon,
-    ) -> Result<bool, MacaroonError> {
-        let dm = self.discharge_macaroons.clone();
-        let dm_opt = dm.iter().find(|dm| *dm.identifier() == caveat.id());
-        match dm_opt {
-            Some(dm) => {
-                if self.id_chain.iter().any(|id| id == dm.identifier()) {
-                    info!(
-                        "Verifier::verify_caveat: caveat verification loop - id {:?} found in \
-                           id chain {:?}",
-                        dm.identifier(),
-                        self.id_chain
-                    );
-                    return Ok(false);
-                }
-                self.id_chain.push(dm.identifier().clone());
-                let key = crypto::decrypt(self.signature, caveat.verifier_id().as_slice())?;
-                dm.verify_as_discharge(self, macaroon, key.as_slice())
+    #[test]
+    fn test_max_caveats_and_discharge_depth() {
+        let key = MacaroonKey::generate(b"root key");
+        let mut root = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        root.add_first_party_caveat("account = 3735928559");
+        root.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let mut bank = Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        bank.add_third_party_caveat("http://auth.other/", b"other key", "other caveat");
+        let mut other =
+            Macaroon::create("http://auth.other/", b"other key", "other caveat").unwrap();
+        other.add_first_party_caveat("account = 3735928559");
+        root.bind(&mut bank);
+        root.bind(&mut other);
+        let discharges = [bank, other];
+
+        let builder = || {
+            let mut builder = VerifierBuilder::new();
+            builder.satisfy_exact("account = 3735928559");
+            builder
+        };
+        assert!(builder().build().verify(&root, &key, &discharges).unwrap());
+
+        let mut limited = builder();
+        limited.set_max_caveats(2);
+        limited.set_max_discharge_depth(2);
+        assert!(limited.build().verify(&root, &key, &discharges).unwrap());
+
+        let mut limited = builder();
+        limited.set_max_caveats(1);
+        match limited
+            .build()
+            .authorize(&root, &key, &discharges, &VerifyContext::new())
+        {
+            Err(MacaroonError::BadMacaroon(reason)) => {
+                assert_eq!("macaroon has too many caveats", reason)
             }
-            None => {
-                info!(
-                    "Verifier::verify_caveat: No discharge macaroon found matching caveat id \
-                       {:?}",
-                    caveat.id()
-                );
-                Ok(false)
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let mut limited = builder();
+        limited.set_max_discharge_depth(1);
+        match limited
+            .build()
+            .authorize(&root, &key, &discharges, &VerifyContext::new())
+        {
+            Err(MacaroonError::BadMacaroon(reason)) => {
+                assert_eq!("discharge chain is too deep", reason)
             }
+            result => panic!("Unexpected result {:?}", result),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::Verifier;
-    use crate::{crypto, Macaroon};
+    #[test]
+    fn test_discharge_requirements() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_483_228_800);
+        let mut context = VerifyContext::new();
+        context.set_time(now);
+        let key = MacaroonKey::generate(b"root key");
+        let mut root = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        root.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let discharge = |predicates: &[String]| {
+            let mut discharge =
+                Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+            for predicate in predicates {
+                discharge.add_first_party_caveat(predicate);
+            }
+            root.bind(&mut discharge);
+            vec![discharge]
+        };
+        let expiring = |secs: u64| std_caveats::time_before(now + Duration::from_secs(secs));
+        fn is_user(predicate: &str, _: &VerifyContext) -> bool {
+            predicate.starts_with("user = ")
+        }
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_general(std_caveats::check_time_before);
+        builder.satisfy_exact("user = alice");
+        builder.require_discharge_caveat(is_user as fn(&str, &VerifyContext) -> bool);
+        builder.set_max_discharge_lifetime(Duration::from_secs(300));
+        let verifier = builder.build();
+
+        let short_lived = discharge(&[expiring(60), String::from("user = alice")]);
+        assert!(verifier
+            .authorize(&root, &key, &short_lived, &context)
+            .is_ok());
+        for (discharges, expected) in [
+            (
+                discharge(&[expiring(60)]),
+                "discharge is missing a required caveat",
+            ),
+            (
+                discharge(&[String::from("user = alice")]),
+                "discharge has no expiry",
+            ),
+            (
+                discharge(&[expiring(3600), String::from("user = alice")]),
+                "discharge expires too late",
+            ),
+        ] {
+            match verifier.authorize(&root, &key, &discharges, &context) {
+                Err(MacaroonError::BadMacaroon(reason)) => assert_eq!(expected, reason),
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+
+        // The root macaroon doesn't need them
+        let mut builder = VerifierBuilder::new();
+        builder.require_discharge_caveat("user = alice");
+        let root = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        assert!(builder.build().verify(&root, &key, &[]).unwrap());
+    }
 
     #[test]
-    fn test_simple_macaroon() {
-        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAyZnNpZ25hdHVyZSB83ueSURxbxvUoSFgF3-myTnheKOKpkwH51xHGCeOO9wo";
-        let macaroon = Macaroon::deserialize(&serialized.as_bytes().to_vec()).unwrap();
-        let mut verifier = Verifier::new();
-        let key = crypto::generate_derived_key(b"this is the key");
-        assert!(macaroon.verify(&key, &mut verifier).unwrap());
+    fn test_satisfy_key_value() {
+        let key = MacaroonKey::generate(b"this is the key");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_key_value("user", "alice");
+        builder.satisfy_number("account", 3735928559);
+        let verifier = builder.build();
+        for (predicate, satisfied) in [
+            ("user = alice", true),
+            ("user=alice", true),
+            ("user  !=  bob", true),
+            ("user = bob", false),
+            ("account = 3735928559", true),
+            ("account < 4000000000", true),
+            ("account >= 3735928559", true),
+            ("account > 3735928559", false),
+            ("account < alice", false),
+            ("user < 5", false),
+            ("role = alice", false),
+            ("alice", false),
+        ] {
+            let mut macaroon =
+                Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+            macaroon.add_first_party_caveat(&predicate::key_value("user", "alice"));
+            macaroon.add_first_party_caveat(predicate);
+            assert_eq!(
+                satisfied,
+                verifier.verify(&macaroon, &key, &[]).unwrap(),
+                "{}",
+                predicate
+            );
+        }
+        assert!(Checker::KeyValue {
+            key: String::from("account"),
+            value: String::from("5"),
+        }
+        .check("account <= 5", &VerifyContext::new()));
     }
 
     #[test]
-    fn test_simple_macaroon_bad_verifier_key() {
-        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAyZnNpZ25hdHVyZSB83ueSURxbxvUoSFgF3-myTnheKOKpkwH51xHGCeOO9wo";
-        let macaroon = Macaroon::deserialize(&serialized.as_bytes().to_vec()).unwrap();
-        let mut verifier = Verifier::new();
-        let key = crypto::generate_derived_key(b"this is not the key");
-        assert!(!macaroon.verify(&key, &mut verifier).unwrap());
+    fn test_dry_run() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("account = 3735928559");
+        macaroon.add_first_party_caveat("user = alice");
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"caveat key", "other keyid");
+        macaroon.add_first_party_caveat("action = read");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"caveat key", "other keyid").unwrap();
+        discharge.add_first_party_caveat("time < 2000-01-01T00:00:00Z");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("action = read");
+        let verifier = builder.build();
+
+        let failures = verifier.dry_run(&macaroon, &[discharge]);
+        let failures: Vec<_> = failures
+            .iter()
+            .map(|failure| match failure {
+                MacaroonError::CaveatNotSatisfied { caveat, index } => (caveat.as_str(), *index),
+                failure => panic!("Unexpected failure {:?}", failure),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("user = alice", 1),
+                ("time < 2000-01-01T00:00:00Z", 0),
+                ("other keyid", 2)
+            ],
+            failures
+        );
+        // Without the discharge, the third-party caveat can't be satisfied either
+        let failures = verifier.dry_run(&macaroon, &[]);
+        assert_eq!(2, failures.len());
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.satisfy_exact("user = alice");
+        builder.satisfy_exact("action = read");
+        builder.set_max_caveats(1);
+        let verifier = builder.build();
+        let discharge =
+            Macaroon::create("http://auth.mybank/", b"caveat key", "other keyid").unwrap();
+        let failures = verifier.dry_run(&macaroon, &[discharge]);
+        assert!(matches!(
+            failures[..],
+            [MacaroonError::BadMacaroon("macaroon has too many caveats")]
+        ));
+        let mut unconstrained = macaroon.clone();
+        unconstrained.caveats.truncate(1);
+        assert!(verifier.dry_run(&unconstrained, &[]).is_empty());
     }
 
     #[test]
-    fn test_macaroon_exact_caveat() {
-        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDJmc2lnbmF0dXJlIPVIB_bcbt-Ivw9zBrOCJWKjYlM9v3M5umF2XaS9JZ2HCg";
-        let macaroon = Macaroon::deserialize(&serialized.as_bytes().to_vec()).unwrap();
-        let mut verifier = Verifier::new();
-        verifier.satisfy_exact("account = 3735928559");
-        let key = crypto::generate_derived_key(b"this is the key");
-        assert!(macaroon.verify(&key, &mut verifier).unwrap());
+    fn test_register_prefix() {
+        fn before_2030(predicate: &str, _: &VerifyContext) -> bool {
+            predicate == "time < 2030-01-01T00:00:00Z"
+        }
+        let key = MacaroonKey::generate(b"this is the key");
+        let mut builder = VerifierBuilder::new();
+        builder.register_prefix("time ", before_2030 as VerifierCallback);
+        builder.register_prefix("account ", Checker::any(["account = 1", "account = 2"]));
+        builder.register_prefix("account = 3", "account = 35");
+        builder.satisfy_exact("time < 2040-01-01T00:00:00Z");
+        builder.satisfy_exact("role = admin");
+        let verifier = builder.build();
+        let verify = |predicate: &str| {
+            let mut macaroon =
+                Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+            macaroon.add_first_party_caveat(predicate);
+            verifier.authorize(&macaroon, &key, &[], &VerifyContext::new())
+        };
+
+        verify("time < 2030-01-01T00:00:00Z").unwrap();
+        verify("account = 2").unwrap();
+        // The longest prefix wins, so this isn't one of the `account ` caveats
+        verify("account = 35").unwrap();
+        verify("role = admin").unwrap();
+        // Routed caveats are only seen by their checkers, so fail as not satisfied
+        for predicate in ["time < 2040-01-01T00:00:00Z", "account = 3"] {
+            match verify(predicate) {
+                Err(MacaroonError::CaveatNotSatisfied { caveat, .. }) => {
+                    assert_eq!(predicate, caveat)
+                }
+                result => panic!("Unexpected result {:?} for {:?}", result, predicate),
+            }
+        }
+        match verify("user = alice") {
+            Err(MacaroonError::UnknownCaveat(prefix)) => assert_eq!("user", prefix),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // Routes in a composed verifier count too
+        let composed = Verifier::all_of([verifier.clone()]);
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat("user = alice");
+        assert!(matches!(
+            composed.authorize(&macaroon, &key, &[], &VerifyContext::new()),
+            Err(MacaroonError::UnknownCaveat(_))
+        ));
     }
 
+    #[cfg(feature = "regex")]
     #[test]
-    fn test_macaroon_exact_caveat_wrong_verifier() {
-        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDJmc2lnbmF0dXJlIPVIB_bcbt-Ivw9zBrOCJWKjYlM9v3M5umF2XaS9JZ2HCg";
-        let macaroon = Macaroon::deserialize(&serialized.as_bytes().to_vec()).unwrap();
-        let mut verifier = Verifier::new();
-        verifier.satisfy_exact("account = 0000000000");
-        let key = crypto::generate_derived_key(b"this is the key");
-        assert!(!macaroon.verify(&key, &mut verifier).unwrap());
+    fn test_satisfy_pattern() {
+        let key = MacaroonKey::generate(b"this is the key");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_pattern(&regex::Regex::new(r"myapp:feature:[a-z-]+").unwrap());
+        builder.satisfy_pattern(&regex::Regex::new("admin|operator").unwrap());
+        let verifier = builder.build();
+        for (predicate, satisfied) in [
+            ("myapp:feature:dark-mode", true),
+            ("myapp:feature:", false),
+            ("myapp:feature:dark-mode; role = admin", false),
+            ("otherapp:myapp:feature:beta", false),
+            ("admin", true),
+            ("operator", true),
+            ("not admin", false),
+            ("administrator", false),
+        ] {
+            let mut macaroon =
+                Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+            macaroon.add_first_party_caveat(predicate);
+            assert_eq!(
+                satisfied,
+                verifier.verify(&macaroon, &key, &[]).unwrap(),
+                "{}",
+                predicate
+            );
+        }
     }
 
     #[test]
-    fn test_macaroon_exact_caveat_wrong_context() {
-        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDJmc2lnbmF0dXJlIPVIB_bcbt-Ivw9zBrOCJWKjYlM9v3M5umF2XaS9JZ2HCg";
-        let macaroon = Macaroon::deserialize(&serialized.as_bytes().to_vec()).unwrap();
-        let mut verifier = Verifier::new();
-        let key = crypto::generate_derived_key(b"this is the key");
-        assert!(!macaroon.verify(&key, &mut verifier).unwrap());
+    fn test_binding_mode() {
+        let key = MacaroonKey::generate(b"root key");
+        let mut macaroon = Macaroon::create("http://example.org/", b"root key", "root").unwrap();
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        for mode in [BindingMode::Libmacaroons, BindingMode::RootSignatureKey] {
+            let mut bound = discharge.clone();
+            macaroon.bind_with_mode(&mut bound, mode);
+            let bound = [bound];
+            let mut builder = VerifierBuilder::new();
+            builder.set_binding_mode(mode);
+            assert!(builder.build().verify(&macaroon, &key, &bound).unwrap());
+            macaroon
+                .check_bindings_with_mode(&key, &bound, mode)
+                .unwrap();
+
+            // Verifying with the other mode fails
+            let other = match mode {
+                BindingMode::Libmacaroons => BindingMode::RootSignatureKey,
+                BindingMode::RootSignatureKey => BindingMode::Libmacaroons,
+            };
+            let mut builder = VerifierBuilder::new();
+            builder.set_binding_mode(other);
+            assert!(!builder.build().verify(&macaroon, &key, &bound).unwrap());
+            assert!(matches!(
+                macaroon.check_bindings_with_mode(&key, &bound, other),
+                Err(MacaroonError::DischargeNotBound { .. })
+            ));
+            assert_eq!(
+                mode == BindingMode::Libmacaroons,
+                Verifier::default().verify(&macaroon, &key, &bound).unwrap()
+            );
+        }
     }
 
     #[test]
-    fn test_macaroon_two_exact_caveats() {
-        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDE1Y2lkIHVzZXIgPSBhbGljZQowMDJmc2lnbmF0dXJlIEvpZ80eoMaya69qSpTumwWxWIbaC6hejEKpPI0OEl78Cg";
-        let macaroon = Macaroon::deserialize(&serialized.as_bytes().to_vec()).unwrap();
-        let mut verifier = Verifier::new();
-        verifier.satisfy_exact("account = 3735928559");
-        verifier.satisfy_exact("user = alice");
-        let key = crypto::generate_derived_key(b"this is the key");
-        assert!(macaroon.verify(&key, &mut verifier).unwrap());
+    fn test_domain() {
+        let key = MacaroonKey::generate(b"shared key");
+        let mut macaroon =
+            Macaroon::create_with_key("http://a.example/", &key.with_domain("a"), "keyid").unwrap();
+        macaroon.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        macaroon.bind(&mut discharge);
+        let discharges = [discharge];
+        let plain = Macaroon::create("http://a.example/", b"shared key", "keyid").unwrap();
+
+        let mut builder = VerifierBuilder::new();
+        builder.set_domain("a");
+        let verifier = builder.build();
+        assert!(verifier.verify(&macaroon, &key, &discharges).unwrap());
+        assert!(!verifier.verify(&plain, &key, &[]).unwrap());
+        // Personalized once, and then reused
+        assert_eq!(1, verifier.personalized.lock().unwrap().len());
+        assert!(verifier.verify(&macaroon, &key, &discharges).unwrap());
+
+        // Another service with the same key, but a different domain or none
+        let mut builder = VerifierBuilder::new();
+        builder.set_domain("b");
+        assert!(!builder
+            .build()
+            .verify(&macaroon, &key, &discharges)
+            .unwrap());
+        assert!(!Verifier::default()
+            .verify(&macaroon, &key, &discharges)
+            .unwrap());
+        assert!(Verifier::default().verify(&plain, &key, &[]).unwrap());
+
+        // Keys personalized for different domains differ from each other and the original
+        assert_ne!(key.with_domain("a"), key.with_domain("b"));
+        assert_ne!(key, key.with_domain(""));
     }
 
     #[test]
-    fn test_macaroon_two_exact_caveats_incomplete_verifier() {
-        let serialized = "MDAyMWxvY2F0aW9uIGh0dHA6Ly9leGFtcGxlLm9yZy8KMDAxNWlkZW50aWZpZXIga2V5aWQKMDAxZGNpZCBhY2NvdW50ID0gMzczNTkyODU1OQowMDE1Y2lkIHVzZXIgPSBhbGljZQowMDJmc2lnbmF0dXJlIEvpZ80eoMaya69qSpTumwWxWIbaC6hejEKpPI0OEl78Cg";
-        let macaroon = Macaroon::deserialize(&serialized.as_bytes().to_vec()).unwrap();
-        let mut verifier = Verifier::new();
-        verifier.satisfy_exact("account = 3735928559");
-        let key = crypto::generate_derived_key(b"this is the key");
-        assert!(!macaroon.verify(&key, &mut verifier).unwrap());
-        let mut verifier = Verifier::new();
-        verifier.satisfy_exact("user = alice");
-        let key = crypto::generate_derived_key(b"this is the key");
-        assert!(!macaroon.verify(&key, &mut verifier).unwrap());
+    fn test_locations() {
+        let key = MacaroonKey::generate(b"root key");
+        let mint = |location: &str| {
+            let mut root = Macaroon::create(location, b"root key", "root").unwrap();
+            root.add_third_party_caveat("https://auth.mybank/", b"bank key", "bank caveat");
+            root
+        };
+        let discharge = |root: &Macaroon, location: &str| {
+            let mut discharge = Macaroon::create(location, b"bank key", "bank caveat").unwrap();
+            root.bind(&mut discharge);
+            vec![discharge]
+        };
+
+        let mut builder = VerifierBuilder::new();
+        builder.add_location("https://api.example.org/");
+        builder.add_location("https://example.org/v1");
+        let verifier = builder.build();
+        let context = VerifyContext::new();
+        for location in &[
+            "https://api.example.org",
+            "HTTPS://API.example.org:443/",
+            "https://example.org/v1/",
+        ] {
+            let root = mint(location);
+            let discharges = discharge(&root, "https://Auth.MyBank:443");
+            assert!(verifier
+                .authorize(&root, &key, &discharges, &context)
+                .is_ok());
+        }
+        for (location, discharge_location, expected) in [
+            (
+                "https://api.example.org:8443",
+                "https://auth.mybank/",
+                "macaroon has the wrong location",
+            ),
+            (
+                "http://api.example.org",
+                "https://auth.mybank/",
+                "macaroon has the wrong location",
+            ),
+            (
+                "https://api.example.org",
+                "https://evil.example/",
+                "discharge has the wrong location",
+            ),
+        ] {
+            let root = mint(location);
+            let discharges = discharge(&root, discharge_location);
+            match verifier.authorize(&root, &key, &discharges, &context) {
+                Err(MacaroonError::BadMacaroon(reason)) => assert_eq!(expected, reason),
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+
+        // Without any locations added, they aren't checked
+        let root = mint("https://elsewhere.example/");
+        let discharges = discharge(&root, "https://evil.example/");
+        assert!(VerifierBuilder::new()
+            .build()
+            .verify(&root, &key, &discharges)
+            .unwrap());
     }
 
-    fn after_time_verifier(caveat: &str) -> bool {
-        if !caveat.starts_with("time > ") {
-            return false;
+    #[test]
+    fn test_verify_batch() {
+        let key = MacaroonKey::generate(b"root key");
+        let mut authorized = Macaroon::create("http://example.org/", b"root key", "one").unwrap();
+        authorized.add_first_party_caveat("account = 3735928559");
+        authorized.add_third_party_caveat("http://auth.mybank/", b"bank key", "bank caveat");
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"bank key", "bank caveat").unwrap();
+        authorized.bind(&mut discharge);
+        let discharges = [discharge];
+        let mut unsatisfied = Macaroon::create("http://example.org/", b"root key", "two").unwrap();
+        unsatisfied.add_first_party_caveat("account = 0");
+        let forged = Macaroon::create("http://example.org/", b"other key", "three").unwrap();
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        let verifier = builder.build();
+        let tokens = [
+            (authorized.clone(), &discharges[..]),
+            (unsatisfied, &[][..]),
+            (forged, &[][..]),
+            // The discharge isn't used, and the one before doesn't leak into this
+            (authorized.clone(), &[][..]),
+            (
+                Macaroon::create("http://example.org/", b"root key", "four").unwrap(),
+                &discharges[..],
+            ),
+        ];
+        let results = verifier.verify_batch(&tokens, &key);
+        assert_eq!(tokens.len(), results.len());
+        for ((root, discharges), result) in tokens.iter().zip(&results) {
+            assert_eq!(
+                format!("{:?}", verifier.verify(root, &key, discharges)),
+                format!("{:?}", result)
+            );
         }
+        assert!(results[0].as_ref().unwrap());
+        assert!(!results[1].as_ref().unwrap());
+        assert!(!results[2].as_ref().unwrap());
+        assert!(!results[3].as_ref().unwrap());
+        assert!(matches!(
+            results[4],
+            Err(MacaroonError::DischargeNotUsed(_))
+        ));
 
-        match time::strptime(&caveat[7..], "%Y-%m-%dT%H:%M") {
-            Ok(compare) => time::now() > compare,
-            Err(_) => false,
+        // Verifiers are shared between threads when verifying in parallel, and the results stay
+        // in the order of the tokens however many there are
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Verifier>();
+        let tokens: Vec<_> = (0..256)
+            .map(|i| {
+                let mut macaroon =
+                    Macaroon::create("http://example.org/", b"root key", &i.to_string()).unwrap();
+                if i % 3 == 0 {
+                    macaroon.add_first_party_caveat("account = 0");
+                }
+                (macaroon, &[][..])
+            })
+            .collect();
+        for (i, result) in verifier.verify_batch(&tokens, &key).into_iter().enumerate() {
+            assert_eq!(i % 3 != 0, result.unwrap(), "{}", i);
         }
     }
 
     #[test]
-    fn test_macaroon_two_exact_and_one_general_caveat() {
+    fn test_max_age() {
+        let issued = UNIX_EPOCH + Duration::from_secs(1_483_228_800);
+        let key = MacaroonKey::generate(b"this is the key");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_general(std_caveats::check_time_before);
+        builder.set_max_age(Duration::from_secs(3600));
+        let verifier = builder.build();
+        let context_at = |secs: u64| {
+            let mut context = VerifyContext::new();
+            context.set_time(issued + Duration::from_secs(secs));
+            context
+        };
+
+        // No issue time at all, even though it hasn't expired
         let mut macaroon =
             Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
-        macaroon.add_first_party_caveat("account = 3735928559");
-        macaroon.add_first_party_caveat("user = alice");
-        macaroon.add_first_party_caveat("time > 2010-01-01T00:00");
-        let mut verifier = Verifier::new();
-        verifier.satisfy_exact("account = 3735928559");
-        verifier.satisfy_exact("user = alice");
-        verifier.satisfy_general(after_time_verifier);
-        let key = crypto::generate_derived_key(b"this is the key");
-        assert!(macaroon.verify(&key, &mut verifier).unwrap());
+        macaroon.add_first_party_caveat(&std_caveats::time_before(
+            issued + Duration::from_secs(86_400),
+        ));
+        match verifier.authorize(&macaroon, &key, &[], &context_at(60)) {
+            Err(MacaroonError::BadMacaroon(reason)) => {
+                assert_eq!("macaroon has no issue time", reason)
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        macaroon.add_first_party_caveat(&std_caveats::issued_at(issued));
+        assert!(verifier
+            .verify_with_context(&macaroon, &key, &[], &context_at(60))
+            .unwrap());
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &context_at(3601))
+            .unwrap());
+
+        // A later issue time added by the holder doesn't make the macaroon any younger
+        let mut refreshed = macaroon.clone();
+        refreshed
+            .add_first_party_caveat(&std_caveats::issued_at(issued + Duration::from_secs(3000)));
+        assert!(!verifier
+            .verify_with_context(&refreshed, &key, &[], &context_at(3601))
+            .unwrap());
+
+        // The issue time can come from the identifier instead
+        let mut builder = VerifierBuilder::new();
+        builder.set_max_age(Duration::from_secs(3600));
+        builder.set_issued_at_extractor(|identifier| {
+            let secs = identifier.strip_prefix("issued ")?.parse().ok()?;
+            Some(UNIX_EPOCH + Duration::from_secs(secs))
+        });
+        let verifier = builder.build();
+        let macaroon = Macaroon::create(
+            "http://example.org/",
+            b"this is the key",
+            "issued 1483228800",
+        )
+        .unwrap();
+        assert!(verifier
+            .verify_with_context(&macaroon, &key, &[], &context_at(60))
+            .unwrap());
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &context_at(3601))
+            .unwrap());
     }
 
     #[test]
-    fn test_macaroon_two_exact_and_one_general_fails_general() {
+    fn test_nonce() {
+        let key = MacaroonKey::generate(b"this is the key");
         let mut macaroon =
             Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
-        macaroon.add_first_party_caveat("account = 3735928559");
-        macaroon.add_first_party_caveat("user = alice");
-        macaroon.add_first_party_caveat("time > 3010-01-01T00:00");
-        let mut verifier = Verifier::new();
-        verifier.satisfy_exact("account = 3735928559");
-        verifier.satisfy_exact("user = alice");
-        verifier.satisfy_general(after_time_verifier);
-        let key = crypto::generate_derived_key(b"this is the key");
-        assert!(!macaroon.verify(&key, &mut verifier).unwrap());
+        macaroon.add_first_party_caveat(&std_caveats::nonce());
+        assert!(!Verifier::default().verify(&macaroon, &key, &[]).unwrap());
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_nonces();
+        assert!(builder.build().verify(&macaroon, &key, &[]).unwrap());
+
+        // Services checking nonces themselves see them as usual
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_nonces();
+        builder.register_prefix(std_caveats::NONCE, "nonce 1234");
+        assert!(!builder.build().verify(&macaroon, &key, &[]).unwrap());
+        let mut builder = VerifierBuilder::new();
+        let nonce = macaroon.first_party_caveats().next().unwrap().predicate();
+        builder.register_prefix(std_caveats::NONCE, nonce.as_str());
+        assert!(builder.build().verify(&macaroon, &key, &[]).unwrap());
     }
 
     #[test]
-    fn test_macaroon_two_exact_and_one_general_incomplete_verifier() {
-        let key = b"this is the key";
-        let mut macaroon = Macaroon::create("http://example.org/", key, "keyid").unwrap();
+    fn test_channel_binding() {
+        let key = MacaroonKey::generate(b"this is the key");
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
+        macaroon.add_first_party_caveat(&std_caveats::tls_exporter(b"exported keying material"));
+        macaroon.add_first_party_caveat(&std_caveats::tls_client_cert([7; 32]));
+        let verifier = Verifier::default();
+
+        let mut context = VerifyContext::new();
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &context)
+            .unwrap());
+        context.set_tls_exporter(b"exported keying material");
+        context.set_client_cert_sha256([7; 32]);
+        assert!(verifier
+            .verify_with_context(&macaroon, &key, &[], &context)
+            .unwrap());
+
+        // Replayed on another connection, or with another certificate
+        let mut other = context.clone();
+        other.set_tls_exporter(b"other keying material");
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &other)
+            .unwrap());
+        let mut other = context.clone();
+        other.set_client_cert_sha256([8; 32]);
+        assert!(!verifier
+            .verify_with_context(&macaroon, &key, &[], &other)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_macaroon_unused_discharge() {
+        let mut macaroon =
+            Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
         macaroon.add_first_party_caveat("account = 3735928559");
-        macaroon.add_first_party_caveat("user = alice");
-        macaroon.add_first_party_caveat("time > 2010-01-01T00:00");
-        let mut verifier = Verifier::new();
-        verifier.satisfy_exact("account = 3735928559");
-        verifier.satisfy_exact("user = alice");
-        assert!(!macaroon.verify(key, &mut verifier).unwrap());
+        let mut discharge =
+            Macaroon::create("http://auth.mybank/", b"this is another key", "other keyid").unwrap();
+        macaroon.bind(&mut discharge);
+        let key = crypto::generate_derived_key(b"this is the key");
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.add_discharge_macaroons(&[discharge.clone()]);
+        let verifier = builder.build();
+        assert!(macaroon.verify(&key, &verifier).is_err());
+
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        builder.add_discharge_macaroons(&[discharge]);
+        builder.set_unused_discharge_policy(UnusedDischargePolicy::Warn);
+        let verifier = builder.build();
+        assert!(macaroon.verify(&key, &verifier).unwrap());
     }
 
     #[test]
-    fn test_macaroon_third_party_caveat() {
+    fn test_verify_discharge_used_twice() {
         let mut macaroon =
             Macaroon::create("http://example.org/", b"this is the key", "keyid").unwrap();
         macaroon.add_third_party_caveat(
@@ -246,15 +3609,17 @@ mod tests {
             b"this is another key",
             "other keyid",
         );
+        macaroon.add_third_party_caveat(
+            "http://auth.mybank/",
+            b"this is another key",
+            "other keyid",
+        );
         let mut discharge =
             Macaroon::create("http://auth.mybank/", b"this is another key", "other keyid").unwrap();
-        discharge.add_first_party_caveat("time > 2010-01-01T00:00");
         macaroon.bind(&mut discharge);
-        let mut verifier = Verifier::new();
-        verifier.satisfy_general(after_time_verifier);
-        verifier.add_discharge_macaroons(&[discharge]);
-        let root_key = crypto::generate_derived_key(b"this is the key");
-        assert!(macaroon.verify(&root_key, &mut verifier).unwrap());
+        let key = MacaroonKey::generate(b"this is the key");
+        let verifier = VerifierBuilder::new().build();
+        assert!(!verifier.verify(&macaroon, &key, &[discharge]).unwrap());
     }
 
     #[test]
@@ -274,10 +3639,11 @@ mod tests {
             "other keyid",
         );
         macaroon.bind(&mut discharge);
-        let mut verifier = Verifier::new();
-        verifier.satisfy_general(after_time_verifier);
-        verifier.add_discharge_macaroons(&[discharge]);
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_general(after_time_verifier);
+        builder.add_discharge_macaroons(&[discharge]);
         let root_key = crypto::generate_derived_key(b"this is the key");
-        assert!(!macaroon.verify(&root_key, &mut verifier).unwrap());
+        let verifier = builder.build();
+        assert!(!macaroon.verify(&root_key, &verifier).unwrap());
     }
 }
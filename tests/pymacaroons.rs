@@ -0,0 +1,56 @@
+//! Macaroons with a third-party caveat and its discharge, minted by pymacaroons (with
+//! `tests/pymacaroons/mint.py`, which CI runs to mint them afresh), deserialized and verified by
+//! this crate.
+use macaroon::{std_caveats, Format, Macaroon, MacaroonKey, Verifier, VerifierBuilder};
+
+const FIXTURE: &str = include_str!("pymacaroons/fixture.txt");
+
+// The root macaroon and its bound discharge, serialized in the version given
+fn fixture(version: &str) -> (Macaroon, Macaroon) {
+    let serialized = |name: &str| {
+        FIXTURE
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("fixture has no {}", name))
+    };
+    let root = Macaroon::deserialize(serialized(version).as_bytes()).unwrap();
+    let discharge =
+        Macaroon::deserialize(serialized(&format!("{}-discharge", version)).as_bytes()).unwrap();
+    (root, discharge)
+}
+
+fn verifier() -> Verifier {
+    let mut builder = VerifierBuilder::new();
+    builder.satisfy_exact("account = 3735928559");
+    builder.satisfy_general(std_caveats::check_time_before);
+    builder.build()
+}
+
+#[test]
+fn test_pymacaroons_discharge() {
+    let key = MacaroonKey::generate(b"this is the key");
+    for (version, format) in [("v1", Format::V1), ("v2", Format::V2)] {
+        let (root, discharge) = fixture(version);
+        assert_eq!(Some("http://example.org/"), root.location().as_deref());
+        let caveat = root.third_party_caveats().next().unwrap();
+        assert_eq!("http://auth.mybank/", caveat.location());
+        assert_eq!("bank caveat", caveat.id());
+        assert_eq!(Some("http://auth.mybank/"), discharge.location().as_deref());
+        assert_eq!("bank caveat", discharge.identifier());
+
+        let discharges = [discharge];
+        assert!(verifier().verify(&root, &key, &discharges).unwrap());
+        // The discharge is needed, and its caveat is checked
+        assert!(!verifier().verify(&root, &key, &[]).unwrap());
+        let mut builder = VerifierBuilder::new();
+        builder.satisfy_exact("account = 3735928559");
+        assert!(!builder.build().verify(&root, &key, &discharges).unwrap());
+        // As is the root macaroon's signature
+        let other = MacaroonKey::generate(b"this is another key");
+        assert!(!verifier().verify(&root, &other, &discharges).unwrap());
+
+        // Serializing again gives what pymacaroons serialized
+        let reserialized = Macaroon::deserialize(&root.serialize(format).unwrap()).unwrap();
+        assert!(verifier().verify(&reserialized, &key, &discharges).unwrap());
+    }
+}
@@ -0,0 +1,52 @@
+#!/usr/bin/env python3
+"""Mints the macaroons in fixture.txt with pymacaroons, for tests/pymacaroons.rs
+
+Needs pymacaroons (and with it PyNaCl) installed, `pip install pymacaroons`. The third-party
+caveat gets a random nonce, as pymacaroons gives it, so the output differs from run to run.
+
+    python3 tests/pymacaroons/mint.py > tests/pymacaroons/fixture.txt
+"""
+import sys
+
+try:
+    import pymacaroons
+    from pymacaroons import Macaroon, MACAROON_V1, MACAROON_V2
+except ImportError:
+    sys.exit("mint.py needs pymacaroons: pip install pymacaroons")
+
+LOCATION = "http://example.org/"
+ROOT_KEY = "this is the key"
+IDENTIFIER = "keyid"
+CAVEAT = "account = 3735928559"
+THIRD_PARTY_LOCATION = "http://auth.mybank/"
+THIRD_PARTY_KEY = "bank key"
+THIRD_PARTY_ID = "bank caveat"
+DISCHARGE_CAVEAT = "time-before 2100-01-01T00:00:00Z"
+
+
+def mint(version):
+    root = Macaroon(location=LOCATION, identifier=IDENTIFIER, key=ROOT_KEY, version=version)
+    root.add_first_party_caveat(CAVEAT)
+    root.add_third_party_caveat(THIRD_PARTY_LOCATION, THIRD_PARTY_KEY, THIRD_PARTY_ID)
+    discharge = Macaroon(
+        location=THIRD_PARTY_LOCATION,
+        identifier=THIRD_PARTY_ID,
+        key=THIRD_PARTY_KEY,
+        version=version,
+    )
+    discharge.add_first_party_caveat(DISCHARGE_CAVEAT)
+    bound = root.prepare_for_request(discharge)
+    return root.serialize(), bound.serialize()
+
+
+def main():
+    version = getattr(pymacaroons, "__version__", "unknown version")
+    print("# Minted by tests/pymacaroons/mint.py with pymacaroons {}".format(version))
+    for name, version in (("v1", MACAROON_V1), ("v2", MACAROON_V2)):
+        root, discharge = mint(version)
+        print("{} {}".format(name, root))
+        print("{}-discharge {}".format(name, discharge))
+
+
+if __name__ == "__main__":
+    main()